@@ -0,0 +1,402 @@
+//! The errors state of a Bento file system.
+//!
+//! A file system enters the errors state when it panics, when an allocation fails, or when it
+//! calls `fs_error`. From then on `dispatch` fails every request that could modify the file
+//! system with `EROFS`, like ext4 mounted with `errors=remount-ro`, while requests that only
+//! read are still passed on. The file system is told through `BentoFilesystem::bento_error`,
+//! and an administrator can read the state from the `trusted.bento.status` extended attribute
+//! of any file.
+//!
+//! Each mount has its own state, which is cleared when it is initialized. `dispatch` records
+//! which task is running a request for which mount, so a panic or error is put on the mount of
+//! the request that caused it, and `bento_error` is called on that mount at its next request.
+//! An error outside of any request, such as in a thread the file system started, can't be traced
+//! back to a mount and puts every mount of the module in the errors state. Up to `MAX_MOUNTS`
+//! instances of a module can be mounted at once.
+//!
+//! Only errors that come back to `dispatch` fail the request with `EIO`. The kernel can't
+//! unwind a Rust panic, so a panic or failed allocation still oopses the task that was running
+//! the request: the request never gets a reply, the locks the task held, in the VFS and in the
+//! file system, are never released, so later requests that need them hang, and with
+//! `panic_on_oops` the whole machine goes down. The mount is in the errors state afterwards,
+//! but it isn't usable. Kernel file systems must return errors, or call `fs_error`, instead of
+//! panicking.
+
+use core::fmt;
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicUsize, Ordering};
+
+use crate::fuse::internal::*;
+use crate::io::KernelDebugWriter;
+use crate::kernel::ffi::current_task_rs;
+use crate::libc;
+
+/// Name of the extended attribute that reports the errors state.
+pub const STATUS_XATTR: &str = "trusted.bento.status";
+
+/// What put the file system in the errors state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCause {
+    /// The file system panicked.
+    Panic,
+    /// A memory allocation failed.
+    OutOfMemory,
+    /// The file system called `fs_error`.
+    Reported,
+}
+
+impl ErrorCause {
+    fn from_u32(val: u32) -> Self {
+        match val {
+            1 => ErrorCause::Panic,
+            2 => ErrorCause::OutOfMemory,
+            _ => ErrorCause::Reported,
+        }
+    }
+
+    fn to_u32(self) -> u32 {
+        match self {
+            ErrorCause::Panic => 1,
+            ErrorCause::OutOfMemory => 2,
+            ErrorCause::Reported => 3,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ErrorCause::Panic => "panic",
+            ErrorCause::OutOfMemory => "oom",
+            ErrorCause::Reported => "reported",
+        }
+    }
+}
+
+/// The errors state of a file system that has hit an error.
+#[derive(Clone, Copy, Debug)]
+pub struct ErrorStatus {
+    /// What caused the first error.
+    pub cause: ErrorCause,
+    /// Errno of the first error.
+    pub errno: libc::c_int,
+    /// Request that was running when the first error happened, or "none". With several requests
+    /// running at once, this is the one that started most recently.
+    pub op: &'static str,
+    /// Number of errors since the file system was mounted.
+    pub count: usize,
+}
+
+impl fmt::Display for ErrorStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "state=errors cause={} op={} errno={} count={}",
+               self.cause.name(), self.op, self.errno, self.count)
+    }
+}
+
+/// Most instances of a file system module that can be mounted at once.
+pub const MAX_MOUNTS: usize = 16;
+
+// Size of the table of tasks running requests, 16 rows of 16.
+const TASK_ROWS: usize = 16;
+
+// The errors state of one mount. Only atomics are used here, since the panic and allocation
+// error handlers update the state and must not allocate or take locks.
+struct MountErrors {
+    // Address of the file system instance the state belongs to, or 0 if the slot is free.
+    owner: AtomicUsize,
+    // Number of init requests for the instance without a destroy. More than one if BentoFS
+    // passes the same instance for several mounts.
+    users: AtomicUsize,
+    errors: AtomicBool,
+    cause: AtomicU32,
+    errno: AtomicI32,
+    error_op: AtomicU32,
+    count: AtomicUsize,
+    // Set when the state changes and `bento_error` hasn't been called yet.
+    pending: AtomicBool,
+    // Opcode of the request that started most recently.
+    current_op: AtomicU32,
+}
+
+impl MountErrors {
+    const fn new() -> Self {
+        MountErrors {
+            owner: AtomicUsize::new(0),
+            users: AtomicUsize::new(0),
+            errors: AtomicBool::new(false),
+            cause: AtomicU32::new(0),
+            errno: AtomicI32::new(0),
+            error_op: AtomicU32::new(0),
+            count: AtomicUsize::new(0),
+            pending: AtomicBool::new(false),
+            current_op: AtomicU32::new(0),
+        }
+    }
+
+    fn read_only(&self) -> bool {
+        self.errors.load(Ordering::SeqCst)
+    }
+
+    fn status(&self) -> Option<ErrorStatus> {
+        if !self.read_only() {
+            return None;
+        }
+        Some(ErrorStatus {
+            cause: ErrorCause::from_u32(self.cause.load(Ordering::SeqCst)),
+            errno: self.errno.load(Ordering::SeqCst),
+            op: op_name(self.error_op.load(Ordering::SeqCst)),
+            count: self.count.load(Ordering::SeqCst),
+        })
+    }
+
+    fn record(&self, cause: ErrorCause, errno: libc::c_int) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        if self.errors.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            self.cause.store(cause.to_u32(), Ordering::SeqCst);
+            self.errno.store(errno, Ordering::SeqCst);
+            self.error_op.store(self.current_op.load(Ordering::SeqCst), Ordering::SeqCst);
+        }
+        self.pending.store(true, Ordering::SeqCst);
+    }
+
+    fn reset(&self) {
+        self.errors.store(false, Ordering::SeqCst);
+        self.cause.store(0, Ordering::SeqCst);
+        self.errno.store(0, Ordering::SeqCst);
+        self.error_op.store(0, Ordering::SeqCst);
+        self.count.store(0, Ordering::SeqCst);
+        self.pending.store(false, Ordering::SeqCst);
+        self.current_op.store(0, Ordering::SeqCst);
+    }
+}
+
+// A task running a request, and the mount the request is for.
+struct RunningTask {
+    // Address of the task, or 0 if the entry is free.
+    task: AtomicUsize,
+    // Index of the mount in `MOUNTS`.
+    mount: AtomicUsize,
+}
+
+impl RunningTask {
+    const fn new() -> Self {
+        RunningTask {
+            task: AtomicUsize::new(0),
+            mount: AtomicUsize::new(0),
+        }
+    }
+}
+
+static MOUNTS: [MountErrors; MAX_MOUNTS] = repeat16!(MountErrors::new());
+static TASKS: [[RunningTask; TASK_ROWS]; TASK_ROWS] = repeat16!(repeat16!(RunningTask::new()));
+
+fn task_entry(idx: usize) -> &'static RunningTask {
+    &TASKS[idx / TASK_ROWS][idx % TASK_ROWS]
+}
+
+fn current_task() -> usize {
+    unsafe { current_task_rs() as usize }
+}
+
+// Index in `TASKS` of the entry for `task`, searching from where it hashes to.
+fn find_task(task: usize) -> Option<usize> {
+    let len = TASK_ROWS * TASK_ROWS;
+    let start = (task / mem::align_of::<usize>()) % len;
+    (0..len)
+        .map(|i| (start + i) % len)
+        .find(|idx| task_entry(*idx).task.load(Ordering::SeqCst) == task)
+}
+
+// The mount whose request the current task is running.
+fn current_mount() -> Option<&'static MountErrors> {
+    let idx = find_task(current_task())?;
+    Some(&MOUNTS[task_entry(idx).mount.load(Ordering::SeqCst)])
+}
+
+fn find_mount(fs: usize) -> Option<usize> {
+    MOUNTS.iter().position(|mount| mount.owner.load(Ordering::SeqCst) == fs)
+}
+
+/// Marks the current task as running a request until dropped.
+pub(crate) struct Entered {
+    task: Option<usize>,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        if let Some(idx) = self.task {
+            task_entry(idx).task.store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Give the file system instance at address `fs` an errors state when it is initialized. An
+/// instance that already has one, because BentoFS passes it for several mounts, shares it.
+/// Fails with `ENOSPC` if `MAX_MOUNTS` instances already have one.
+pub(crate) fn attach(fs: usize) -> Result<(), libc::c_int> {
+    if let Some(idx) = find_mount(fs) {
+        MOUNTS[idx].users.fetch_add(1, Ordering::SeqCst);
+        return Ok(());
+    }
+    for mount in MOUNTS.iter() {
+        if mount.owner.compare_exchange(0, fs, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            mount.reset();
+            mount.users.store(1, Ordering::SeqCst);
+            return Ok(());
+        }
+    }
+    Err(libc::ENOSPC)
+}
+
+/// Drop the errors state of the instance at address `fs` after it is destroyed, or after its
+/// init fails.
+pub(crate) fn detach(fs: usize) {
+    if let Some(idx) = find_mount(fs) {
+        if MOUNTS[idx].users.fetch_sub(1, Ordering::SeqCst) == 1 {
+            MOUNTS[idx].owner.store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Record that the current task is running request `opcode` for the instance at address `fs`,
+/// so the functions here use its errors state.
+///
+/// A task that oopses never drops the result, and its entry stays until a task at the same
+/// address enters again. If the table is full, errors of the request are put on every mount.
+pub(crate) fn enter(fs: usize, opcode: fuse_opcode) -> Entered {
+    let mount = match find_mount(fs) {
+        Some(idx) => idx,
+        None => return Entered { task: None },
+    };
+    MOUNTS[mount].current_op.store(opcode, Ordering::SeqCst);
+    let task = current_task();
+    let idx = find_task(task).or_else(|| {
+        let len = TASK_ROWS * TASK_ROWS;
+        let start = (task / mem::align_of::<usize>()) % len;
+        (0..len).map(|i| (start + i) % len).find(|idx| {
+            task_entry(*idx).task.compare_exchange(0, task, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+        })
+    });
+    if let Some(idx) = idx {
+        task_entry(idx).mount.store(mount, Ordering::SeqCst);
+    }
+    Entered { task: idx }
+}
+
+/// Put the file system in the errors state.
+///
+/// File systems call this when they find an error they can't recover from, such as corrupted
+/// metadata or an aborted journal, and should then fail the request, usually with `EIO`.
+///
+/// Arguments:
+/// * `errno: c_int` - Error code describing the problem.
+pub fn fs_error(errno: libc::c_int) {
+    record(ErrorCause::Reported, errno);
+}
+
+/// Whether the file system is in the errors state, so requests that modify it are refused.
+///
+/// Outside of a request, this is true if any mount of the module is in the errors state.
+pub fn read_only() -> bool {
+    match current_mount() {
+        Some(mount) => mount.read_only(),
+        None => MOUNTS.iter().any(|mount| mount.owner.load(Ordering::SeqCst) != 0 && mount.read_only()),
+    }
+}
+
+/// Get the errors state, or `None` if the file system hasn't hit an error or this isn't called
+/// while handling a request.
+pub fn status() -> Option<ErrorStatus> {
+    current_mount().and_then(|mount| mount.status())
+}
+
+pub(crate) fn record(cause: ErrorCause, errno: libc::c_int) {
+    match current_mount() {
+        Some(mount) => mount.record(cause, errno),
+        None => {
+            for mount in MOUNTS.iter().filter(|mount| mount.owner.load(Ordering::SeqCst) != 0) {
+                mount.record(cause, errno);
+            }
+        }
+    }
+}
+
+pub(crate) fn take_pending() -> bool {
+    match current_mount() {
+        Some(mount) => mount.pending.swap(false, Ordering::SeqCst),
+        None => false,
+    }
+}
+
+/// Write the value of the status attribute into `writer`.
+pub(crate) fn write_status(writer: &mut KernelDebugWriter) {
+    use core::fmt::Write;
+    let _ = match status() {
+        Some(status) => writeln!(writer, "{}", status),
+        None => writeln!(writer, "state=ok"),
+    };
+}
+
+/// Whether a request could modify the file system.
+pub(crate) fn modifies(opcode: fuse_opcode, open_flags: u32) -> bool {
+    match opcode {
+        fuse_opcode_FUSE_SETATTR | fuse_opcode_FUSE_SYMLINK | fuse_opcode_FUSE_MKNOD
+        | fuse_opcode_FUSE_MKDIR | fuse_opcode_FUSE_UNLINK | fuse_opcode_FUSE_RMDIR
+        | fuse_opcode_FUSE_RENAME | fuse_opcode_FUSE_RENAME2 | fuse_opcode_FUSE_LINK
+        | fuse_opcode_FUSE_WRITE | fuse_opcode_FUSE_SETXATTR | fuse_opcode_FUSE_REMOVEXATTR
+        | fuse_opcode_FUSE_CREATE | fuse_opcode_FUSE_FALLOCATE
+        | fuse_opcode_FUSE_COPY_FILE_RANGE => true,
+        fuse_opcode_FUSE_OPEN => {
+            let flags = open_flags as libc::c_int;
+            flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0
+        }
+        _ => false,
+    }
+}
+
+fn op_name(opcode: u32) -> &'static str {
+    match opcode {
+        fuse_opcode_FUSE_LOOKUP => "lookup",
+        fuse_opcode_FUSE_FORGET => "forget",
+        fuse_opcode_FUSE_GETATTR => "getattr",
+        fuse_opcode_FUSE_SETATTR => "setattr",
+        fuse_opcode_FUSE_READLINK => "readlink",
+        fuse_opcode_FUSE_SYMLINK => "symlink",
+        fuse_opcode_FUSE_MKNOD => "mknod",
+        fuse_opcode_FUSE_MKDIR => "mkdir",
+        fuse_opcode_FUSE_UNLINK => "unlink",
+        fuse_opcode_FUSE_RMDIR => "rmdir",
+        fuse_opcode_FUSE_RENAME => "rename",
+        fuse_opcode_FUSE_LINK => "link",
+        fuse_opcode_FUSE_OPEN => "open",
+        fuse_opcode_FUSE_READ => "read",
+        fuse_opcode_FUSE_WRITE => "write",
+        fuse_opcode_FUSE_STATFS => "statfs",
+        fuse_opcode_FUSE_RELEASE => "release",
+        fuse_opcode_FUSE_FSYNC => "fsync",
+        fuse_opcode_FUSE_SETXATTR => "setxattr",
+        fuse_opcode_FUSE_GETXATTR => "getxattr",
+        fuse_opcode_FUSE_LISTXATTR => "listxattr",
+        fuse_opcode_FUSE_REMOVEXATTR => "removexattr",
+        fuse_opcode_FUSE_FLUSH => "flush",
+        fuse_opcode_FUSE_INIT => "init",
+        fuse_opcode_FUSE_OPENDIR => "opendir",
+        fuse_opcode_FUSE_READDIR => "readdir",
+        fuse_opcode_FUSE_RELEASEDIR => "releasedir",
+        fuse_opcode_FUSE_FSYNCDIR => "fsyncdir",
+        fuse_opcode_FUSE_GETLK => "getlk",
+        fuse_opcode_FUSE_SETLK => "setlk",
+        fuse_opcode_FUSE_SETLKW => "setlkw",
+        fuse_opcode_FUSE_ACCESS => "access",
+        fuse_opcode_FUSE_CREATE => "create",
+        fuse_opcode_FUSE_BMAP => "bmap",
+        fuse_opcode_FUSE_DESTROY => "destroy",
+        fuse_opcode_FUSE_RENAME2 => "rename2",
+        fuse_opcode_FUSE_IOCTL => "ioctl",
+        fuse_opcode_FUSE_POLL => "poll",
+        fuse_opcode_FUSE_FALLOCATE => "fallocate",
+        fuse_opcode_FUSE_READDIRPLUS => "readdirplus",
+        fuse_opcode_FUSE_LSEEK => "lseek",
+        fuse_opcode_FUSE_COPY_FILE_RANGE => "copy_file_range",
+        _ => "none",
+    }
+}
@@ -1,10 +1,16 @@
 mod disk;
 pub use self::disk::*;
+pub mod errors;
+pub mod options;
+
+use alloc::boxed::Box;
 
 use libc::ENOSYS;
 
 use crate::fuse::*;
 
+use self::options::{MountOption, MountOptions};
+
 use kernel::ffi::{register_bento_fs,unregister_bento_fs, reregister_bento_fs};
 use kernel::raw;
 
@@ -14,6 +20,9 @@ use std::path::Path;
 use time::Timespec;
 
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+
+pub use crate::serial::TransferState;
 
 pub const BENTO_KERNEL_VERSION: u32 = 1;
 pub const BENTO_KERNEL_MINOR_VERSION: u32 = 0;
@@ -54,6 +63,33 @@ pub trait BentoFilesystem<'de, TransferIn: Send + Deserialize<'de>=i32,TransferO
     /// This must be provided to mount the filesystem.
     fn get_name(&self) -> &'static str;
 
+    /// Create the file system for a new mount.
+    ///
+    /// Bento calls this on the registered file system each time it is mounted and passes every
+    /// request for that mount, starting with `bento_init`, to the returned instance. The
+    /// instance is dropped after the mount's `bento_destroy`, leaving other mounts running.
+    /// State that belongs to one mount, such as allocation hints, must be kept in the instance
+    /// rather than in statics.
+    fn new_instance(&self) -> Box<Self>
+    where
+        Self: core::marker::Sized;
+
+    /// Get the mount options the file system accepts.
+    ///
+    /// Mounting with an option not in this list fails with `EINVAL`.
+    fn mount_options(&self) -> &'static [MountOption] {
+        &[]
+    }
+
+    /// Whether the file system is mounted read-only.
+    ///
+    /// While this returns true, requests that could modify the file system are refused with
+    /// `EROFS`, as in the errors state. A file system can return true when it finds on-disk
+    /// features it can read but not safely write.
+    fn read_only(&self) -> bool {
+        false
+    }
+
     /// Register the filesystem with Bento.
     ///
     /// This should be called when the filesystem module is inserted and before
@@ -61,6 +97,8 @@ pub trait BentoFilesystem<'de, TransferIn: Send + Deserialize<'de>=i32,TransferO
     fn register(&self) -> i32
     where
         Self: core::marker::Sized,
+        TransferIn: DeserializeOwned + TransferState,
+        TransferOut: TransferState,
     {
         return unsafe {
             register_bento_fs(
@@ -75,10 +113,14 @@ pub trait BentoFilesystem<'de, TransferIn: Send + Deserialize<'de>=i32,TransferO
     ///
     /// This should be called when the filesystem module is inserted using the
     /// name of an existing filesystem that has been previously inserted. The existing
-    /// filesystem implementation will be overwritten with the new filesystem.
+    /// filesystem implementation will be overwritten with the new filesystem. Each mount
+    /// gets a new instance, which receives the state of the mount's old instance through
+    /// `bento_update_transfer`.
     fn reregister(&self) -> i32
     where
         Self: core::marker::Sized,
+        TransferIn: DeserializeOwned + TransferState,
+        TransferOut: TransferState,
     {
         return unsafe {
             reregister_bento_fs(
@@ -99,39 +141,75 @@ pub trait BentoFilesystem<'de, TransferIn: Send + Deserialize<'de>=i32,TransferO
         };
     }
 
+    /// Prepare state to hand to a new version of the file system during a live upgrade.
+    ///
+    /// The returned state is encoded with `bento::serial` in an envelope holding
+    /// `TransferOut::NAME` and `TransferOut::VERSION`. The value is not dropped after encoding,
+    /// so any kernel handles it holds (such as a `Journal`) are passed on to the new module.
     fn bento_update_prepare(&mut self) -> Option<TransferOut> {
-    //fn bento_update_prepare(&mut self) -> Option<*const raw::c_void> {
         None
     }
 
-    fn bento_update_transfer(&mut self, Option<TransferIn>) { }
-    //fn bento_update_transfer(&mut self, Option<*const raw::c_void>) { }
+    /// Receive state from the previous version of the file system during a live upgrade.
+    ///
+    /// State whose envelope names a different type or a newer schema version than `TransferIn`
+    /// is rejected before this is called.
+    ///
+    /// Arguments:
+    /// * `state: Option<TransferIn>` - State from the previous module, if it provided any.
+    fn bento_update_transfer(&mut self, _state: Option<TransferIn>) { }
 
     /// Initialize the file system and fill in initialization flags.
     ///
     /// Possible initialization flags are defined /include/uapi/linux/fuse.h.
-    /// No support is provided for readdirplus and async DIO.
+    /// No support is provided for async DIO.
     ///
     /// Arguments:
     /// * `req: &Request` - Request data structure.
     /// * `devname: &OsStr` - Name of the backing device file.
+    /// * `opts: &MountOptions` - Mount options, checked against `mount_options`.
     /// * `fc_info: &mut FuseConnInfo` - Connection information used to pass initialization
     /// arguments to Bento.
     fn bento_init(
         &mut self,
         _req: &Request,
         _devname: &OsStr,
+        _opts: &MountOptions,
         _fc_info: &mut FuseConnInfo,
     ) -> Result<(), i32> {
         return Err(ENOSYS);
     }
 
+    /// Change mount options on a mounted file system.
+    ///
+    /// Called for `mount -o remount` and for a setxattr of `options::REMOUNT_XATTR`. Requests
+    /// may be running at the same time.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `opts: &MountOptions` - The options to change. Options not given keep their values.
+    fn bento_remount(&self, _req: &Request, _opts: &MountOptions) -> Result<(), i32> {
+        return Err(ENOSYS);
+    }
+
     /// Perform any necessary cleanup on the file system.
     ///
     /// Arguments:
     /// * `req: &Request` - Request data structure.
     fn bento_destroy(&mut self, _req: &Request) {}
 
+    /// Handle the file system entering the errors state.
+    ///
+    /// Called once after the file system panics, fails an allocation, or calls
+    /// `errors::fs_error`. Requests that would modify the file system are already refused with
+    /// `EROFS`. In the kernel this runs at the start of the next request, after the task that
+    /// hit the error has been killed. A file system might abort its journal here so the
+    /// interrupted update is never committed.
+    ///
+    /// Arguments:
+    /// * `status: &ErrorStatus` - The errors state.
+    fn bento_error(&self, _status: &errors::ErrorStatus) {}
+
     /// Lookup a directory entry by name and get its attributes.
     ///
     /// If the entry exists, fill `reply` with the attributes.
@@ -671,6 +749,33 @@ pub trait BentoFilesystem<'de, TransferIn: Send + Deserialize<'de>=i32,TransferO
         return reply.error(ENOSYS);
     }
 
+    /// Read directory entries along with their attributes.
+    ///
+    /// This is called instead of `readdir()` if the file system set `FUSE_DO_READDIRPLUS` (or
+    /// `FUSE_READDIRPLUS_AUTO`) in `bento_init`. Each entry fills in the same attributes as a
+    /// `lookup()` would, so listing a directory doesn't need a lookup and getattr per entry. The
+    /// kernel takes a lookup reference on every entry other than "." and "..". The offset
+    /// semantics are the same as for `readdir()`.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino: u64` - Filesystem-provided inode number.
+    /// * `fh: u64` - Filesystem-provided file handle.
+    /// * `offset: i64` - Offset into the directory. From the offset values used in previous
+    /// readdirplus requests.
+    /// * `reply: ReplyDirectoryPlus` - Output data structure for the directory entries and their
+    /// attributes or error value.
+    fn bento_readdirplus(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        reply: ReplyDirectoryPlus,
+    ) {
+        return reply.error(ENOSYS);
+    }
+
     /// Release an open directory
     ///
     /// For every opendir call there will be exactly one releasedir call (unless the filesystem is
@@ -982,4 +1087,159 @@ pub trait BentoFilesystem<'de, TransferIn: Send + Deserialize<'de>=i32,TransferO
     ) {
         return reply.error(ENOSYS);
     }
+
+    /// Ioctl
+    ///
+    /// Note: For unrestricted ioctls, data in and out areas can be discovered by giving iovs and
+    /// setting `FUSE_IOCTL_RETRY` in flags. For restricted ioctls, Bento prepares in/out data area
+    /// according to the information encoded in `cmd`.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino: u64` - Filesystem-provided inode number.
+    /// * `fh: u64` - Filesystem-provided file handle.
+    /// * `flags: u32` - Ioctl flags (`FUSE_IOCTL_*`).
+    /// * `cmd: u32` - Ioctl command.
+    /// * `arg: u64` - Ioctl argument.
+    /// * `in_data: &[u8]` - Data copied in from the caller.
+    /// * `out_size: u32` - Maximum size of the data to copy out to the caller.
+    /// * `reply: ReplyIoctl` - Output data structure for the ioctl result and data or error value.
+    fn bento_ioctl(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _flags: u32,
+        _cmd: u32,
+        _arg: u64,
+        _in_data: &[u8],
+        _out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        return reply.error(ENOSYS);
+    }
+
+    /// Poll for IO readiness
+    ///
+    /// If this request is answered with an error code of `ENOSYS`, this is treated as success
+    /// (with a kernel-defined default poll-mask) and future calls to `poll()` will succeed the
+    /// same way without being sent to the filesystem.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino: u64` - Filesystem-provided inode number.
+    /// * `fh: u64` - Filesystem-provided file handle.
+    /// * `kh: u64` - Kernel poll handle, used to notify the kernel of readiness.
+    /// * `events: u32` - Requested poll events.
+    /// * `flags: u32` - Poll flags (`FUSE_POLL_SCHEDULE_NOTIFY`).
+    /// * `reply: ReplyPoll` - Output data structure for the ready events or error value.
+    fn bento_poll(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _kh: u64,
+        _events: u32,
+        _flags: u32,
+        reply: ReplyPoll,
+    ) {
+        return reply.error(ENOSYS);
+    }
+
+    /// Allocate requested space. If this function returns success then subsequent writes to the
+    /// specified range shall not fail due to the lack of free space on the file system storage
+    /// media.
+    ///
+    /// If this request is answered with an error code of `ENOSYS`, this is treated as a permanent
+    /// failure with error code `EOPNOTSUPP`, i.e. all future `fallocate()` requests will fail with
+    /// `EOPNOTSUPP` without being sent to the filesystem.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino: u64` - Filesystem-provided inode number.
+    /// * `fh: u64` - Filesystem-provided file handle.
+    /// * `offset: i64` - Start of the range to allocate.
+    /// * `length: i64` - Length of the range to allocate.
+    /// * `mode: i32` - Allocation mode (`FALLOC_FL_*`).
+    /// * `reply: ReplyEmpty` - Output data structure for a possible error value.
+    fn bento_fallocate(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _length: i64,
+        _mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        return reply.error(ENOSYS);
+    }
+
+    /// Find next data or hole after the specified offset
+    ///
+    /// Only `SEEK_DATA` and `SEEK_HOLE` are sent to the filesystem. If the offset is at or beyond
+    /// the end of the file, `ENXIO` should be returned.
+    ///
+    /// If this request is answered with an error code of `ENOSYS`, this is treated as a permanent
+    /// failure, i.e. all future `lseek()` requests will fail with the same error code without being
+    /// sent to the filesystem.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino: u64` - Filesystem-provided inode number.
+    /// * `fh: u64` - Filesystem-provided file handle.
+    /// * `offset: i64` - Offset to start searching from.
+    /// * `whence: i32` - `SEEK_DATA` or `SEEK_HOLE`.
+    /// * `reply: ReplyLseek` - Output data structure for the resulting offset or error value.
+    fn bento_lseek(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _whence: i32,
+        reply: ReplyLseek,
+    ) {
+        return reply.error(ENOSYS);
+    }
+
+    /// Copy a range of data from one file to another
+    ///
+    /// Lets the file system copy the data itself, without it passing through the page cache and
+    /// the file system's read and write. The copy may be shorter than `len`; the number of bytes
+    /// copied is replied with `ReplyWrite::written`, and 0 means `offset_in` is at or beyond the
+    /// end of the source file.
+    ///
+    /// If this request is answered with an error code of `ENOSYS`, this is treated as a permanent
+    /// failure with error code `EOPNOTSUPP`, i.e. all future `copy_file_range()` requests will
+    /// fail with `EOPNOTSUPP` without being sent to the filesystem, and callers fall back to
+    /// reading and writing the data.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino_in: u64` - Filesystem-provided inode number of the source file.
+    /// * `fh_in: u64` - Filesystem-provided file handle of the source file.
+    /// * `offset_in: i64` - Offset to copy from.
+    /// * `ino_out: u64` - Filesystem-provided inode number of the destination file.
+    /// * `fh_out: u64` - Filesystem-provided file handle of the destination file.
+    /// * `offset_out: i64` - Offset to copy to.
+    /// * `len: u64` - Number of bytes to copy.
+    /// * `flags: u32` - Flags passed to `copy_file_range()`, currently always 0.
+    /// * `reply: ReplyWrite` - Output data structure for the number of bytes copied or error
+    /// value.
+    fn bento_copy_file_range(
+        &self,
+        _req: &Request,
+        _ino_in: u64,
+        _fh_in: u64,
+        _offset_in: i64,
+        _ino_out: u64,
+        _fh_out: u64,
+        _offset_out: i64,
+        _len: u64,
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        return reply.error(ENOSYS);
+    }
 }
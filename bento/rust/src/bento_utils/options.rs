@@ -0,0 +1,231 @@
+//! Mount options of a Bento file system.
+//!
+//! A file system lists the options it accepts in `BentoFilesystem::mount_options`. The options
+//! given to `mount -o`, a comma-separated list of `name` or `name=value`, are checked against
+//! that list and passed to `bento_init` as a `MountOptions`. An option the file system doesn't
+//! list fails the mount with `EINVAL`. Options bentofs handles itself are removed first.
+//!
+//! Options can be changed on a mounted file system with `mount -o remount,...` or by setting
+//! the `trusted.bento.remount` extended attribute of any file to the new options. Only the
+//! options given are passed to `bento_remount`; the others keep their values.
+
+use alloc::collections::btree_map::{self, BTreeMap};
+use alloc::string::{String, ToString};
+
+use core::fmt;
+
+use crate::libc;
+use crate::std::ffi::OsStr;
+
+/// Name of the extended attribute that remounts the file system with new options.
+pub const REMOUNT_XATTR: &str = "trusted.bento.remount";
+
+/// What a mount option takes as its value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MountOptionKind {
+    /// `name` alone, with no value.
+    Flag,
+    /// `name=on` or `name=off`, also `true`/`false`, `yes`/`no` and `1`/`0`. `name` alone is
+    /// on.
+    Bool,
+    /// `name=N`, in decimal or `0x` hex.
+    Number,
+    /// `name=value`.
+    String,
+}
+
+/// A mount option accepted by a file system.
+#[derive(Clone, Copy, Debug)]
+pub struct MountOption {
+    pub name: &'static str,
+    pub kind: MountOptionKind,
+}
+
+/// The value of a parsed mount option.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MountOptionValue {
+    Flag,
+    Bool(bool),
+    Number(u64),
+    String(String),
+}
+
+/// Errors produced while parsing mount options.
+#[derive(Debug)]
+pub enum MountOptionError {
+    /// The options are not valid UTF-8.
+    InvalidUtf8,
+    /// The file system doesn't accept this option.
+    Unknown(String),
+    /// The option needs a value.
+    MissingValue(&'static str),
+    /// The option is a flag but was given a value.
+    UnexpectedValue(&'static str),
+    /// The value can't be parsed as the option's kind.
+    BadValue(&'static str, String),
+}
+
+impl MountOptionError {
+    /// Errno to return to Bento for this error.
+    pub fn errno(&self) -> libc::c_int {
+        libc::EINVAL
+    }
+}
+
+impl fmt::Display for MountOptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MountOptionError::InvalidUtf8 => write!(f, "mount options are not valid utf-8"),
+            MountOptionError::Unknown(x) => write!(f, "unknown mount option \"{}\"", x),
+            MountOptionError::MissingValue(x) => write!(f, "mount option \"{}\" needs a value", x),
+            MountOptionError::UnexpectedValue(x) => {
+                write!(f, "mount option \"{}\" doesn't take a value", x)
+            }
+            MountOptionError::BadValue(x, val) => {
+                write!(f, "bad value \"{}\" for mount option \"{}\"", val, x)
+            }
+        }
+    }
+}
+
+/// Mount options given to a file system, checked against the options it accepts.
+#[derive(Clone, Debug, Default)]
+pub struct MountOptions {
+    options: BTreeMap<&'static str, MountOptionValue>,
+}
+
+impl MountOptions {
+    /// Create an empty set of options.
+    pub fn new() -> Self {
+        MountOptions {
+            options: BTreeMap::new(),
+        }
+    }
+
+    /// Parse comma-separated `options` against the options in `spec`.
+    ///
+    /// If an option is given more than once, the last value is kept.
+    ///
+    /// Arguments:
+    /// * `options: &OsStr` - The options, such as `commit=10,prov=off`.
+    /// * `spec: &[MountOption]` - The options the file system accepts.
+    pub fn parse(options: &OsStr, spec: &[MountOption]) -> Result<Self, MountOptionError> {
+        let options = options.to_str().ok_or(MountOptionError::InvalidUtf8)?;
+        let mut parsed = MountOptions::new();
+        for option in options.split(',').filter(|x| !x.is_empty()) {
+            let (name, value) = match option.find('=') {
+                Some(idx) => (&option[..idx], Some(&option[idx + 1..])),
+                None => (option, None),
+            };
+            let opt = spec
+                .iter()
+                .find(|x| x.name == name)
+                .ok_or_else(|| MountOptionError::Unknown(name.to_string()))?;
+            let value = match (opt.kind, value) {
+                (MountOptionKind::Flag, None) => MountOptionValue::Flag,
+                (MountOptionKind::Flag, Some(_)) => {
+                    return Err(MountOptionError::UnexpectedValue(opt.name));
+                }
+                (MountOptionKind::Bool, None) => MountOptionValue::Bool(true),
+                (MountOptionKind::Bool, Some(val)) => {
+                    MountOptionValue::Bool(parse_bool(val).ok_or_else(|| {
+                        MountOptionError::BadValue(opt.name, val.to_string())
+                    })?)
+                }
+                (MountOptionKind::Number, Some(val)) => {
+                    MountOptionValue::Number(parse_number(val).ok_or_else(|| {
+                        MountOptionError::BadValue(opt.name, val.to_string())
+                    })?)
+                }
+                (MountOptionKind::String, Some(val)) if !val.is_empty() => {
+                    MountOptionValue::String(val.to_string())
+                }
+                (_, _) => return Err(MountOptionError::MissingValue(opt.name)),
+            };
+            parsed.options.insert(opt.name, value);
+        }
+        Ok(parsed)
+    }
+
+    /// Whether no options were given.
+    pub fn is_empty(&self) -> bool {
+        self.options.is_empty()
+    }
+
+    /// Whether option `name` was given.
+    pub fn contains(&self, name: &str) -> bool {
+        self.options.contains_key(name)
+    }
+
+    /// Get the value of option `name`, if it was given.
+    pub fn get(&self, name: &str) -> Option<&MountOptionValue> {
+        self.options.get(name)
+    }
+
+    /// Whether flag `name` was given.
+    pub fn flag(&self, name: &str) -> bool {
+        self.get(name) == Some(&MountOptionValue::Flag)
+    }
+
+    /// Get the value of bool option `name`, if it was given.
+    pub fn get_bool(&self, name: &str) -> Option<bool> {
+        match self.get(name) {
+            Some(MountOptionValue::Bool(x)) => Some(*x),
+            _ => None,
+        }
+    }
+
+    /// Get the value of number option `name`, if it was given.
+    pub fn get_u64(&self, name: &str) -> Option<u64> {
+        match self.get(name) {
+            Some(MountOptionValue::Number(x)) => Some(*x),
+            _ => None,
+        }
+    }
+
+    /// Get the value of string option `name`, if it was given.
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        match self.get(name) {
+            Some(MountOptionValue::String(x)) => Some(x.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Iterate over the options in name order.
+    pub fn iter(&self) -> btree_map::Iter<'_, &'static str, MountOptionValue> {
+        self.options.iter()
+    }
+}
+
+impl fmt::Display for MountOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (idx, (name, value)) in self.options.iter().enumerate() {
+            if idx != 0 {
+                write!(f, ",")?;
+            }
+            match value {
+                MountOptionValue::Flag => write!(f, "{}", name)?,
+                MountOptionValue::Bool(x) => write!(f, "{}={}", name, if *x { "on" } else { "off" })?,
+                MountOptionValue::Number(x) => write!(f, "{}={}", name, x)?,
+                MountOptionValue::String(x) => write!(f, "{}={}", name, x)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_bool(val: &str) -> Option<bool> {
+    match val {
+        "on" | "true" | "yes" | "1" => Some(true),
+        "off" | "false" | "no" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_number(val: &str) -> Option<u64> {
+    if val.starts_with("0x") || val.starts_with("0X") {
+        u64::from_str_radix(&val[2..], 16).ok()
+    } else {
+        val.parse().ok()
+    }
+}
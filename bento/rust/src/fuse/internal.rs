@@ -42,7 +42,35 @@ pub use bindings::fuse_opcode_FUSE_CREATE;
 pub use bindings::fuse_opcode_FUSE_BMAP;
 pub use bindings::fuse_opcode_FUSE_DESTROY;
 pub use bindings::fuse_opcode_FUSE_RENAME2;
+pub use bindings::fuse_opcode_FUSE_IOCTL;
+pub use bindings::fuse_opcode_FUSE_POLL;
+pub use bindings::fuse_opcode_FUSE_FALLOCATE;
+pub use bindings::fuse_opcode_FUSE_READDIRPLUS;
+pub use bindings::fuse_opcode_FUSE_LSEEK;
 
+// Copy_file_range is newer than the fuse.h of the kernels Bento builds against, so its opcode
+// and argument are defined here instead of generated.
+pub const fuse_opcode_FUSE_COPY_FILE_RANGE: fuse_opcode = 47;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct fuse_copy_file_range_in {
+    pub fh_in: u64,
+    pub off_in: u64,
+    pub nodeid_out: u64,
+    pub fh_out: u64,
+    pub off_out: u64,
+    pub len: u64,
+    pub flags: u64,
+}
+
+pub use bindings::fuse_ioctl_in;
+pub use bindings::fuse_ioctl_out;
+pub use bindings::fuse_poll_in;
+pub use bindings::fuse_poll_out;
+pub use bindings::fuse_fallocate_in;
+pub use bindings::fuse_lseek_in;
+pub use bindings::fuse_lseek_out;
 pub use bindings::fuse_bmap_in;
 pub use bindings::fuse_bmap_out;
 pub use bindings::fuse_lk_in;
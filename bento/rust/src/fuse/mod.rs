@@ -1,4 +1,5 @@
-mod internal;
+pub(crate) mod internal;
+mod notify;
 mod reply;
 mod request;
 
@@ -7,7 +8,9 @@ use time::Timespec;
 pub use self::reply::{ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr, ReplyOpen};
 pub use self::reply::{ReplyWrite, ReplyStatfs, ReplyCreate, ReplyLock, ReplyBmap, ReplyDirectory};
 pub use self::reply::ReplyXattr;
+pub use self::reply::{ReplyIoctl, ReplyPoll, ReplyLseek, ReplyDirectoryPlus};
 pub use self::request::{Request,FuseConnInfo,dispatch};
+pub use self::notify::Notifier;
 
 pub mod consts {
     // Bitmasks for fuse_setattr_in.valid
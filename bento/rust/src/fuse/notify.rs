@@ -0,0 +1,171 @@
+use crate::libc;
+
+use crate::bento_utils::errors::MAX_MOUNTS;
+use crate::std::ffi::OsStr;
+use crate::std::os::unix::ffi::OsStrExt;
+use crate::std::sync::RwLock;
+
+use kernel::ffi::*;
+use kernel::raw;
+
+use alloc::sync::Arc;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+// Longest name the kernel accepts in a notification.
+const FUSE_NAME_MAX: usize = 1024;
+
+// The super block notifications of a mount go to. Set to 0 when the mount is destroyed, and
+// held for reading while a notification runs so the mount can't go away under it.
+struct Target {
+    sb: RwLock<usize>,
+}
+
+// The target of the mount of a file system instance, kept so dispatch can close it.
+struct Slot {
+    // Address of the file system instance, or 0 if the slot is free.
+    owner: AtomicUsize,
+    target: AtomicPtr<Target>,
+}
+
+impl Slot {
+    const fn new() -> Slot {
+        Slot {
+            owner: AtomicUsize::new(0),
+            target: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+}
+
+static SLOTS: [Slot; MAX_MOUNTS] = repeat16!(Slot::new());
+
+/// Give the mount being initialized for the file system instance at address `fs` a notifier
+/// sending to `sb`, valid until `detach`.
+///
+/// Without a super block, or if the instance already has a notifier because BentoFS passes it
+/// for several mounts, the notifier fails with `ENOTCONN`.
+pub(crate) fn attach(fs: usize, sb: *const raw::c_void) -> Notifier {
+    if sb.is_null() || SLOTS.iter().any(|slot| slot.owner.load(Ordering::SeqCst) == fs) {
+        return Notifier::default();
+    }
+    for slot in SLOTS.iter() {
+        if slot.owner.compare_exchange(0, fs, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            let target = Arc::new(Target { sb: RwLock::new(sb as usize) });
+            slot.target.store(Arc::into_raw(Arc::clone(&target)) as *mut Target, Ordering::SeqCst);
+            return Notifier { target: Some(target) };
+        }
+    }
+    Notifier::default()
+}
+
+/// Close the notifier of the instance at address `fs` after it is destroyed, or after its init
+/// fails. Waits for notifications in progress, and later ones fail with `ENODEV`.
+pub(crate) fn detach(fs: usize) {
+    let slot = match SLOTS.iter().find(|slot| slot.owner.load(Ordering::SeqCst) == fs) {
+        Some(slot) => slot,
+        None => return,
+    };
+    let target = slot.target.swap(ptr::null_mut(), Ordering::SeqCst);
+    if !target.is_null() {
+        let target = unsafe { Arc::from_raw(target as *const Target) };
+        *target.sb.write().unwrap() = 0;
+    }
+    slot.owner.store(0, Ordering::SeqCst);
+}
+
+/// Tells the kernel that something it may have cached for a mount has changed.
+///
+/// A file system gets the notifier of its mount from `FuseConnInfo::notifier` in `bento_init`.
+/// Notifiers can be cloned and used from any thread, such as one that watches a server for
+/// changes. Once `bento_destroy` returns, notifications fail with `ENODEV`.
+///
+/// BentoFS passes the mount's `super_block` in the init request, and the notifications call
+/// the `bento_notify_*` functions it exports. Those are looked up when a notification is sent,
+/// so the module loads against a BentoFS without them, and notifications then fail with
+/// `ENOSYS`.
+///
+/// A notification about an inode or directory waits for locks the kernel holds while a request
+/// on it is being handled, so it must not be sent while handling a request on the same inode or
+/// directory, or while holding a lock such a request needs.
+#[derive(Clone, Default)]
+pub struct Notifier {
+    target: Option<Arc<Target>>,
+}
+
+impl Notifier {
+    // Convert the result of a BentoFS call, failing if there is no mount to notify.
+    fn check(&self, f: impl FnOnce(*const raw::c_void) -> i32) -> Result<(), libc::c_int> {
+        let target = match self.target {
+            Some(ref target) => target,
+            None => return Err(libc::ENOTCONN),
+        };
+        let sb = target.sb.read().unwrap();
+        if *sb == 0 {
+            return Err(libc::ENODEV);
+        }
+        match f(*sb as *const raw::c_void) {
+            0 => Ok(()),
+            x => Err(-x),
+        }
+    }
+
+    /// Invalidate the cached attributes of an inode and a range of its cached data.
+    ///
+    /// Fails with `ENOENT` if the kernel doesn't have the inode cached.
+    ///
+    /// Arguments:
+    /// * `ino: u64` - Inode number.
+    /// * `offset: i64` - Start of the range of data. If negative, only the attributes are
+    /// invalidated.
+    /// * `len: i64` - Length of the range of data. If 0 or negative, the range goes to the end of
+    /// the file.
+    pub fn notify_inval_inode(&self, ino: u64, offset: i64, len: i64) -> Result<(), libc::c_int> {
+        self.check(|sb| unsafe { rs_bento_notify_inval_inode(sb, ino, offset, len) })
+    }
+
+    /// Invalidate a cached directory entry, so the next access to it looks it up again.
+    ///
+    /// Fails with `ENOENT` if the kernel doesn't have the directory cached.
+    ///
+    /// Arguments:
+    /// * `parent: u64` - Inode number of the directory.
+    /// * `name: &OsStr` - Name of the entry.
+    pub fn notify_inval_entry(&self, parent: u64, name: &OsStr) -> Result<(), libc::c_int> {
+        self.notify_delete(parent, 0, name)
+    }
+
+    /// Tell the kernel that a directory entry was removed.
+    ///
+    /// This invalidates the entry like `notify_inval_entry`, and if it still points to `child`,
+    /// also removes it from the kernel's caches as an unlink would.
+    ///
+    /// Arguments:
+    /// * `parent: u64` - Inode number of the directory.
+    /// * `child: u64` - Inode number the entry pointed to.
+    /// * `name: &OsStr` - Name of the entry.
+    pub fn notify_delete(&self, parent: u64, child: u64, name: &OsStr) -> Result<(), libc::c_int> {
+        let name = name.as_bytes();
+        if name.len() > FUSE_NAME_MAX {
+            return Err(libc::ENAMETOOLONG);
+        }
+        // A child of 0 only invalidates the entry, as in fuse_reverse_inval_entry.
+        self.check(|sb| unsafe {
+            rs_bento_notify_inval_entry(sb, parent, child, name.as_ptr() as *const raw::c_char, name.len() as u32)
+        })
+    }
+
+    /// Put data into the page cache of an inode, so reads of that range don't have to reach the
+    /// file system.
+    ///
+    /// Fails with `ENOENT` if the kernel doesn't have the inode cached.
+    ///
+    /// Arguments:
+    /// * `ino: u64` - Inode number.
+    /// * `offset: u64` - Offset in the file to put the data.
+    /// * `data: &[u8]` - The data.
+    pub fn notify_store(&self, ino: u64, offset: u64, data: &[u8]) -> Result<(), libc::c_int> {
+        self.check(|sb| unsafe {
+            rs_bento_notify_store(sb, ino, offset, data.as_ptr() as *const raw::c_void, data.len() as u32)
+        })
+    }
+}
@@ -1,4 +1,9 @@
+use core::mem;
+use core::ptr;
+
 use crate::libc;
+use crate::std::ffi::OsStr;
+use crate::std::os::unix::ffi::OsStrExt;
 
 use fuse::internal::*;
 
@@ -21,7 +26,7 @@ use crate::time::Timespec;
 /// `bento_add_direntry` should be passed the offset of the second directory entry).
 pub fn bento_add_direntry(
     buf_slice: &mut [raw::c_uchar],
-    name: &str,
+    name: &OsStr,
     nodeid: u64,
     mode: u16,
     off: u64,
@@ -60,6 +65,70 @@ pub fn bento_add_direntry(
     return Ok(entlen_padded);
 }
 
+/// Add a directory entry with its attributes to the buffer, for readdirplus.
+///
+/// The entry is a `fuse_entry_out` followed by a `fuse_dirent`. Like `bento_add_direntry`, the
+/// entry is not filled in and `EOVERFLOW` is returned if the buffer is too small.
+pub fn bento_add_direntry_plus(
+    buf_slice: &mut [raw::c_uchar],
+    name: &OsStr,
+    entry: &fuse_entry_out,
+    mode: u16,
+    off: u64,
+) -> Result<usize, libc::c_int> {
+    let entry_len = mem::size_of::<fuse_entry_out>();
+    let entlen_padded = fuse_dirent_align(FUSE_NAME_OFFSET + name.len());
+
+    if entry_len + entlen_padded > buf_slice.len() {
+        return Err(libc::EOVERFLOW);
+    }
+
+    unsafe {
+        ptr::copy_nonoverlapping(
+            entry as *const fuse_entry_out as *const raw::c_uchar,
+            buf_slice.as_mut_ptr(),
+            entry_len,
+        );
+    }
+    let dirent_len =
+        bento_add_direntry(&mut buf_slice[entry_len..], name, entry.nodeid, mode, off)?;
+
+    return Ok(entry_len + dirent_len);
+}
+
+/// Fill in a `fuse_entry_out` for `attr`.
+fn fill_entry_out(rep: &mut fuse_entry_out, ttl: &Timespec, attr: &FileAttr, generation: u64) {
+    rep.nodeid = attr.ino;
+    rep.generation = generation;
+    rep.entry_valid = ttl.sec as u64;
+    rep.entry_valid_nsec = ttl.nsec as u32;
+
+    rep.attr_valid = ttl.sec as u64;
+    rep.attr_valid_nsec = ttl.nsec as u32;
+    rep.attr.ino = attr.ino;
+    rep.attr.size = attr.size;
+    rep.attr.blocks = attr.blocks;
+    rep.attr.atime = attr.atime.sec as u64;
+    rep.attr.mtime = attr.mtime.sec as u64;
+    rep.attr.ctime = attr.ctime.sec as u64;
+    rep.attr.atimensec = attr.atime.nsec as u32;
+    rep.attr.mtimensec = attr.mtime.nsec as u32;
+    rep.attr.ctimensec = attr.ctime.nsec as u32;
+    rep.attr.mode = match attr.kind {
+        FileType::NamedPipe => stat::S_IFIFO,
+        FileType::CharDevice => stat::S_IFCHR,
+        FileType::BlockDevice => stat::S_IFBLK,
+        FileType::Directory => stat::S_IFDIR,
+        FileType::RegularFile => stat::S_IFREG,
+        FileType::Symlink => stat::S_IFLNK,
+        FileType::Socket => stat::S_IFSOCK,
+    } as u32 | attr.perm as u32;
+    rep.attr.nlink = attr.nlink;
+    rep.attr.uid = attr.uid;
+    rep.attr.gid = attr.gid;
+    rep.attr.rdev = attr.rdev;
+}
+
 pub type ReplyEntry<'a, 'b> = &'a mut ReplyEntryInternal<'b>;
 
 #[derive(Debug)]
@@ -70,35 +139,7 @@ pub struct ReplyEntryInternal<'a> {
 impl<'a> ReplyEntryInternal<'a> {
     pub fn entry(&mut self, ttl: &Timespec, attr: &FileAttr, generation: u64) {
         if let Ok(rep) = &mut self.reply {
-            rep.nodeid = attr.ino;
-            rep.generation = generation;
-            rep.entry_valid = ttl.sec as u64;
-            rep.entry_valid_nsec = ttl.nsec as u32;
-
-            rep.attr_valid = ttl.sec as u64;
-            rep.attr_valid_nsec = ttl.nsec as u32;
-            rep.attr.ino = attr.ino;
-            rep.attr.size = attr.size;
-            rep.attr.blocks = attr.blocks;
-            rep.attr.atime = attr.atime.sec as u64;
-            rep.attr.mtime = attr.mtime.sec as u64;
-            rep.attr.ctime = attr.ctime.sec as u64;
-            rep.attr.atimensec = attr.atime.nsec as u32;
-            rep.attr.mtimensec = attr.mtime.nsec as u32;
-            rep.attr.ctimensec = attr.ctime.nsec as u32;
-            rep.attr.mode = match attr.kind {
-                FileType::NamedPipe => stat::S_IFIFO,
-                FileType::CharDevice => stat::S_IFCHR,
-                FileType::BlockDevice => stat::S_IFBLK,
-                FileType::Directory => stat::S_IFDIR,
-                FileType::RegularFile => stat::S_IFREG,
-                FileType::Symlink => stat::S_IFLNK,
-                FileType::Socket => stat::S_IFSOCK,
-            } as u32 | attr.perm as u32;
-            rep.attr.nlink = attr.nlink;
-            rep.attr.uid = attr.uid;
-            rep.attr.gid = attr.gid;
-            rep.attr.rdev = attr.rdev;
+            fill_entry_out(rep, ttl, attr, generation);
         }
     }
 
@@ -262,7 +303,7 @@ pub struct ReplyDirectoryInternal<'a> {
 }
 
 impl<'a> ReplyDirectoryInternal<'a> {
-    pub fn add(&mut self, ino: u64, offset: i64, kind: FileType, name: &str) -> bool {
+    pub fn add<T: AsRef<OsStr>>(&mut self, ino: u64, offset: i64, kind: FileType, name: T) -> bool {
         if let Ok(rep) = &mut self.reply {
             let buf = rep.to_slice_mut();
             let buf_slice = &mut buf[self.length..];
@@ -275,7 +316,63 @@ impl<'a> ReplyDirectoryInternal<'a> {
                 FileType::Symlink => stat::S_IFLNK,
                 FileType::Socket => stat::S_IFSOCK,
             };
-            return match bento_add_direntry(buf_slice, name, ino, file_kind, offset as u64) {
+            return match bento_add_direntry(buf_slice, name.as_ref(), ino, file_kind, offset as u64) {
+                Ok(len) => {
+                    self.length += len;
+                    false
+                }
+                Err(libc::EOVERFLOW) => true,
+                _ => false,
+            };
+        }
+        return false;
+    }
+
+    pub fn ok(&mut self) {
+        if let Ok(rep) = &mut self.reply {
+            rep.truncate(self.length);
+        }
+    }
+
+    pub fn error(&mut self, err: i32) {
+        self.reply = Err(err);
+    }
+
+    pub fn reply(&self) -> &Result<&'a mut MemContainer<raw::c_uchar>, i32> {
+        return &self.reply;
+    }
+}
+pub type ReplyDirectoryPlus<'a, 'b> = &'a mut ReplyDirectoryPlusInternal<'b>;
+
+#[derive(Debug)]
+pub struct ReplyDirectoryPlusInternal<'a> {
+    pub reply: Result<&'a mut MemContainer<raw::c_uchar>, i32>,
+    pub length: usize,
+}
+
+impl<'a> ReplyDirectoryPlusInternal<'a> {
+    /// Add an entry and its attributes to the directory reply buffer. Returns true if the
+    /// buffer is full.
+    ///
+    /// The kernel takes a lookup reference on every entry other than "." and "..", so the
+    /// file system should expect a matching forget.
+    pub fn add<T: AsRef<OsStr>>(&mut self, ino: u64, offset: i64, attr: &FileAttr, ttl: &Timespec, name: T) -> bool {
+        if let Ok(rep) = &mut self.reply {
+            let buf = rep.to_slice_mut();
+            let buf_slice = &mut buf[self.length..];
+            let mut entry: fuse_entry_out = unsafe { mem::zeroed() };
+            fill_entry_out(&mut entry, ttl, attr, 0);
+            entry.nodeid = ino;
+            let file_kind = match attr.kind {
+                FileType::NamedPipe => stat::S_IFIFO,
+                FileType::CharDevice => stat::S_IFCHR,
+                FileType::BlockDevice => stat::S_IFBLK,
+                FileType::Directory => stat::S_IFDIR,
+                FileType::RegularFile => stat::S_IFREG,
+                FileType::Symlink => stat::S_IFLNK,
+                FileType::Socket => stat::S_IFSOCK,
+            };
+            return match bento_add_direntry_plus(buf_slice, name.as_ref(), &entry, file_kind, offset as u64) {
                 Ok(len) => {
                     self.length += len;
                     false
@@ -301,46 +398,6 @@ impl<'a> ReplyDirectoryInternal<'a> {
         return &self.reply;
     }
 }
-//impl<'a> ReplyDirectory<'a,'_> {
-//    pub fn add(&mut self, ino: u64, offset: i64, kind: FileType, name: &str) -> bool {
-//        if let Ok(rep) = &mut self.reply {
-//            let buf = rep.to_slice_mut();
-//            let buf_slice = &mut buf[self.length..];
-//            let file_kind = match kind {
-//                FileType::NamedPipe => stat::S_IFIFO,
-//                FileType::CharDevice => stat::S_IFCHR,
-//                FileType::BlockDevice => stat::S_IFBLK,
-//                FileType::Directory => stat::S_IFDIR,
-//                FileType::RegularFile => stat::S_IFREG,
-//                FileType::Symlink => stat::S_IFLNK,
-//                FileType::Socket => stat::S_IFSOCK,
-//            };
-//            return match bento_add_direntry(buf_slice, name, ino, file_kind, offset as u64) {
-//                Ok(len) => {
-//                    self.length += len;
-//                    false
-//                }
-//                Err(libc::EOVERFLOW) => true,
-//                _ => false,
-//            };
-//        }
-//        return false;
-//    }
-//
-//    pub fn ok(&mut self) {
-//        if let Ok(rep) = &mut self.reply {
-//            rep.truncate(self.length);
-//        }
-//    }
-//
-//    pub fn error(&mut self, err: i32) {
-//        self.reply = Err(err);
-//    }
-//
-//    pub fn reply(&self) -> &Result<&mut MemContainer<raw::c_uchar>, i32> {
-//        return &self.reply;
-//    }
-//}
 
 pub type ReplyStatfs<'a, 'b> = &'a mut ReplyStatfsInternal<'b>;
 
@@ -529,3 +586,98 @@ impl<'a> ReplyBmapInternal<'a> {
         return &self.reply;
     }
 }
+
+pub type ReplyIoctl<'a, 'b> = &'a mut ReplyIoctlInternal<'b>;
+
+#[derive(Debug)]
+pub struct ReplyIoctlInternal<'a> {
+    pub reply_arg: Result<&'a mut fuse_ioctl_out, i32>,
+    pub reply_buf: Result<&'a mut MemContainer<raw::c_uchar>, i32>,
+}
+
+impl<'a> ReplyIoctlInternal<'a> {
+    pub fn ioctl(&mut self, result: i32, data: &[u8]) {
+        if let Ok(rep) = &mut self.reply_arg {
+            rep.result = result;
+            rep.flags = 0;
+            rep.in_iovs = 0;
+            rep.out_iovs = 0;
+        }
+        match &mut self.reply_buf {
+            Ok(rep) => {
+                if data.len() > rep.len() {
+                    self.reply_arg = Err(libc::EINVAL);
+                    return;
+                }
+                rep.truncate(data.len());
+                let rep_slice = rep.to_slice_mut();
+                rep_slice.copy_from_slice(data);
+            }
+            // The request had no room for output, so the data would be lost.
+            Err(_) if !data.is_empty() => {
+                self.reply_arg = Err(libc::EINVAL);
+            }
+            Err(_) => {}
+        }
+    }
+
+    pub fn error(&mut self, err: i32) {
+        self.reply_buf = Err(err);
+        self.reply_arg = Err(err);
+    }
+
+    pub fn reply_arg(&self) -> &Result<&'a mut fuse_ioctl_out, i32> {
+        return &self.reply_arg;
+    }
+
+    pub fn reply_buf(&self) -> &Result<&'a mut MemContainer<raw::c_uchar>, i32> {
+        return &self.reply_buf;
+    }
+}
+
+pub type ReplyPoll<'a, 'b> = &'a mut ReplyPollInternal<'b>;
+
+#[derive(Debug)]
+pub struct ReplyPollInternal<'a> {
+    pub reply: Result<&'a mut fuse_poll_out, i32>,
+}
+
+impl<'a> ReplyPollInternal<'a> {
+    pub fn poll(&mut self, revents: u32) {
+        if let Ok(rep) = &mut self.reply {
+            rep.revents = revents;
+            rep.padding = 0;
+        }
+    }
+
+    pub fn error(&mut self, err: i32) {
+        self.reply = Err(err);
+    }
+
+    pub fn reply(&self) -> &Result<&'a mut fuse_poll_out, i32> {
+        return &self.reply;
+    }
+}
+
+pub type ReplyLseek<'a, 'b> = &'a mut ReplyLseekInternal<'b>;
+
+#[derive(Debug)]
+pub struct ReplyLseekInternal<'a> {
+    pub reply: Result<&'a mut fuse_lseek_out, i32>,
+}
+
+impl<'a> ReplyLseekInternal<'a> {
+    pub fn offset(&mut self, offset: i64) {
+        if let Ok(rep) = &mut self.reply {
+            rep.offset = offset as u64;
+        }
+    }
+
+    pub fn error(&mut self, err: i32) {
+        self.reply = Err(err);
+    }
+
+    pub fn reply(&self) -> &Result<&'a mut fuse_lseek_out, i32> {
+        return &self.reply;
+    }
+}
@@ -1,9 +1,12 @@
 use alloc::boxed::Box;
-use core::str;
+use core::mem;
+use core::ptr;
+use core::slice;
 
 use crate::libc;
 
 use crate::std::ffi::OsStr;
+use crate::std::os::unix::ffi::OsStrExt;
 use crate::std::path::Path;
 
 use kernel::kobj::*;
@@ -11,14 +14,27 @@ use kernel::mem::*;
 use kernel::raw;
 use crate::time::Timespec;
 
+use fuse::notify::{self, Notifier};
 use fuse::reply::*;
-use crate::bento_utils::BentoFilesystem;
+use crate::bento_utils::{errors, BentoFilesystem, TransferState};
+use crate::bento_utils::options::{self, MountOptions};
 use fuse::internal::*;
+use crate::io::KernelDebugWriter;
+use crate::serial;
 
-use serde::{Serialize, Deserialize};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 
 const BENTO_UPDATE_PREPARE: u32 = 8192;
 const BENTO_UPDATE_TRANSFER: u32 = 8193;
+const BENTO_REMOUNT: u32 = 8194;
+// Sent to the registered file system when it is mounted. Replies with a new instance in the
+// first out arg, which bentofs passes in place of the registered file system for every later
+// request of the mount. Live upgrades send BENTO_UPDATE_PREPARE to each old instance and
+// BENTO_UPDATE_TRANSFER to the new instance that replaces it.
+const BENTO_MOUNT: u32 = 8195;
+// Sent to an instance after the mount's FUSE_DESTROY to drop it.
+const BENTO_UNMOUNT: u32 = 8196;
 
 #[repr(C)]
 pub struct bento_in_arg {
@@ -64,19 +80,26 @@ pub struct FuseConnInfo {
     pub max_background: u32,
     pub congestion_threshold: u32,
     pub time_gran: u32,
+    notifier: Notifier,
     reserved: [u32; 22],
 }
 
 impl FuseConnInfo {
-    fn from_init_in(inarg: &bento_init_in) -> Self {
+    fn from_init_in(inarg: &bento_init_in, notifier: Notifier) -> Self {
         let mut me: Self = Default::default();
         me.proto_major = inarg.major;
         me.proto_minor = inarg.minor;
         me.max_readahead = inarg.max_readahead;
         me.capable = inarg.flags;
+        me.notifier = notifier;
         me
     }
 
+    /// Returns the `Notifier` of the mount being initialized.
+    pub fn notifier(&self) -> Notifier {
+        self.notifier.clone()
+    }
+
     fn to_init_out(&self, outarg: &mut fuse_init_out) {
         outarg.major = self.proto_major;
         outarg.minor = self.proto_minor;
@@ -130,11 +153,157 @@ struct bento_init_in {
     devname: CStr,
 }
 
-pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Serialize, T: BentoFilesystem<'de, TransferIn, TransferOut>>(
+// Init request of a BentoFS that also passes mount options and the super block. BentoFS sets
+// the size of the init argument to the size of the struct it sends, so these fields are only
+// read when the argument is big enough to hold them. Older BentoFS sends a `bento_init_in`.
+#[repr(C)]
+struct bento_init_in_ext {
+    base: bento_init_in,
+    options: CStr,
+    // Super block of the mount, passed back to BentoFS with notifications.
+    sb: *const raw::c_void,
+}
+
+pub fn dispatch<'de, TransferIn: Send + DeserializeOwned + TransferState, TransferOut: Send + Serialize + TransferState, T: BentoFilesystem<'de, TransferIn, TransferOut>>(
     fs: &'static mut T,
     opcode: fuse_opcode,
     inarg: &bento_in,
     outarg: &mut bento_out,
+) -> i32 {
+    match opcode {
+        BENTO_MOUNT => return mount(fs, outarg),
+        BENTO_UNMOUNT => return unmount(fs),
+        _ => {}
+    }
+    let instance = &*fs as *const T as usize;
+    if opcode == fuse_opcode_FUSE_INIT {
+        if let Err(x) = errors::attach(instance) {
+            println!("bento: {}: more than {} mounts", fs.get_name(), errors::MAX_MOUNTS);
+            return -x;
+        }
+    }
+    let entered = errors::enter(instance, opcode);
+    if errors::take_pending() {
+        report_error(fs);
+    }
+    if opcode == fuse_opcode_FUSE_SETXATTR && inarg.numargs == 3 {
+        let name = unsafe { CStr::from_raw(inarg.args[1].value as *const raw::c_char) };
+        if name.to_bytes_with_nul() == options::REMOUNT_XATTR.as_bytes() {
+            let req = Request { h: &inarg.h };
+            let value_in =
+                unsafe { &*(inarg.args[2].value as *const MemContainer<raw::c_uchar>) };
+            return remount(fs, &req, OsStr::from_bytes(value_in.to_slice()));
+        }
+    }
+    if (errors::read_only() || fs.read_only()) && errors::modifies(opcode, open_flags(opcode, inarg)) {
+        return -libc::EROFS;
+    }
+    if opcode == fuse_opcode_FUSE_GETXATTR && inarg.numargs == 2 && outarg.numargs == 1 {
+        let name = unsafe { CStr::from_raw(inarg.args[1].value as *const raw::c_char) };
+        if name.to_bytes_with_nul() == errors::STATUS_XATTR.as_bytes() {
+            return get_status_xattr(inarg, outarg);
+        }
+    }
+
+    let ret = dispatch_op(fs, opcode, inarg, outarg);
+    if errors::take_pending() {
+        report_error(fs);
+    }
+    drop(entered);
+    if opcode == fuse_opcode_FUSE_DESTROY || (opcode == fuse_opcode_FUSE_INIT && ret != 0) {
+        errors::detach(instance);
+        notify::detach(instance);
+    }
+    ret
+}
+
+// Create an instance of the registered file system `fs` for a new mount.
+fn mount<'de, TransferIn: Send + DeserializeOwned + TransferState, TransferOut: Send + Serialize + TransferState, T: BentoFilesystem<'de, TransferIn, TransferOut>>(
+    fs: &T,
+    outarg: &mut bento_out,
+) -> i32 {
+    if outarg.numargs != 1 {
+        return -1;
+    }
+    let instance = Box::into_raw(fs.new_instance());
+    outarg.args[0].value = instance as *const raw::c_void;
+    0
+}
+
+// Drop the instance `fs` of an unmounted file system.
+fn unmount<T>(fs: &'static mut T) -> i32 {
+    // Notifiers may outlive the instance; make sure they can't reach its mount.
+    notify::detach(&*fs as *const T as usize);
+    drop(unsafe { Box::from_raw(fs as *mut T) });
+    0
+}
+
+fn report_error<'de, TransferIn: Send + DeserializeOwned + TransferState, TransferOut: Send + Serialize + TransferState, T: BentoFilesystem<'de, TransferIn, TransferOut>>(
+    fs: &mut T,
+) {
+    if let Some(status) = errors::status() {
+        println!("bento: {}: file system error, now read-only: {}", fs.get_name(), status);
+        fs.bento_error(&status);
+    }
+}
+
+// Change mount options to `opts`.
+fn remount<'de, TransferIn: Send + DeserializeOwned + TransferState, TransferOut: Send + Serialize + TransferState, T: BentoFilesystem<'de, TransferIn, TransferOut>>(
+    fs: &T,
+    req: &Request,
+    opts: &OsStr,
+) -> i32 {
+    let opts = match MountOptions::parse(opts, fs.mount_options()) {
+        Ok(opts) => opts,
+        Err(x) => {
+            println!("bento: {}: remount failed: {}", fs.get_name(), x);
+            return -x.errno();
+        }
+    };
+    match fs.bento_remount(req, &opts) {
+        Ok(()) => 0,
+        Err(x) => -x,
+    }
+}
+
+fn open_flags(opcode: fuse_opcode, inarg: &bento_in) -> u32 {
+    if opcode != fuse_opcode_FUSE_OPEN || inarg.numargs != 1 {
+        return 0;
+    }
+    let open_in = unsafe { &*(inarg.args[0].value as *const fuse_open_in) };
+    open_in.flags
+}
+
+// Reply to a getxattr of `errors::STATUS_XATTR`.
+fn get_status_xattr(inarg: &bento_in, outarg: &mut bento_out) -> i32 {
+    let getxattr_in = unsafe { &*(inarg.args[0].value as *const fuse_getxattr_in) };
+    let mut writer = KernelDebugWriter::new();
+    errors::write_status(&mut writer);
+    let value = writer.as_bytes();
+    if getxattr_in.size != 0 && (getxattr_in.size as usize) < value.len() {
+        return -libc::ERANGE;
+    }
+    let mut reply = if outarg.argvar == 1 {
+        ReplyXattrInternal {
+            reply_arg: Err(libc::ENOSYS),
+            reply_buf: Ok(unsafe { &mut *(outarg.args[0].value as *mut MemContainer<raw::c_uchar>) }),
+        }
+    } else {
+        ReplyXattrInternal {
+            reply_arg: Ok(unsafe { &mut *(outarg.args[0].value as *mut fuse_getxattr_out) }),
+            reply_buf: Err(libc::ENOSYS),
+        }
+    };
+    reply.size(value.len() as u32);
+    reply.data(value);
+    0
+}
+
+fn dispatch_op<'de, TransferIn: Send + DeserializeOwned + TransferState, TransferOut: Send + Serialize + TransferState, T: BentoFilesystem<'de, TransferIn, TransferOut>>(
+    fs: &mut T,
+    opcode: fuse_opcode,
+    inarg: &bento_in,
+    outarg: &mut bento_out,
 ) -> i32 {
     match opcode {
         fuse_opcode_FUSE_INIT => {
@@ -145,15 +314,38 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
             let req = Request { h: &inarg.h };
 
             let init_in = unsafe { &*(inarg.args[0].value as *const bento_init_in) };
+            let init_ext = if inarg.args[0].size as usize >= mem::size_of::<bento_init_in_ext>() {
+                Some(unsafe { &*(inarg.args[0].value as *const bento_init_in_ext) })
+            } else {
+                None
+            };
             let init_out = unsafe { &mut *(outarg.args[0].value as *mut fuse_init_out) };
-            let mut fc_info = FuseConnInfo::from_init_in(&init_in);
-            let devname_str = if init_in.devname.to_raw().is_null() {
-                ""
+            let sb = match init_ext {
+                Some(ext) => ext.sb,
+                None => ptr::null(),
+            };
+            let instance = &*fs as *const T as usize;
+            let mut fc_info = FuseConnInfo::from_init_in(&init_in, notify::attach(instance, sb));
+            let devname = if init_in.devname.to_raw().is_null() {
+                OsStr::new("")
             } else {
-                str::from_utf8(init_in.devname.to_bytes_with_nul()).unwrap()
+                OsStr::from_bytes(init_in.devname.to_bytes_with_nul())
+            };
+            // Without the extended request, the file system is mounted with no options.
+            let options = match init_ext {
+                Some(ext) if !ext.options.to_raw().is_null() => {
+                    OsStr::from_bytes(ext.options.to_bytes_with_nul())
+                }
+                _ => OsStr::new(""),
             };
-            let devname = OsStr::new(devname_str);
-            match fs.bento_init(&req, devname, &mut fc_info) {
+            let opts = match MountOptions::parse(options, fs.mount_options()) {
+                Ok(opts) => opts,
+                Err(x) => {
+                    println!("bento: {}: {}", fs.get_name(), x);
+                    return -x.errno();
+                }
+            };
+            match fs.bento_init(&req, devname, &opts, &mut fc_info) {
                 Ok(()) => {
                     fc_info.to_init_out(init_out);
                     0
@@ -176,7 +368,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
             let mut reply = ReplyEntryInternal {
                 reply: Ok(entry_out),
             };
-            let name_str = OsStr::new(str::from_utf8(name.to_bytes_with_nul()).unwrap());
+            let name_str = OsStr::from_bytes(name.to_bytes_with_nul());
             fs.bento_lookup(&req, inarg.h.nodeid, name_str, &mut reply);
             match reply.reply() {
                 Ok(_) => 0,
@@ -291,11 +483,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
             };
             fs.bento_readlink(&req, inarg.h.nodeid, &mut reply);
             match reply.reply() {
-                Ok(buf) => {
-                    let buf_slice = buf.to_slice();
-                    let buf_str = str::from_utf8(buf_slice).unwrap_or("");
-                    buf_str.len() as i32
-                }
+                Ok(buf) => buf.to_slice().len() as i32,
                 Err(x) => -*x,
             }
         }
@@ -306,7 +494,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
             let req = Request { h: &inarg.h };
             let mknod_in = unsafe { &*(inarg.args[0].value as *const fuse_mknod_in) };
             let name = unsafe { CStr::from_raw(inarg.args[1].value as *const raw::c_char) };
-            let name_str = OsStr::new(str::from_utf8(name.to_bytes_with_nul()).unwrap());
+            let name_str = OsStr::from_bytes(name.to_bytes_with_nul());
             let entry_out = unsafe { &mut *(outarg.args[0].value as *mut fuse_entry_out) };
             let mut reply = ReplyEntryInternal {
                 reply: Ok(entry_out),
@@ -331,7 +519,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
             let req = Request { h: &inarg.h };
             let mkdir_in = unsafe { &*(inarg.args[0].value as *const fuse_mkdir_in) };
             let name = unsafe { CStr::from_raw(inarg.args[1].value as *const raw::c_char) };
-            let name_str = OsStr::new(str::from_utf8(name.to_bytes_with_nul()).unwrap());
+            let name_str = OsStr::from_bytes(name.to_bytes_with_nul());
             let entry_out = unsafe { &mut *(outarg.args[0].value as *mut fuse_entry_out) };
             let mut reply = ReplyEntryInternal {
                 reply: Ok(entry_out),
@@ -348,7 +536,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
             }
             let req = Request { h: &inarg.h };
             let name = unsafe { CStr::from_raw(inarg.args[0].value as *const raw::c_char) };
-            let name_str = OsStr::new(str::from_utf8(name.to_bytes_with_nul()).unwrap());
+            let name_str = OsStr::from_bytes(name.to_bytes_with_nul());
             let mut reply = ReplyEmptyInternal {
                 reply: Err(libc::ENOSYS),
             };
@@ -364,7 +552,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
             }
             let req = Request { h: &inarg.h };
             let name = unsafe { CStr::from_raw(inarg.args[0].value as *const raw::c_char) };
-            let name_str = OsStr::new(str::from_utf8(name.to_bytes_with_nul()).unwrap());
+            let name_str = OsStr::from_bytes(name.to_bytes_with_nul());
             let mut reply = ReplyEmptyInternal {
                 reply: Err(libc::ENOSYS),
             };
@@ -381,9 +569,9 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
 
             let req = Request { h: &inarg.h };
             let name = unsafe { CStr::from_raw(inarg.args[0].value as *const raw::c_char) };
-            let name_str = OsStr::new(str::from_utf8(name.to_bytes_with_nul()).unwrap());
+            let name_str = OsStr::from_bytes(name.to_bytes_with_nul());
             let link = unsafe { CStr::from_raw(inarg.args[1].value as *const raw::c_char) };
-            let link_path = Path::new(str::from_utf8(link.to_bytes_with_nul()).unwrap());
+            let link_path = Path::new(OsStr::from_bytes(link.to_bytes_with_nul()));
             let entry_out = unsafe { &mut *(outarg.args[0].value as *mut fuse_entry_out) };
             let mut reply = ReplyEntryInternal {
                 reply: Ok(entry_out),
@@ -401,9 +589,9 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
             let req = Request { h: &inarg.h };
             let rename_in = unsafe { &*(inarg.args[0].value as *const fuse_rename2_in) };
             let oldname = unsafe { CStr::from_raw(inarg.args[1].value as *const raw::c_char) };
-            let oldname_str = OsStr::new(str::from_utf8(oldname.to_bytes_with_nul()).unwrap());
+            let oldname_str = OsStr::from_bytes(oldname.to_bytes_with_nul());
             let newname = unsafe { CStr::from_raw(inarg.args[2].value as *const raw::c_char) };
-            let newname_str = OsStr::new(str::from_utf8(newname.to_bytes_with_nul()).unwrap());
+            let newname_str = OsStr::from_bytes(newname.to_bytes_with_nul());
             let mut reply = ReplyEmptyInternal {
                 reply: Err(libc::ENOSYS),
             };
@@ -429,7 +617,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
             let req = Request { h: &inarg.h };
             let link_in = unsafe { &*(inarg.args[0].value as *const fuse_link_in) };
             let name = unsafe { CStr::from_raw(inarg.args[1].value as *const raw::c_char) };
-            let name_str = OsStr::new(str::from_utf8(name.to_bytes_with_nul()).unwrap());
+            let name_str = OsStr::from_bytes(name.to_bytes_with_nul());
             let entry_out = unsafe { &mut *(outarg.args[0].value as *mut fuse_entry_out) };
             let mut reply = ReplyEntryInternal {
                 reply: Ok(entry_out),
@@ -628,6 +816,34 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 Err(x) => -*x,
             }
         }
+        fuse_opcode_FUSE_READDIRPLUS => {
+            if inarg.numargs != 1 || outarg.numargs != 1 {
+                return -1;
+            }
+
+            let req = Request { h: &inarg.h };
+            let read_in = unsafe { &*(inarg.args[0].value as *const fuse_read_in) };
+            let data_out =
+                unsafe { &mut *(outarg.args[0].value as *mut MemContainer<raw::c_uchar>) };
+            let mut reply = ReplyDirectoryPlusInternal {
+                reply: Ok(data_out),
+                length: 0,
+            };
+            fs.bento_readdirplus(
+                &req,
+                inarg.h.nodeid,
+                read_in.fh,
+                read_in.offset as i64,
+                &mut reply,
+            );
+            match reply.reply() {
+                Ok(buf) => {
+                    outarg.args[0].size = buf.len() as u32;
+                    0
+                }
+                Err(x) => -*x,
+            }
+        }
         fuse_opcode_FUSE_RELEASEDIR => {
             if inarg.numargs != 1 {
                 return -1;
@@ -695,7 +911,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
 
             let setxattr_in = unsafe { &*(inarg.args[0].value as *const fuse_setxattr_in) };
             let name = unsafe { CStr::from_raw(inarg.args[1].value as *const raw::c_char) };
-            let name_str = OsStr::new(str::from_utf8(name.to_bytes_with_nul()).unwrap());
+            let name_str = OsStr::from_bytes(name.to_bytes_with_nul());
             let value_in =
                 unsafe { &mut *(inarg.args[2].value as *mut MemContainer<raw::c_uchar>) };
             let value = value_in.to_slice();
@@ -725,7 +941,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
 
             let getxattr_in = unsafe { &*(inarg.args[0].value as *const fuse_getxattr_in) };
             let name = unsafe { CStr::from_raw(inarg.args[1].value as *const raw::c_char) };
-            let name_str = OsStr::new(str::from_utf8(name.to_bytes_with_nul()).unwrap());
+            let name_str = OsStr::from_bytes(name.to_bytes_with_nul());
             if outarg.argvar == 1 {
                 let data_out =
                     unsafe { &mut *(outarg.args[0].value as *mut MemContainer<raw::c_uchar>) };
@@ -794,7 +1010,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
             let req = Request { h: &inarg.h };
 
             let name = unsafe { CStr::from_raw(inarg.args[0].value as *const raw::c_char) };
-            let name_str = OsStr::new(str::from_utf8(name.to_bytes_with_nul()).unwrap());
+            let name_str = OsStr::from_bytes(name.to_bytes_with_nul());
             let mut reply = ReplyEmptyInternal {
                 reply: Err(libc::ENOSYS),
             };
@@ -829,7 +1045,7 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
             let req = Request { h: &inarg.h };
             let create_in = unsafe { &*(inarg.args[0].value as *const fuse_create_in) };
             let name = unsafe { CStr::from_raw(inarg.args[1].value as *const raw::c_char) };
-            let name_str = OsStr::new(str::from_utf8(name.to_bytes_with_nul()).unwrap());
+            let name_str = OsStr::from_bytes(name.to_bytes_with_nul());
             let entry_out = unsafe { &mut *(outarg.args[0].value as *mut fuse_entry_out) };
             let open_out = unsafe { &mut *(outarg.args[1].value as *mut fuse_open_out) };
             let mut reply = ReplyCreateInternal {
@@ -952,6 +1168,147 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 Err(x) => -*x,
             }
         }
+        fuse_opcode_FUSE_IOCTL => {
+            if inarg.numargs < 1 || inarg.numargs > 2 || outarg.numargs < 1 || outarg.numargs > 2 {
+                return -1;
+            }
+
+            let req = Request { h: &inarg.h };
+            let ioctl_in = unsafe { &*(inarg.args[0].value as *const fuse_ioctl_in) };
+            let in_data: &[u8] = if inarg.numargs == 2 {
+                let data_in =
+                    unsafe { &*(inarg.args[1].value as *const MemContainer<raw::c_uchar>) };
+                data_in.to_slice()
+            } else {
+                &[]
+            };
+            let ioctl_out = unsafe { &mut *(outarg.args[0].value as *mut fuse_ioctl_out) };
+            let reply_buf = if outarg.numargs == 2 {
+                Ok(unsafe { &mut *(outarg.args[1].value as *mut MemContainer<raw::c_uchar>) })
+            } else {
+                Err(libc::ENOSYS)
+            };
+            let mut reply = ReplyIoctlInternal {
+                reply_arg: Ok(ioctl_out),
+                reply_buf: reply_buf,
+            };
+            fs.bento_ioctl(
+                &req,
+                inarg.h.nodeid,
+                ioctl_in.fh,
+                ioctl_in.flags,
+                ioctl_in.cmd,
+                ioctl_in.arg,
+                in_data,
+                ioctl_in.out_size,
+                &mut reply,
+            );
+            match reply.reply_arg() {
+                Ok(_) => 0,
+                Err(x) => -*x,
+            }
+        }
+        fuse_opcode_FUSE_POLL => {
+            if inarg.numargs != 1 || outarg.numargs != 1 {
+                return -1;
+            }
+
+            let req = Request { h: &inarg.h };
+            let poll_in = unsafe { &*(inarg.args[0].value as *const fuse_poll_in) };
+            let poll_out = unsafe { &mut *(outarg.args[0].value as *mut fuse_poll_out) };
+            let mut reply = ReplyPollInternal {
+                reply: Ok(poll_out),
+            };
+            fs.bento_poll(
+                &req,
+                inarg.h.nodeid,
+                poll_in.fh,
+                poll_in.kh,
+                poll_in.events,
+                poll_in.flags,
+                &mut reply,
+            );
+            match reply.reply() {
+                Ok(_) => 0,
+                Err(x) => -*x,
+            }
+        }
+        fuse_opcode_FUSE_FALLOCATE => {
+            if inarg.numargs != 1 {
+                return -1;
+            }
+
+            let req = Request { h: &inarg.h };
+            let fallocate_in = unsafe { &*(inarg.args[0].value as *const fuse_fallocate_in) };
+            let mut reply = ReplyEmptyInternal {
+                reply: Err(libc::ENOSYS),
+            };
+            fs.bento_fallocate(
+                &req,
+                inarg.h.nodeid,
+                fallocate_in.fh,
+                fallocate_in.offset as i64,
+                fallocate_in.length as i64,
+                fallocate_in.mode as i32,
+                &mut reply,
+            );
+            match reply.reply() {
+                Ok(_) => 0,
+                Err(x) => -*x,
+            }
+        }
+        fuse_opcode_FUSE_LSEEK => {
+            if inarg.numargs != 1 || outarg.numargs != 1 {
+                return -1;
+            }
+
+            let req = Request { h: &inarg.h };
+            let lseek_in = unsafe { &*(inarg.args[0].value as *const fuse_lseek_in) };
+            let lseek_out = unsafe { &mut *(outarg.args[0].value as *mut fuse_lseek_out) };
+            let mut reply = ReplyLseekInternal {
+                reply: Ok(lseek_out),
+            };
+            fs.bento_lseek(
+                &req,
+                inarg.h.nodeid,
+                lseek_in.fh,
+                lseek_in.offset as i64,
+                lseek_in.whence as i32,
+                &mut reply,
+            );
+            match reply.reply() {
+                Ok(_) => 0,
+                Err(x) => -*x,
+            }
+        }
+        fuse_opcode_FUSE_COPY_FILE_RANGE => {
+            if inarg.numargs != 1 || outarg.numargs != 1 {
+                return -1;
+            }
+
+            let req = Request { h: &inarg.h };
+            let copy_in = unsafe { &*(inarg.args[0].value as *const fuse_copy_file_range_in) };
+            let write_out = unsafe { &mut *(outarg.args[0].value as *mut fuse_write_out) };
+            let mut reply = ReplyWriteInternal {
+                reply: Ok(write_out),
+            };
+            fs.bento_copy_file_range(
+                &req,
+                inarg.h.nodeid,
+                copy_in.fh_in,
+                copy_in.off_in as i64,
+                copy_in.nodeid_out,
+                copy_in.fh_out,
+                copy_in.off_out as i64,
+                copy_in.len,
+                copy_in.flags as u32,
+                &mut reply,
+            );
+            match reply.reply() {
+                Ok(rep) => rep.size as i32,
+                Err(x) => -*x,
+            }
+        }
         BENTO_UPDATE_PREPARE => {
             if outarg.numargs != 1 {
                 return -1;
@@ -959,8 +1316,19 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
 
             match fs.bento_update_prepare() {
                 Some(x_val) => {
-                    // TODO: Serialize the struct once bincode supports no_std
-                    outarg.args[0].value = Box::into_raw(Box::new(x_val)) as *const _ as *const raw::c_void;
+                    let buf = match serial::encode_transfer(&x_val) {
+                        Ok(buf) => buf,
+                        Err(x) => {
+                            println!("bento: failed to encode update state: {}", x);
+                            return -x.errno();
+                        }
+                    };
+                    // The encoded state now owns anything x_val referred to, so don't run its
+                    // destructors here.
+                    mem::forget(x_val);
+                    let buf = buf.into_boxed_slice();
+                    outarg.args[0].size = buf.len() as u32;
+                    outarg.args[0].value = Box::into_raw(buf) as *mut u8 as *const raw::c_void;
                     0
                 }
                 None => 0
@@ -971,16 +1339,47 @@ pub fn dispatch<'de, TransferIn: Send + Deserialize<'de>, TransferOut: Send + Se
                 return -1;
             }
 
-            let transfer_ptr = inarg.args[0].value as *mut raw::c_void;
+            let transfer_ptr = inarg.args[0].value as *mut u8;
             let transfer_in = if transfer_ptr.is_null() {
                 None
             } else {
-                // TODO: Deserialize the struct once bincode supports no_std
-                unsafe { Some(*Box::from_raw(transfer_ptr as *mut TransferIn)) }
+                let len = match unsafe { serial::transfer_len(transfer_ptr) } {
+                    Ok(len) => len,
+                    Err(x) => {
+                        println!("bento: rejecting update state: {}", x);
+                        return -x.errno();
+                    }
+                };
+                let buf = unsafe {
+                    Box::from_raw(slice::from_raw_parts_mut(transfer_ptr, len) as *mut [u8])
+                };
+                match serial::decode_transfer::<TransferIn>(&buf) {
+                    Ok(x_val) => Some(x_val),
+                    Err(x) => {
+                        println!("bento: rejecting update state: {}", x);
+                        return -x.errno();
+                    }
+                }
             };
             fs.bento_update_transfer(transfer_in);
             0
         }
+        BENTO_REMOUNT => {
+            // Only a BentoFS that sends `bento_init_in_ext` sends remounts, and the options
+            // must be a string that fits in the argument.
+            if inarg.numargs != 1 || inarg.args[0].size == 0 || inarg.args[0].value.is_null() {
+                return -libc::EINVAL;
+            }
+            let opts_len = inarg.args[0].size as usize;
+            let opts_buf = unsafe { slice::from_raw_parts(inarg.args[0].value as *const u8, opts_len) };
+            if opts_buf[opts_len - 1] != 0 {
+                return -libc::EINVAL;
+            }
+
+            let req = Request { h: &inarg.h };
+            let opts = unsafe { CStr::from_raw(inarg.args[0].value as *const raw::c_char) };
+            remount(fs, &req, OsStr::from_bytes(opts.to_bytes_with_nul()))
+        }
         _ => {
             println!("got a different opcode");
             0
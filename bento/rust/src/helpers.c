@@ -34,6 +34,20 @@ wait_for_interrupt(void) {
 	schedule();
 }
 
+/*
+ * Sleep until kthread_stop is called on the current task. The state is set before
+ * kthread_should_stop is checked, so a kthread_stop in between only makes schedule return.
+ */
+void
+wait_until_stopped(void) {
+	set_current_state(TASK_INTERRUPTIBLE);
+	while (!kthread_should_stop()) {
+		schedule();
+		set_current_state(TASK_INTERRUPTIBLE);
+	}
+	__set_current_state(TASK_RUNNING);
+}
+
 struct task_struct *
 kthread_run_helper(int (*threadfn)(void *data), void *data, const char *namefmt){
 	return kthread_run(threadfn, data, namefmt);
@@ -44,6 +58,16 @@ current_net() {
 	return current->nsproxy->net_ns;
 }
 
+struct task_struct *
+current_task_rs(void) {
+	return current;
+}
+
+void
+rs_bug(void) {
+	BUG();
+}
+
 void
 rs_dump_super_block(struct super_block* sb) {
     printk(KERN_INFO "s_blocksize = %lx\n", sb->s_blocksize);
@@ -207,6 +231,16 @@ int rs_jbd2_journal_force_commit(journal_t *journal) {
     return jbd2_journal_force_commit(journal);
 }
 
+void rs_jbd2_journal_abort(journal_t *journal, int err) {
+    jbd2_journal_abort(journal, err);
+}
+
+void rs_jbd2_journal_set_commit_interval(journal_t *journal, unsigned int secs) {
+	write_lock(&journal->j_state_lock);
+	journal->j_commit_interval = (secs ? secs : JBD2_DEFAULT_MAX_COMMIT_AGE) * HZ;
+	write_unlock(&journal->j_state_lock);
+}
+
 void rs_jbd2_journal_set_barrier(journal_t *journal) {
 	journal->j_flags |= JBD2_BARRIER;
 	jbd2_journal_set_features(journal, 0, 0,
@@ -238,3 +272,57 @@ void rs_jbd2_journal_setup(journal_t *journal) {
         jbd2_journal_clear_features(journal, 0, 0,
                         JBD2_FEATURE_INCOMPAT_ASYNC_COMMIT);
 }
+
+/*
+ * Cache invalidation is exported by BentoFS versions that support it. The
+ * functions are looked up when called instead of linked against, so the module
+ * still loads against a BentoFS without them.
+ */
+int bento_notify_inval_inode(struct super_block *sb, u64 nodeid, loff_t offset,
+			     loff_t len);
+int bento_notify_inval_entry(struct super_block *sb, u64 parent, u64 child,
+			     const char *name, u32 namelen);
+int bento_notify_store(struct super_block *sb, u64 nodeid, u64 offset,
+		       const void *data, u32 size);
+
+int rs_bento_notify_inval_inode(struct super_block *sb, u64 nodeid,
+				loff_t offset, loff_t len)
+{
+	typeof(&bento_notify_inval_inode) notify;
+	int ret;
+
+	notify = symbol_get(bento_notify_inval_inode);
+	if (!notify)
+		return -ENOSYS;
+	ret = notify(sb, nodeid, offset, len);
+	symbol_put(bento_notify_inval_inode);
+	return ret;
+}
+
+int rs_bento_notify_inval_entry(struct super_block *sb, u64 parent, u64 child,
+				const char *name, u32 namelen)
+{
+	typeof(&bento_notify_inval_entry) notify;
+	int ret;
+
+	notify = symbol_get(bento_notify_inval_entry);
+	if (!notify)
+		return -ENOSYS;
+	ret = notify(sb, parent, child, name, namelen);
+	symbol_put(bento_notify_inval_entry);
+	return ret;
+}
+
+int rs_bento_notify_store(struct super_block *sb, u64 nodeid, u64 offset,
+			  const void *data, u32 size)
+{
+	typeof(&bento_notify_store) notify;
+	int ret;
+
+	notify = symbol_get(bento_notify_store);
+	if (!notify)
+		return -ENOSYS;
+	ret = notify(sb, nodeid, offset, data, size);
+	symbol_put(bento_notify_store);
+	return ret;
+}
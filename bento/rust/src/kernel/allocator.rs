@@ -33,6 +33,10 @@ unsafe impl GlobalAlloc for KernelAllocator {
 }
 
 #[alloc_error_handler]
-fn oom(_layout: Layout) -> ! {
-    loop {}
+fn oom(layout: Layout) -> ! {
+    crate::bento_utils::errors::record(crate::bento_utils::errors::ErrorCause::OutOfMemory,
+                                       crate::libc::ENOMEM);
+    println!("bento: failed to allocate {} bytes", layout.size());
+    // Like a panic, this oopses the current task instead of failing the request.
+    unsafe { rs_bug() }
 }
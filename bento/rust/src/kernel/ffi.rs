@@ -278,6 +278,8 @@ extern "C" {
     pub fn rs_jbd2_journal_get_create_access(handle: *const raw::c_void, bh: *const raw::c_void) -> i32;
     pub fn rs_jbd2_journal_dirty_metadata(handle: *const raw::c_void, bh: *const raw::c_void) -> i32;
     pub fn rs_jbd2_journal_force_commit(journal: *const raw::c_void) -> i32;
+    pub fn rs_jbd2_journal_abort(journal: *const raw::c_void, errno: i32);
+    pub fn rs_jbd2_journal_set_commit_interval(journal: *const raw::c_void, secs: u32);
     pub fn rs_jbd2_journal_set_barrier(journal: *const raw::c_void);
     pub fn rs_jbd2_journal_set_async_commit(journal: *const raw::c_void);
     pub fn rs_jbd2_journal_setup(journal: *const raw::c_void);
@@ -290,11 +292,13 @@ extern "C" {
 
     // debugging relaed
     pub fn rs_dump_super_block(sb: *const raw::c_void);
+    pub fn rs_bug() -> !;
     pub fn msleep(msecs: u32);
     pub fn rs_ndelay(usecs: u32);
 
     pub fn getnstimeofday64(ts: *const raw::c_void);
     pub fn current_kernel_time_rs() -> bindings::timespec;
+    pub fn current_task_rs() -> *const raw::c_void;
 
     pub fn rs_get_wait_queue_head() -> *mut raw::c_void;
     pub fn rs_put_wait_queue_head(wq_head: *const raw::c_void);
@@ -312,6 +316,23 @@ extern "C" {
         dispatch: *const raw::c_void,
     ) -> i32;
     pub fn unregister_bento_fs(fs_name: *const raw::c_void) -> i32;
+    // Wrappers in helpers.c that look up BentoFS's bento_notify_* functions when called, and
+    // return -ENOSYS if it doesn't export them.
+    pub fn rs_bento_notify_inval_inode(sb: *const raw::c_void, nodeid: u64, offset: i64, len: i64) -> i32;
+    pub fn rs_bento_notify_inval_entry(
+        sb: *const raw::c_void,
+        parent: u64,
+        child: u64,
+        name: *const raw::c_char,
+        namelen: u32,
+    ) -> i32;
+    pub fn rs_bento_notify_store(
+        sb: *const raw::c_void,
+        nodeid: u64,
+        offset: u64,
+        data: *const raw::c_void,
+        size: u32,
+    ) -> i32;
     pub fn mount() -> i32;
     pub fn lookup_bdev(dev_name: *const raw::c_char, mode: u32) -> *mut raw::c_void;
     pub fn get_bdev_helper(dev_name: *const raw::c_char, mode: u32) -> *mut raw::c_void;
@@ -371,6 +392,7 @@ extern "C" {
     pub fn kthread_should_stop() -> bool;
     pub fn wait_a_bit();
     pub fn wait_for_interrupt();
+    pub fn wait_until_stopped();
 }
 
 pub unsafe fn sb_bread(sb: *const raw::c_void, blockno: u64) -> *const raw::c_void {
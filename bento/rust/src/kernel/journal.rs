@@ -21,6 +21,8 @@ use kernel::fs::*;
 use serde::{Serialize, Serializer, Deserialize, Deserializer};
 
 use crate::bento_utils::Disk;
+use crate::bindings;
+use crate::libc;
 
 /// Wrapper around the kernel `journal_t`.
 #[derive(Debug)]
@@ -100,20 +102,23 @@ impl Journal {
     }
 
     // begin transaction of size blocks
-    pub fn begin_op(&self, blocks: u32) -> Handle {
+    // fails with EROFS once the journal has been aborted
+    pub fn begin_op(&self, blocks: u32) -> Result<Handle, c_int> {
         let handle;
         unsafe {
             handle = rs_jbd2_journal_start((*self.journal.get()).get_raw() as *const c_void, blocks as i32)
         }
         if handle.is_null() {
-            panic!("transaction begin failed")
+            return Err(libc::ENOMEM);
+        } else if unsafe { bindings::IS_ERR(handle as u64) } {
+            return Err(-(handle as i64) as c_int);
         } else {
             unsafe {
-                return Handle {
+                return Ok(Handle {
                     handle: UnsafeCell::new(RsHandle::from_raw(handle as *const c_void)),
                     requested: blocks,
                     blocks: RefCell::new(BTreeSet::new()),
-                };
+                });
             }
         }
     }
@@ -124,6 +129,20 @@ impl Journal {
             return rs_jbd2_journal_force_commit((*self.journal.get()).get_raw() as *const c_void);
         }
     }
+
+    // abort the journal so the running transaction and any later ones are never committed
+    pub fn abort(&self, errno: i32) {
+        unsafe {
+            rs_jbd2_journal_abort((*self.journal.get()).get_raw() as *const c_void, -errno);
+        }
+    }
+
+    // commit a running transaction after at most secs seconds, or the jbd2 default if 0
+    pub fn set_commit_interval(&self, secs: u32) {
+        unsafe {
+            rs_jbd2_journal_set_commit_interval((*self.journal.get()).get_raw() as *const c_void, secs);
+        }
+    }
 }
 
 impl Drop for Journal {
@@ -187,7 +206,7 @@ impl Drop for Handle {
              ()
         } else {
              println!("some log transaction was aborted");
-             loop {};
+             crate::bento_utils::errors::fs_error(-res);
         }
     }
 }
@@ -24,6 +24,13 @@ extern crate serde;
 #[cfg(feature = "capnproto")]
 extern crate capnp;
 
+// Sixteen copies of `$e`. Statics of arrays of atomics can't use a repeat expression.
+macro_rules! repeat16 {
+    ($e:expr) => {
+        [$e, $e, $e, $e, $e, $e, $e, $e, $e, $e, $e, $e, $e, $e, $e, $e]
+    };
+}
+
 pub mod bento_utils;
 pub mod bindings;
 #[macro_use]
@@ -33,6 +40,7 @@ pub mod fuse;
 pub mod kernel;
 #[allow(non_camel_case_types)]
 pub mod libc;
+pub mod serial;
 pub mod std;
 pub mod time;
 
@@ -54,8 +62,17 @@ use core::panic::PanicInfo;
 
 #[panic_handler]
 // #[cfg(not(test))]
-fn panic(_info: &PanicInfo) -> ! {
-    loop {}
+fn panic(info: &PanicInfo) -> ! {
+    bento_utils::errors::record(bento_utils::errors::ErrorCause::Panic, libc::EIO);
+    match (info.message(), info.location()) {
+        (Some(msg), Some(loc)) => println!("bento: panicked at '{}', {}", msg, loc),
+        (Some(msg), None) => println!("bento: panicked at '{}'", msg),
+        _ => println!("bento: panicked"),
+    }
+    // The panic can't be unwound back to dispatch, so the request can't fail with EIO. Oops the
+    // current task rather than spinning: its locks stay held, and with panic_on_oops the machine
+    // goes down.
+    unsafe { kernel::ffi::rs_bug() }
 }
 
 #[global_allocator]
@@ -20,6 +20,7 @@ pub type ssize_t = isize;
 pub type pid_t = i32;
 pub type uid_t = u32;
 pub type gid_t = u32;
+pub type mode_t = u32;
 pub type in_addr_t = u32;
 pub type in_port_t = u16;
 pub type sighandler_t = size_t;
@@ -82,6 +83,7 @@ pub const EXFULL: c_int = 54;
 pub const ENOANO: c_int = 55;
 pub const EBADRQC: c_int = 56;
 pub const EBADSLT: c_int = 57;
+pub const ENODATA: c_int = 61;
 pub const EMULTIHOP: c_int = 72;
 pub const EOVERFLOW: c_int = 75;
 pub const ENOTUNIQ: c_int = 76;
@@ -139,6 +141,11 @@ pub const ENOTRECOVERABLE: c_int = 131;
 pub const EHWPOISON: c_int = 133;
 pub const ERFKILL: c_int = 132;
 
+pub const O_RDONLY: c_int = 0;
+pub const O_WRONLY: c_int = 1;
+pub const O_RDWR: c_int = 2;
+pub const O_ACCMODE: c_int = 3;
+
 pub const O_DIRECT: c_int = 0x4000;
 pub const O_DIRECTORY: c_int = 0x10000;
 pub const O_NOFOLLOW: c_int = 0x20000;
@@ -164,3 +171,30 @@ pub const SOCK_CLOEXEC: c_int = O_CLOEXEC;
 pub const RENAME_NOREPLACE: c_int = 1;
 pub const RENAME_EXCHANGE: c_int = 2;
 pub const RENAME_WHITEOUT: c_int = 4;
+
+pub const SEEK_SET: c_int = 0;
+pub const SEEK_CUR: c_int = 1;
+pub const SEEK_END: c_int = 2;
+pub const SEEK_DATA: c_int = 3;
+pub const SEEK_HOLE: c_int = 4;
+
+pub const FALLOC_FL_KEEP_SIZE: c_int = 0x01;
+pub const FALLOC_FL_PUNCH_HOLE: c_int = 0x02;
+pub const FALLOC_FL_ZERO_RANGE: c_int = 0x10;
+
+pub const F_OK: c_int = 0;
+pub const X_OK: c_int = 1;
+pub const W_OK: c_int = 2;
+pub const R_OK: c_int = 4;
+
+pub const S_IFIFO: mode_t = 0o1_0000;
+pub const S_IFCHR: mode_t = 0o2_0000;
+pub const S_IFBLK: mode_t = 0o6_0000;
+pub const S_IFDIR: mode_t = 0o4_0000;
+pub const S_IFREG: mode_t = 0o10_0000;
+pub const S_IFLNK: mode_t = 0o12_0000;
+pub const S_IFSOCK: mode_t = 0o14_0000;
+pub const S_IFMT: mode_t = 0o17_0000;
+
+pub const XATTR_CREATE: c_int = 0x1;
+pub const XATTR_REPLACE: c_int = 0x2;
@@ -0,0 +1,199 @@
+/*
+ * SPDX-License-Identifier: GPL-2.0
+ * Copyright (C) 2020 Samantha Miller, Kaiyuan Zhang, Danyang Zhuo, Tom
+      Anderson, Ang Chen, University of Washington
+ *
+ */
+
+//! A small no_std binary encoding for serde.
+//!
+//! This is used to hand file system state from an old module to its replacement during a live
+//! upgrade. The encoding is not self-describing: integers are fixed-width little endian, strings,
+//! byte arrays, sequences and maps are prefixed with a `u64` length, and enum variants are
+//! written as a `u32` index.
+//!
+//! Structs are written as a `u32` field count followed by the fields in declaration order. A
+//! newer version of a struct may append fields marked `#[serde(default)]` and still decode state
+//! written by the older version. Removing, reordering or retyping fields is not compatible and
+//! requires bumping `TransferState::VERSION`.
+//!
+//! Upgrade state is wrapped in an envelope (see `encode_transfer`) recording the type name and
+//! schema version so that a mismatched transfer is rejected instead of being misinterpreted.
+
+mod de;
+mod ser;
+
+pub use self::de::{from_bytes, Deserializer};
+pub use self::ser::{to_bytes, Serializer};
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use core::fmt;
+
+use crate::libc;
+
+use serde::{Deserialize, Serialize};
+
+/// Magic number at the start of every transfer envelope ("BNTO").
+pub const TRANSFER_MAGIC: u32 = 0x424e_544f;
+/// Version of the envelope layout itself.
+pub const TRANSFER_FORMAT: u32 = 1;
+/// Size of the fixed part of the envelope: magic, format and total length.
+pub const TRANSFER_HEADER_LEN: usize = 16;
+
+/// Type that can be passed between file system modules during a live upgrade.
+///
+/// `NAME` identifies the state type and must be the same in the old and new module. `VERSION`
+/// is the schema version of the type. A module accepts state written with a schema version
+/// less than or equal to its own, so a new version may only append `#[serde(default)]` fields.
+pub trait TransferState {
+    const NAME: &'static str;
+    const VERSION: u32;
+}
+
+impl TransferState for i32 {
+    const NAME: &'static str = "i32";
+    const VERSION: u32 = 0;
+}
+
+/// Errors produced while encoding or decoding.
+#[derive(Debug)]
+pub enum Error {
+    /// The input ended before the value was complete.
+    Eof,
+    /// Bytes were left over after decoding the value.
+    TrailingBytes,
+    /// The encoded struct has more fields than the type being decoded.
+    TrailingFields,
+    /// A bool was not 0 or 1.
+    InvalidBool(u8),
+    /// A char was not a valid unicode scalar value.
+    InvalidChar(u32),
+    /// An option tag was not 0 or 1.
+    InvalidTag(u8),
+    /// A string was not valid UTF-8.
+    InvalidUtf8,
+    /// A length does not fit in `usize`.
+    InvalidLength(u64),
+    /// The type needs a self-describing format.
+    Unsupported(&'static str),
+    /// The envelope magic or format is wrong.
+    BadEnvelope,
+    /// The envelope holds a different type.
+    TypeMismatch,
+    /// The envelope was written by a newer schema version.
+    VersionTooNew(u32),
+    /// Error reported by a `Serialize` or `Deserialize` impl.
+    Custom(String),
+}
+
+impl Error {
+    /// Errno to return to Bento for this error.
+    pub fn errno(&self) -> libc::c_int {
+        match self {
+            Error::BadEnvelope | Error::TypeMismatch | Error::VersionTooNew(_) => libc::EINVAL,
+            Error::Unsupported(_) => libc::EOPNOTSUPP,
+            _ => libc::EIO,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Eof => write!(f, "unexpected end of input"),
+            Error::TrailingBytes => write!(f, "trailing bytes after value"),
+            Error::TrailingFields => write!(f, "encoded struct has unknown trailing fields"),
+            Error::InvalidBool(x) => write!(f, "invalid bool {}", x),
+            Error::InvalidChar(x) => write!(f, "invalid char {:#x}", x),
+            Error::InvalidTag(x) => write!(f, "invalid option tag {}", x),
+            Error::InvalidUtf8 => write!(f, "invalid utf-8"),
+            Error::InvalidLength(x) => write!(f, "invalid length {}", x),
+            Error::Unsupported(x) => write!(f, "unsupported: {}", x),
+            Error::BadEnvelope => write!(f, "bad transfer envelope"),
+            Error::TypeMismatch => write!(f, "transfer type mismatch"),
+            Error::VersionTooNew(x) => write!(f, "transfer schema version {} is too new", x),
+            Error::Custom(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+impl serde::ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::Custom(msg.to_string())
+    }
+}
+
+impl serde::de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::Custom(msg.to_string())
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Encode `value` in a transfer envelope.
+///
+/// The envelope is the fixed header (magic, format, total length), followed by the type name,
+/// the schema version and the encoded value.
+pub fn encode_transfer<T: Serialize + TransferState>(value: &T) -> Result<Vec<u8>> {
+    let mut ser = Serializer::new();
+    ser.write_u32(TRANSFER_MAGIC);
+    ser.write_u32(TRANSFER_FORMAT);
+    ser.write_u64(0);
+    ser.write_bytes(T::NAME.as_bytes());
+    ser.write_u32(T::VERSION);
+    value.serialize(&mut ser)?;
+
+    let mut out = ser.into_inner();
+    let total = out.len() as u64;
+    out[8..TRANSFER_HEADER_LEN].copy_from_slice(&total.to_le_bytes());
+    Ok(out)
+}
+
+/// Read the total length of the envelope starting at `ptr`.
+///
+/// Only the fixed header is read, so this can be used to recover the length of a buffer passed
+/// as a bare pointer before building a slice over it.
+///
+/// # Safety
+/// `ptr` must point to at least `TRANSFER_HEADER_LEN` readable bytes.
+pub unsafe fn transfer_len(ptr: *const u8) -> Result<usize> {
+    let header = core::slice::from_raw_parts(ptr, TRANSFER_HEADER_LEN);
+    let mut de = Deserializer::new(header);
+    if de.read_u32()? != TRANSFER_MAGIC || de.read_u32()? != TRANSFER_FORMAT {
+        return Err(Error::BadEnvelope);
+    }
+    let total = de.read_u64()?;
+    if total < TRANSFER_HEADER_LEN as u64 || total > usize::max_value() as u64 {
+        return Err(Error::BadEnvelope);
+    }
+    Ok(total as usize)
+}
+
+/// Decode a value of type `T` from a transfer envelope.
+///
+/// Fails with `TypeMismatch` if the envelope holds a different type and with `VersionTooNew`
+/// if it was written by a newer schema version of `T`.
+pub fn decode_transfer<'de, T: Deserialize<'de> + TransferState>(bytes: &'de [u8]) -> Result<T> {
+    if bytes.len() < TRANSFER_HEADER_LEN {
+        return Err(Error::BadEnvelope);
+    }
+    if unsafe { transfer_len(bytes.as_ptr())? } != bytes.len() {
+        return Err(Error::BadEnvelope);
+    }
+
+    let mut de = Deserializer::new(&bytes[TRANSFER_HEADER_LEN..]);
+    if de.read_bytes()? != T::NAME.as_bytes() {
+        return Err(Error::TypeMismatch);
+    }
+    let version = de.read_u32()?;
+    if version > T::VERSION {
+        return Err(Error::VersionTooNew(version));
+    }
+
+    let value = T::deserialize(&mut de)?;
+    de.end()?;
+    Ok(value)
+}
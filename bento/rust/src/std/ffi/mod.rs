@@ -1,3 +1,3 @@
-mod os_str;
+pub(crate) mod os_str;
 
 pub use self::os_str::OsStr;
@@ -8,7 +8,7 @@ pub struct Slice {
 }
 
 impl Slice {
-    fn from_u8_slice(s: &[u8]) -> &Slice {
+    pub(crate) fn from_u8_slice(s: &[u8]) -> &Slice {
         unsafe { mem::transmute(s) }
     }
 
@@ -22,6 +22,9 @@ impl Slice {
 }
 
 /// Copy of Rust libstd OsStr
+///
+/// Like the libstd type on Unix, this is any sequence of bytes. Use `OsStrExt` to convert
+/// to and from bytes.
 pub struct OsStr {
     inner: Slice,
 }
@@ -31,7 +34,7 @@ impl OsStr {
         s.as_ref()
     }
 
-    fn from_inner(inner: &Slice) -> &OsStr {
+    pub(crate) fn from_inner(inner: &Slice) -> &OsStr {
         unsafe { &*(inner as *const Slice as *const OsStr) }
     }
 
@@ -43,7 +46,11 @@ impl OsStr {
         self.inner.inner.len()
     }
 
-    fn bytes(&self) -> &[u8] {
+    pub fn is_empty(&self) -> bool {
+        self.inner.inner.is_empty()
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8] {
         unsafe { &*(&self.inner as *const Slice as *const [u8]) }
     }
 }
@@ -54,6 +61,27 @@ impl Hash for OsStr {
     }
 }
 
+impl PartialEq for OsStr {
+    fn eq(&self, other: &OsStr) -> bool {
+        self.bytes() == other.bytes()
+    }
+}
+
+impl Eq for OsStr {}
+
+impl PartialEq<str> for OsStr {
+    fn eq(&self, other: &str) -> bool {
+        self.bytes() == other.as_bytes()
+    }
+}
+
+impl AsRef<OsStr> for OsStr {
+    #[inline]
+    fn as_ref(&self) -> &OsStr {
+        self
+    }
+}
+
 impl AsRef<OsStr> for str {
     #[inline]
     fn as_ref(&self) -> &OsStr {
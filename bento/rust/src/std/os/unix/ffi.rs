@@ -0,0 +1,21 @@
+use crate::std::ffi::os_str::Slice;
+use crate::std::ffi::OsStr;
+
+/// Copy of Rust libstd OsStrExt
+pub trait OsStrExt {
+    fn from_bytes(slice: &[u8]) -> &Self;
+
+    fn as_bytes(&self) -> &[u8];
+}
+
+impl OsStrExt for OsStr {
+    #[inline]
+    fn from_bytes(slice: &[u8]) -> &OsStr {
+        OsStr::from_inner(Slice::from_u8_slice(slice))
+    }
+
+    #[inline]
+    fn as_bytes(&self) -> &[u8] {
+        self.bytes()
+    }
+}
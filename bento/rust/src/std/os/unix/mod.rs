@@ -1,2 +1,3 @@
+pub mod ffi;
 pub mod fs;
 pub mod io;
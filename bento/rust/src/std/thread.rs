@@ -1,55 +1,187 @@
 use kernel::ffi;
 use kernel::raw;
 
-use core::mem;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 
-fn spawn_thread_helper<T>(data: *mut raw::c_void) -> T
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::bindings;
+use crate::libc;
+use crate::std::io;
+
+/// State shared between a spawned thread and its `JoinHandle`.
+struct Packet<T> {
+    started: AtomicBool,
+    result: UnsafeCell<Option<T>>,
+}
+
+// The result is written once by the spawned thread before it waits to be stopped and is only
+// read by the joining thread after `kthread_stop` returns.
+unsafe impl<T: Send> Sync for Packet<T> {}
+
+/// Data handed to a new kernel thread.
+struct ThreadStart<T> {
+    main: Box<dyn FnOnce() -> T + Send>,
+    packet: Arc<Packet<T>>,
+}
+
+extern "C" fn thread_start<T>(data: *mut raw::c_void) -> raw::c_int
 where
     T: Send + 'static
 {
+    let start = unsafe { Box::from_raw(data as *mut ThreadStart<T>) };
+    let ThreadStart { main, packet } = *start;
+    packet.started.store(true, Ordering::SeqCst);
+
+    let ret_val = main();
+    unsafe {
+        *packet.result.get() = Some(ret_val);
+    }
+    drop(packet);
+
+    // The task must stay around until `kthread_stop` is called on it.
     unsafe {
-        // let function run
-        let func: fn() -> T = mem::transmute(data);
-        let ret_val = func();
-        // When function finishes, wait on stop
-        loop {
-            if ffi::kthread_should_stop() {
-                return ret_val;
+        ffi::wait_until_stopped();
+    }
+    return 0;
+}
+
+/// Thread factory, which can be used in order to configure the properties of a new thread.
+///
+/// Kernel threads have a fixed stack size, so only the name can be configured.
+pub struct Builder {
+    name: Option<String>,
+}
+
+impl Builder {
+    /// Generates the base configuration for spawning a thread.
+    pub fn new() -> Builder {
+        Builder { name: None }
+    }
+
+    /// Names the thread-to-be. The name shows up as the task's `comm` and may be truncated by the
+    /// kernel.
+    pub fn name(mut self, name: String) -> Builder {
+        self.name = Some(name);
+        self
+    }
+
+    /// Spawns a new kernel thread running `f`, returning a `JoinHandle` for it.
+    pub fn spawn<F, T>(self, f: F) -> io::Result<JoinHandle<T>>
+    where
+        F: FnOnce() -> T,
+        F: Send + 'static,
+        T: Send + 'static,
+    {
+        // kthread_run takes a printf format, so escape any '%' in the name.
+        let mut namefmt: Vec<u8> = Vec::new();
+        if let Some(name) = &self.name {
+            for b in name.bytes().filter(|b| *b != 0) {
+                if b == b'%' {
+                    namefmt.push(b'%');
+                }
+                namefmt.push(b);
             }
-            ffi::wait_for_interrupt();
+        }
+        namefmt.push(0);
+
+        let packet = Arc::new(Packet {
+            started: AtomicBool::new(false),
+            result: UnsafeCell::new(None),
+        });
+        let start = Box::new(ThreadStart::<T> {
+            main: Box::new(f),
+            packet: packet.clone(),
+        });
+        let data = Box::into_raw(start);
+
+        unsafe {
+            let tstruct = ffi::kthread_run_helper(
+                thread_start::<T> as *const raw::c_void,
+                data as *mut raw::c_void,
+                namefmt.as_ptr() as *const raw::c_void
+            );
+            if tstruct.is_null() || bindings::IS_ERR(tstruct as u64) {
+                drop(Box::from_raw(data));
+                let err = if tstruct.is_null() { libc::ENOMEM } else { tstruct as isize as i32 };
+                return Err(io::Error::from_raw_os_error(err));
+            }
+            Ok(JoinHandle {
+                inner: tstruct,
+                packet: packet,
+                joined: false,
+            })
         }
     }
 }
 
-pub fn spawn<F, T>(_f: F) -> JoinHandle
+/// Spawns a new kernel thread running `f`, returning a `JoinHandle` for it.
+///
+/// Panics if the thread can't be created. Use `Builder::spawn` to handle the error instead.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
 where
     F: FnOnce() -> T,
     F: Send + 'static,
     T: Send + 'static,
 {
+    Builder::new().spawn(f).expect("failed to spawn thread")
+}
+
+/// Puts the current thread to sleep for at least `dur`, rounded up to a millisecond.
+pub fn sleep(dur: core::time::Duration) {
+    let msecs = dur.as_secs() * 1000 + ((dur.subsec_nanos() + 999_999) / 1_000_000) as u64;
     unsafe {
-        let c: extern "rust-call" fn(F, ()) -> T = <F as FnOnce<()>>::call_once;
-        let data = mem::transmute(c);
-        let tstruct = ffi::kthread_run_helper(
-            spawn_thread_helper::<T> as *const raw::c_void,
-            data,
-            "".as_ptr() as *const raw::c_void
-        );
-        return JoinHandle { inner: tstruct as *mut raw::c_void };
+        ffi::msleep(core::cmp::min(msecs, u32::MAX as u64) as u32);
     }
 }
 
-pub struct JoinHandle {
-    inner: *mut raw::c_void
+/// An owned permission to join on a kernel thread.
+///
+/// Unlike `std`, dropping a `JoinHandle` does not detach the thread. A kernel thread must not
+/// outlive the module that spawned it, so dropping the handle waits for the thread to finish.
+pub struct JoinHandle<T> {
+    inner: *mut raw::c_void,
+    packet: Arc<Packet<T>>,
+    joined: bool,
 }
 
-impl JoinHandle {
-    pub fn join(&mut self) -> Result<i32, i32> {
-        unsafe {
-            return Ok(ffi::kthread_stop(self.inner as *mut raw::c_void));
+impl<T> JoinHandle<T> {
+    /// Waits for the thread to finish and returns the value returned by its closure.
+    ///
+    /// Returns the error from `kthread_stop` if the closure didn't produce a value.
+    pub fn join(mut self) -> Result<T, i32> {
+        let ret = self.stop();
+        match unsafe { (*self.packet.result.get()).take() } {
+            Some(val) => Ok(val),
+            None => Err(ret),
         }
     }
+
+    fn stop(&mut self) -> i32 {
+        if self.joined {
+            return 0;
+        }
+        self.joined = true;
+        // kthread_stop on a thread that hasn't started yet keeps it from ever running the
+        // closure, so wait until it has.
+        while !self.packet.started.load(Ordering::SeqCst) {
+            unsafe {
+                ffi::wait_a_bit();
+            }
+        }
+        unsafe { ffi::kthread_stop(self.inner) }
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        self.stop();
+    }
 }
 
-unsafe impl Send for JoinHandle {}
-unsafe impl Sync for JoinHandle {}
+unsafe impl<T: Send> Send for JoinHandle<T> {}
+unsafe impl<T: Send> Sync for JoinHandle<T> {}
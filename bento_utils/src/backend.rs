@@ -0,0 +1,383 @@
+use std::alloc;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use std::cmp::{max, min};
+
+/// A block-sized buffer aligned to the block size, as O_DIRECT requires.
+pub(crate) struct AlignedBlock {
+    ptr: *mut u8,
+    layout: alloc::Layout,
+}
+
+impl AlignedBlock {
+    pub(crate) fn new(bsize: usize) -> Result<Self, libc::c_int> {
+        let layout = alloc::Layout::from_size_align(bsize, bsize).map_err(|_| libc::EINVAL)?;
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            return Err(libc::ENOMEM);
+        }
+        Ok(Self {
+            ptr: ptr,
+            layout: layout,
+        })
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBlock {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, self.layout) };
+    }
+}
+
+/// Storage behind a `Disk`.
+///
+/// `Disk` reads and writes whole blocks at block-aligned offsets, into buffers aligned to the
+/// block size, so backends only need to handle that case.
+pub trait BlockBackend: Send + Sync {
+    /// Read `buf.len()` bytes at byte `offset`.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<(), libc::c_int>;
+
+    /// Write `buf` at byte `offset`.
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<(), libc::c_int>;
+
+    /// Make written data durable.
+    fn sync_data(&self) -> Result<(), libc::c_int> {
+        Ok(())
+    }
+
+    /// Make written data and metadata durable.
+    fn sync_all(&self) -> Result<(), libc::c_int> {
+        self.sync_data()
+    }
+
+    /// File descriptor of the underlying device, or -1 if there is none.
+    fn as_raw_fd(&self) -> RawFd {
+        -1
+    }
+}
+
+impl<B: BlockBackend + ?Sized> BlockBackend for Arc<B> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<(), libc::c_int> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<(), libc::c_int> {
+        (**self).write_at(buf, offset)
+    }
+
+    fn sync_data(&self) -> Result<(), libc::c_int> {
+        (**self).sync_data()
+    }
+
+    fn sync_all(&self) -> Result<(), libc::c_int> {
+        (**self).sync_all()
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        (**self).as_raw_fd()
+    }
+}
+
+/// A block device or image file, read and written with O_DIRECT.
+pub struct FileBackend {
+    file: File,
+}
+
+impl FileBackend {
+    /// Open `path` for reading and writing.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)?;
+        Ok(FileBackend { file: file })
+    }
+
+    /// Open `path` for reading only. Writes fail with `EIO`.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)?;
+        Ok(FileBackend { file: file })
+    }
+}
+
+impl BlockBackend for FileBackend {
+    // A short read or write, such as one past the end of a device, fails with `EIO`.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<(), libc::c_int> {
+        self.file.read_exact_at(buf, offset).map_err(|_| libc::EIO)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<(), libc::c_int> {
+        self.file.write_all_at(buf, offset).map_err(|_| libc::EIO)
+    }
+
+    fn sync_data(&self) -> Result<(), libc::c_int> {
+        self.file.sync_data().map_err(|_| libc::EIO)
+    }
+
+    fn sync_all(&self) -> Result<(), libc::c_int> {
+        self.file.sync_all().map_err(|_| libc::EIO)
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// A fixed-size image held in memory.
+///
+/// Reads and writes past the end of the image fail with `EIO`, like those past the end of a
+/// block device.
+pub struct RamBackend {
+    image: Mutex<Vec<u8>>,
+}
+
+impl RamBackend {
+    /// Create a zeroed image of `size` bytes.
+    pub fn new(size: u64) -> Self {
+        Self::from_image(vec![0; size as usize])
+    }
+
+    /// Use `image`, such as one written by mkfs, as the disk contents.
+    pub fn from_image(image: Vec<u8>) -> Self {
+        RamBackend { image: Mutex::new(image) }
+    }
+
+    /// Copy of the image. Blocks still dirty in a `Disk`'s cache are only included after
+    /// `Disk::sync_all`.
+    pub fn image(&self) -> Vec<u8> {
+        self.image.lock().unwrap().clone()
+    }
+}
+
+impl BlockBackend for RamBackend {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<(), libc::c_int> {
+        let image = self.image.lock().unwrap();
+        let start = offset as usize;
+        if start + buf.len() > image.len() {
+            return Err(libc::EIO);
+        }
+        buf.copy_from_slice(&image[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<(), libc::c_int> {
+        let mut image = self.image.lock().unwrap();
+        let start = offset as usize;
+        if start + buf.len() > image.len() {
+            return Err(libc::EIO);
+        }
+        image[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+// Blocks written to a sparse image, keyed by block number. Blocks never written read as
+// zeros.
+struct BlockMap {
+    bsize: u64,
+    blocks: BTreeMap<u64, Box<[u8]>>,
+}
+
+impl BlockMap {
+    fn new(bsize: u64) -> Self {
+        BlockMap {
+            bsize: bsize,
+            blocks: BTreeMap::new(),
+        }
+    }
+
+    // Call `f` with each block-sized piece of the range `offset..offset + len`, as the block
+    // number, the offset in the block and the offset in the range.
+    fn for_each_piece<F: FnMut(u64, usize, usize, usize)>(&self, offset: u64, len: usize, mut f: F) {
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let blockno = pos / self.bsize;
+            let block_off = (pos % self.bsize) as usize;
+            let size = min(self.bsize as usize - block_off, len - done);
+            f(blockno, block_off, done, size);
+            done += size;
+        }
+    }
+
+    // Copy the blocks in the map into `buf`, leaving parts not in the map untouched.
+    fn read(&self, buf: &mut [u8], offset: u64) {
+        let blocks = &self.blocks;
+        self.for_each_piece(offset, buf.len(), |blockno, block_off, buf_off, size| {
+            if let Some(block) = blocks.get(&blockno) {
+                buf[buf_off..buf_off + size].copy_from_slice(&block[block_off..block_off + size]);
+            }
+        });
+    }
+
+    // Write `buf` into the map. A block that isn't in the map yet is first filled by `fill`.
+    fn write<F>(&mut self, buf: &[u8], offset: u64, mut fill: F) -> Result<(), libc::c_int>
+    where
+        F: FnMut(u64, &mut [u8]) -> Result<(), libc::c_int>,
+    {
+        let mut pieces = Vec::new();
+        self.for_each_piece(offset, buf.len(), |blockno, block_off, buf_off, size| {
+            pieces.push((blockno, block_off, buf_off, size));
+        });
+        for (blockno, block_off, buf_off, size) in pieces {
+            if !self.blocks.contains_key(&blockno) {
+                let mut block = vec![0; self.bsize as usize].into_boxed_slice();
+                if size != self.bsize as usize {
+                    fill(blockno, &mut block)?;
+                }
+                self.blocks.insert(blockno, block);
+            }
+            let block = self.blocks.get_mut(&blockno).unwrap();
+            block[block_off..block_off + size].copy_from_slice(&buf[buf_off..buf_off + size]);
+        }
+        Ok(())
+    }
+}
+
+/// A sparse image held in memory. Only blocks that have been written take up memory, and
+/// blocks never written read as zeros.
+///
+/// Like a sparse file, the image grows when written past its end, and the blocks skipped over
+/// read as zeros. Reads past the end fail with `EIO`.
+pub struct SparseBackend {
+    // Size of the image and the blocks written to it.
+    state: Mutex<(u64, BlockMap)>,
+}
+
+impl SparseBackend {
+    /// Create an image of `size` bytes that stores data in blocks of `bsize` bytes.
+    pub fn new(size: u64, bsize: u64) -> Self {
+        SparseBackend {
+            state: Mutex::new((size, BlockMap::new(bsize))),
+        }
+    }
+
+    /// Size of the image in bytes.
+    pub fn size(&self) -> u64 {
+        self.state.lock().unwrap().0
+    }
+
+    /// Number of blocks that have been written.
+    pub fn allocated_blocks(&self) -> usize {
+        self.state.lock().unwrap().1.blocks.len()
+    }
+}
+
+impl BlockBackend for SparseBackend {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<(), libc::c_int> {
+        let state = self.state.lock().unwrap();
+        if offset + buf.len() as u64 > state.0 {
+            return Err(libc::EIO);
+        }
+        for byte in buf.iter_mut() {
+            *byte = 0;
+        }
+        state.1.read(buf, offset);
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<(), libc::c_int> {
+        let mut state = self.state.lock().unwrap();
+        state.1.write(buf, offset, |_, _| Ok(()))?;
+        state.0 = max(state.0, offset + buf.len() as u64);
+        Ok(())
+    }
+}
+
+/// A copy-on-write overlay on a read-only base image.
+///
+/// Writes go to blocks held in memory and reads of blocks that were never written go to the
+/// base, so a file system can run against a throwaway copy of an image without changing it.
+pub struct CowBackend<B> {
+    base: B,
+    map: Mutex<BlockMap>,
+}
+
+impl<B: BlockBackend> CowBackend<B> {
+    /// Put an overlay with blocks of `bsize` bytes on `base`.
+    pub fn new(base: B, bsize: u64) -> Self {
+        CowBackend {
+            base: base,
+            map: Mutex::new(BlockMap::new(bsize)),
+        }
+    }
+
+    /// The base image.
+    pub fn base(&self) -> &B {
+        &self.base
+    }
+
+    /// Number of blocks that have been written to the overlay.
+    pub fn dirty_blocks(&self) -> usize {
+        self.map.lock().unwrap().blocks.len()
+    }
+
+    /// Throw away everything written, going back to the contents of the base. A `Disk` using
+    /// the backend must not have blocks cached.
+    pub fn reset(&self) {
+        self.map.lock().unwrap().blocks.clear();
+    }
+}
+
+impl<B: BlockBackend> BlockBackend for CowBackend<B> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<(), libc::c_int> {
+        let map = self.map.lock().unwrap();
+        let mut ret = Ok(());
+        let base = &self.base;
+        let bsize = map.bsize;
+        map.for_each_piece(offset, buf.len(), |blockno, block_off, buf_off, size| {
+            if ret.is_err() {
+                return;
+            }
+            let piece = &mut buf[buf_off..buf_off + size];
+            match map.blocks.get(&blockno) {
+                Some(block) => piece.copy_from_slice(&block[block_off..block_off + size]),
+                None if size == bsize as usize && piece.as_ptr() as usize % bsize as usize == 0 => {
+                    ret = base.read_at(piece, blockno * bsize)
+                }
+                None => {
+                    // Read the whole block through an aligned buffer, as a FileBackend needs.
+                    ret = AlignedBlock::new(bsize as usize).and_then(|mut block| {
+                        base.read_at(block.as_mut_slice(), blockno * bsize)?;
+                        piece.copy_from_slice(&block.as_slice()[block_off..block_off + size]);
+                        Ok(())
+                    });
+                }
+            }
+        });
+        ret
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<(), libc::c_int> {
+        let base = &self.base;
+        self.map.lock().unwrap().write(buf, offset, |blockno, block| {
+            let bsize = block.len() as u64;
+            let mut aligned = AlignedBlock::new(block.len())?;
+            base.read_at(aligned.as_mut_slice(), blockno * bsize)?;
+            block.copy_from_slice(aligned.as_slice());
+            Ok(())
+        })
+    }
+}
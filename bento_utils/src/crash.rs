@@ -0,0 +1,581 @@
+//! Crash-consistency testing by recording writes and replaying them.
+//!
+//! A workload is run against a `Disk` on a `RecordingBackend`, which logs every block write
+//! and every flush barrier the file system issues. `crash_states` then works out which disk
+//! contents a crash could have left behind: every prefix of the writes, and, because a device
+//! may persist the writes it has not been told to flush in any order, subsets of the writes
+//! issued since the last flush. `check` mounts each of those states in a `Harness`, which runs
+//! the file system's recovery (the journal or `recover_from_log`) in `bento_init`, and checks
+//! that
+//!
+//! * the mount succeeds,
+//! * every file, directory and symbolic link can be listed, stat'ed and read,
+//! * the file system doesn't enter the errors state,
+//! * and the oracle given by the test, which knows what the workload promised, accepts it.
+//!
+//! A workload tells the oracle what it promised with `RecordingBackend::mark`, called after an
+//! operation that must be durable, such as `fsync`, returns. A crash state has passed a mark
+//! if every write recorded before the mark reached the disk, so an oracle checks for data
+//! only in states that passed the mark that made it durable.
+//!
+//! Each crash state runs on a `CowBackend` over the image the workload started from, so the
+//! image is never changed and states don't see each other's recovery writes.
+
+use backend::{BlockBackend, CowBackend};
+use disk::Disk;
+use fuse::FileType;
+use testing::Harness;
+use BentoFilesystem;
+
+use libc::c_int;
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+
+/// An operation recorded by a `RecordingBackend`.
+#[derive(Clone, Debug)]
+pub enum Op {
+    /// `data` was written at byte `offset`.
+    Write { offset: u64, data: Vec<u8> },
+    /// Everything written before this is durable.
+    Flush,
+    /// A point named by the workload, see `RecordingBackend::mark`.
+    Mark(String),
+}
+
+/// A backend that records the writes and flushes passed on to another backend.
+pub struct RecordingBackend<B> {
+    inner: B,
+    log: Mutex<Vec<Op>>,
+}
+
+impl<B: BlockBackend> RecordingBackend<B> {
+    /// Record the operations on `inner`.
+    pub fn new(inner: B) -> Self {
+        RecordingBackend {
+            inner: inner,
+            log: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The backend the operations are passed on to.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// Record a point the workload has reached, such as an `fsync` returning. Blocks still dirty
+    /// in the `Disk`'s cache have not been recorded yet, so a mark only promises what the file
+    /// system has already written or flushed.
+    pub fn mark<S: Into<String>>(&self, label: S) {
+        self.log.lock().unwrap().push(Op::Mark(label.into()));
+    }
+
+    /// Copy of the operations recorded so far.
+    pub fn log(&self) -> Vec<Op> {
+        self.log.lock().unwrap().clone()
+    }
+
+    /// Forget the operations recorded so far.
+    pub fn clear(&self) {
+        self.log.lock().unwrap().clear();
+    }
+}
+
+impl<B: BlockBackend> BlockBackend for RecordingBackend<B> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<(), c_int> {
+        self.inner.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<(), c_int> {
+        // Hold the log while writing so the log is in the order the writes happened.
+        let mut log = self.log.lock().unwrap();
+        self.inner.write_at(buf, offset)?;
+        log.push(Op::Write {
+            offset: offset,
+            data: buf.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn sync_data(&self) -> Result<(), c_int> {
+        let mut log = self.log.lock().unwrap();
+        self.inner.sync_data()?;
+        log.push(Op::Flush);
+        Ok(())
+    }
+
+    fn sync_all(&self) -> Result<(), c_int> {
+        let mut log = self.log.lock().unwrap();
+        self.inner.sync_all()?;
+        log.push(Op::Flush);
+        Ok(())
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+/// Limits on the crash states generated from a log.
+#[derive(Clone, Copy, Debug)]
+pub struct CrashConfig {
+    /// Try every subset of the writes between two flushes if there are at most this many.
+    /// Larger groups are only tried with one write dropped or one write kept.
+    pub max_exhaustive: usize,
+    /// Stop after this many crash states.
+    pub max_states: usize,
+}
+
+impl Default for CrashConfig {
+    fn default() -> Self {
+        CrashConfig {
+            max_exhaustive: 6,
+            max_states: 10000,
+        }
+    }
+}
+
+/// How a crash state was made.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CrashKind {
+    /// The first `n` writes reached the disk.
+    Prefix(usize),
+    /// The writes are split into groups at each flush, numbered from 0. Every write of the
+    /// groups before group `epoch` reached the disk, and of group `epoch` only `kept` did.
+    /// Writes are numbered from 0 in log order.
+    Reordered { epoch: usize, kept: Vec<usize> },
+}
+
+/// Disk contents a crash could leave behind.
+#[derive(Clone, Debug)]
+pub struct CrashState {
+    pub kind: CrashKind,
+    /// Indices in the log of the writes that reached the disk, in log order.
+    pub writes: Vec<usize>,
+    /// Labels of the marks whose earlier writes all reached the disk.
+    pub marks: Vec<String>,
+}
+
+impl CrashState {
+    /// Whether every write recorded before mark `label` reached the disk.
+    pub fn passed(&self, label: &str) -> bool {
+        self.marks.iter().any(|x| x == label)
+    }
+}
+
+impl fmt::Display for CrashState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            CrashKind::Prefix(n) => write!(f, "first {} writes", n)?,
+            CrashKind::Reordered { epoch, kept } => {
+                write!(f, "groups before {}, then writes {:?} of group {}", epoch, kept, epoch)?
+            }
+        }
+        if let Some(mark) = self.marks.last() {
+            write!(f, " (after mark \"{}\")", mark)?;
+        }
+        Ok(())
+    }
+}
+
+/// Work out the crash states of `log`.
+///
+/// Prefixes come first, shortest first, followed by the reorderings of each group of writes
+/// between flushes. Reorderings that are the same as a prefix are left out, and writes kept
+/// from a group are applied in the order they were issued.
+///
+/// Arguments:
+/// * `log: &[Op]` - Operations recorded by a `RecordingBackend`.
+/// * `config: &CrashConfig` - How many states to generate.
+pub fn crash_states(log: &[Op], config: &CrashConfig) -> Vec<CrashState> {
+    generate(log, config).0
+}
+
+// The crash states of `log`, and whether `config.max_states` left some out.
+fn generate(log: &[Op], config: &CrashConfig) -> (Vec<CrashState>, bool) {
+    let writes: Vec<usize> = log
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, op)| match op {
+            Op::Write { .. } => Some(idx),
+            _ => None,
+        })
+        .collect();
+
+    // Writes between each pair of flushes, as indices into `writes`.
+    let mut epochs: Vec<Vec<usize>> = vec![Vec::new()];
+    let mut nwrites = 0;
+    for op in log {
+        match op {
+            Op::Write { .. } => {
+                epochs.last_mut().unwrap().push(nwrites);
+                nwrites += 1;
+            }
+            Op::Flush => epochs.push(Vec::new()),
+            Op::Mark(_) => {}
+        }
+    }
+
+    let mut states = Vec::new();
+    for n in 0..=writes.len() {
+        if states.len() >= config.max_states {
+            return (states, true);
+        }
+        states.push(new_state(log, CrashKind::Prefix(n), writes[..n].to_vec()));
+    }
+
+    let mut persisted = 0;
+    for (epoch, group) in epochs.iter().enumerate() {
+        let mut seen = BTreeSet::new();
+        for kept in subsets(group.len(), config.max_exhaustive) {
+            // A prefix of the group is a prefix of the log.
+            if kept.iter().enumerate().all(|(idx, x)| idx == *x) || !seen.insert(kept.clone()) {
+                continue;
+            }
+            if states.len() >= config.max_states {
+                return (states, true);
+            }
+            let mut state_writes = writes[..persisted].to_vec();
+            state_writes.extend(kept.iter().map(|x| writes[group[*x]]));
+            let kind = CrashKind::Reordered {
+                epoch: epoch,
+                kept: kept.iter().map(|x| group[*x]).collect(),
+            };
+            states.push(new_state(log, kind, state_writes));
+        }
+        persisted += group.len();
+    }
+    (states, false)
+}
+
+// Subsets of `0..len` as sorted lists: all of them if `len` is at most `max_exhaustive`,
+// otherwise those missing one element or holding one element.
+fn subsets(len: usize, max_exhaustive: usize) -> Vec<Vec<usize>> {
+    if len <= max_exhaustive {
+        return (0..1u64 << len)
+            .map(|bits| (0..len).filter(|x| bits & (1 << x) != 0).collect())
+            .collect();
+    }
+    let mut ret = Vec::new();
+    for skip in 0..len {
+        ret.push((0..len).filter(|x| *x != skip).collect());
+    }
+    for keep in 0..len {
+        ret.push(vec![keep]);
+    }
+    ret
+}
+
+fn new_state(log: &[Op], kind: CrashKind, writes: Vec<usize>) -> CrashState {
+    let included: BTreeSet<usize> = writes.iter().cloned().collect();
+    let mut marks = Vec::new();
+    let mut complete = true;
+    for (idx, op) in log.iter().enumerate() {
+        match op {
+            Op::Write { .. } => complete = complete && included.contains(&idx),
+            Op::Mark(label) if complete => marks.push(label.clone()),
+            _ => {}
+        }
+    }
+    CrashState {
+        kind: kind,
+        writes: writes,
+        marks: marks,
+    }
+}
+
+/// A crash state that failed a check.
+#[derive(Clone, Debug)]
+pub struct CrashFailure {
+    pub state: CrashState,
+    /// What went wrong.
+    pub error: String,
+}
+
+impl fmt::Display for CrashFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.state, self.error)
+    }
+}
+
+/// The result of `check`.
+#[derive(Clone, Debug, Default)]
+pub struct CrashReport {
+    /// Number of crash states checked.
+    pub states: usize,
+    /// Whether there were more crash states than `CrashConfig::max_states`, so some weren't
+    /// checked.
+    pub truncated: bool,
+    pub failures: Vec<CrashFailure>,
+}
+
+impl CrashReport {
+    /// Whether every crash state passed.
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} crash states", self.states)?;
+        if self.truncated {
+            write!(f, " (stopped at max_states)")?;
+        }
+        write!(f, ", {} failed", self.failures.len())?;
+        for failure in &self.failures {
+            write!(f, "\n  {}", failure)?;
+        }
+        Ok(())
+    }
+}
+
+/// Mount every crash state of `log` and check it.
+///
+/// Arguments:
+/// * `base: Arc<dyn BlockBackend>` - The disk as it was when recording started. Not changed.
+/// * `bsize: u64` - Block size of the file system.
+/// * `log: &[Op]` - Operations recorded by a `RecordingBackend` during the workload.
+/// * `config: &CrashConfig` - How many states to generate.
+/// * `options: &str` - Mount options passed to `bento_init`.
+/// * `make_fs: M` - Creates a file system on the disk of a crash state.
+/// * `oracle: C` - Checks a mounted crash state. Called after the built-in checks pass.
+pub fn check<'de, I, O, FS, M, C>(
+    base: Arc<dyn BlockBackend>,
+    bsize: u64,
+    log: &[Op],
+    config: &CrashConfig,
+    options: &str,
+    mut make_fs: M,
+    mut oracle: C,
+) -> CrashReport
+where
+    FS: BentoFilesystem<'de, I, O>,
+    M: FnMut(Arc<Disk>) -> FS,
+    C: FnMut(&mut Harness<'de, I, O, FS>, &CrashState) -> Result<(), String>,
+{
+    let (states, truncated) = generate(log, config);
+    let mut report = CrashReport {
+        states: states.len(),
+        truncated: truncated,
+        failures: Vec::new(),
+    };
+    for state in states {
+        if let Err(error) = check_state(&base, bsize, log, &state, options, &mut make_fs, &mut oracle) {
+            report.failures.push(CrashFailure {
+                state: state,
+                error: error,
+            });
+        }
+    }
+    report
+}
+
+fn check_state<'de, I, O, FS, M, C>(
+    base: &Arc<dyn BlockBackend>,
+    bsize: u64,
+    log: &[Op],
+    state: &CrashState,
+    options: &str,
+    make_fs: &mut M,
+    oracle: &mut C,
+) -> Result<(), String>
+where
+    FS: BentoFilesystem<'de, I, O>,
+    M: FnMut(Arc<Disk>) -> FS,
+    C: FnMut(&mut Harness<'de, I, O, FS>, &CrashState) -> Result<(), String>,
+{
+    let backend = CowBackend::new(Arc::clone(base), bsize);
+    for idx in &state.writes {
+        if let Op::Write { offset, data } = &log[*idx] {
+            backend
+                .write_at(data, *offset)
+                .map_err(|x| format!("replaying write {} failed with errno {}", idx, x))?;
+        }
+    }
+    let disk = Arc::new(Disk::with_backend(Arc::new(backend), bsize));
+
+    let mut harness = Harness::new(make_fs(disk));
+    harness
+        .init(options)
+        .map_err(|x| format!("mount failed with errno {}", x))?;
+    let ret = walk(&mut harness, "/")
+        .and_then(|_| {
+            harness
+                .statfs(::testing::ROOT_ID)
+                .map(|_| ())
+                .map_err(|x| format!("statfs failed with errno {}", x))
+        })
+        .and_then(|_| errors_state(&harness))
+        .and_then(|_| oracle(&mut harness, state))
+        .and_then(|_| errors_state(&harness));
+    harness.destroy();
+    ret.and_then(|_| errors_state(&harness))
+}
+
+fn errors_state<'de, I, O, FS>(harness: &Harness<'de, I, O, FS>) -> Result<(), String>
+where
+    FS: BentoFilesystem<'de, I, O>,
+{
+    match harness.error_status() {
+        Some(status) => Err(format!("file system entered the errors state: {}", status)),
+        None => Ok(()),
+    }
+}
+
+// List, stat and read everything under the directory at `path`.
+fn walk<'de, I, O, FS>(harness: &mut Harness<'de, I, O, FS>, path: &str) -> Result<(), String>
+where
+    FS: BentoFilesystem<'de, I, O>,
+{
+    let entries = harness
+        .readdir_path(path)
+        .map_err(|x| format!("listing {} failed with errno {}", path, x))?;
+    for (name, _) in entries {
+        if name == "." || name == ".." {
+            continue;
+        }
+        let child = format!("{}{}", path, name.to_string_lossy());
+        let attr = harness
+            .stat(&child)
+            .map_err(|x| format!("stat of {} failed with errno {}", child, x))?;
+        match attr.kind {
+            FileType::Directory => walk(harness, &format!("{}/", child))?,
+            FileType::Symlink => {
+                harness
+                    .readlink_path(&child)
+                    .map_err(|x| format!("readlink of {} failed with errno {}", child, x))?;
+            }
+            FileType::RegularFile => {
+                let data = read_file(harness, &child)
+                    .map_err(|x| format!("reading {} failed with errno {}", child, x))?;
+                if data.len() as u64 != attr.size {
+                    return Err(format!("{} has size {} but {} bytes were read",
+                                       child, attr.size, data.len()));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Read the whole file at `path`.
+pub fn read_file<'de, I, O, FS>(harness: &mut Harness<'de, I, O, FS>, path: &str) -> Result<Vec<u8>, c_int>
+where
+    FS: BentoFilesystem<'de, I, O>,
+{
+    let fd = harness.open_path(path, libc::O_RDONLY, 0)?;
+    let mut data = Vec::new();
+    let ret = loop {
+        match harness.read_fd(fd, 65536) {
+            Ok(ref chunk) if chunk.is_empty() => break Ok(()),
+            Ok(chunk) => data.extend(chunk),
+            Err(x) => break Err(x),
+        }
+    };
+    let _ = harness.close(fd);
+    ret.map(|_| data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(byte: u8) -> Op {
+        Op::Write {
+            offset: byte as u64 * 512,
+            data: vec![byte; 512],
+        }
+    }
+
+    // Writes 0 and 1, a flush, write 2, mark "a", writes 3 and 4 and a flush. In log order the
+    // writes are at 0, 1, 3, 5 and 6.
+    fn log() -> Vec<Op> {
+        vec![write(0), write(1), Op::Flush, write(2), Op::Mark("a".to_string()), write(3), write(4),
+             Op::Flush]
+    }
+
+    fn config(max_states: usize) -> CrashConfig {
+        CrashConfig {
+            max_exhaustive: 6,
+            max_states: max_states,
+        }
+    }
+
+    #[test]
+    fn every_prefix_comes_first() {
+        let states = crash_states(&log(), &config(100));
+        let writes = [0, 1, 3, 5, 6];
+        for n in 0..=5 {
+            assert_eq!(states[n].kind, CrashKind::Prefix(n));
+            assert_eq!(states[n].writes, writes[..n].to_vec());
+        }
+    }
+
+    #[test]
+    fn writes_are_reordered_within_a_flush() {
+        let states = crash_states(&log(), &config(100));
+        let reordered: Vec<(CrashKind, Vec<usize>)> =
+            states[6..].iter().map(|x| (x.kind.clone(), x.writes.clone())).collect();
+        // Subsets that are prefixes of their group are left out, and the group after the last
+        // flush is empty.
+        assert_eq!(reordered, vec![
+            (CrashKind::Reordered { epoch: 0, kept: vec![1] }, vec![1]),
+            (CrashKind::Reordered { epoch: 1, kept: vec![3] }, vec![0, 1, 5]),
+            (CrashKind::Reordered { epoch: 1, kept: vec![4] }, vec![0, 1, 6]),
+            (CrashKind::Reordered { epoch: 1, kept: vec![2, 4] }, vec![0, 1, 3, 6]),
+            (CrashKind::Reordered { epoch: 1, kept: vec![3, 4] }, vec![0, 1, 5, 6]),
+        ]);
+    }
+
+    #[test]
+    fn no_write_crosses_a_flush() {
+        let log = log();
+        for state in crash_states(&log, &config(100)) {
+            for (flush, _) in log.iter().enumerate().filter(|(_, op)| match op {
+                Op::Flush => true,
+                _ => false,
+            }) {
+                // A write after a flush only reaches the disk once every write before it has.
+                let after = state.writes.iter().any(|x| *x > flush);
+                let before = log[..flush].iter().enumerate().all(|(idx, op)| match op {
+                    Op::Write { .. } => state.writes.contains(&idx),
+                    _ => true,
+                });
+                assert!(!after || before, "{} crosses the flush at {}", state, flush);
+            }
+        }
+    }
+
+    #[test]
+    fn marks_are_passed_once_the_writes_before_them_are_in() {
+        let states = crash_states(&log(), &config(100));
+        let passed: Vec<bool> = states.iter().map(|x| x.passed("a")).collect();
+        assert_eq!(passed, vec![false, false, false, true, true, true, false, false, false, true,
+                                false]);
+        assert!(!states[5].passed("b"));
+        assert_eq!(states[3].to_string(), "first 3 writes (after mark \"a\")");
+    }
+
+    #[test]
+    fn large_groups_drop_or_keep_one_write() {
+        let log: Vec<Op> = (0..8).map(write).collect();
+        let states = crash_states(&log, &CrashConfig { max_exhaustive: 4, max_states: 100 });
+        // Eight with one dropped and eight with one kept, less the two that are prefixes.
+        assert_eq!(states.len(), 9 + 14);
+        for state in &states[9..] {
+            assert!(state.writes.len() == 1 || state.writes.len() == 7, "{}", state);
+        }
+    }
+
+    #[test]
+    fn max_states_truncates() {
+        let (states, truncated) = generate(&log(), &config(8));
+        assert_eq!((states.len(), truncated), (8, true));
+        let (states, truncated) = generate(&log(), &config(11));
+        assert_eq!((states.len(), truncated), (11, false));
+    }
+}
@@ -1,132 +1,182 @@
-use std::alloc;
-use std::fs::File;
-use std::fs::OpenOptions;
+use backend::{AlignedBlock, BlockBackend, FileBackend, RamBackend};
+
+use std::cell::UnsafeCell;
 use std::io;
 use std::ops::Deref;
 use std::ops::DerefMut;
 use std::os::unix::fs::FileExt;
-use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::io::RawFd;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::sync::Weak;
-use std::sync::RwLock;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
 
 use std::cmp::min;
 
-unsafe fn alloc_aligned_vec(bsize: usize) -> Result<Vec<u8>, alloc::LayoutErr> {
-    let layout = alloc::Layout::from_size_align(bsize as usize, bsize as usize)?;
-    let ptr = alloc::alloc(layout);
-    Ok(Vec::from_raw_parts(ptr, bsize as usize, bsize as usize))
-}
-
-struct ArrWrapper {
-    arr: Vec<u8>,
-    dirty: bool,
+/// Number of blocks the buffer cache keeps by default.
+pub const DEFAULT_CACHE_BLOCKS: usize = 8192;
+
+/// How often the flusher thread writes dirty blocks back to disk.
+const WRITEBACK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A cached disk block, shared by every `BufferHead` for it.
+///
+/// Like a kernel buffer_head, the data can be read and written by anyone holding a reference to
+/// the buffer. Concurrent writers must be serialized by the buffer lock or by the file system.
+/// Writeback takes the buffer lock, so data written under the lock is never written out torn.
+struct Buffer {
+    data: UnsafeCell<AlignedBlock>,
+    dirty: AtomicBool,
+    locked: Mutex<bool>,
+    unlocked: Condvar,
     blockno: u64,
-    disk: Arc<File>,
+    disk: Arc<dyn BlockBackend>,
     bsize: u64,
 }
 
-impl ArrWrapper {
-    fn new(blockno: u64, disk: Arc<File>, bsize: u64) -> Result<ArrWrapper, libc::c_int> {
-        // Allocate a vector aligned to `bsize`
-        let mut block_arr = unsafe {
-            alloc_aligned_vec(bsize as usize)
-        }.map_err(|_| libc::EIO)?;
-        disk.read_at(block_arr.as_mut_slice(), blockno * bsize).map_err(|_| libc::EIO)?;
+unsafe impl Send for Buffer {}
+unsafe impl Sync for Buffer {}
+
+impl Buffer {
+    fn read(blockno: u64, disk: Arc<dyn BlockBackend>, bsize: u64) -> Result<Buffer, libc::c_int> {
+        let mut block = AlignedBlock::new(bsize as usize)?;
+        disk.read_at(block.as_mut_slice(), blockno * bsize)?;
         Ok(Self {
-            arr: block_arr,
-            dirty: false,
+            data: UnsafeCell::new(block),
+            dirty: AtomicBool::new(false),
+            locked: Mutex::new(false),
+            unlocked: Condvar::new(),
             blockno: blockno,
             disk: disk,
             bsize: bsize,
         })
     }
 
-    fn mark_buffer_dirty(&mut self) {
-        self.dirty = true;
+    fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::SeqCst)
     }
 
-    fn set_buffer_uptodate(&mut self) {}
-
-    fn sync_dirty_buffer(&mut self) {
-        if self.dirty {
-            let _ = self.disk.write_at(self.arr.as_slice(), self.blockno * self.bsize);
+    fn lock(&self) {
+        let mut locked = self.locked.lock().unwrap();
+        while *locked {
+            locked = self.unlocked.wait(locked).unwrap();
         }
-        self.dirty = false;
+        *locked = true;
+    }
+
+    fn unlock(&self) {
+        *self.locked.lock().unwrap() = false;
+        self.unlocked.notify_one();
     }
 
-    fn data(&self) -> &[u8] {
-        self.arr.as_slice()
+    /// Write the block to disk if it's dirty. The caller must hold the buffer lock. Returns
+    /// whether anything was written.
+    fn write_locked(&self) -> Result<bool, libc::c_int> {
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return Ok(false);
+        }
+        let data = unsafe { (*self.data.get()).as_slice() };
+        if self.disk.write_at(data, self.blockno * self.bsize).is_err() {
+            self.dirty.store(true, Ordering::SeqCst);
+            return Err(libc::EIO);
+        }
+        Ok(true)
     }
 
-    fn data_mut(&mut self) -> &mut [u8] {
-        self.arr.as_mut_slice()
+    fn write_back(&self) -> Result<bool, libc::c_int> {
+        self.lock();
+        let ret = self.write_locked();
+        self.unlock();
+        ret
     }
 }
 
-impl Drop for ArrWrapper {
+impl Drop for Buffer {
     fn drop(&mut self) {
-        if self.dirty {
-            let _ = self.disk.write_at(self.arr.as_mut_slice(), self.blockno * self.bsize);
+        // Only reached if the buffer outlives its cache, which writes back everything it holds.
+        if *self.dirty.get_mut() {
+            let data = self.data.get_mut().as_slice();
+            let _ = self.disk.write_at(data, self.blockno * self.bsize);
         }
     }
 }
 
 pub struct BufferHead {
-    buffer: Arc<ArrWrapper>,
+    buffer: Arc<Buffer>,
+    locked: bool,
     pub blk_no: u64
 }
 
-/// Currently not thread-safe. Multiple threads can mutate the same block at the same time.
+/// A reference to a cached block. The block stays in the cache after the last `BufferHead` for
+/// it is dropped, until it is evicted.
+///
+/// As with a kernel buffer_head, `data_mut` doesn't lock the block. Use `lock` to serialize
+/// writers that aren't already serialized by the file system.
 impl BufferHead {
-    fn new(buffer: Arc<ArrWrapper>, blk_no: u64) -> Self {
+    fn new(buffer: Arc<Buffer>, blk_no: u64) -> Self {
         Self {
-            buffer: buffer, 
+            buffer: buffer,
+            locked: false,
             blk_no: blk_no,
         }
     }
 
     pub fn mark_buffer_dirty(&mut self) {
-        unsafe {
-            Arc::get_mut_unchecked(&mut self.buffer).mark_buffer_dirty();
-        }
+        self.buffer.dirty.store(true, Ordering::SeqCst);
     }
 
-    pub fn set_buffer_uptodate(&mut self) {
-        unsafe {
-            Arc::get_mut_unchecked(&mut self.buffer).set_buffer_uptodate();
-        }
+    pub fn set_buffer_uptodate(&mut self) {}
+
+    /// Another unlocked reference to the same cached block, which keeps it from being evicted.
+    pub(crate) fn clone_ref(&self) -> BufferHead {
+        BufferHead::new(Arc::clone(&self.buffer), self.blk_no)
     }
 
+    /// Write the block to disk now if it's dirty.
     pub fn sync_dirty_buffer(&mut self) {
-        unsafe {
-            Arc::get_mut_unchecked(&mut self.buffer).sync_dirty_buffer();
-        }
+        let _ = if self.locked {
+            self.buffer.write_locked()
+        } else {
+            self.buffer.write_back()
+        };
     }
 
     pub fn lock(&mut self) -> BHLockGuard<'_> {
+        self.buffer.lock();
+        self.locked = true;
         return BHLockGuard {
             bh: self
         }
     }
 
-    pub fn unlock(&mut self) {}
+    pub fn unlock(&mut self) {
+        if self.locked {
+            self.locked = false;
+            self.buffer.unlock();
+        }
+    }
 
     pub fn data(&self) -> &[u8] {
-        self.buffer.data()
+        unsafe { (*self.buffer.data.get()).as_slice() }
     }
 
     pub fn data_mut(&mut self) -> &mut [u8] {
-        unsafe {
-            Arc::get_mut_unchecked(&mut self.buffer).data_mut()
-        }
+        unsafe { (*self.buffer.data.get()).as_mut_slice() }
     }
 
 }
 
+impl Drop for BufferHead {
+    fn drop(&mut self) {
+        self.unlock();
+    }
+}
+
 pub struct BHLockGuard<'a> {
     bh: &'a mut BufferHead
 }
@@ -141,91 +191,282 @@ impl Deref for BHLockGuard<'_> {
     type Target = BufferHead;
 
     fn deref(&self) -> &BufferHead {
-        unsafe { &*self.bh }
+        &*self.bh
     }
 }
 
 impl DerefMut for BHLockGuard<'_> {
     fn deref_mut(&mut self) -> &mut BufferHead {
-        unsafe { &mut *self.bh }
+        &mut *self.bh
     }
 }
 
+/// Buffer cache counters, as returned by `Disk::cache_stats`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    /// `bread` and `getblk` calls that found the block in the cache.
+    pub hits: u64,
+    /// `bread` and `getblk` calls that had to read the block from disk.
+    pub misses: u64,
+    /// Blocks dropped from the cache to make room for others.
+    pub evictions: u64,
+    /// Dirty blocks written back by the flusher thread and the `sync_*` calls.
+    pub writebacks: u64,
+    /// Blocks in the cache now.
+    pub cached: usize,
+    /// Blocks the cache holds before it starts evicting.
+    pub capacity: usize,
+}
+
+struct CacheEntry {
+    buffer: Arc<Buffer>,
+    last_use: u64,
+}
+
+struct CacheState {
+    entries: HashMap<u64, CacheEntry>,
+    // Block numbers by last use, oldest first.
+    lru: BTreeMap<u64, u64>,
+    clock: u64,
+}
+
+impl CacheState {
+    fn touch(&mut self, blockno: u64) -> Option<Arc<Buffer>> {
+        self.clock += 1;
+        let clock = self.clock;
+        let entry = self.entries.get_mut(&blockno)?;
+        self.lru.remove(&entry.last_use);
+        self.lru.insert(clock, blockno);
+        entry.last_use = clock;
+        Some(Arc::clone(&entry.buffer))
+    }
+}
+
+struct FlusherState {
+    wake: bool,
+    stop: bool,
+}
+
+/// The parts of the cache shared with the flusher thread.
+struct CacheShared {
+    state: Mutex<CacheState>,
+    capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    writebacks: AtomicU64,
+    flusher: Mutex<FlusherState>,
+    flusher_wake: Condvar,
+}
+
+impl CacheShared {
+    fn wake_flusher(&self) {
+        self.flusher.lock().unwrap().wake = true;
+        self.flusher_wake.notify_one();
+    }
+
+    /// Drop the least recently used blocks until the cache is back to its capacity. Blocks
+    /// that are in use or dirty are skipped, so the cache can go over capacity until they are
+    /// released or written back.
+    fn evict(&self, state: &mut CacheState) {
+        let mut excess = state.entries.len().saturating_sub(self.capacity);
+        if excess == 0 {
+            return;
+        }
+        let mut victims = Vec::new();
+        let mut saw_dirty = false;
+        for (last_use, blockno) in state.lru.iter() {
+            let buffer = &state.entries[blockno].buffer;
+            if Arc::strong_count(buffer) > 1 {
+                continue;
+            }
+            if buffer.is_dirty() {
+                saw_dirty = true;
+                continue;
+            }
+            victims.push((*last_use, *blockno));
+            excess -= 1;
+            if excess == 0 {
+                break;
+            }
+        }
+        for (last_use, blockno) in victims {
+            state.lru.remove(&last_use);
+            state.entries.remove(&blockno);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        if saw_dirty {
+            self.wake_flusher();
+        }
+    }
+
+    /// Write back every dirty block. Returns the first error, after trying all of them.
+    fn write_back(&self) -> Result<(), libc::c_int> {
+        let dirty: Vec<Arc<Buffer>> = {
+            let state = self.state.lock().unwrap();
+            state.entries.values()
+                .filter(|e| e.buffer.is_dirty())
+                .map(|e| Arc::clone(&e.buffer))
+                .collect()
+        };
+        let mut ret = Ok(());
+        for buffer in dirty {
+            match buffer.write_back() {
+                Ok(true) => {
+                    self.writebacks.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(false) => {}
+                Err(x) => {
+                    if ret.is_ok() {
+                        ret = Err(x);
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    fn run_flusher(&self) {
+        loop {
+            {
+                let mut flusher = self.flusher.lock().unwrap();
+                if !flusher.wake && !flusher.stop {
+                    flusher = self.flusher_wake.wait_timeout(flusher, WRITEBACK_INTERVAL).unwrap().0;
+                }
+                if flusher.stop {
+                    return;
+                }
+                flusher.wake = false;
+            }
+            let _ = self.write_back();
+            // Blocks written back can be evicted now.
+            let mut state = self.state.lock().unwrap();
+            self.evict(&mut state);
+        }
+    }
+}
 
+/// A bounded LRU cache of disk blocks. Blocks stay cached after they are released, and dirty
+/// blocks are written back by a flusher thread every `WRITEBACK_INTERVAL`, when they need to be
+/// evicted, and on `sync_all`, `sync_data` and drop.
 struct BufferCache {
-    file: Arc<File>,
-    cache: RwLock<HashMap<u64, Weak<ArrWrapper>>>,
+    backend: Arc<dyn BlockBackend>,
+    shared: Arc<CacheShared>,
+    flusher: Option<thread::JoinHandle<()>>,
     bsize: u64
 }
 
 impl BufferCache {
-    fn new(name: &str, bsize: u64) -> Self {
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .custom_flags(libc::O_DIRECT)
-            .open(name)
+    fn open(name: &str, bsize: u64, capacity: usize) -> Self {
+        let backend = FileBackend::open(name).unwrap();
+        Self::new(Arc::new(backend), bsize, capacity)
+    }
+
+    fn new(backend: Arc<dyn BlockBackend>, bsize: u64, capacity: usize) -> Self {
+        let shared = Arc::new(CacheShared {
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+            }),
+            capacity: capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            writebacks: AtomicU64::new(0),
+            flusher: Mutex::new(FlusherState {
+                wake: false,
+                stop: false,
+            }),
+            flusher_wake: Condvar::new(),
+        });
+        let flusher_shared = Arc::clone(&shared);
+        let flusher = thread::Builder::new()
+            .name("bento-flush".to_string())
+            .spawn(move || flusher_shared.run_flusher())
             .unwrap();
         Self {
-            file: Arc::new(f),
-            cache: RwLock::new(HashMap::new()),
+            backend: backend,
+            shared: shared,
+            flusher: Some(flusher),
             bsize: bsize,
         }
     }
 
     fn sync_all(&self) -> Result<(), libc::c_int> {
-        let cache_read = self.cache.read().unwrap();
-        for weak in cache_read.values() {
-            if let Some(mut bh) = weak.upgrade() {
-                unsafe {
-                    Arc::get_mut_unchecked(&mut bh).sync_dirty_buffer();
-                }
-            }
-        }
-        self.file.sync_all().map_err(|_| libc::EIO)
+        self.shared.write_back()?;
+        self.backend.sync_all()
     }
 
     fn sync_data(&self) -> Result<(), libc::c_int> {
-        let cache_read = self.cache.read().unwrap();
-        for weak in cache_read.values() {
-            if let Some(mut bh) = weak.upgrade() {
-                unsafe {
-                    Arc::get_mut_unchecked(&mut bh).sync_dirty_buffer();
-                }
-            }
-        }
-        self.file.sync_data().map_err(|_| libc::EIO)
+        self.shared.write_back()?;
+        self.backend.sync_data()
     }
 
     #[allow(dead_code)]
     fn sync_block(&self, blockno: u64) -> Result<(), libc::c_int> {
-        let cache_read = self.cache.read().unwrap();
-        if let Some(weak) = cache_read.get(&blockno) {
-            if let Some(mut bh) = weak.upgrade() {
-                unsafe {
-                    Arc::get_mut_unchecked(&mut bh).sync_dirty_buffer();
-                }
+        let buffer = {
+            let state = self.shared.state.lock().unwrap();
+            state.entries.get(&blockno).map(|e| Arc::clone(&e.buffer))
+        };
+        if let Some(buffer) = buffer {
+            if buffer.write_back()? {
+                self.shared.writebacks.fetch_add(1, Ordering::Relaxed);
             }
         }
-        self.file.sync_data().map_err(|_| libc::EIO)
+        self.backend.sync_data()
     }
 
     fn bread(&self, blockno: u64) -> Result<BufferHead, libc::c_int> {
-        let mut cache_lock = self.cache.write().unwrap();
-        if let Some(weak) = cache_lock.get_mut(&blockno) {
-            if let Some(buf_lock) = weak.upgrade() {
-                return Ok(BufferHead::new(Arc::clone(&buf_lock), blockno));
-            }
+        if let Some(buffer) = self.shared.state.lock().unwrap().touch(blockno) {
+            self.shared.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(BufferHead::new(buffer, blockno));
+        }
+        self.shared.misses.fetch_add(1, Ordering::Relaxed);
+
+        // Read without holding the cache lock. If another thread read the same block in the
+        // meantime, use its copy.
+        let new_buffer = Arc::new(Buffer::read(blockno, Arc::clone(&self.backend), self.bsize)?);
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(buffer) = state.touch(blockno) {
+            return Ok(BufferHead::new(buffer, blockno));
         }
-        let bh_buf = ArrWrapper::new(blockno, Arc::clone(&self.file), self.bsize)?;
-        let new_arc = Arc::new(bh_buf);
-        cache_lock.insert(blockno, Arc::downgrade(&new_arc));
-        return Ok(BufferHead::new(new_arc, blockno));
+        state.clock += 1;
+        let clock = state.clock;
+        state.lru.insert(clock, blockno);
+        state.entries.insert(blockno, CacheEntry {
+            buffer: Arc::clone(&new_buffer),
+            last_use: clock,
+        });
+        self.shared.evict(&mut state);
+        return Ok(BufferHead::new(new_buffer, blockno));
     }
 
     fn getblk(&self, blockno: u64) -> Result<BufferHead, libc::c_int> {
         self.bread(blockno)
     }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.shared.hits.load(Ordering::Relaxed),
+            misses: self.shared.misses.load(Ordering::Relaxed),
+            evictions: self.shared.evictions.load(Ordering::Relaxed),
+            writebacks: self.shared.writebacks.load(Ordering::Relaxed),
+            cached: self.shared.state.lock().unwrap().entries.len(),
+            capacity: self.shared.capacity,
+        }
+    }
+}
+
+impl Drop for BufferCache {
+    fn drop(&mut self) {
+        self.shared.flusher.lock().unwrap().stop = true;
+        self.shared.flusher_wake.notify_one();
+        if let Some(flusher) = self.flusher.take() {
+            let _ = flusher.join();
+        }
+        let _ = self.shared.write_back();
+    }
 }
 
 pub struct Disk {
@@ -234,11 +475,39 @@ pub struct Disk {
 
 impl Disk {
     pub fn new(name: &str, bsize: u64) -> Self {
+        Self::with_cache_size(name, bsize, DEFAULT_CACHE_BLOCKS)
+    }
+
+    /// Open `name` with a buffer cache holding up to `blocks` blocks.
+    pub fn with_cache_size(name: &str, bsize: u64, blocks: usize) -> Self {
+        Self {
+            cache: BufferCache::open(name, bsize, blocks),
+        }
+    }
+
+    /// Use `backend` for the disk's blocks, with the default cache size. Keep a clone of the
+    /// `Arc` to look at the backend, such as a `RamBackend`'s image, after `sync_all`.
+    pub fn with_backend(backend: Arc<dyn BlockBackend>, bsize: u64) -> Self {
+        Self::with_backend_and_cache_size(backend, bsize, DEFAULT_CACHE_BLOCKS)
+    }
+
+    /// Use `backend` for the disk's blocks, with a buffer cache holding up to `blocks` blocks.
+    pub fn with_backend_and_cache_size(backend: Arc<dyn BlockBackend>, bsize: u64, blocks: usize) -> Self {
         Self {
-            cache: BufferCache::new(name, bsize),
+            cache: BufferCache::new(backend, bsize, blocks),
         }
     }
 
+    /// Create a zeroed disk of `blocks` blocks held in memory, for testing.
+    pub fn new_ram(bsize: u64, blocks: u64) -> Self {
+        Self::with_backend(Arc::new(RamBackend::new(bsize * blocks)), bsize)
+    }
+
+    /// Create a disk held in memory from a disk image, such as one written by mkfs.
+    pub fn from_image(image: Vec<u8>, bsize: u64) -> Self {
+        Self::with_backend(Arc::new(RamBackend::from_image(image)), bsize)
+    }
+
     pub fn sync_all(&self) -> Result<(), libc::c_int> {
         self.cache.sync_all()
     }
@@ -259,11 +528,15 @@ impl Disk {
     pub fn getblk(&self, blockno: u64) -> Result<BufferHead, libc::c_int> {
         self.cache.getblk(blockno)
     }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
 }
 
 impl AsRawFd for Disk {
     fn as_raw_fd(&self) -> RawFd {
-        self.cache.file.as_raw_fd()
+        self.cache.backend.as_raw_fd()
     }
 }
 
@@ -275,13 +548,13 @@ pub struct DiskFile {
 impl DiskFile {
     pub fn new(name: &str, bsize: u64) -> Self {
         Self {
-            cache: BufferCache::new(name, bsize),
+            cache: BufferCache::open(name, bsize, DEFAULT_CACHE_BLOCKS),
             bsize: bsize,
         }
     }
 
     pub fn sync_all(&self) -> Result<(), libc::c_int> {
-        self.cache.file.sync_all().map_err(|_| libc::EIO)
+        self.cache.sync_all()
     }
 
     pub fn sync_data(&self) -> Result<(), libc::c_int> {
@@ -291,11 +564,15 @@ impl DiskFile {
     pub fn sync_block(&self, blockno: u64) -> Result<(), libc::c_int> {
         self.cache.sync_block(blockno)
     }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
 }
 
 impl AsRawFd for DiskFile {
     fn as_raw_fd(&self) -> RawFd {
-        self.cache.file.as_raw_fd()
+        self.cache.backend.as_raw_fd()
     }
 }
 
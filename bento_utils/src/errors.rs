@@ -0,0 +1,337 @@
+//! The errors state of a Bento file system.
+//!
+//! A file system enters the errors state when it panics or when it calls `fs_error`. From then
+//! on `session` fails every request that could modify the file system with `EROFS`,
+//! like ext4 mounted with `errors=remount-ro`, while requests that only read are still passed
+//! on. The file system is told through `BentoFilesystem::bento_error`, and an administrator can
+//! read the state from the `trusted.bento.status` extended attribute of any file.
+//!
+//! Each mount has its own state, which is cleared when it is initialized. The functions here
+//! use the state of the mount whose request the calling thread is running, set up by `session`
+//! or `testing::Harness` with `enter`. Threads a file system starts itself, such as a journal's
+//! commit thread, have no state unless they call `enter` with the one `current` returned when
+//! they were started; without one, `fs_error` only logs the error.
+//!
+//! A panic is caught at the end of the request that caused it. Its reply is dropped without
+//! being sent, which fails the request with `EIO`.
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Name of the extended attribute that reports the errors state.
+pub const STATUS_XATTR: &str = "trusted.bento.status";
+
+/// What put the file system in the errors state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCause {
+    /// The file system panicked.
+    Panic,
+    /// A memory allocation failed. Only reported in the kernel; in userspace a failed
+    /// allocation aborts the process.
+    OutOfMemory,
+    /// The file system called `fs_error`.
+    Reported,
+}
+
+impl ErrorCause {
+    fn from_u32(val: u32) -> Self {
+        match val {
+            1 => ErrorCause::Panic,
+            2 => ErrorCause::OutOfMemory,
+            _ => ErrorCause::Reported,
+        }
+    }
+
+    fn to_u32(self) -> u32 {
+        match self {
+            ErrorCause::Panic => 1,
+            ErrorCause::OutOfMemory => 2,
+            ErrorCause::Reported => 3,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ErrorCause::Panic => "panic",
+            ErrorCause::OutOfMemory => "oom",
+            ErrorCause::Reported => "reported",
+        }
+    }
+}
+
+/// The errors state of a file system that has hit an error.
+#[derive(Clone, Copy, Debug)]
+pub struct ErrorStatus {
+    /// What caused the first error.
+    pub cause: ErrorCause,
+    /// Errno of the first error.
+    pub errno: libc::c_int,
+    /// Request that was running when the first error happened, or "none".
+    pub op: &'static str,
+    /// Number of errors since the file system was mounted.
+    pub count: usize,
+}
+
+impl fmt::Display for ErrorStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "state=errors cause={} op={} errno={} count={}",
+               self.cause.name(), self.op, self.errno, self.count)
+    }
+}
+
+/// FUSE opcodes of the requests passed on to a file system.
+#[doc(hidden)]
+pub mod opcode {
+    pub const LOOKUP: u32 = 1;
+    pub const FORGET: u32 = 2;
+    pub const GETATTR: u32 = 3;
+    pub const SETATTR: u32 = 4;
+    pub const READLINK: u32 = 5;
+    pub const SYMLINK: u32 = 6;
+    pub const MKNOD: u32 = 8;
+    pub const MKDIR: u32 = 9;
+    pub const UNLINK: u32 = 10;
+    pub const RMDIR: u32 = 11;
+    pub const RENAME: u32 = 12;
+    pub const LINK: u32 = 13;
+    pub const OPEN: u32 = 14;
+    pub const READ: u32 = 15;
+    pub const WRITE: u32 = 16;
+    pub const STATFS: u32 = 17;
+    pub const RELEASE: u32 = 18;
+    pub const FSYNC: u32 = 20;
+    pub const SETXATTR: u32 = 21;
+    pub const GETXATTR: u32 = 22;
+    pub const LISTXATTR: u32 = 23;
+    pub const REMOVEXATTR: u32 = 24;
+    pub const FLUSH: u32 = 25;
+    pub const INIT: u32 = 26;
+    pub const OPENDIR: u32 = 27;
+    pub const READDIR: u32 = 28;
+    pub const RELEASEDIR: u32 = 29;
+    pub const FSYNCDIR: u32 = 30;
+    pub const GETLK: u32 = 31;
+    pub const SETLK: u32 = 32;
+    pub const SETLKW: u32 = 33;
+    pub const ACCESS: u32 = 34;
+    pub const CREATE: u32 = 35;
+    pub const BMAP: u32 = 37;
+    pub const DESTROY: u32 = 38;
+    pub const IOCTL: u32 = 39;
+    pub const POLL: u32 = 40;
+    pub const FALLOCATE: u32 = 43;
+    pub const READDIRPLUS: u32 = 44;
+    pub const RENAME2: u32 = 45;
+    pub const LSEEK: u32 = 46;
+    pub const COPY_FILE_RANGE: u32 = 47;
+}
+
+/// The errors state of one mount.
+// Atomics keep the state the same shape as in the kernel, where the panic handler updates it.
+#[doc(hidden)]
+#[derive(Debug, Default)]
+pub struct ErrorState {
+    errors: AtomicBool,
+    cause: AtomicU32,
+    errno: AtomicI32,
+    error_op: AtomicU32,
+    count: AtomicUsize,
+    // Set when the state changes and `bento_error` hasn't been called yet.
+    pending: AtomicBool,
+}
+
+impl ErrorState {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Whether the mount is in the errors state.
+    pub fn read_only(&self) -> bool {
+        self.errors.load(Ordering::SeqCst)
+    }
+
+    /// Get the errors state, or `None` if the mount hasn't hit an error.
+    pub fn status(&self) -> Option<ErrorStatus> {
+        if !self.read_only() {
+            return None;
+        }
+        Some(ErrorStatus {
+            cause: ErrorCause::from_u32(self.cause.load(Ordering::SeqCst)),
+            errno: self.errno.load(Ordering::SeqCst),
+            op: op_name(self.error_op.load(Ordering::SeqCst)),
+            count: self.count.load(Ordering::SeqCst),
+        })
+    }
+
+    fn record(&self, cause: ErrorCause, errno: libc::c_int) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        if self.errors.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            self.cause.store(cause.to_u32(), Ordering::SeqCst);
+            self.errno.store(errno, Ordering::SeqCst);
+            self.error_op.store(CURRENT_OP.with(|op| op.get()), Ordering::SeqCst);
+        }
+        self.pending.store(true, Ordering::SeqCst);
+    }
+
+    fn reset(&self) {
+        self.errors.store(false, Ordering::SeqCst);
+        self.cause.store(0, Ordering::SeqCst);
+        self.errno.store(0, Ordering::SeqCst);
+        self.error_op.store(0, Ordering::SeqCst);
+        self.count.store(0, Ordering::SeqCst);
+        self.pending.store(false, Ordering::SeqCst);
+    }
+}
+
+thread_local! {
+    // Opcode of the request this thread is running.
+    static CURRENT_OP: Cell<u32> = Cell::new(0);
+    // Errors state of the mount this thread runs requests for.
+    static CURRENT: RefCell<Option<Arc<ErrorState>>> = RefCell::new(None);
+}
+
+/// Restores the errors state the thread had before `enter` when dropped.
+#[doc(hidden)]
+pub struct Entered {
+    prev: Option<Arc<ErrorState>>,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        let prev = self.prev.take();
+        CURRENT.with(|cur| *cur.borrow_mut() = prev);
+    }
+}
+
+/// Use `state` for the errors of the calling thread until the returned value is dropped.
+#[doc(hidden)]
+pub fn enter(state: &Arc<ErrorState>) -> Entered {
+    let prev = CURRENT.with(|cur| cur.borrow_mut().replace(Arc::clone(state)));
+    Entered { prev: prev }
+}
+
+/// The errors state of the calling thread, to pass to `enter` in threads it starts.
+pub fn current() -> Option<Arc<ErrorState>> {
+    CURRENT.with(|cur| cur.borrow().clone())
+}
+
+/// Put the file system in the errors state.
+///
+/// File systems call this when they find an error they can't recover from, such as corrupted
+/// metadata or an aborted journal, and should then fail the request, usually with `EIO`.
+///
+/// Arguments:
+/// * `errno: c_int` - Error code describing the problem.
+pub fn fs_error(errno: libc::c_int) {
+    if !record(ErrorCause::Reported, errno) {
+        println!("bento: file system error {} outside of a mount's requests", errno);
+    }
+}
+
+/// Whether the file system is in the errors state, so requests that modify it are refused.
+pub fn read_only() -> bool {
+    CURRENT.with(|cur| cur.borrow().as_ref().map_or(false, |state| state.read_only()))
+}
+
+/// Get the errors state, or `None` if the file system hasn't hit an error.
+pub fn status() -> Option<ErrorStatus> {
+    CURRENT.with(|cur| cur.borrow().as_ref().and_then(|state| state.status()))
+}
+
+// Record an error in the state of the calling thread. Returns false if it has none.
+fn record(cause: ErrorCause, errno: libc::c_int) -> bool {
+    CURRENT.with(|cur| match *cur.borrow() {
+        Some(ref state) => {
+            state.record(cause, errno);
+            true
+        }
+        None => false,
+    })
+}
+
+/// Run request `opcode`, catching a panic. Returns `None` if `f` panicked.
+#[doc(hidden)]
+pub fn guard<R, F: FnOnce() -> R>(opcode: u32, f: F) -> Option<R> {
+    if opcode == opcode::INIT {
+        CURRENT.with(|cur| cur.borrow().as_ref().map(|state| state.reset()));
+    }
+    CURRENT_OP.with(|op| op.set(opcode));
+    let ret = panic::catch_unwind(AssertUnwindSafe(f));
+    if ret.is_err() {
+        record(ErrorCause::Panic, libc::EIO);
+    }
+    CURRENT_OP.with(|op| op.set(0));
+    ret.ok()
+}
+
+#[doc(hidden)]
+pub fn take_pending() -> bool {
+    CURRENT.with(|cur| cur.borrow().as_ref().map_or(false, |state| state.pending.swap(false, Ordering::SeqCst)))
+}
+
+/// The value of the status attribute.
+#[doc(hidden)]
+pub fn status_value() -> String {
+    match status() {
+        Some(status) => format!("{}\n", status),
+        None => "state=ok\n".to_string(),
+    }
+}
+
+/// Whether an open with `flags` could modify the file.
+#[doc(hidden)]
+pub fn open_modifies(flags: u32) -> bool {
+    let flags = flags as libc::c_int;
+    flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0
+}
+
+fn op_name(opcode: u32) -> &'static str {
+    match opcode {
+        opcode::LOOKUP => "lookup",
+        opcode::FORGET => "forget",
+        opcode::GETATTR => "getattr",
+        opcode::SETATTR => "setattr",
+        opcode::READLINK => "readlink",
+        opcode::SYMLINK => "symlink",
+        opcode::MKNOD => "mknod",
+        opcode::MKDIR => "mkdir",
+        opcode::UNLINK => "unlink",
+        opcode::RMDIR => "rmdir",
+        opcode::RENAME => "rename",
+        opcode::LINK => "link",
+        opcode::OPEN => "open",
+        opcode::READ => "read",
+        opcode::WRITE => "write",
+        opcode::STATFS => "statfs",
+        opcode::RELEASE => "release",
+        opcode::FSYNC => "fsync",
+        opcode::SETXATTR => "setxattr",
+        opcode::GETXATTR => "getxattr",
+        opcode::LISTXATTR => "listxattr",
+        opcode::REMOVEXATTR => "removexattr",
+        opcode::FLUSH => "flush",
+        opcode::INIT => "init",
+        opcode::OPENDIR => "opendir",
+        opcode::READDIR => "readdir",
+        opcode::RELEASEDIR => "releasedir",
+        opcode::FSYNCDIR => "fsyncdir",
+        opcode::GETLK => "getlk",
+        opcode::SETLK => "setlk",
+        opcode::SETLKW => "setlkw",
+        opcode::ACCESS => "access",
+        opcode::CREATE => "create",
+        opcode::BMAP => "bmap",
+        opcode::DESTROY => "destroy",
+        opcode::IOCTL => "ioctl",
+        opcode::POLL => "poll",
+        opcode::FALLOCATE => "fallocate",
+        opcode::READDIRPLUS => "readdirplus",
+        opcode::RENAME2 => "rename2",
+        opcode::LSEEK => "lseek",
+        opcode::COPY_FILE_RANGE => "copy_file_range",
+        _ => "none",
+    }
+}
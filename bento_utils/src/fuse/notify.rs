@@ -0,0 +1,124 @@
+use libc::{c_int, ENAMETOOLONG, ENOTCONN};
+
+use std::ffi::OsStr;
+use std::sync::Arc;
+
+// Longest name the kernel accepts in a notification.
+const FUSE_NAME_MAX: usize = 1024;
+
+/// A notification passed to the callback of a `Notifier`.
+#[derive(Clone, Copy, Debug)]
+pub enum Notification<'a> {
+    /// Invalidate the attributes of an inode and a range of its cached data.
+    InvalInode { ino: u64, offset: i64, len: i64 },
+    /// Invalidate a directory entry.
+    InvalEntry { parent: u64, name: &'a OsStr },
+    /// A directory entry was removed.
+    Delete { parent: u64, child: u64, name: &'a OsStr },
+    /// Put data into the page cache of an inode.
+    Store { ino: u64, offset: u64, data: &'a [u8] },
+}
+
+/// Tells the kernel that something it may have cached for a mount has changed.
+///
+/// A file system gets the notifier of its mount from `FuseConnInfo::notifier` in `bento_init`.
+/// Notifiers can be cloned and used from any thread, such as one that watches a server for
+/// changes, for as long as the file system is mounted. Afterwards every notification fails
+/// with `ENODEV`. A notifier that was never given to a mount fails with `ENOTCONN`, and the one
+/// a `testing::Harness` gives records notifications instead of sending them.
+///
+/// A notification about an inode or directory waits for locks the kernel holds while a request
+/// on it is being handled, so it must not be sent while handling a request on the same inode or
+/// directory, or while holding a lock such a request needs.
+#[derive(Clone, Default)]
+pub struct Notifier {
+    callback: Option<Arc<dyn Fn(Notification) -> Result<(), c_int> + Send + Sync>>,
+}
+
+impl Notifier {
+    pub fn new<F>(callback: F) -> Notifier
+    where
+        F: Fn(Notification) -> Result<(), c_int> + Send + Sync + 'static,
+    {
+        Notifier { callback: Some(Arc::new(callback)) }
+    }
+
+    fn send(&self, notification: Notification) -> Result<(), c_int> {
+        match self.callback {
+            Some(ref callback) => callback(notification),
+            None => Err(ENOTCONN),
+        }
+    }
+
+    /// Invalidate the cached attributes of an inode and a range of its cached data.
+    ///
+    /// Fails with `ENOENT` if the kernel doesn't have the inode cached.
+    ///
+    /// Arguments:
+    /// * `ino: u64` - Inode number.
+    /// * `offset: i64` - Start of the range of data. If negative, only the attributes are
+    /// invalidated.
+    /// * `len: i64` - Length of the range of data. If 0 or negative, the range goes to the end of
+    /// the file.
+    pub fn notify_inval_inode(&self, ino: u64, offset: i64, len: i64) -> Result<(), c_int> {
+        self.send(Notification::InvalInode {
+            ino: ino,
+            offset: offset,
+            len: len,
+        })
+    }
+
+    /// Invalidate a cached directory entry, so the next access to it looks it up again.
+    ///
+    /// Fails with `ENOENT` if the kernel doesn't have the directory cached.
+    ///
+    /// Arguments:
+    /// * `parent: u64` - Inode number of the directory.
+    /// * `name: &OsStr` - Name of the entry.
+    pub fn notify_inval_entry(&self, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        if name.len() > FUSE_NAME_MAX {
+            return Err(ENAMETOOLONG);
+        }
+        self.send(Notification::InvalEntry {
+            parent: parent,
+            name: name,
+        })
+    }
+
+    /// Tell the kernel that a directory entry was removed.
+    ///
+    /// This invalidates the entry like `notify_inval_entry`, and if it still points to `child`,
+    /// also removes it from the kernel's caches as an unlink would.
+    ///
+    /// Arguments:
+    /// * `parent: u64` - Inode number of the directory.
+    /// * `child: u64` - Inode number the entry pointed to.
+    /// * `name: &OsStr` - Name of the entry.
+    pub fn notify_delete(&self, parent: u64, child: u64, name: &OsStr) -> Result<(), c_int> {
+        if name.len() > FUSE_NAME_MAX {
+            return Err(ENAMETOOLONG);
+        }
+        self.send(Notification::Delete {
+            parent: parent,
+            child: child,
+            name: name,
+        })
+    }
+
+    /// Put data into the page cache of an inode, so reads of that range don't have to reach the
+    /// file system.
+    ///
+    /// Fails with `ENOENT` if the kernel doesn't have the inode cached.
+    ///
+    /// Arguments:
+    /// * `ino: u64` - Inode number.
+    /// * `offset: u64` - Offset in the file to put the data.
+    /// * `data: &[u8]` - The data.
+    pub fn notify_store(&self, ino: u64, offset: u64, data: &[u8]) -> Result<(), c_int> {
+        self.send(Notification::Store {
+            ino: ino,
+            offset: offset,
+            data: data,
+        })
+    }
+}
@@ -0,0 +1,649 @@
+use fuse::{FileAttr, FileType};
+
+use libc::{c_int, EIO};
+
+use std::ffi::{OsStr, OsString};
+use std::mem;
+
+use time::Timespec;
+
+// Sizes of a `fuse_dirent` header and a `fuse_entry_out`, used to fill directory replies up
+// to the size the kernel asked for.
+const FUSE_NAME_OFFSET: usize = 24;
+const FUSE_ENTRY_OUT_SIZE: usize = 128;
+
+fn fuse_dirent_align(x: usize) -> usize {
+    (x + 7) & !7
+}
+
+// Callback that receives the result of a reply. If the reply is dropped without being sent,
+// the callback gets `EIO`.
+struct Sender<T> {
+    callback: Option<Box<dyn FnOnce(Result<T, c_int>) + Send>>,
+}
+
+impl<T> Sender<T> {
+    fn new<F>(callback: F) -> Sender<T>
+    where
+        F: FnOnce(Result<T, c_int>) + Send + 'static,
+    {
+        Sender { callback: Some(Box::new(callback)) }
+    }
+
+    fn send(&mut self, result: Result<T, c_int>) {
+        if let Some(callback) = self.callback.take() {
+            callback(result);
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.send(Err(EIO));
+    }
+}
+
+/// Result of a lookup, mknod, mkdir, symlink or link request.
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    pub ttl: Timespec,
+    pub attr: FileAttr,
+    pub generation: u64,
+}
+
+/// Result of a getattr or setattr request.
+#[derive(Clone, Copy, Debug)]
+pub struct Attr {
+    pub ttl: Timespec,
+    pub attr: FileAttr,
+}
+
+/// Result of an open or opendir request.
+#[derive(Clone, Copy, Debug)]
+pub struct Open {
+    pub fh: u64,
+    pub flags: u32,
+}
+
+/// Result of a statfs request.
+#[derive(Clone, Copy, Debug)]
+pub struct Statfs {
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub bsize: u32,
+    pub namelen: u32,
+    pub frsize: u32,
+}
+
+/// Result of a create request.
+#[derive(Clone, Copy, Debug)]
+pub struct Created {
+    pub ttl: Timespec,
+    pub attr: FileAttr,
+    pub generation: u64,
+    pub fh: u64,
+    pub flags: u32,
+}
+
+/// Result of a getlk request.
+#[derive(Clone, Copy, Debug)]
+pub struct Lock {
+    pub start: u64,
+    pub end: u64,
+    pub typ: u32,
+    pub pid: u32,
+}
+
+/// Result of a getxattr or listxattr request.
+#[derive(Clone, Copy, Debug)]
+pub enum Xattr<'a> {
+    /// Size of the value, when the request asked for size 0.
+    Size(u32),
+    /// The value.
+    Data(&'a [u8]),
+}
+
+/// An entry added to a `ReplyDirectory`.
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub ino: u64,
+    pub offset: i64,
+    pub kind: FileType,
+    pub name: OsString,
+}
+
+/// An entry added to a `ReplyDirectoryPlus`.
+#[derive(Clone, Debug)]
+pub struct DirEntryPlus {
+    pub ino: u64,
+    pub offset: i64,
+    pub attr: FileAttr,
+    pub ttl: Timespec,
+    pub name: OsString,
+}
+
+/// Reply to a request with nothing to return.
+pub struct ReplyEmpty {
+    sender: Sender<()>,
+}
+
+impl ReplyEmpty {
+    pub fn new<F>(callback: F) -> ReplyEmpty
+    where
+        F: FnOnce(Result<(), c_int>) + Send + 'static,
+    {
+        ReplyEmpty { sender: Sender::new(callback) }
+    }
+
+    /// Reply to a request with nothing.
+    pub fn ok(mut self) {
+        self.sender.send(Ok(()));
+    }
+
+    /// Reply to a request with the given error code.
+    pub fn error(mut self, err: c_int) {
+        self.sender.send(Err(err));
+    }
+}
+
+/// Reply to a request with data, such as read or readlink.
+pub struct ReplyData {
+    callback: Option<Box<dyn FnOnce(Result<&[u8], c_int>) + Send>>,
+}
+
+impl ReplyData {
+    pub fn new<F>(callback: F) -> ReplyData
+    where
+        F: FnOnce(Result<&[u8], c_int>) + Send + 'static,
+    {
+        ReplyData { callback: Some(Box::new(callback)) }
+    }
+
+    /// Reply to a request with the given data.
+    pub fn data(mut self, data: &[u8]) {
+        if let Some(callback) = self.callback.take() {
+            callback(Ok(data));
+        }
+    }
+
+    /// Reply to a request with the given error code.
+    pub fn error(mut self, err: c_int) {
+        if let Some(callback) = self.callback.take() {
+            callback(Err(err));
+        }
+    }
+}
+
+impl Drop for ReplyData {
+    fn drop(&mut self) {
+        if let Some(callback) = self.callback.take() {
+            callback(Err(EIO));
+        }
+    }
+}
+
+/// Reply to a request with a directory entry.
+pub struct ReplyEntry {
+    sender: Sender<Entry>,
+}
+
+impl ReplyEntry {
+    pub fn new<F>(callback: F) -> ReplyEntry
+    where
+        F: FnOnce(Result<Entry, c_int>) + Send + 'static,
+    {
+        ReplyEntry { sender: Sender::new(callback) }
+    }
+
+    /// Reply to a request with the given entry.
+    pub fn entry(mut self, ttl: &Timespec, attr: &FileAttr, generation: u64) {
+        self.sender.send(Ok(Entry {
+            ttl: *ttl,
+            attr: *attr,
+            generation: generation,
+        }));
+    }
+
+    /// Reply to a request with the given error code.
+    pub fn error(mut self, err: c_int) {
+        self.sender.send(Err(err));
+    }
+}
+
+/// Reply to a request with file attributes.
+pub struct ReplyAttr {
+    sender: Sender<Attr>,
+}
+
+impl ReplyAttr {
+    pub fn new<F>(callback: F) -> ReplyAttr
+    where
+        F: FnOnce(Result<Attr, c_int>) + Send + 'static,
+    {
+        ReplyAttr { sender: Sender::new(callback) }
+    }
+
+    /// Reply to a request with the given attributes.
+    pub fn attr(mut self, ttl: &Timespec, attr: &FileAttr) {
+        self.sender.send(Ok(Attr {
+            ttl: *ttl,
+            attr: *attr,
+        }));
+    }
+
+    /// Reply to a request with the given error code.
+    pub fn error(mut self, err: c_int) {
+        self.sender.send(Err(err));
+    }
+}
+
+/// Reply to an open or opendir request.
+pub struct ReplyOpen {
+    sender: Sender<Open>,
+}
+
+impl ReplyOpen {
+    pub fn new<F>(callback: F) -> ReplyOpen
+    where
+        F: FnOnce(Result<Open, c_int>) + Send + 'static,
+    {
+        ReplyOpen { sender: Sender::new(callback) }
+    }
+
+    /// Reply to a request with the given file handle and open flags.
+    pub fn opened(mut self, fh: u64, flags: u32) {
+        self.sender.send(Ok(Open {
+            fh: fh,
+            flags: flags,
+        }));
+    }
+
+    /// Reply to a request with the given error code.
+    pub fn error(mut self, err: c_int) {
+        self.sender.send(Err(err));
+    }
+}
+
+/// Reply to a write request.
+pub struct ReplyWrite {
+    sender: Sender<u32>,
+}
+
+impl ReplyWrite {
+    pub fn new<F>(callback: F) -> ReplyWrite
+    where
+        F: FnOnce(Result<u32, c_int>) + Send + 'static,
+    {
+        ReplyWrite { sender: Sender::new(callback) }
+    }
+
+    /// Reply to a request with the number of bytes written.
+    pub fn written(mut self, size: u32) {
+        self.sender.send(Ok(size));
+    }
+
+    /// Reply to a request with the given error code.
+    pub fn error(mut self, err: c_int) {
+        self.sender.send(Err(err));
+    }
+}
+
+/// Reply to a statfs request.
+pub struct ReplyStatfs {
+    sender: Sender<Statfs>,
+}
+
+impl ReplyStatfs {
+    pub fn new<F>(callback: F) -> ReplyStatfs
+    where
+        F: FnOnce(Result<Statfs, c_int>) + Send + 'static,
+    {
+        ReplyStatfs { sender: Sender::new(callback) }
+    }
+
+    /// Reply to a request with the given file system statistics.
+    pub fn statfs(
+        mut self,
+        blocks: u64,
+        bfree: u64,
+        bavail: u64,
+        files: u64,
+        ffree: u64,
+        bsize: u32,
+        namelen: u32,
+        frsize: u32,
+    ) {
+        self.sender.send(Ok(Statfs {
+            blocks: blocks,
+            bfree: bfree,
+            bavail: bavail,
+            files: files,
+            ffree: ffree,
+            bsize: bsize,
+            namelen: namelen,
+            frsize: frsize,
+        }));
+    }
+
+    /// Reply to a request with the given error code.
+    pub fn error(mut self, err: c_int) {
+        self.sender.send(Err(err));
+    }
+}
+
+/// Reply to a create request.
+pub struct ReplyCreate {
+    sender: Sender<Created>,
+}
+
+impl ReplyCreate {
+    pub fn new<F>(callback: F) -> ReplyCreate
+    where
+        F: FnOnce(Result<Created, c_int>) + Send + 'static,
+    {
+        ReplyCreate { sender: Sender::new(callback) }
+    }
+
+    /// Reply to a request with the created entry and its file handle.
+    pub fn created(mut self, ttl: &Timespec, attr: &FileAttr, generation: u64, fh: u64, flags: u32) {
+        self.sender.send(Ok(Created {
+            ttl: *ttl,
+            attr: *attr,
+            generation: generation,
+            fh: fh,
+            flags: flags,
+        }));
+    }
+
+    /// Reply to a request with the given error code.
+    pub fn error(mut self, err: c_int) {
+        self.sender.send(Err(err));
+    }
+}
+
+/// Reply to a getlk request.
+pub struct ReplyLock {
+    sender: Sender<Lock>,
+}
+
+impl ReplyLock {
+    pub fn new<F>(callback: F) -> ReplyLock
+    where
+        F: FnOnce(Result<Lock, c_int>) + Send + 'static,
+    {
+        ReplyLock { sender: Sender::new(callback) }
+    }
+
+    /// Reply to a request with the conflicting lock.
+    pub fn locked(mut self, start: u64, end: u64, typ: u32, pid: u32) {
+        self.sender.send(Ok(Lock {
+            start: start,
+            end: end,
+            typ: typ,
+            pid: pid,
+        }));
+    }
+
+    /// Reply to a request with the given error code.
+    pub fn error(mut self, err: c_int) {
+        self.sender.send(Err(err));
+    }
+}
+
+/// Reply to a bmap request.
+pub struct ReplyBmap {
+    sender: Sender<u64>,
+}
+
+impl ReplyBmap {
+    pub fn new<F>(callback: F) -> ReplyBmap
+    where
+        F: FnOnce(Result<u64, c_int>) + Send + 'static,
+    {
+        ReplyBmap { sender: Sender::new(callback) }
+    }
+
+    /// Reply to a request with the block number on the device.
+    pub fn bmap(mut self, block: u64) {
+        self.sender.send(Ok(block));
+    }
+
+    /// Reply to a request with the given error code.
+    pub fn error(mut self, err: c_int) {
+        self.sender.send(Err(err));
+    }
+}
+
+/// Reply to a readdir request.
+///
+/// Entries are added until they would overflow the size the kernel asked for.
+pub struct ReplyDirectory {
+    size: usize,
+    length: usize,
+    entries: Vec<DirEntry>,
+    sender: Sender<Vec<DirEntry>>,
+}
+
+impl ReplyDirectory {
+    pub fn new<F>(size: usize, callback: F) -> ReplyDirectory
+    where
+        F: FnOnce(Result<Vec<DirEntry>, c_int>) + Send + 'static,
+    {
+        ReplyDirectory {
+            size: size,
+            length: 0,
+            entries: Vec::new(),
+            sender: Sender::new(callback),
+        }
+    }
+
+    /// Add an entry to the directory reply buffer. Returns true if the buffer is full.
+    pub fn add<T: AsRef<OsStr>>(&mut self, ino: u64, offset: i64, kind: FileType, name: T) -> bool {
+        let name = name.as_ref();
+        let entlen = fuse_dirent_align(FUSE_NAME_OFFSET + name.len());
+        if self.length + entlen > self.size {
+            return true;
+        }
+        self.length += entlen;
+        self.entries.push(DirEntry {
+            ino: ino,
+            offset: offset,
+            kind: kind,
+            name: name.to_os_string(),
+        });
+        false
+    }
+
+    /// Reply to a request with the filled directory buffer.
+    pub fn ok(mut self) {
+        let entries = mem::replace(&mut self.entries, Vec::new());
+        self.sender.send(Ok(entries));
+    }
+
+    /// Reply to a request with the given error code.
+    pub fn error(mut self, err: c_int) {
+        self.sender.send(Err(err));
+    }
+}
+
+/// Reply to a readdirplus request.
+pub struct ReplyDirectoryPlus {
+    size: usize,
+    length: usize,
+    entries: Vec<DirEntryPlus>,
+    sender: Sender<Vec<DirEntryPlus>>,
+}
+
+impl ReplyDirectoryPlus {
+    pub fn new<F>(size: usize, callback: F) -> ReplyDirectoryPlus
+    where
+        F: FnOnce(Result<Vec<DirEntryPlus>, c_int>) + Send + 'static,
+    {
+        ReplyDirectoryPlus {
+            size: size,
+            length: 0,
+            entries: Vec::new(),
+            sender: Sender::new(callback),
+        }
+    }
+
+    /// Add an entry and its attributes to the directory reply buffer. Returns true if the
+    /// buffer is full.
+    pub fn add<T: AsRef<OsStr>>(&mut self, ino: u64, offset: i64, attr: &FileAttr, ttl: &Timespec, name: T) -> bool {
+        let name = name.as_ref();
+        let entlen = FUSE_ENTRY_OUT_SIZE + fuse_dirent_align(FUSE_NAME_OFFSET + name.len());
+        if self.length + entlen > self.size {
+            return true;
+        }
+        self.length += entlen;
+        self.entries.push(DirEntryPlus {
+            ino: ino,
+            offset: offset,
+            attr: *attr,
+            ttl: *ttl,
+            name: name.to_os_string(),
+        });
+        false
+    }
+
+    /// Reply to a request with the filled directory buffer.
+    pub fn ok(mut self) {
+        let entries = mem::replace(&mut self.entries, Vec::new());
+        self.sender.send(Ok(entries));
+    }
+
+    /// Reply to a request with the given error code.
+    pub fn error(mut self, err: c_int) {
+        self.sender.send(Err(err));
+    }
+}
+
+/// Reply to a getxattr or listxattr request.
+pub struct ReplyXattr {
+    callback: Option<Box<dyn for<'a> FnOnce(Result<Xattr<'a>, c_int>) + Send>>,
+}
+
+impl ReplyXattr {
+    pub fn new<F>(callback: F) -> ReplyXattr
+    where
+        F: for<'a> FnOnce(Result<Xattr<'a>, c_int>) + Send + 'static,
+    {
+        ReplyXattr { callback: Some(Box::new(callback)) }
+    }
+
+    /// Reply to a request with the size of the value.
+    pub fn size(mut self, size: u32) {
+        if let Some(callback) = self.callback.take() {
+            callback(Ok(Xattr::Size(size)));
+        }
+    }
+
+    /// Reply to a request with the value.
+    pub fn data(mut self, data: &[u8]) {
+        if let Some(callback) = self.callback.take() {
+            callback(Ok(Xattr::Data(data)));
+        }
+    }
+
+    /// Reply to a request with the given error code.
+    pub fn error(mut self, err: c_int) {
+        if let Some(callback) = self.callback.take() {
+            callback(Err(err));
+        }
+    }
+}
+
+impl Drop for ReplyXattr {
+    fn drop(&mut self) {
+        if let Some(callback) = self.callback.take() {
+            callback(Err(EIO));
+        }
+    }
+}
+
+/// Reply to an ioctl request.
+pub struct ReplyIoctl {
+    callback: Option<Box<dyn FnOnce(Result<(i32, &[u8]), c_int>) + Send>>,
+}
+
+impl ReplyIoctl {
+    pub fn new<F>(callback: F) -> ReplyIoctl
+    where
+        F: FnOnce(Result<(i32, &[u8]), c_int>) + Send + 'static,
+    {
+        ReplyIoctl { callback: Some(Box::new(callback)) }
+    }
+
+    /// Reply to a request with the ioctl result and the data to copy out.
+    pub fn ioctl(mut self, result: i32, data: &[u8]) {
+        if let Some(callback) = self.callback.take() {
+            callback(Ok((result, data)));
+        }
+    }
+
+    /// Reply to a request with the given error code.
+    pub fn error(mut self, err: c_int) {
+        if let Some(callback) = self.callback.take() {
+            callback(Err(err));
+        }
+    }
+}
+
+impl Drop for ReplyIoctl {
+    fn drop(&mut self) {
+        if let Some(callback) = self.callback.take() {
+            callback(Err(EIO));
+        }
+    }
+}
+
+/// Reply to a poll request.
+pub struct ReplyPoll {
+    sender: Sender<u32>,
+}
+
+impl ReplyPoll {
+    pub fn new<F>(callback: F) -> ReplyPoll
+    where
+        F: FnOnce(Result<u32, c_int>) + Send + 'static,
+    {
+        ReplyPoll { sender: Sender::new(callback) }
+    }
+
+    /// Reply to a request with the ready events.
+    pub fn poll(mut self, revents: u32) {
+        self.sender.send(Ok(revents));
+    }
+
+    /// Reply to a request with the given error code.
+    pub fn error(mut self, err: c_int) {
+        self.sender.send(Err(err));
+    }
+}
+
+/// Reply to an lseek request.
+pub struct ReplyLseek {
+    sender: Sender<i64>,
+}
+
+impl ReplyLseek {
+    pub fn new<F>(callback: F) -> ReplyLseek
+    where
+        F: FnOnce(Result<i64, c_int>) + Send + 'static,
+    {
+        ReplyLseek { sender: Sender::new(callback) }
+    }
+
+    /// Reply to a request with the resulting offset.
+    pub fn offset(mut self, offset: i64) {
+        self.sender.send(Ok(offset));
+    }
+
+    /// Reply to a request with the given error code.
+    pub fn error(mut self, err: c_int) {
+        self.sender.send(Err(err));
+    }
+}
@@ -0,0 +1,52 @@
+/// Request data structure.
+///
+/// Holds the header fields of a FUSE request that a file system can look at.
+#[derive(Clone, Copy, Debug)]
+pub struct Request {
+    unique: u64,
+    uid: u32,
+    gid: u32,
+    pid: u32,
+}
+
+impl Request {
+    /// Create a request as if sent by the given process.
+    ///
+    /// Arguments:
+    /// * `unique: u64` - Unique ID of the request.
+    /// * `uid: u32` - User ID of the process that sent the request.
+    /// * `gid: u32` - Group ID of the process that sent the request.
+    /// * `pid: u32` - Process ID of the process that sent the request.
+    pub fn new(unique: u64, uid: u32, gid: u32, pid: u32) -> Request {
+        Request {
+            unique: unique,
+            uid: uid,
+            gid: gid,
+            pid: pid,
+        }
+    }
+
+    /// Returns the unique identifier of this request
+    #[inline]
+    pub fn unique(&self) -> u64 {
+        self.unique
+    }
+
+    /// Returns the uid of this request
+    #[inline]
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    /// Returns the gid of this request
+    #[inline]
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    /// Returns the pid of this request
+    #[inline]
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+}
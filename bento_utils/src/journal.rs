@@ -0,0 +1,781 @@
+// Userspace jbd2 journal.
+//
+// This reads and writes the on-disk format used by the kernel's jbd2, so an image can be
+// mounted by the kernel and the FUSE builds of a file system interchangeably, even after a
+// crash. The API matches `bento::kernel::journal`.
+//
+// Transactions are committed by a commit thread every commit interval, when the running
+// transaction is full, and on `force_commit`. Each commit writes descriptor and data blocks at
+// the start of the log followed by a commit block, then checkpoints the blocks to their home
+// locations and marks the log empty. Blocks in the running transaction are kept out of the
+// buffer cache's writeback until they are checkpointed.
+//
+// Recovery handles logs written by the kernel too: transactions can wrap around the end of
+// the log, blocks can be revoked, and commit blocks can carry a CRC32 of the transaction.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use disk::{BHLockGuard, BufferHead, Disk};
+use errors;
+
+const JBD2_MAGIC_NUMBER: u32 = 0xc03b3998;
+
+const JBD2_DESCRIPTOR_BLOCK: u32 = 1;
+const JBD2_COMMIT_BLOCK: u32 = 2;
+const JBD2_SUPERBLOCK_V1: u32 = 3;
+const JBD2_SUPERBLOCK_V2: u32 = 4;
+const JBD2_REVOKE_BLOCK: u32 = 5;
+
+const JBD2_FLAG_ESCAPE: u16 = 1;
+const JBD2_FLAG_SAME_UUID: u16 = 2;
+const JBD2_FLAG_LAST_TAG: u16 = 8;
+
+const JBD2_FEATURE_COMPAT_CHECKSUM: u32 = 0x1;
+const JBD2_FEATURE_INCOMPAT_REVOKE: u32 = 0x1;
+const JBD2_FEATURE_INCOMPAT_64BIT: u32 = 0x2;
+const JBD2_FEATURE_INCOMPAT_ASYNC_COMMIT: u32 = 0x4;
+const JBD2_KNOWN_INCOMPAT_FEATURES: u32 =
+    JBD2_FEATURE_INCOMPAT_REVOKE | JBD2_FEATURE_INCOMPAT_64BIT | JBD2_FEATURE_INCOMPAT_ASYNC_COMMIT;
+
+const JBD2_CRC32_CHKSUM: u8 = 1;
+const JBD2_CRC32_CHKSUM_SIZE: u8 = 4;
+
+// Size of journal_header_t, and of the UUID that follows the first tag in a descriptor.
+const HEADER_SIZE: usize = 12;
+const UUID_SIZE: usize = 16;
+
+// Offsets of the journal_superblock_t fields this file uses.
+const SB_BLOCKSIZE: usize = 12;
+const SB_MAXLEN: usize = 16;
+const SB_FIRST: usize = 20;
+const SB_SEQUENCE: usize = 24;
+const SB_START: usize = 28;
+const SB_FEATURE_COMPAT: usize = 36;
+const SB_FEATURE_INCOMPAT: usize = 40;
+const SB_UUID: usize = 48;
+
+// Offsets of the commit_header fields after the journal header.
+const COMMIT_CHKSUM_TYPE: usize = 12;
+const COMMIT_CHKSUM_SIZE: usize = 13;
+const COMMIT_CHKSUM: usize = 16;
+const COMMIT_SEC: usize = 48;
+const COMMIT_NSEC: usize = 56;
+
+// Size of the revoke header, journal header followed by r_count.
+const REVOKE_HEADER_SIZE: usize = 16;
+
+/// How long a transaction can run before the commit thread commits it by default, as in jbd2.
+const COMMIT_INTERVAL: Duration = Duration::from_secs(5);
+
+thread_local! {
+    // Journals this thread holds a handle on, so nested begin_op calls don't wait for a commit
+    // that is waiting for the outer handle.
+    static HANDLES_HELD: RefCell<Vec<usize>> = RefCell::new(Vec::new());
+}
+
+fn get_be16(buf: &[u8], off: usize) -> u16 {
+    let mut b = [0; 2];
+    b.copy_from_slice(&buf[off..off + 2]);
+    u16::from_be_bytes(b)
+}
+
+fn get_be32(buf: &[u8], off: usize) -> u32 {
+    let mut b = [0; 4];
+    b.copy_from_slice(&buf[off..off + 4]);
+    u32::from_be_bytes(b)
+}
+
+fn put_be16(buf: &mut [u8], off: usize, val: u16) {
+    buf[off..off + 2].copy_from_slice(&val.to_be_bytes());
+}
+
+fn put_be32(buf: &mut [u8], off: usize, val: u32) {
+    buf[off..off + 4].copy_from_slice(&val.to_be_bytes());
+}
+
+fn put_be64(buf: &mut [u8], off: usize, val: u64) {
+    buf[off..off + 8].copy_from_slice(&val.to_be_bytes());
+}
+
+// Transaction IDs wrap, so compare them the way jbd2's tid_gt and tid_geq do.
+fn tid_gt(x: u32, y: u32) -> bool {
+    (x.wrapping_sub(y) as i32) > 0
+}
+
+fn tid_geq(x: u32, y: u32) -> bool {
+    (x.wrapping_sub(y) as i32) >= 0
+}
+
+// The kernel's crc32_be, used by JBD2_FEATURE_COMPAT_CHECKSUM.
+fn crc32_be(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// The parts of the jbd2 superblock the journal needs.
+struct Superblock {
+    maxlen: u32,
+    first: u32,
+    sequence: u32,
+    start: u32,
+    compat: u32,
+    incompat: u32,
+    uuid: [u8; 16],
+}
+
+impl Superblock {
+    fn parse(buf: &[u8], bsize: u32, len: u32) -> Result<Superblock, &'static str> {
+        if get_be32(buf, 0) != JBD2_MAGIC_NUMBER {
+            return Err("bad magic number");
+        }
+        let blocktype = get_be32(buf, 4);
+        if blocktype != JBD2_SUPERBLOCK_V1 && blocktype != JBD2_SUPERBLOCK_V2 {
+            return Err("unknown superblock type");
+        }
+        if get_be32(buf, SB_BLOCKSIZE) != bsize {
+            return Err("block size doesn't match");
+        }
+        let mut sb = Superblock {
+            maxlen: get_be32(buf, SB_MAXLEN),
+            first: get_be32(buf, SB_FIRST),
+            sequence: get_be32(buf, SB_SEQUENCE),
+            start: get_be32(buf, SB_START),
+            compat: 0,
+            incompat: 0,
+            uuid: [0; 16],
+        };
+        if blocktype == JBD2_SUPERBLOCK_V2 {
+            sb.compat = get_be32(buf, SB_FEATURE_COMPAT);
+            sb.incompat = get_be32(buf, SB_FEATURE_INCOMPAT);
+            sb.uuid.copy_from_slice(&buf[SB_UUID..SB_UUID + 16]);
+        }
+        if sb.maxlen > len {
+            return Err("journal is longer than its device area");
+        }
+        if sb.first == 0 || sb.first >= sb.maxlen {
+            return Err("bad first log block");
+        }
+        if sb.incompat & !JBD2_KNOWN_INCOMPAT_FEATURES != 0 {
+            return Err("unsupported incompatible features");
+        }
+        Ok(sb)
+    }
+
+    fn tag_bytes(&self) -> usize {
+        if self.incompat & JBD2_FEATURE_INCOMPAT_64BIT != 0 {
+            12
+        } else {
+            8
+        }
+    }
+
+    fn checksum(&self) -> bool {
+        self.compat & JBD2_FEATURE_COMPAT_CHECKSUM != 0
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Pass {
+    Scan,
+    Revoke,
+    Replay,
+}
+
+struct JournalState {
+    // ID of the running transaction.
+    sequence: u32,
+    // Blocks written in the running transaction, held so they stay cached until checkpointed.
+    blocks: BTreeMap<u64, BufferHead>,
+    // Credits given to handles of the running transaction.
+    reserved: u32,
+    handles: u32,
+    // Set while a commit waits for handles to finish and writes the transaction. New handles
+    // wait for it to clear.
+    locked: bool,
+    commit_requested: bool,
+    stop: bool,
+    // Errno the journal was aborted with, or 0.
+    error: libc::c_int,
+    // How long a transaction can run before the commit thread commits it.
+    commit_interval: Duration,
+}
+
+struct JournalInner {
+    disk: Arc<Disk>,
+    fs_disk: Arc<Disk>,
+    start: u64,
+    bsize: usize,
+    first: u32,
+    last: u32,
+    tag_bytes: usize,
+    checksum: bool,
+    uuid: [u8; 16],
+    max_transaction: u32,
+    state: Mutex<JournalState>,
+    changed: Condvar,
+}
+
+/// Userspace equivalent of the kernel `journal_t` wrapper.
+pub struct Journal {
+    inner: Arc<JournalInner>,
+    commit_thread: Option<thread::JoinHandle<()>>,
+}
+
+/// Userspace equivalent of the kernel `handle_t` wrapper. The handle's updates become part of
+/// the running transaction, which can commit once every handle on it is dropped.
+pub struct Handle {
+    journal: Arc<JournalInner>,
+    requested: u32,
+}
+
+impl JournalInner {
+    fn read_log(&self, blockno: u32) -> Result<Vec<u8>, libc::c_int> {
+        let bh = self.disk.bread(self.start + blockno as u64)?;
+        Ok(bh.data().to_vec())
+    }
+
+    fn write_log(&self, blockno: u32, data: &[u8]) -> Result<(), libc::c_int> {
+        let mut bh = self.disk.getblk(self.start + blockno as u64)?;
+        bh.data_mut().copy_from_slice(data);
+        bh.mark_buffer_dirty();
+        bh.sync_dirty_buffer();
+        Ok(())
+    }
+
+    fn wrap(&self, blockno: u32) -> u32 {
+        if blockno >= self.last {
+            blockno - (self.last - self.first)
+        } else {
+            blockno
+        }
+    }
+
+    // Update the dynamic superblock fields: the first transaction in the log and where it
+    // starts, or 0 if the log is empty.
+    fn update_sb(&self, sequence: u32, start: u32) -> Result<(), libc::c_int> {
+        let mut bh = self.disk.bread(self.start)?;
+        let data = bh.data_mut();
+        put_be32(data, SB_SEQUENCE, sequence);
+        put_be32(data, SB_START, start);
+        bh.mark_buffer_dirty();
+        bh.sync_dirty_buffer();
+        self.disk.sync_data()
+    }
+
+    // Tags in a descriptor block: the home block number and flags of each block that follows.
+    fn parse_tags(&self, desc: &[u8]) -> Vec<(u64, u16)> {
+        let mut tags = Vec::new();
+        let mut off = HEADER_SIZE;
+        while off + self.tag_bytes <= self.bsize {
+            let mut blocknr = get_be32(desc, off) as u64;
+            let flags = get_be16(desc, off + 6);
+            if self.tag_bytes == 12 {
+                blocknr |= (get_be32(desc, off + 8) as u64) << 32;
+            }
+            tags.push((blocknr, flags));
+            off += self.tag_bytes;
+            if flags & JBD2_FLAG_SAME_UUID == 0 {
+                off += UUID_SIZE;
+            }
+            if flags & JBD2_FLAG_LAST_TAG != 0 {
+                break;
+            }
+        }
+        tags
+    }
+
+    // One pass over the log, as in jbd2's do_one_pass. The scan pass finds the first
+    // transaction that isn't completely committed, the revoke pass collects revoke records,
+    // and the replay pass writes logged blocks that weren't revoked to their home locations.
+    // Returns the ID of the transaction after the last one the pass went through.
+    fn one_pass(&self, sb: &Superblock, pass: Pass, end: u32, revoked: &mut HashMap<u64, u32>)
+        -> Result<u32, libc::c_int>
+    {
+        let mut sequence = sb.sequence;
+        let mut next = sb.start;
+        let mut crc = !0;
+        loop {
+            if pass != Pass::Scan && tid_geq(sequence, end) {
+                break;
+            }
+            let buf = self.read_log(next)?;
+            next = self.wrap(next + 1);
+            if get_be32(&buf, 0) != JBD2_MAGIC_NUMBER || get_be32(&buf, 8) != sequence {
+                break;
+            }
+            match get_be32(&buf, 4) {
+                JBD2_DESCRIPTOR_BLOCK => {
+                    if pass == Pass::Scan && self.checksum {
+                        crc = crc32_be(crc, &buf);
+                    }
+                    for (blocknr, flags) in self.parse_tags(&buf) {
+                        let io_block = next;
+                        next = self.wrap(next + 1);
+                        if pass == Pass::Scan && self.checksum {
+                            crc = crc32_be(crc, &self.read_log(io_block)?);
+                        } else if pass == Pass::Replay {
+                            if let Some(revoked_in) = revoked.get(&blocknr) {
+                                if tid_geq(*revoked_in, sequence) {
+                                    continue;
+                                }
+                            }
+                            let mut data = self.read_log(io_block)?;
+                            if flags & JBD2_FLAG_ESCAPE != 0 {
+                                put_be32(&mut data, 0, JBD2_MAGIC_NUMBER);
+                            }
+                            let mut bh = self.fs_disk.getblk(blocknr)?;
+                            bh.data_mut().copy_from_slice(&data);
+                            bh.mark_buffer_dirty();
+                            bh.sync_dirty_buffer();
+                        }
+                    }
+                }
+                JBD2_COMMIT_BLOCK => {
+                    if pass == Pass::Scan && self.checksum {
+                        let found = get_be32(&buf, COMMIT_CHKSUM);
+                        let chksum_type = buf[COMMIT_CHKSUM_TYPE];
+                        let chksum_size = buf[COMMIT_CHKSUM_SIZE];
+                        let matches = chksum_type == JBD2_CRC32_CHKSUM
+                            && chksum_size == JBD2_CRC32_CHKSUM_SIZE
+                            && found == crc;
+                        let unused = chksum_type == 0 && chksum_size == 0 && found == 0;
+                        if !matches && !unused {
+                            // The transaction wasn't completely written.
+                            break;
+                        }
+                        crc = !0;
+                    }
+                    sequence = sequence.wrapping_add(1);
+                }
+                JBD2_REVOKE_BLOCK => {
+                    if pass != Pass::Revoke {
+                        continue;
+                    }
+                    let record_size = if self.tag_bytes == 12 { 8 } else { 4 };
+                    let count = get_be32(&buf, HEADER_SIZE) as usize;
+                    let mut off = REVOKE_HEADER_SIZE;
+                    while off + record_size <= count.min(self.bsize) {
+                        let blocknr = if record_size == 8 {
+                            (get_be32(&buf, off) as u64) << 32 | get_be32(&buf, off + 4) as u64
+                        } else {
+                            get_be32(&buf, off) as u64
+                        };
+                        let entry = revoked.entry(blocknr).or_insert(sequence);
+                        if tid_gt(sequence, *entry) {
+                            *entry = sequence;
+                        }
+                        off += record_size;
+                    }
+                }
+                _ => break,
+            }
+        }
+        Ok(sequence)
+    }
+
+    // Replay committed transactions, as in jbd2_journal_recover. Returns the ID to use for
+    // the next transaction.
+    fn recover(&self, sb: &Superblock) -> Result<u32, libc::c_int> {
+        if sb.start == 0 {
+            return Ok(sb.sequence);
+        }
+        let mut revoked = HashMap::new();
+        let end = self.one_pass(sb, Pass::Scan, 0, &mut revoked)?;
+        self.one_pass(sb, Pass::Revoke, end, &mut revoked)?;
+        self.one_pass(sb, Pass::Replay, end, &mut revoked)?;
+        self.fs_disk.sync_data()?;
+        println!("journal: recovered transactions {} to {}", sb.sequence, end.wrapping_sub(1));
+        // Skip an ID, as jbd2 does, in case blocks of the incomplete transaction are in the
+        // log.
+        Ok(end.wrapping_add(1))
+    }
+
+    // Log blocks a transaction of `nblocks` blocks needs, including descriptors and the
+    // commit block.
+    fn log_blocks(&self, nblocks: usize) -> usize {
+        let per_descriptor = 1 + (self.bsize - HEADER_SIZE - self.tag_bytes - UUID_SIZE) / self.tag_bytes;
+        nblocks + (nblocks + per_descriptor - 1) / per_descriptor + 1
+    }
+
+    // Write a transaction to the log, commit it, and checkpoint it.
+    fn write_transaction(&self, sequence: u32, blocks: BTreeMap<u64, BufferHead>) -> Result<(), libc::c_int> {
+        if self.log_blocks(blocks.len()) > (self.last - self.first) as usize {
+            println!("journal: transaction {} has {} blocks, too many for the log", sequence, blocks.len());
+            return Err(libc::ENOSPC);
+        }
+        self.update_sb(sequence, self.first)?;
+
+        let mut pos = self.first;
+        let mut crc = !0;
+        let mut iter = blocks.iter().peekable();
+        while iter.peek().is_some() {
+            let mut desc = vec![0; self.bsize];
+            put_be32(&mut desc, 0, JBD2_MAGIC_NUMBER);
+            put_be32(&mut desc, 4, JBD2_DESCRIPTOR_BLOCK);
+            put_be32(&mut desc, 8, sequence);
+            let mut off = HEADER_SIZE;
+            let mut last_tag = off;
+            let mut data_blocks = Vec::new();
+            while let Some((blocknr, bh)) = iter.peek() {
+                let first_tag = data_blocks.is_empty();
+                let need = self.tag_bytes + if first_tag { UUID_SIZE } else { 0 };
+                if off + need > self.bsize {
+                    break;
+                }
+                let mut data = bh.data().to_vec();
+                let mut flags = 0;
+                if get_be32(&data, 0) == JBD2_MAGIC_NUMBER {
+                    put_be32(&mut data, 0, 0);
+                    flags |= JBD2_FLAG_ESCAPE;
+                }
+                if !first_tag {
+                    flags |= JBD2_FLAG_SAME_UUID;
+                }
+                put_be32(&mut desc, off, **blocknr as u32);
+                put_be16(&mut desc, off + 6, flags);
+                if self.tag_bytes == 12 {
+                    put_be32(&mut desc, off + 8, (**blocknr >> 32) as u32);
+                }
+                last_tag = off;
+                off += self.tag_bytes;
+                if first_tag {
+                    desc[off..off + UUID_SIZE].copy_from_slice(&self.uuid);
+                    off += UUID_SIZE;
+                }
+                data_blocks.push(data);
+                iter.next();
+            }
+            let flags = get_be16(&desc, last_tag + 6);
+            put_be16(&mut desc, last_tag + 6, flags | JBD2_FLAG_LAST_TAG);
+
+            if self.checksum {
+                crc = crc32_be(crc, &desc);
+            }
+            self.write_log(pos, &desc)?;
+            pos += 1;
+            for data in data_blocks {
+                if self.checksum {
+                    crc = crc32_be(crc, &data);
+                }
+                self.write_log(pos, &data)?;
+                pos += 1;
+            }
+        }
+        self.disk.sync_data()?;
+
+        let mut commit = vec![0; self.bsize];
+        put_be32(&mut commit, 0, JBD2_MAGIC_NUMBER);
+        put_be32(&mut commit, 4, JBD2_COMMIT_BLOCK);
+        put_be32(&mut commit, 8, sequence);
+        if self.checksum {
+            commit[COMMIT_CHKSUM_TYPE] = JBD2_CRC32_CHKSUM;
+            commit[COMMIT_CHKSUM_SIZE] = JBD2_CRC32_CHKSUM_SIZE;
+            put_be32(&mut commit, COMMIT_CHKSUM, crc);
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        put_be64(&mut commit, COMMIT_SEC, now.as_secs());
+        put_be32(&mut commit, COMMIT_NSEC, now.subsec_nanos());
+        self.write_log(pos, &commit)?;
+        self.disk.sync_data()?;
+
+        // The transaction is durable. Write the blocks home and empty the log.
+        for (_, mut bh) in blocks {
+            bh.mark_buffer_dirty();
+            bh.sync_dirty_buffer();
+        }
+        self.fs_disk.sync_data()?;
+        self.update_sb(sequence.wrapping_add(1), 0)
+    }
+
+    // Commit the running transaction once its handles are done. Called with the state lock
+    // held, which is released while the transaction is written.
+    fn commit<'a>(&'a self, mut state: std::sync::MutexGuard<'a, JournalState>)
+        -> std::sync::MutexGuard<'a, JournalState>
+    {
+        state.commit_requested = false;
+        if state.blocks.is_empty() || state.error != 0 {
+            state.blocks.clear();
+            state.reserved = 0;
+            self.changed.notify_all();
+            return state;
+        }
+        state.locked = true;
+        while state.handles > 0 {
+            state = self.changed.wait(state).unwrap();
+        }
+        let sequence = state.sequence;
+        let blocks = std::mem::take(&mut state.blocks);
+        drop(state);
+
+        let ret = self.write_transaction(sequence, blocks);
+
+        let mut state = self.state.lock().unwrap();
+        if let Err(x) = ret {
+            println!("journal: commit of transaction {} failed, aborting the journal", sequence);
+            state.error = x;
+            errors::fs_error(x);
+        }
+        state.sequence = sequence.wrapping_add(1);
+        state.reserved = 0;
+        state.locked = false;
+        self.changed.notify_all();
+        state
+    }
+
+    fn run_commit_thread(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if !state.commit_requested && !state.stop {
+                let interval = state.commit_interval;
+                state = self.changed.wait_timeout(state, interval).unwrap().0;
+            }
+            state = self.commit(state);
+            if state.stop {
+                return;
+            }
+        }
+    }
+
+    fn held(&self) -> bool {
+        let key = self as *const JournalInner as usize;
+        HANDLES_HELD.with(|held| held.borrow().contains(&key))
+    }
+}
+
+impl Journal {
+    pub fn new_from_disk(disk: Arc<Disk>, fs_disk: Arc<Disk>, start: u64, len: i32, bsize: i32) -> Option<Journal> {
+        let sb_buf = match disk.bread(start) {
+            Ok(bh) => bh.data().to_vec(),
+            Err(_) => {
+                println!("journal: can't read the journal superblock");
+                return None;
+            }
+        };
+        let sb = match Superblock::parse(&sb_buf, bsize as u32, len as u32) {
+            Ok(sb) => sb,
+            Err(e) => {
+                println!("journal: {}", e);
+                return None;
+            }
+        };
+        let inner = JournalInner {
+            disk: disk,
+            fs_disk: fs_disk,
+            start: start,
+            bsize: bsize as usize,
+            first: sb.first,
+            last: sb.maxlen,
+            tag_bytes: sb.tag_bytes(),
+            checksum: sb.checksum(),
+            uuid: sb.uuid,
+            max_transaction: (sb.maxlen - sb.first) / 4,
+            state: Mutex::new(JournalState {
+                sequence: 0,
+                blocks: BTreeMap::new(),
+                reserved: 0,
+                handles: 0,
+                locked: false,
+                commit_requested: false,
+                stop: false,
+                error: 0,
+                commit_interval: COMMIT_INTERVAL,
+            }),
+            changed: Condvar::new(),
+        };
+
+        let sequence = match inner.recover(&sb).and_then(|seq| inner.update_sb(seq, 0).map(|_| seq)) {
+            Ok(seq) => seq,
+            Err(_) => {
+                println!("journal: recovery failed");
+                return None;
+            }
+        };
+        inner.state.lock().unwrap().sequence = sequence;
+
+        let inner = Arc::new(inner);
+        let thread_inner = Arc::clone(&inner);
+        // Commits that fail in the thread put the mount that created the journal in the errors
+        // state.
+        let error_state = errors::current();
+        let commit_thread = thread::Builder::new()
+            .name("bento-jbd2".to_string())
+            .spawn(move || {
+                let _errors = error_state.as_ref().map(errors::enter);
+                thread_inner.run_commit_thread()
+            })
+            .ok()?;
+        Some(Journal {
+            inner: inner,
+            commit_thread: Some(commit_thread),
+        })
+    }
+
+    /// Whether the journal at `start` on `disk` has transactions that haven't been
+    /// checkpointed, so mounting it would replay them.
+    pub fn needs_recovery(disk: &Disk, start: u64, len: i32, bsize: i32) -> Result<bool, libc::c_int> {
+        let bh = disk.bread(start)?;
+        let sb = Superblock::parse(bh.data(), bsize as u32, len as u32).map_err(|_| libc::EINVAL)?;
+        Ok(sb.start != 0)
+    }
+
+    // begin transaction of size blocks
+    // fails with EROFS once the journal has been aborted, as jbd2_journal_start does
+    pub fn begin_op(&self, blocks: u32) -> Result<Handle, libc::c_int> {
+        if blocks > self.inner.max_transaction {
+            return Err(libc::ENOSPC);
+        }
+        let nested = self.inner.held();
+        let mut state = self.inner.state.lock().unwrap();
+        if !nested {
+            loop {
+                if state.error != 0 {
+                    break;
+                } else if state.locked {
+                    state = self.inner.changed.wait(state).unwrap();
+                } else if state.reserved + blocks > self.inner.max_transaction {
+                    state.commit_requested = true;
+                    self.inner.changed.notify_all();
+                    state = self.inner.changed.wait(state).unwrap();
+                } else {
+                    break;
+                }
+            }
+        }
+        if state.error != 0 {
+            return Err(libc::EROFS);
+        }
+        state.reserved += blocks;
+        state.handles += 1;
+        drop(state);
+
+        let key = &*self.inner as *const JournalInner as usize;
+        HANDLES_HELD.with(|held| held.borrow_mut().push(key));
+        Ok(Handle {
+            journal: Arc::clone(&self.inner),
+            requested: blocks,
+        })
+    }
+
+    // force completed transactions to write to disk
+    pub fn force_commit(&self) -> i32 {
+        if self.inner.held() {
+            return -libc::EBUSY;
+        }
+        let mut state = self.inner.state.lock().unwrap();
+        if state.blocks.is_empty() && !state.locked {
+            return -state.error;
+        }
+        let target = state.sequence;
+        state.commit_requested = true;
+        self.inner.changed.notify_all();
+        while !tid_gt(state.sequence, target) && state.error == 0 {
+            state = self.inner.changed.wait(state).unwrap();
+        }
+        -state.error
+    }
+
+    // abort the journal so the running transaction and any later ones are never committed
+    pub fn abort(&self, errno: i32) {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.error == 0 {
+            state.error = errno;
+        }
+        self.inner.changed.notify_all();
+    }
+
+    // commit a running transaction after at most secs seconds, or the jbd2 default if 0
+    pub fn set_commit_interval(&self, secs: u32) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.commit_interval = if secs == 0 {
+            COMMIT_INTERVAL
+        } else {
+            Duration::from_secs(secs as u64)
+        };
+        self.inner.changed.notify_all();
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            state.stop = true;
+            state.commit_requested = true;
+            self.inner.changed.notify_all();
+        }
+        if let Some(commit_thread) = self.commit_thread.take() {
+            let _ = commit_thread.join();
+        }
+    }
+}
+
+impl Handle {
+    fn status(&self) -> i32 {
+        if self.journal.state.lock().unwrap().error != 0 {
+            -libc::EROFS
+        } else {
+            0
+        }
+    }
+
+    // notify intent to modify BufferHead as a part of this transaction
+    pub fn get_write_access(&self, _bh: &BufferHead) -> i32 {
+        self.status()
+    }
+
+    pub fn get_create_access(&self, _bh: &BHLockGuard) -> i32 {
+        self.status()
+    }
+
+    // register a block as part of the transaction associated with this handle
+    pub fn journal_write(&self, bh: &mut BufferHead) -> i32 {
+        let mut state = self.journal.state.lock().unwrap();
+        if state.error != 0 {
+            return -libc::EROFS;
+        }
+        if !state.blocks.contains_key(&bh.blk_no) {
+            if state.blocks.len() as u32 >= self.journal.max_transaction {
+                println!("journal: too many blocks in transaction, handle asked for {}", self.requested);
+            }
+            state.blocks.insert(bh.blk_no, bh.clone_ref());
+        }
+        0
+    }
+}
+
+// ends transaction
+impl Drop for Handle {
+    fn drop(&mut self) {
+        let key = &*self.journal as *const JournalInner as usize;
+        HANDLES_HELD.with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(idx) = held.iter().rposition(|k| *k == key) {
+                held.remove(idx);
+            }
+        });
+        let mut state = self.journal.state.lock().unwrap();
+        state.handles -= 1;
+        if state.error != 0 {
+            println!("some log transaction was aborted");
+        }
+        if state.handles == 0 {
+            self.journal.changed.notify_all();
+        }
+    }
+}
@@ -1,12 +1,19 @@
-#![feature(get_mut_unchecked)]
-
-extern crate fuse;
 extern crate libc;
 extern crate serde;
 extern crate time;
 
+mod backend;
+pub use self::backend::*;
+pub mod crash;
 mod disk;
 pub use self::disk::*;
+pub mod errors;
+pub mod fuse;
+pub use self::fuse::{ReplyIoctl, ReplyPoll, ReplyLseek, ReplyDirectoryPlus};
+pub mod journal;
+pub mod options;
+pub mod session;
+pub mod testing;
 
 use libc::ENOSYS;
 
@@ -17,6 +24,8 @@ use time::Timespec;
 
 use fuse::*;
 
+use options::{MountOption, MountOptions};
+
 use serde::{Serialize, Deserialize};
 
 pub const BENTO_KERNEL_VERSION: u32 = 1;
@@ -44,6 +53,20 @@ pub mod consts {
     pub const FUSE_POSIX_ACL: u32           = 1 << 20;
 }
 
+/// Type that can be passed between file system modules during a live upgrade.
+///
+/// Live upgrade is only supported in the kernel. This trait is provided so that file systems
+/// can implement it in code shared by the kernel and userspace builds.
+pub trait TransferState {
+    const NAME: &'static str;
+    const VERSION: u32;
+}
+
+impl TransferState for i32 {
+    const NAME: &'static str = "i32";
+    const VERSION: u32 = 0;
+}
+
 #[derive(Default)]
 #[allow(dead_code)]
 pub struct FuseConnInfo {
@@ -57,6 +80,7 @@ pub struct FuseConnInfo {
     pub max_background: u32,
     pub congestion_threshold: u32,
     pub time_gran: u32,
+    notifier: Notifier,
     reserved: [u32; 22],
 }
 
@@ -73,9 +97,15 @@ impl FuseConnInfo {
             max_background: 0,
             congestion_threshold: 0,
             time_gran: 0,
+            notifier: Notifier::default(),
             reserved: [0; 22]
         }
     }
+
+    /// Returns the `Notifier` of the mount being initialized.
+    pub fn notifier(&self) -> Notifier {
+        self.notifier.clone()
+    }
 }
 
 /// Filesystem trait
@@ -92,31 +122,79 @@ pub trait BentoFilesystem<'de, TransferIn=i32, TransferOut=i32> {
     /// This must be provided to mount the filesystem.
     fn get_name(&self) -> &'static str;
 
+    /// Create the file system for a new mount.
+    ///
+    /// Unused in user version, where each process mounts the file system it constructs.
+    fn new_instance(&self) -> Box<Self>
+    where
+        Self: Sized;
+
+    /// Get the mount options the file system accepts.
+    ///
+    /// Mounting with an option not in this list fails.
+    fn mount_options(&self) -> &'static [MountOption] {
+        &[]
+    }
+
+    /// Whether the file system is mounted read-only.
+    ///
+    /// While this returns true, requests that could modify the file system are refused with
+    /// `EROFS`, as in the errors state. A file system can return true when it finds on-disk
+    /// features it can read but not safely write.
+    fn read_only(&self) -> bool {
+        false
+    }
+
     /// Initialize the file system and fill in initialization flags.
     ///
     /// Possible initialization flags are defined /include/uapi/linux/fuse.h.
-    /// No support is provided for readdirplus and async DIO.
+    /// No support is provided for async DIO.
     ///
     /// Arguments:
     /// * `req: &Request` - Request data structure.
     /// * `devname: &OsStr` - Name of the backing device file.
+    /// * `opts: &MountOptions` - Mount options given to `options::mount`, checked against
+    /// `mount_options`.
     /// * `fc_info: &mut FuseConnInfo` - Connection information used to pass initialization
     /// arguments to Bento.
     fn bento_init(
         &mut self,
         _req: &Request,
         _devname: &OsStr,
+        _opts: &MountOptions,
         _fc_info: &mut FuseConnInfo,
     ) -> Result<(), i32> {
         return Err(ENOSYS);
     }
 
+    /// Change mount options on a mounted file system.
+    ///
+    /// Called for a setxattr of `options::REMOUNT_XATTR`.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `opts: &MountOptions` - The options to change. Options not given keep their values.
+    fn bento_remount(&self, _req: &Request, _opts: &MountOptions) -> Result<(), i32> {
+        return Err(ENOSYS);
+    }
+
     /// Perform any necessary cleanup on the file system.
     ///
     /// Arguments:
     /// * `req: &Request` - Request data structure.
     fn bento_destroy(&mut self, _req: &Request) {}
 
+    /// Handle the file system entering the errors state.
+    ///
+    /// Called once after the file system panics or calls `errors::fs_error`, once the request
+    /// that hit the error has finished. Requests that would modify the file system are already
+    /// refused with `EROFS`. A file system might abort its journal here so the interrupted
+    /// update is never committed.
+    ///
+    /// Arguments:
+    /// * `status: &ErrorStatus` - The errors state.
+    fn bento_error(&self, _status: &errors::ErrorStatus) {}
+
     /// Lookup a directory entry by name and get its attributes.
     ///
     /// If the entry exists, fill `reply` with the attributes.
@@ -656,6 +734,33 @@ pub trait BentoFilesystem<'de, TransferIn=i32, TransferOut=i32> {
         return reply.error(ENOSYS);
     }
 
+    /// Read directory entries along with their attributes.
+    ///
+    /// This is called instead of `readdir()` if the file system set `FUSE_DO_READDIRPLUS` (or
+    /// `FUSE_READDIRPLUS_AUTO`) in `bento_init`. Each entry fills in the same attributes as a
+    /// `lookup()` would, so listing a directory doesn't need a lookup and getattr per entry. The
+    /// kernel takes a lookup reference on every entry other than "." and "..". The offset
+    /// semantics are the same as for `readdir()`.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino: u64` - Filesystem-provided inode number.
+    /// * `fh: u64` - Filesystem-provided file handle.
+    /// * `offset: i64` - Offset into the directory. From the offset values used in previous
+    /// readdirplus requests.
+    /// * `reply: ReplyDirectoryPlus` - Output data structure for the directory entries and their
+    /// attributes or error value.
+    fn bento_readdirplus(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        reply: ReplyDirectoryPlus,
+    ) {
+        return reply.error(ENOSYS);
+    }
+
     /// Release an open directory
     ///
     /// For every opendir call there will be exactly one releasedir call (unless the filesystem is
@@ -968,6 +1073,161 @@ pub trait BentoFilesystem<'de, TransferIn=i32, TransferOut=i32> {
         return reply.error(ENOSYS);
     }
 
+    /// Ioctl
+    ///
+    /// Note: For unrestricted ioctls, data in and out areas can be discovered by giving iovs and
+    /// setting `FUSE_IOCTL_RETRY` in flags. For restricted ioctls, Bento prepares in/out data area
+    /// according to the information encoded in `cmd`.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino: u64` - Filesystem-provided inode number.
+    /// * `fh: u64` - Filesystem-provided file handle.
+    /// * `flags: u32` - Ioctl flags (`FUSE_IOCTL_*`).
+    /// * `cmd: u32` - Ioctl command.
+    /// * `arg: u64` - Ioctl argument.
+    /// * `in_data: &[u8]` - Data copied in from the caller.
+    /// * `out_size: u32` - Maximum size of the data to copy out to the caller.
+    /// * `reply: ReplyIoctl` - Output data structure for the ioctl result and data or error value.
+    fn bento_ioctl(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _flags: u32,
+        _cmd: u32,
+        _arg: u64,
+        _in_data: &[u8],
+        _out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        return reply.error(ENOSYS);
+    }
+
+    /// Poll for IO readiness
+    ///
+    /// If this request is answered with an error code of `ENOSYS`, this is treated as success
+    /// (with a kernel-defined default poll-mask) and future calls to `poll()` will succeed the
+    /// same way without being sent to the filesystem.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino: u64` - Filesystem-provided inode number.
+    /// * `fh: u64` - Filesystem-provided file handle.
+    /// * `kh: u64` - Kernel poll handle, used to notify the kernel of readiness.
+    /// * `events: u32` - Requested poll events.
+    /// * `flags: u32` - Poll flags (`FUSE_POLL_SCHEDULE_NOTIFY`).
+    /// * `reply: ReplyPoll` - Output data structure for the ready events or error value.
+    fn bento_poll(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _kh: u64,
+        _events: u32,
+        _flags: u32,
+        reply: ReplyPoll,
+    ) {
+        return reply.error(ENOSYS);
+    }
+
+    /// Allocate requested space. If this function returns success then subsequent writes to the
+    /// specified range shall not fail due to the lack of free space on the file system storage
+    /// media.
+    ///
+    /// If this request is answered with an error code of `ENOSYS`, this is treated as a permanent
+    /// failure with error code `EOPNOTSUPP`, i.e. all future `fallocate()` requests will fail with
+    /// `EOPNOTSUPP` without being sent to the filesystem.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino: u64` - Filesystem-provided inode number.
+    /// * `fh: u64` - Filesystem-provided file handle.
+    /// * `offset: i64` - Start of the range to allocate.
+    /// * `length: i64` - Length of the range to allocate.
+    /// * `mode: i32` - Allocation mode (`FALLOC_FL_*`).
+    /// * `reply: ReplyEmpty` - Output data structure for a possible error value.
+    fn bento_fallocate(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _length: i64,
+        _mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        return reply.error(ENOSYS);
+    }
+
+    /// Find next data or hole after the specified offset
+    ///
+    /// Only `SEEK_DATA` and `SEEK_HOLE` are sent to the filesystem. If the offset is at or beyond
+    /// the end of the file, `ENXIO` should be returned.
+    ///
+    /// If this request is answered with an error code of `ENOSYS`, this is treated as a permanent
+    /// failure, i.e. all future `lseek()` requests will fail with the same error code without being
+    /// sent to the filesystem.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino: u64` - Filesystem-provided inode number.
+    /// * `fh: u64` - Filesystem-provided file handle.
+    /// * `offset: i64` - Offset to start searching from.
+    /// * `whence: i32` - `SEEK_DATA` or `SEEK_HOLE`.
+    /// * `reply: ReplyLseek` - Output data structure for the resulting offset or error value.
+    fn bento_lseek(
+        &self,
+        _req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _whence: i32,
+        reply: ReplyLseek,
+    ) {
+        return reply.error(ENOSYS);
+    }
+
+    /// Copy a range of data from one file to another
+    ///
+    /// Lets the file system copy the data itself, without it passing through the page cache and
+    /// the file system's read and write. The copy may be shorter than `len`; the number of bytes
+    /// copied is replied with `ReplyWrite::written`, and 0 means `offset_in` is at or beyond the
+    /// end of the source file.
+    ///
+    /// If this request is answered with an error code of `ENOSYS`, this is treated as a permanent
+    /// failure with error code `EOPNOTSUPP`, i.e. all future `copy_file_range()` requests will
+    /// fail with `EOPNOTSUPP` without being sent to the filesystem, and callers fall back to
+    /// reading and writing the data.
+    ///
+    /// Arguments:
+    /// * `req: &Request` - Request data structure.
+    /// * `ino_in: u64` - Filesystem-provided inode number of the source file.
+    /// * `fh_in: u64` - Filesystem-provided file handle of the source file.
+    /// * `offset_in: i64` - Offset to copy from.
+    /// * `ino_out: u64` - Filesystem-provided inode number of the destination file.
+    /// * `fh_out: u64` - Filesystem-provided file handle of the destination file.
+    /// * `offset_out: i64` - Offset to copy to.
+    /// * `len: u64` - Number of bytes to copy.
+    /// * `flags: u32` - Flags passed to `copy_file_range()`, currently always 0.
+    /// * `reply: ReplyWrite` - Output data structure for the number of bytes copied or error
+    /// value.
+    fn bento_copy_file_range(
+        &self,
+        _req: &Request,
+        _ino_in: u64,
+        _fh_in: u64,
+        _offset_in: i64,
+        _ino_out: u64,
+        _fh_out: u64,
+        _offset_out: i64,
+        _len: u64,
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        return reply.error(ENOSYS);
+    }
+
     /// Unused in user version
     fn bento_update_prepare(&mut self) -> Option<TransferOut> {
         None
@@ -976,366 +1236,3 @@ pub trait BentoFilesystem<'de, TransferIn=i32, TransferOut=i32> {
     /// Unused in user version
     fn bento_update_transfer(&mut self, Option<TransferIn>) { }
 }
-
-#[macro_export]
-macro_rules! impl_filesystem {
-    ($name:ty) => {
-        impl Filesystem for $name {
-            fn init(
-                &mut self,
-                req: &Request,
-            ) -> Result<(), libc::c_int> {
-                let mut fc_info = FuseConnInfo::new();
-                self.bento_init(req, OsStr::new(""), &mut fc_info)
-            }
-        
-            fn destroy(&mut self, req: &Request) {
-                self.bento_destroy(req);
-            }
-        
-            fn lookup(
-                &mut self,
-                req: &Request,
-                parent: u64,
-                name: &OsStr,
-                reply: ReplyEntry,
-            ) {
-                self.bento_lookup(req, parent, name, reply)
-            }
-        
-            fn forget(&mut self, req: &Request, ino: u64, nlookup: u64) {
-                self.bento_forget(req, ino, nlookup)
-            }
-        
-            fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
-                self.bento_getattr(req, ino, reply)
-            }
-        
-            fn setattr(
-                &mut self,
-                req: &Request,
-                ino: u64,
-                mode: Option<u32>,
-                uid: Option<u32>,
-                gid: Option<u32>,
-                size: Option<u64>,
-                atime: Option<Timespec>,
-                mtime: Option<Timespec>,
-                fh: Option<u64>,
-                crtime: Option<Timespec>,
-                chgtime: Option<Timespec>,
-                bkuptime: Option<Timespec>,
-                flags: Option<u32>,
-                reply: ReplyAttr,
-            ) {
-                self.bento_setattr(req, ino, mode, uid, gid, size, atime, mtime, fh, crtime, chgtime, bkuptime, flags, reply)
-            }
-        
-            fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
-                self.bento_readlink(req, ino, reply)
-            }
-        
-            fn mknod(
-                &mut self,
-                req: &Request,
-                parent: u64,
-                name: &OsStr,
-                mode: u32,
-                rdev: u32,
-                reply: ReplyEntry,
-            ) {
-                self.bento_mknod(req, parent, name, mode, rdev, reply)
-            }
-        
-            fn mkdir(
-                &mut self,
-                req: &Request,
-                parent: u64,
-                name: &OsStr,
-                mode: u32,
-                reply: ReplyEntry,
-            ) {
-                self.bento_mkdir(req, parent, name, mode, reply)
-            }
-        
-            fn unlink(
-                &mut self,
-                req: &Request,
-                parent: u64,
-                name: &OsStr,
-                reply: ReplyEmpty,
-            ) {
-                self.bento_unlink(req, parent, name, reply)
-            }
-        
-            fn rmdir(
-                &mut self,
-                req: &Request,
-                parent: u64,
-                name: &OsStr,
-                reply: ReplyEmpty,
-            ) {
-                self.bento_rmdir(req, parent, name, reply)
-            }
-        
-            fn symlink(
-                &mut self,
-                req: &Request,
-                parent: u64,
-                name: &OsStr,
-                link: &Path,
-                reply: ReplyEntry,
-            ) {
-                self.bento_symlink(req, parent, name, link, reply)
-            }
-        
-            fn rename(
-                &mut self,
-                req: &Request,
-                parent: u64,
-                name: &OsStr,
-                newparent: u64,
-                newname: &OsStr,
-                reply: ReplyEmpty,
-            ) {
-                self.bento_rename(req, parent, name, newparent, newname, 0, reply)
-            }
-        
-            fn link(
-                &mut self,
-                req: &Request,
-                ino: u64,
-                newparent: u64,
-                newname: &OsStr,
-                reply: ReplyEntry,
-            ) {
-                self.bento_link(req, ino, newparent, newname, reply)
-            }
-        
-            fn open(
-                &mut self,
-                req: &Request,
-                ino: u64,
-                flags: u32,
-                reply: ReplyOpen,
-            ) {
-                self.bento_open(req, ino, flags, reply)
-            }
-        
-            fn read(
-                &mut self,
-                req: &Request,
-                ino: u64,
-                fh: u64,
-                offset: i64,
-                size: u32,
-                reply: ReplyData,
-            ) {
-                self.bento_read(req, ino, fh,offset, size, reply)
-            }
-        
-            fn write(
-                &mut self,
-                req: &Request,
-                ino: u64,
-                fh: u64,
-                offset: i64,
-                data: &[u8],
-                flags: u32,
-                reply: ReplyWrite,
-            ) {
-                self.bento_write(req, ino, fh, offset, data, flags, reply)
-            }
-        
-            fn flush(
-                &mut self,
-                req: &Request,
-                ino: u64,
-                fh: u64,
-                lock_owner: u64,
-                reply: ReplyEmpty,
-            ) {
-                self.bento_flush(req, ino, fh, lock_owner, reply)
-            }
-        
-            fn release(
-                &mut self,
-                req: &Request,
-                ino: u64,
-                fh: u64,
-                flags: u32,
-                lock_owner: u64,
-                flush: bool,
-                reply: ReplyEmpty,
-            ) {
-                self.bento_release(req, ino, fh, flags, lock_owner, flush, reply)
-            }
-        
-            fn fsync(
-                &mut self,
-                req: &Request,
-                ino: u64,
-                fh: u64,
-                datasync: bool,
-                reply: ReplyEmpty,
-            ) {
-                self.bento_fsync(req, ino, fh, datasync, reply)
-            }
-        
-            fn opendir(
-                &mut self,
-                req: &Request,
-                ino: u64,
-                flags: u32,
-                reply: ReplyOpen,
-            ) {
-                self.bento_opendir(req, ino, flags, reply)
-            }
-        
-            fn readdir(
-                &mut self,
-                req: &Request,
-                ino: u64,
-                fh: u64,
-                offset: i64,
-                reply: ReplyDirectory,
-            ) {
-                self.bento_readdir(req, ino, fh, offset, reply)
-            }
-        
-            fn releasedir(
-                &mut self,
-                req: &Request,
-                ino: u64,
-                fh: u64,
-                flags: u32,
-                reply: ReplyEmpty,
-            ) {
-                self.bento_releasedir(req, ino, fh, flags, reply)
-            }
-        
-            fn fsyncdir(
-                &mut self,
-                req: &Request,
-                ino: u64,
-                fh: u64,
-                datasync: bool,
-                reply: ReplyEmpty,
-            ) {
-                self.bento_fsyncdir(req, ino, fh, datasync, reply)
-            }
-        
-            fn statfs(&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
-                self.bento_statfs(req, ino, reply)
-            }
-        
-            fn setxattr(
-                &mut self,
-                req: &Request,
-                ino: u64,
-                name: &OsStr,
-                value: &[u8],
-                flags: u32,
-                position: u32,
-                reply: ReplyEmpty,
-            ) {
-                self.bento_setxattr(req, ino, name, value, flags, position, reply)
-            }
-        
-            fn getxattr(
-                &mut self,
-                req: &Request,
-                ino: u64,
-                name: &OsStr,
-                size: u32,
-                reply: ReplyXattr,
-            ) {
-                self.bento_getxattr(req, ino, name, size, reply)
-            }
-        
-            fn listxattr(
-                &mut self,
-                req: &Request,
-                ino: u64,
-                size: u32,
-                reply: ReplyXattr,
-            ) {
-                self.bento_listxattr(req, ino, size, reply)
-            }
-        
-            fn removexattr(
-                &mut self,
-                req: &Request,
-                ino: u64,
-                name: &OsStr,
-                reply: ReplyEmpty,
-            ) {
-                self.bento_removexattr(req, ino, name, reply)
-            }
-        
-            fn access(
-                &mut self,
-                req: &Request,
-                ino: u64,
-                mask: u32,
-                reply: ReplyEmpty,
-            ) {
-                self.bento_access(req, ino, mask, reply)
-            }
-        
-            fn create(
-                &mut self,
-                req: &Request,
-                parent: u64,
-                name: &OsStr,
-                mode: u32,
-                flags: u32,
-                reply: ReplyCreate,
-            ) {
-                self.bento_create(req, parent, name, mode, flags, reply)
-            }
-        
-            fn getlk(
-                &mut self,
-                req: &Request,
-                ino: u64,
-                fh: u64,
-                lock_owner: u64,
-                start: u64,
-                end: u64,
-                typ: u32,
-                pid: u32,
-                reply: ReplyLock,
-            ) {
-                self.bento_getlk(req, ino, fh, lock_owner, start, end, typ, pid, reply)
-            }
-        
-            fn setlk(
-                &mut self,
-                req: &Request,
-                ino: u64,
-                fh: u64,
-                lock_owner: u64,
-                start: u64,
-                end: u64,
-                typ: u32,
-                pid: u32,
-                sleep: bool,
-                reply: ReplyEmpty,
-            ) {
-                self.bento_setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep, reply)
-            }
-        
-            fn bmap(
-                &mut self,
-                req: &Request,
-                ino: u64,
-                blocksize: u32,
-                idx: u64,
-                reply: ReplyBmap,
-            ) {
-                self.bento_bmap(req, ino, blocksize, idx, reply)
-            }
-        }
-    }
-}
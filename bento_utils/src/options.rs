@@ -0,0 +1,477 @@
+//! Mount options of a Bento file system.
+//!
+//! A file system lists the options it accepts in `BentoFilesystem::mount_options`. The options
+//! given to `bento_utils::mount`, a comma-separated list of `name` or `name=value`, are checked
+//! against that list and passed to `bento_init` as a `MountOptions`. An option the file system
+//! doesn't list fails the mount. Options FUSE itself handles, such as `fsname` and `blkdev`,
+//! are used to mount the file system instead, and `threads=N` sets how many threads serve it.
+//!
+//! Options can be changed on a mounted file system by setting the `trusted.bento.remount`
+//! extended attribute of any file to the new options. Only the options given are passed to
+//! `bento_remount`; the others keep their values.
+
+use std::collections::btree_map::{self, BTreeMap};
+use std::ffi::{OsStr, OsString};
+use std::cmp::min;
+use std::fmt;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use session;
+use BentoFilesystem;
+
+/// Name of the extended attribute that remounts the file system with new options.
+pub const REMOUNT_XATTR: &str = "trusted.bento.remount";
+
+// Options handled by the mount and the kernel rather than the file system.
+const FUSE_OPTIONS: &[&str] = &[
+    "allow_other", "allow_root", "auto_unmount", "default_permissions", "nonempty", "blkdev",
+    "blksize", "fsname", "subtype", "max_read", "user", "ro", "rw", "dev", "nodev", "suid",
+    "nosuid", "exec", "noexec", "atime", "noatime", "sync", "async", "dirsync", "large_read",
+    "kernel_cache", "auto_cache", "direct_io",
+];
+
+/// What a mount option takes as its value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MountOptionKind {
+    /// `name` alone, with no value.
+    Flag,
+    /// `name=on` or `name=off`, also `true`/`false`, `yes`/`no` and `1`/`0`. `name` alone is
+    /// on.
+    Bool,
+    /// `name=N`, in decimal or `0x` hex.
+    Number,
+    /// `name=value`.
+    String,
+}
+
+/// A mount option accepted by a file system.
+#[derive(Clone, Copy, Debug)]
+pub struct MountOption {
+    pub name: &'static str,
+    pub kind: MountOptionKind,
+}
+
+/// The value of a parsed mount option.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MountOptionValue {
+    Flag,
+    Bool(bool),
+    Number(u64),
+    String(String),
+}
+
+/// Errors produced while parsing mount options.
+#[derive(Debug)]
+pub enum MountOptionError {
+    /// The options are not valid UTF-8.
+    InvalidUtf8,
+    /// The file system doesn't accept this option.
+    Unknown(String),
+    /// The option needs a value.
+    MissingValue(&'static str),
+    /// The option is a flag but was given a value.
+    UnexpectedValue(&'static str),
+    /// The value can't be parsed as the option's kind.
+    BadValue(&'static str, String),
+}
+
+impl MountOptionError {
+    /// Errno to return to FUSE for this error.
+    pub fn errno(&self) -> libc::c_int {
+        libc::EINVAL
+    }
+}
+
+impl fmt::Display for MountOptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MountOptionError::InvalidUtf8 => write!(f, "mount options are not valid utf-8"),
+            MountOptionError::Unknown(x) => write!(f, "unknown mount option \"{}\"", x),
+            MountOptionError::MissingValue(x) => write!(f, "mount option \"{}\" needs a value", x),
+            MountOptionError::UnexpectedValue(x) => {
+                write!(f, "mount option \"{}\" doesn't take a value", x)
+            }
+            MountOptionError::BadValue(x, val) => {
+                write!(f, "bad value \"{}\" for mount option \"{}\"", val, x)
+            }
+        }
+    }
+}
+
+/// Mount options given to a file system, checked against the options it accepts.
+#[derive(Clone, Debug, Default)]
+pub struct MountOptions {
+    options: BTreeMap<&'static str, MountOptionValue>,
+}
+
+impl MountOptions {
+    /// Create an empty set of options.
+    pub fn new() -> Self {
+        MountOptions {
+            options: BTreeMap::new(),
+        }
+    }
+
+    /// Parse comma-separated `options` against the options in `spec`.
+    ///
+    /// If an option is given more than once, the last value is kept.
+    ///
+    /// Arguments:
+    /// * `options: &OsStr` - The options, such as `commit=10,prov=off`.
+    /// * `spec: &[MountOption]` - The options the file system accepts.
+    pub fn parse(options: &OsStr, spec: &[MountOption]) -> Result<Self, MountOptionError> {
+        let options = options.to_str().ok_or(MountOptionError::InvalidUtf8)?;
+        let mut parsed = MountOptions::new();
+        for option in options.split(',').filter(|x| !x.is_empty()) {
+            let (name, value) = match option.find('=') {
+                Some(idx) => (&option[..idx], Some(&option[idx + 1..])),
+                None => (option, None),
+            };
+            let opt = spec
+                .iter()
+                .find(|x| x.name == name)
+                .ok_or_else(|| MountOptionError::Unknown(name.to_string()))?;
+            let value = match (opt.kind, value) {
+                (MountOptionKind::Flag, None) => MountOptionValue::Flag,
+                (MountOptionKind::Flag, Some(_)) => {
+                    return Err(MountOptionError::UnexpectedValue(opt.name));
+                }
+                (MountOptionKind::Bool, None) => MountOptionValue::Bool(true),
+                (MountOptionKind::Bool, Some(val)) => {
+                    MountOptionValue::Bool(parse_bool(val).ok_or_else(|| {
+                        MountOptionError::BadValue(opt.name, val.to_string())
+                    })?)
+                }
+                (MountOptionKind::Number, Some(val)) => {
+                    MountOptionValue::Number(parse_number(val).ok_or_else(|| {
+                        MountOptionError::BadValue(opt.name, val.to_string())
+                    })?)
+                }
+                (MountOptionKind::String, Some(val)) if !val.is_empty() => {
+                    MountOptionValue::String(val.to_string())
+                }
+                (_, _) => return Err(MountOptionError::MissingValue(opt.name)),
+            };
+            parsed.options.insert(opt.name, value);
+        }
+        Ok(parsed)
+    }
+
+    /// Whether no options were given.
+    pub fn is_empty(&self) -> bool {
+        self.options.is_empty()
+    }
+
+    /// Whether option `name` was given.
+    pub fn contains(&self, name: &str) -> bool {
+        self.options.contains_key(name)
+    }
+
+    /// Get the value of option `name`, if it was given.
+    pub fn get(&self, name: &str) -> Option<&MountOptionValue> {
+        self.options.get(name)
+    }
+
+    /// Whether flag `name` was given.
+    pub fn flag(&self, name: &str) -> bool {
+        self.get(name) == Some(&MountOptionValue::Flag)
+    }
+
+    /// Get the value of bool option `name`, if it was given.
+    pub fn get_bool(&self, name: &str) -> Option<bool> {
+        match self.get(name) {
+            Some(MountOptionValue::Bool(x)) => Some(*x),
+            _ => None,
+        }
+    }
+
+    /// Get the value of number option `name`, if it was given.
+    pub fn get_u64(&self, name: &str) -> Option<u64> {
+        match self.get(name) {
+            Some(MountOptionValue::Number(x)) => Some(*x),
+            _ => None,
+        }
+    }
+
+    /// Get the value of string option `name`, if it was given.
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        match self.get(name) {
+            Some(MountOptionValue::String(x)) => Some(x.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Iterate over the options in name order.
+    pub fn iter(&self) -> btree_map::Iter<'_, &'static str, MountOptionValue> {
+        self.options.iter()
+    }
+}
+
+impl fmt::Display for MountOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (idx, (name, value)) in self.options.iter().enumerate() {
+            if idx != 0 {
+                write!(f, ",")?;
+            }
+            match value {
+                MountOptionValue::Flag => write!(f, "{}", name)?,
+                MountOptionValue::Bool(x) => write!(f, "{}={}", name, if *x { "on" } else { "off" })?,
+                MountOptionValue::Number(x) => write!(f, "{}={}", name, x)?,
+                MountOptionValue::String(x) => write!(f, "{}={}", name, x)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_bool(val: &str) -> Option<bool> {
+    match val {
+        "on" | "true" | "yes" | "1" => Some(true),
+        "off" | "false" | "no" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_number(val: &str) -> Option<u64> {
+    if val.starts_with("0x") || val.starts_with("0X") {
+        u64::from_str_radix(&val[2..], 16).ok()
+    } else {
+        val.parse().ok()
+    }
+}
+
+/// Mount a file system, passing its mount options to `bento_init`.
+///
+/// This takes the same arguments as `fuse::mount` did and serves the file system until it is
+/// unmounted. The options following each `-o` are split: those FUSE handles are used to mount
+/// the file system, and the rest are checked against `BentoFilesystem::mount_options`. An
+/// unknown option fails with `InvalidInput` before anything is mounted.
+///
+/// The `threads=N` option serves the file system from `N` threads with `session::mount`, or
+/// from one thread per CPU with `threads=0`. Without it, requests are served on this thread.
+///
+/// Arguments:
+/// * `fs: FS` - The file system.
+/// * `mountpoint: &P` - Where to mount it.
+/// * `options: &[&OsStr]` - Arguments such as `-o fsname=disk,commit=10,threads=4`.
+pub fn mount<'de, I, O, FS, P>(fs: FS, mountpoint: &P, options: &[&OsStr]) -> io::Result<()>
+where
+    FS: BentoFilesystem<'de, I, O> + Send + Sync,
+    P: AsRef<Path> + ?Sized,
+{
+    match threads_arg(options)? {
+        Some(threads) => session::mount(fs, mountpoint, options, threads),
+        None => session::mount_local(fs, mountpoint.as_ref(), options),
+    }
+}
+
+// The value of the last `threads` option in the arguments given to `mount`.
+fn threads_arg(options: &[&OsStr]) -> io::Result<Option<usize>> {
+    let mut threads = None;
+    let mut args = options.iter();
+    while let Some(arg) = args.next() {
+        if arg.as_bytes() != b"-o" {
+            continue;
+        }
+        let list = match args.next() {
+            Some(list) => list,
+            None => continue,
+        };
+        for option in list.as_bytes().split(|x| *x == b',') {
+            if option == b"threads" || option.starts_with(b"threads=") {
+                let val = OsStr::from_bytes(&option[min(option.len(), 8)..]).to_string_lossy();
+                match parse_number(&val) {
+                    Some(x) => threads = Some(x as usize),
+                    None => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                  format!("threads needs a number, not \"{}\"", val)));
+                    }
+                }
+            }
+        }
+    }
+    Ok(threads)
+}
+
+// Split the arguments given to `mount` into those for the FUSE mount and the file system's
+// options, checked against `BentoFilesystem::mount_options`.
+pub(crate) fn split_args<'de, I, O, FS>(fs: &FS, options: &[&OsStr]) -> io::Result<(Vec<OsString>, MountOptions)>
+where
+    FS: BentoFilesystem<'de, I, O>,
+{
+    let mut fuse_args: Vec<OsString> = Vec::new();
+    let mut fs_options: Vec<&[u8]> = Vec::new();
+    let mut args = options.iter();
+    while let Some(arg) = args.next() {
+        if arg.as_bytes() != b"-o" {
+            fuse_args.push(arg.to_os_string());
+            continue;
+        }
+        let list = match args.next() {
+            Some(list) => list,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "-o needs options"));
+            }
+        };
+        let mut fuse_options: Vec<&[u8]> = Vec::new();
+        for option in list.as_bytes().split(|x| *x == b',').filter(|x| !x.is_empty()) {
+            let name = option.split(|x| *x == b'=').next().unwrap_or(option);
+            if name == b"threads" {
+                // Used by `mount` to pick the session loop.
+                continue;
+            }
+            if FUSE_OPTIONS.iter().any(|x| x.as_bytes() == name) {
+                fuse_options.push(option);
+            } else {
+                fs_options.push(option);
+            }
+        }
+        if !fuse_options.is_empty() {
+            fuse_args.push(OsString::from("-o"));
+            fuse_args.push(OsStr::from_bytes(&fuse_options.join(&b',')).to_os_string());
+        }
+    }
+
+    let fs_options = fs_options.join(&b',');
+    let opts = MountOptions::parse(OsStr::from_bytes(&fs_options), fs.mount_options())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", fs.get_name(), e)))?;
+    Ok((fuse_args, opts))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{OsStr, OsString};
+    use std::io;
+
+    use fuse::Request;
+    use BentoFilesystem;
+
+    use super::*;
+
+    const SPEC: &[MountOption] = &[
+        MountOption { name: "debug", kind: MountOptionKind::Flag },
+        MountOption { name: "prov", kind: MountOptionKind::Bool },
+        MountOption { name: "commit", kind: MountOptionKind::Number },
+        MountOption { name: "server", kind: MountOptionKind::String },
+    ];
+
+    struct TestFs;
+
+    impl<'de> BentoFilesystem<'de> for TestFs {
+        fn get_name(&self) -> &'static str {
+            "testfs"
+        }
+
+        fn new_instance(&self) -> Box<Self> {
+            Box::new(TestFs)
+        }
+
+        fn mount_options(&self) -> &'static [MountOption] {
+            SPEC
+        }
+
+        fn bento_remount(&self, _req: &Request, _opts: &MountOptions) -> Result<(), i32> {
+            Ok(())
+        }
+    }
+
+    fn parse(options: &str) -> Result<MountOptions, MountOptionError> {
+        MountOptions::parse(OsStr::new(options), SPEC)
+    }
+
+    fn args<'a>(args: &'a [&'a str]) -> Vec<&'a OsStr> {
+        args.iter().map(OsStr::new).collect()
+    }
+
+    #[test]
+    fn unknown_options_are_rejected() {
+        match parse("commit=5,journal=big") {
+            Err(MountOptionError::Unknown(name)) => assert_eq!(name, "journal"),
+            x => panic!("unexpected {:?}", x),
+        }
+        let err = split_args(&TestFs, &args(&["-o", "fsname=disk,journal=big"])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "testfs: unknown mount option \"journal\"");
+    }
+
+    #[test]
+    fn flags_take_no_value() {
+        assert!(parse("debug").unwrap().flag("debug"));
+        match parse("debug=1") {
+            Err(MountOptionError::UnexpectedValue("debug")) => {}
+            x => panic!("unexpected {:?}", x),
+        }
+    }
+
+    #[test]
+    fn bools_are_on_without_a_value() {
+        assert_eq!(parse("prov").unwrap().get_bool("prov"), Some(true));
+        for (val, expected) in &[("on", true), ("yes", true), ("1", true), ("off", false),
+                                 ("false", false), ("0", false)] {
+            let opts = parse(&format!("prov={}", val)).unwrap();
+            assert_eq!(opts.get_bool("prov"), Some(*expected));
+        }
+        match parse("prov=maybe") {
+            Err(MountOptionError::BadValue("prov", val)) => assert_eq!(val, "maybe"),
+            x => panic!("unexpected {:?}", x),
+        }
+    }
+
+    #[test]
+    fn numbers_are_decimal_or_hex() {
+        assert_eq!(parse("commit=10").unwrap().get_u64("commit"), Some(10));
+        assert_eq!(parse("commit=0x10").unwrap().get_u64("commit"), Some(16));
+        for val in &["ten", "-1", "0x", ""] {
+            match parse(&format!("commit={}", val)) {
+                Err(MountOptionError::BadValue("commit", x)) => assert_eq!(x, *val),
+                x => panic!("unexpected {:?} for {:?}", x, val),
+            }
+        }
+        match parse("commit") {
+            Err(MountOptionError::MissingValue("commit")) => {}
+            x => panic!("unexpected {:?}", x),
+        }
+    }
+
+    #[test]
+    fn strings_need_a_value() {
+        assert_eq!(parse("server=10.0.0.1:1234").unwrap().get_str("server"), Some("10.0.0.1:1234"));
+        for options in &["server", "server="] {
+            match parse(options) {
+                Err(MountOptionError::MissingValue("server")) => {}
+                x => panic!("unexpected {:?} for {:?}", x, options),
+            }
+        }
+    }
+
+    #[test]
+    fn remounts_only_change_the_options_given() {
+        let mounted = parse("commit=5,prov=off,server=a").unwrap();
+        assert_eq!(mounted.to_string(), "commit=5,prov=off,server=a");
+        // The options left out keep their values, so they aren't passed to `bento_remount`.
+        let remount = parse("commit=10,commit=20").unwrap();
+        assert_eq!(remount.iter().count(), 1);
+        assert_eq!(remount.get_u64("commit"), Some(20));
+        assert!(!remount.contains("prov") && !remount.contains("server"));
+        assert!(parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn threads_is_not_passed_to_fuse_or_the_file_system() {
+        let options = args(&["-o", "fsname=disk,threads=4,commit=5", "-o", "threads=0", "-d"]);
+        assert_eq!(threads_arg(&options).unwrap(), Some(0));
+        let (fuse_args, opts) = split_args(&TestFs, &options).unwrap();
+        assert_eq!(fuse_args, vec![OsString::from("-o"), OsString::from("fsname=disk"),
+                                   OsString::from("-d")]);
+        assert_eq!(opts.to_string(), "commit=5");
+
+        assert_eq!(threads_arg(&args(&["-o", "threads=0x8"])).unwrap(), Some(8));
+        assert_eq!(threads_arg(&args(&["-o", "commit=5"])).unwrap(), None);
+        for options in &["threads=many", "threads"] {
+            let err = threads_arg(&args(&["-o", options])).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
@@ -0,0 +1,697 @@
+// Structures and constants of the FUSE kernel protocol, from include/uapi/linux/fuse.h. Only
+// the parts the session speaks are here.
+
+#![allow(non_camel_case_types, dead_code)]
+
+use std::mem;
+use std::ptr;
+use std::slice;
+
+pub const FUSE_KERNEL_VERSION: u32 = 7;
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+// Oldest minor version the session can talk to.
+pub const FUSE_MIN_MINOR_VERSION: u32 = 19;
+
+pub const FUSE_ROOT_ID: u64 = 1;
+
+// Size of the init reply sent to kernels older than minor version 23.
+pub const FUSE_COMPAT_22_INIT_OUT_SIZE: usize = 24;
+
+// Init flags.
+pub const FUSE_ASYNC_READ: u32 = 1 << 0;
+pub const FUSE_POSIX_LOCKS: u32 = 1 << 1;
+pub const FUSE_ATOMIC_O_TRUNC: u32 = 1 << 3;
+pub const FUSE_EXPORT_SUPPORT: u32 = 1 << 4;
+pub const FUSE_BIG_WRITES: u32 = 1 << 5;
+pub const FUSE_DONT_MASK: u32 = 1 << 6;
+pub const FUSE_FLOCK_LOCKS: u32 = 1 << 10;
+pub const FUSE_HAS_IOCTL_DIR: u32 = 1 << 11;
+pub const FUSE_AUTO_INVAL_DATA: u32 = 1 << 12;
+pub const FUSE_DO_READDIRPLUS: u32 = 1 << 13;
+pub const FUSE_READDIRPLUS_AUTO: u32 = 1 << 14;
+pub const FUSE_ASYNC_DIO: u32 = 1 << 15;
+pub const FUSE_WRITEBACK_CACHE: u32 = 1 << 16;
+pub const FUSE_NO_OPEN_SUPPORT: u32 = 1 << 17;
+pub const FUSE_PARALLEL_DIROPS: u32 = 1 << 18;
+pub const FUSE_HANDLE_KILLPRIV: u32 = 1 << 19;
+pub const FUSE_POSIX_ACL: u32 = 1 << 20;
+pub const FUSE_ABORT_ERROR: u32 = 1 << 21;
+pub const FUSE_MAX_PAGES: u32 = 1 << 22;
+pub const FUSE_CACHE_SYMLINKS: u32 = 1 << 23;
+pub const FUSE_NO_OPENDIR_SUPPORT: u32 = 1 << 24;
+pub const FUSE_EXPLICIT_INVAL_DATA: u32 = 1 << 25;
+
+// Setattr valid bits.
+pub const FATTR_MODE: u32 = 1 << 0;
+pub const FATTR_UID: u32 = 1 << 1;
+pub const FATTR_GID: u32 = 1 << 2;
+pub const FATTR_SIZE: u32 = 1 << 3;
+pub const FATTR_ATIME: u32 = 1 << 4;
+pub const FATTR_MTIME: u32 = 1 << 5;
+pub const FATTR_FH: u32 = 1 << 6;
+pub const FATTR_ATIME_NOW: u32 = 1 << 7;
+pub const FATTR_MTIME_NOW: u32 = 1 << 8;
+pub const FATTR_LOCKOWNER: u32 = 1 << 9;
+pub const FATTR_CTIME: u32 = 1 << 10;
+
+pub const FUSE_RELEASE_FLUSH: u32 = 1 << 0;
+pub const FUSE_FSYNC_FDATASYNC: u32 = 1 << 0;
+
+pub const FUSE_LOOKUP: u32 = 1;
+pub const FUSE_FORGET: u32 = 2;
+pub const FUSE_GETATTR: u32 = 3;
+pub const FUSE_SETATTR: u32 = 4;
+pub const FUSE_READLINK: u32 = 5;
+pub const FUSE_SYMLINK: u32 = 6;
+pub const FUSE_MKNOD: u32 = 8;
+pub const FUSE_MKDIR: u32 = 9;
+pub const FUSE_UNLINK: u32 = 10;
+pub const FUSE_RMDIR: u32 = 11;
+pub const FUSE_RENAME: u32 = 12;
+pub const FUSE_LINK: u32 = 13;
+pub const FUSE_OPEN: u32 = 14;
+pub const FUSE_READ: u32 = 15;
+pub const FUSE_WRITE: u32 = 16;
+pub const FUSE_STATFS: u32 = 17;
+pub const FUSE_RELEASE: u32 = 18;
+pub const FUSE_FSYNC: u32 = 20;
+pub const FUSE_SETXATTR: u32 = 21;
+pub const FUSE_GETXATTR: u32 = 22;
+pub const FUSE_LISTXATTR: u32 = 23;
+pub const FUSE_REMOVEXATTR: u32 = 24;
+pub const FUSE_FLUSH: u32 = 25;
+pub const FUSE_INIT: u32 = 26;
+pub const FUSE_OPENDIR: u32 = 27;
+pub const FUSE_READDIR: u32 = 28;
+pub const FUSE_RELEASEDIR: u32 = 29;
+pub const FUSE_FSYNCDIR: u32 = 30;
+pub const FUSE_GETLK: u32 = 31;
+pub const FUSE_SETLK: u32 = 32;
+pub const FUSE_SETLKW: u32 = 33;
+pub const FUSE_ACCESS: u32 = 34;
+pub const FUSE_CREATE: u32 = 35;
+pub const FUSE_INTERRUPT: u32 = 36;
+pub const FUSE_BMAP: u32 = 37;
+pub const FUSE_DESTROY: u32 = 38;
+pub const FUSE_IOCTL: u32 = 39;
+pub const FUSE_POLL: u32 = 40;
+pub const FUSE_NOTIFY_REPLY: u32 = 41;
+pub const FUSE_BATCH_FORGET: u32 = 42;
+pub const FUSE_FALLOCATE: u32 = 43;
+pub const FUSE_READDIRPLUS: u32 = 44;
+pub const FUSE_RENAME2: u32 = 45;
+pub const FUSE_LSEEK: u32 = 46;
+pub const FUSE_COPY_FILE_RANGE: u32 = 47;
+
+// Notification codes, sent in the error field of a message with unique 0.
+pub const FUSE_NOTIFY_POLL: i32 = 1;
+pub const FUSE_NOTIFY_INVAL_INODE: i32 = 2;
+pub const FUSE_NOTIFY_INVAL_ENTRY: i32 = 3;
+pub const FUSE_NOTIFY_STORE: i32 = 4;
+pub const FUSE_NOTIFY_RETRIEVE: i32 = 5;
+pub const FUSE_NOTIFY_DELETE: i32 = 6;
+
+// _IOR(229, 0, uint32_t)
+pub const FUSE_DEV_IOC_CLONE: libc::c_ulong = 0x8004_e500;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_attr {
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+    pub atimensec: u32,
+    pub mtimensec: u32,
+    pub ctimensec: u32,
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u32,
+    pub blksize: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_kstatfs {
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub bsize: u32,
+    pub namelen: u32,
+    pub frsize: u32,
+    pub padding: u32,
+    pub spare: [u32; 6],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_file_lock {
+    pub start: u64,
+    pub end: u64,
+    pub typ: u32,
+    pub pid: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_entry_out {
+    pub nodeid: u64,
+    pub generation: u64,
+    pub entry_valid: u64,
+    pub attr_valid: u64,
+    pub entry_valid_nsec: u32,
+    pub attr_valid_nsec: u32,
+    pub attr: fuse_attr,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_forget_in {
+    pub nlookup: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_forget_one {
+    pub nodeid: u64,
+    pub nlookup: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_batch_forget_in {
+    pub count: u32,
+    pub dummy: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_getattr_in {
+    pub getattr_flags: u32,
+    pub dummy: u32,
+    pub fh: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_attr_out {
+    pub attr_valid: u64,
+    pub attr_valid_nsec: u32,
+    pub dummy: u32,
+    pub attr: fuse_attr,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_mknod_in {
+    pub mode: u32,
+    pub rdev: u32,
+    pub umask: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_mkdir_in {
+    pub mode: u32,
+    pub umask: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_rename_in {
+    pub newdir: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_rename2_in {
+    pub newdir: u64,
+    pub flags: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_link_in {
+    pub oldnodeid: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_setattr_in {
+    pub valid: u32,
+    pub padding: u32,
+    pub fh: u64,
+    pub size: u64,
+    pub lock_owner: u64,
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+    pub atimensec: u32,
+    pub mtimensec: u32,
+    pub ctimensec: u32,
+    pub mode: u32,
+    pub unused4: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub unused5: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_open_in {
+    pub flags: u32,
+    pub unused: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_create_in {
+    pub flags: u32,
+    pub mode: u32,
+    pub umask: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_open_out {
+    pub fh: u64,
+    pub open_flags: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_release_in {
+    pub fh: u64,
+    pub flags: u32,
+    pub release_flags: u32,
+    pub lock_owner: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_flush_in {
+    pub fh: u64,
+    pub unused: u32,
+    pub padding: u32,
+    pub lock_owner: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_read_in {
+    pub fh: u64,
+    pub offset: u64,
+    pub size: u32,
+    pub read_flags: u32,
+    pub lock_owner: u64,
+    pub flags: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_write_in {
+    pub fh: u64,
+    pub offset: u64,
+    pub size: u32,
+    pub write_flags: u32,
+    pub lock_owner: u64,
+    pub flags: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_write_out {
+    pub size: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_statfs_out {
+    pub st: fuse_kstatfs,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_fsync_in {
+    pub fh: u64,
+    pub fsync_flags: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_setxattr_in {
+    pub size: u32,
+    pub flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_getxattr_in {
+    pub size: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_getxattr_out {
+    pub size: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_lk_in {
+    pub fh: u64,
+    pub owner: u64,
+    pub lk: fuse_file_lock,
+    pub lk_flags: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_lk_out {
+    pub lk: fuse_file_lock,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_access_in {
+    pub mask: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_init_in {
+    pub major: u32,
+    pub minor: u32,
+    pub max_readahead: u32,
+    pub flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_init_out {
+    pub major: u32,
+    pub minor: u32,
+    pub max_readahead: u32,
+    pub flags: u32,
+    pub max_background: u16,
+    pub congestion_threshold: u16,
+    pub max_write: u32,
+    pub time_gran: u32,
+    pub max_pages: u16,
+    pub map_alignment: u16,
+    pub unused: [u32; 8],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_bmap_in {
+    pub block: u64,
+    pub blocksize: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_bmap_out {
+    pub block: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_ioctl_in {
+    pub fh: u64,
+    pub flags: u32,
+    pub cmd: u32,
+    pub arg: u64,
+    pub in_size: u32,
+    pub out_size: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_ioctl_out {
+    pub result: i32,
+    pub flags: u32,
+    pub in_iovs: u32,
+    pub out_iovs: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_poll_in {
+    pub fh: u64,
+    pub kh: u64,
+    pub flags: u32,
+    pub events: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_poll_out {
+    pub revents: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_fallocate_in {
+    pub fh: u64,
+    pub offset: u64,
+    pub length: u64,
+    pub mode: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_lseek_in {
+    pub fh: u64,
+    pub offset: u64,
+    pub whence: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_lseek_out {
+    pub offset: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_copy_file_range_in {
+    pub fh_in: u64,
+    pub off_in: u64,
+    pub nodeid_out: u64,
+    pub fh_out: u64,
+    pub off_out: u64,
+    pub len: u64,
+    pub flags: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_in_header {
+    pub len: u32,
+    pub opcode: u32,
+    pub unique: u64,
+    pub nodeid: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_out_header {
+    pub len: u32,
+    pub error: i32,
+    pub unique: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_dirent {
+    pub ino: u64,
+    pub off: u64,
+    pub namelen: u32,
+    pub typ: u32,
+}
+
+// A readdirplus entry is a `fuse_entry_out` followed by a `fuse_dirent` and the name.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_direntplus {
+    pub entry_out: fuse_entry_out,
+    pub dirent: fuse_dirent,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_notify_inval_inode_out {
+    pub ino: u64,
+    pub off: i64,
+    pub len: i64,
+}
+
+// Followed by the name and a NUL.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_notify_inval_entry_out {
+    pub parent: u64,
+    pub namelen: u32,
+    pub padding: u32,
+}
+
+// Followed by the name and a NUL.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_notify_delete_out {
+    pub parent: u64,
+    pub child: u64,
+    pub namelen: u32,
+    pub padding: u32,
+}
+
+// Followed by the data.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct fuse_notify_store_out {
+    pub nodeid: u64,
+    pub offset: u64,
+    pub size: u32,
+    pub padding: u32,
+}
+
+// Bytes of a plain-data protocol structure.
+pub fn as_bytes<T: Copy>(x: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(x as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+// Arguments of a request, taken from the front in order.
+pub struct ArgIter<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> ArgIter<'a> {
+    pub fn new(data: &'a [u8]) -> ArgIter<'a> {
+        ArgIter { data: data }
+    }
+
+    // Take a structure. The buffer has no alignment guarantee, so it is copied out.
+    pub fn fetch<T: Copy>(&mut self) -> Option<T> {
+        let size = mem::size_of::<T>();
+        if self.data.len() < size {
+            return None;
+        }
+        let value = unsafe { ptr::read_unaligned(self.data.as_ptr() as *const T) };
+        self.data = &self.data[size..];
+        Some(value)
+    }
+
+    // Take `len` bytes.
+    pub fn fetch_bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    // Take a NUL-terminated string, without the NUL.
+    pub fn fetch_str(&mut self) -> Option<&'a [u8]> {
+        let len = self.data.iter().position(|x| *x == 0)?;
+        let bytes = &self.data[..len];
+        self.data = &self.data[len + 1..];
+        Some(bytes)
+    }
+
+    // Take everything left.
+    pub fn fetch_all(&mut self) -> &'a [u8] {
+        let bytes = self.data;
+        self.data = &[];
+        bytes
+    }
+}
+
+// Sizes of the structures in fuse_kernel.h 7.31. A layout that doesn't match fails the build.
+macro_rules! assert_size {
+    ($($t:ty = $size:expr,)*) => {
+        $(const _: [(); $size] = [(); mem::size_of::<$t>()];)*
+    };
+}
+
+assert_size! {
+    fuse_attr = 88,
+    fuse_kstatfs = 80,
+    fuse_file_lock = 24,
+    fuse_entry_out = 128,
+    fuse_forget_in = 8,
+    fuse_forget_one = 16,
+    fuse_batch_forget_in = 8,
+    fuse_getattr_in = 16,
+    fuse_attr_out = 104,
+    fuse_mknod_in = 16,
+    fuse_mkdir_in = 8,
+    fuse_rename_in = 8,
+    fuse_rename2_in = 16,
+    fuse_link_in = 8,
+    fuse_setattr_in = 88,
+    fuse_open_in = 8,
+    fuse_create_in = 16,
+    fuse_open_out = 16,
+    fuse_release_in = 24,
+    fuse_flush_in = 24,
+    fuse_read_in = 40,
+    fuse_write_in = 40,
+    fuse_write_out = 8,
+    fuse_statfs_out = 80,
+    fuse_fsync_in = 16,
+    fuse_setxattr_in = 8,
+    fuse_getxattr_in = 8,
+    fuse_getxattr_out = 8,
+    fuse_lk_in = 48,
+    fuse_lk_out = 24,
+    fuse_access_in = 8,
+    fuse_init_in = 16,
+    fuse_init_out = 64,
+    fuse_bmap_in = 16,
+    fuse_bmap_out = 8,
+    fuse_ioctl_in = 32,
+    fuse_ioctl_out = 16,
+    fuse_poll_in = 24,
+    fuse_poll_out = 8,
+    fuse_fallocate_in = 32,
+    fuse_lseek_in = 24,
+    fuse_lseek_out = 8,
+    fuse_copy_file_range_in = 56,
+    fuse_in_header = 40,
+    fuse_out_header = 16,
+    fuse_dirent = 24,
+    fuse_direntplus = 152,
+    fuse_notify_inval_inode_out = 24,
+    fuse_notify_inval_entry_out = 16,
+    fuse_notify_delete_out = 24,
+    fuse_notify_store_out = 24,
+}
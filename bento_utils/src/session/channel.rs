@@ -0,0 +1,309 @@
+// The /dev/fuse connection of a mount, and mounting and unmounting it.
+
+use std::ffi::{CString, OsStr};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::abi::*;
+
+const FUSE_DEVICE: &str = "/dev/fuse";
+const FUSERMOUNT: &[&str] = &["fusermount", "fusermount3"];
+
+// A file descriptor reading requests from and sending replies to a FUSE connection.
+pub struct Channel {
+    file: File,
+}
+
+impl Channel {
+    // Read one request into `buf`.
+    pub fn receive(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let ret = unsafe { libc::read(self.file.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as usize)
+    }
+
+    // Reply to request `unique` with `result`, an error or the pieces of the reply.
+    pub fn reply(&self, unique: u64, result: Result<&[&[u8]], libc::c_int>) -> io::Result<()> {
+        match result {
+            Ok(data) => self.send(unique, 0, data),
+            Err(err) => self.send(unique, -err, &[]),
+        }
+    }
+
+    // Send notification `code`, made of the pieces in `data`.
+    pub fn notify(&self, code: i32, data: &[&[u8]]) -> io::Result<()> {
+        self.send(0, code, data)
+    }
+
+    fn send(&self, unique: u64, error: i32, data: &[&[u8]]) -> io::Result<()> {
+        let len = mem::size_of::<fuse_out_header>() + data.iter().map(|x| x.len()).sum::<usize>();
+        let header = fuse_out_header {
+            len: len as u32,
+            error: error,
+            unique: unique,
+        };
+        let mut iov = vec![libc::iovec {
+            iov_base: as_bytes(&header).as_ptr() as *mut libc::c_void,
+            iov_len: mem::size_of::<fuse_out_header>(),
+        }];
+        iov.extend(data.iter().filter(|x| !x.is_empty()).map(|x| libc::iovec {
+            iov_base: x.as_ptr() as *mut libc::c_void,
+            iov_len: x.len(),
+        }));
+        let ret = unsafe { libc::writev(self.file.as_raw_fd(), iov.as_ptr(), iov.len() as libc::c_int) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Open another file descriptor on the same connection, so a thread can read requests from
+    // a queue of its own.
+    pub fn clone_fd(&self) -> io::Result<Channel> {
+        let file = open_device()?;
+        let mut fd = self.file.as_raw_fd() as u32;
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), FUSE_DEV_IOC_CLONE as _, &mut fd) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Channel { file: file })
+    }
+
+    // A channel on one end of a socket pair, and the other end, to stand in for the kernel in
+    // tests. Each reply arrives as one message, as it does from /dev/fuse.
+    #[cfg(test)]
+    pub fn pair() -> io::Result<(Channel, File)> {
+        let mut fds = [0; 2];
+        let ret = unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (chan, kernel) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        Ok((Channel { file: chan }, kernel))
+    }
+}
+
+fn open_device() -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_CLOEXEC)
+        .open(FUSE_DEVICE)
+}
+
+// A mounted FUSE file system, unmounted when dropped.
+pub struct Mount {
+    mountpoint: PathBuf,
+    // Whether fusermount mounted it, so it must also unmount it.
+    fusermount: bool,
+    mounted: AtomicBool,
+}
+
+impl Mount {
+    // Mount a FUSE file system at `mountpoint`.
+    //
+    // `options` are the FUSE options given to `options::mount`, such as `fsname=disk` or `ro`.
+    // Root mounts with mount(2); anyone else goes through fusermount.
+    pub fn new(mountpoint: &Path, options: &[&[u8]], name: &str) -> io::Result<(Channel, Mount)> {
+        let mountpoint = mountpoint.canonicalize()?;
+        let file = open_device()?;
+        match mount_kernel(&mountpoint, file.as_raw_fd(), options, name) {
+            Ok(()) => {
+                let mount = Mount {
+                    mountpoint: mountpoint,
+                    fusermount: false,
+                    mounted: AtomicBool::new(true),
+                };
+                return Ok((Channel { file: file }, mount));
+            }
+            Err(ref e) if e.raw_os_error() == Some(libc::EPERM) => {}
+            Err(e) => return Err(e),
+        }
+        let file = mount_fusermount(&mountpoint, options)?;
+        let mount = Mount {
+            mountpoint: mountpoint,
+            fusermount: true,
+            mounted: AtomicBool::new(true),
+        };
+        Ok((Channel { file: file }, mount))
+    }
+
+    // Lazily unmount the file system. Does nothing after the first call.
+    pub fn unmount(&self) {
+        if !self.mounted.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        if self.fusermount {
+            for prog in FUSERMOUNT {
+                let status = Command::new(prog)
+                    .args(["-u", "-q", "-z", "--"])
+                    .arg(&self.mountpoint)
+                    .status();
+                if status.is_ok() {
+                    return;
+                }
+            }
+        } else if let Ok(path) = CString::new(self.mountpoint.as_os_str().as_bytes()) {
+            unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) };
+        }
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        self.unmount();
+    }
+}
+
+fn mount_kernel(mountpoint: &Path, fd: RawFd, options: &[&[u8]], name: &str) -> io::Result<()> {
+    // File system names end in a NUL for the kernel build.
+    let mut source = name.trim_end_matches('\0').as_bytes().to_vec();
+    let mut subtype = None;
+    let mut blkdev = false;
+    let mut flags = libc::MS_NOSUID | libc::MS_NODEV;
+    let rootmode = mountpoint.metadata()?.mode() & libc::S_IFMT;
+    let mut data = format!("fd={},rootmode={:o},user_id={},group_id={}",
+                           fd, rootmode, unsafe { libc::getuid() }, unsafe { libc::getgid() });
+    for option in options {
+        let (key, value) = match option.iter().position(|x| *x == b'=') {
+            Some(idx) => (&option[..idx], Some(&option[idx + 1..])),
+            None => (&option[..], None),
+        };
+        match (key, value) {
+            (b"fsname", Some(x)) => source = x.to_vec(),
+            (b"subtype", Some(x)) => subtype = Some(x.to_vec()),
+            (b"blkdev", None) => blkdev = true,
+            (b"allow_other", None) | (b"allow_root", None) => data.push_str(",allow_other"),
+            (b"default_permissions", None) => data.push_str(",default_permissions"),
+            (b"max_read", Some(_)) | (b"blksize", Some(_)) => {
+                data.push(',');
+                data.push_str(&String::from_utf8_lossy(option));
+            }
+            (b"ro", None) => flags |= libc::MS_RDONLY,
+            (b"rw", None) => flags &= !libc::MS_RDONLY,
+            (b"nosuid", None) => flags |= libc::MS_NOSUID,
+            (b"suid", None) => flags &= !libc::MS_NOSUID,
+            (b"nodev", None) => flags |= libc::MS_NODEV,
+            (b"dev", None) => flags &= !libc::MS_NODEV,
+            (b"noexec", None) => flags |= libc::MS_NOEXEC,
+            (b"exec", None) => flags &= !libc::MS_NOEXEC,
+            (b"noatime", None) => flags |= libc::MS_NOATIME,
+            (b"atime", None) => flags &= !libc::MS_NOATIME,
+            (b"sync", None) => flags |= libc::MS_SYNCHRONOUS,
+            (b"async", None) => flags &= !libc::MS_SYNCHRONOUS,
+            (b"dirsync", None) => flags |= libc::MS_DIRSYNC,
+            // Options only fusermount or libfuse act on.
+            (b"user", _) | (b"auto_unmount", None) | (b"nonempty", None) | (b"large_read", None)
+            | (b"kernel_cache", None) | (b"auto_cache", None) | (b"direct_io", None) => {}
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("bad mount option \"{}\"", String::from_utf8_lossy(option))));
+            }
+        }
+    }
+    let mut fstype = if blkdev { b"fuseblk".to_vec() } else { b"fuse".to_vec() };
+    if let Some(subtype) = subtype {
+        fstype.push(b'.');
+        fstype.extend(subtype);
+    }
+
+    let source = cstring(&source)?;
+    let target = cstring(mountpoint.as_os_str().as_bytes())?;
+    let fstype = cstring(&fstype)?;
+    let data = cstring(data.as_bytes())?;
+    let ret = unsafe {
+        libc::mount(source.as_ptr(), target.as_ptr(), fstype.as_ptr(), flags,
+                    data.as_ptr() as *const libc::c_void)
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Have fusermount mount the file system and pass back the /dev/fuse file descriptor over a
+// socket.
+fn mount_fusermount(mountpoint: &Path, options: &[&[u8]]) -> io::Result<File> {
+    let mut fds = [0; 2];
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ours = unsafe { File::from_raw_fd(fds[0]) };
+    let theirs = unsafe { File::from_raw_fd(fds[1]) };
+    unsafe { libc::fcntl(ours.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) };
+
+    let mut spawned = Err(io::Error::new(io::ErrorKind::NotFound, "fusermount not found"));
+    for prog in FUSERMOUNT {
+        let mut cmd = Command::new(prog);
+        if !options.is_empty() {
+            cmd.arg("-o").arg(OsStr::from_bytes(&options.join(&b","[..])));
+        }
+        cmd.arg("--").arg(mountpoint).env("_FUSE_COMMFD", theirs.as_raw_fd().to_string());
+        spawned = cmd.spawn().map(|child| (prog, child));
+        if spawned.is_ok() {
+            break;
+        }
+    }
+    let (prog, mut child) = spawned?;
+    drop(theirs);
+    let ret = receive_fd(&ours);
+    let status = child.wait()?;
+    match ret {
+        Ok(file) => Ok(file),
+        Err(_) if !status.success() => {
+            Err(io::Error::new(io::ErrorKind::Other, format!("{} failed: {}", prog, status)))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn receive_fd(sock: &File) -> io::Result<File> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: byte.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<libc::c_int>() as u32) } as usize;
+    let mut control = vec![0u64; space.div_ceil(8)];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+    loop {
+        let ret = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, 0) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EINTR) {
+                continue;
+            }
+            return Err(err);
+        }
+        if ret == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "fusermount sent no file descriptor"));
+        }
+        break;
+    }
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null() || (*cmsg).cmsg_level != libc::SOL_SOCKET || (*cmsg).cmsg_type != libc::SCM_RIGHTS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "fusermount sent no file descriptor"));
+        }
+        let fd = *(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        Ok(File::from_raw_fd(fd))
+    }
+}
+
+fn cstring(bytes: &[u8]) -> io::Result<CString> {
+    CString::new(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
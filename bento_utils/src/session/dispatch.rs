@@ -0,0 +1,1063 @@
+// Decoding requests, calling the file system and encoding its replies.
+//
+// Requests get the same treatment as in the kernel's dispatch: panics are caught, the file
+// system is told when it enters the errors state, requests that could modify a read-only file
+// system fail with `EROFS`, and the status and remount extended attributes are handled here.
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::{Arc, Weak};
+
+use libc::{c_int, EIO, ENODEV, ENOSYS, ERANGE, EROFS};
+
+use time::{self, Timespec};
+
+use errors::{self, opcode};
+use fuse::*;
+use options::{self, MountOptions};
+use BentoFilesystem;
+
+use super::abi::*;
+use super::channel::Channel;
+
+// Where to send the reply to a request.
+#[derive(Clone)]
+struct Out {
+    chan: Arc<Channel>,
+    unique: u64,
+}
+
+impl Out {
+    fn send(&self, result: Result<&[&[u8]], c_int>) {
+        // Fails with ENOENT if the request was interrupted, and there is no one else to tell.
+        let _ = self.chan.reply(self.unique, result);
+    }
+
+    fn ok(&self) {
+        self.send(Ok(&[]));
+    }
+
+    fn error(&self, err: c_int) {
+        self.send(Err(err));
+    }
+
+    fn send_struct<T: Copy>(&self, result: Result<T, c_int>) {
+        match result {
+            Ok(x) => self.send(Ok(&[as_bytes(&x)])),
+            Err(err) => self.error(err),
+        }
+    }
+
+    fn empty(&self) -> ReplyEmpty {
+        let out = self.clone();
+        ReplyEmpty::new(move |result| match result {
+            Ok(()) => out.ok(),
+            Err(err) => out.error(err),
+        })
+    }
+
+    fn data(&self) -> ReplyData {
+        let out = self.clone();
+        ReplyData::new(move |result| match result {
+            Ok(data) => out.send(Ok(&[data])),
+            Err(err) => out.error(err),
+        })
+    }
+
+    fn entry(&self) -> ReplyEntry {
+        let out = self.clone();
+        ReplyEntry::new(move |result| {
+            out.send_struct(result.map(|x| entry_out(&x.ttl, &x.attr, x.generation)))
+        })
+    }
+
+    fn attr(&self) -> ReplyAttr {
+        let out = self.clone();
+        ReplyAttr::new(move |result| {
+            out.send_struct(result.map(|x| fuse_attr_out {
+                attr_valid: x.ttl.sec as u64,
+                attr_valid_nsec: x.ttl.nsec as u32,
+                dummy: 0,
+                attr: fuse_attr(&x.attr),
+            }))
+        })
+    }
+
+    fn open(&self) -> ReplyOpen {
+        let out = self.clone();
+        ReplyOpen::new(move |result| {
+            out.send_struct(result.map(|x| fuse_open_out {
+                fh: x.fh,
+                open_flags: x.flags,
+                padding: 0,
+            }))
+        })
+    }
+
+    fn write(&self) -> ReplyWrite {
+        let out = self.clone();
+        ReplyWrite::new(move |result| {
+            out.send_struct(result.map(|size| fuse_write_out {
+                size: size,
+                padding: 0,
+            }))
+        })
+    }
+
+    fn statfs(&self) -> ReplyStatfs {
+        let out = self.clone();
+        ReplyStatfs::new(move |result| {
+            out.send_struct(result.map(|x| fuse_statfs_out {
+                st: fuse_kstatfs {
+                    blocks: x.blocks,
+                    bfree: x.bfree,
+                    bavail: x.bavail,
+                    files: x.files,
+                    ffree: x.ffree,
+                    bsize: x.bsize,
+                    namelen: x.namelen,
+                    frsize: x.frsize,
+                    padding: 0,
+                    spare: [0; 6],
+                },
+            }))
+        })
+    }
+
+    fn create(&self) -> ReplyCreate {
+        let out = self.clone();
+        ReplyCreate::new(move |result| match result {
+            Ok(x) => {
+                let entry = entry_out(&x.ttl, &x.attr, x.generation);
+                let open = fuse_open_out {
+                    fh: x.fh,
+                    open_flags: x.flags,
+                    padding: 0,
+                };
+                out.send(Ok(&[as_bytes(&entry), as_bytes(&open)]));
+            }
+            Err(err) => out.error(err),
+        })
+    }
+
+    fn lock(&self) -> ReplyLock {
+        let out = self.clone();
+        ReplyLock::new(move |result| {
+            out.send_struct(result.map(|x| fuse_lk_out {
+                lk: fuse_file_lock {
+                    start: x.start,
+                    end: x.end,
+                    typ: x.typ,
+                    pid: x.pid,
+                },
+            }))
+        })
+    }
+
+    fn bmap(&self) -> ReplyBmap {
+        let out = self.clone();
+        ReplyBmap::new(move |result| out.send_struct(result.map(|block| fuse_bmap_out { block: block })))
+    }
+
+    fn directory(&self, size: u32) -> ReplyDirectory {
+        let out = self.clone();
+        ReplyDirectory::new(size as usize, move |result| match result {
+            Ok(entries) => {
+                let mut buf = Vec::new();
+                for entry in entries {
+                    let dirent = fuse_dirent {
+                        ino: entry.ino,
+                        off: entry.offset as u64,
+                        namelen: entry.name.len() as u32,
+                        typ: mode_from_kind(entry.kind) >> 12,
+                    };
+                    push_dirent(&mut buf, &dirent, entry.name.as_bytes());
+                }
+                out.send(Ok(&[&buf]));
+            }
+            Err(err) => out.error(err),
+        })
+    }
+
+    fn directory_plus(&self, size: u32) -> ReplyDirectoryPlus {
+        let out = self.clone();
+        ReplyDirectoryPlus::new(size as usize, move |result| match result {
+            Ok(entries) => {
+                let mut buf = Vec::new();
+                for entry in entries {
+                    let direntplus = fuse_direntplus {
+                        entry_out: fuse_entry_out {
+                            nodeid: entry.ino,
+                            ..entry_out(&entry.ttl, &entry.attr, 0)
+                        },
+                        dirent: fuse_dirent {
+                            ino: entry.ino,
+                            off: entry.offset as u64,
+                            namelen: entry.name.len() as u32,
+                            typ: mode_from_kind(entry.attr.kind) >> 12,
+                        },
+                    };
+                    push_dirent(&mut buf, &direntplus, entry.name.as_bytes());
+                }
+                out.send(Ok(&[&buf]));
+            }
+            Err(err) => out.error(err),
+        })
+    }
+
+    fn xattr(&self) -> ReplyXattr {
+        let out = self.clone();
+        ReplyXattr::new(move |result| match result {
+            Ok(Xattr::Size(size)) => out.send_struct(Ok(fuse_getxattr_out {
+                size: size,
+                padding: 0,
+            })),
+            Ok(Xattr::Data(data)) => out.send(Ok(&[data])),
+            Err(err) => out.error(err),
+        })
+    }
+
+    fn ioctl(&self) -> ReplyIoctl {
+        let out = self.clone();
+        ReplyIoctl::new(move |result| match result {
+            Ok((result, data)) => {
+                let ioctl_out = fuse_ioctl_out {
+                    result: result,
+                    ..Default::default()
+                };
+                out.send(Ok(&[as_bytes(&ioctl_out), data]));
+            }
+            Err(err) => out.error(err),
+        })
+    }
+
+    fn lseek(&self) -> ReplyLseek {
+        let out = self.clone();
+        ReplyLseek::new(move |result| {
+            out.send_struct(result.map(|offset| fuse_lseek_out { offset: offset as u64 }))
+        })
+    }
+
+    fn poll(&self) -> ReplyPoll {
+        let out = self.clone();
+        ReplyPoll::new(move |result| {
+            out.send_struct(result.map(|revents| fuse_poll_out {
+                revents: revents,
+                padding: 0,
+            }))
+        })
+    }
+}
+
+fn fuse_attr(attr: &FileAttr) -> fuse_attr {
+    fuse_attr {
+        ino: attr.ino,
+        size: attr.size,
+        blocks: attr.blocks,
+        atime: attr.atime.sec as u64,
+        mtime: attr.mtime.sec as u64,
+        ctime: attr.ctime.sec as u64,
+        atimensec: attr.atime.nsec as u32,
+        mtimensec: attr.mtime.nsec as u32,
+        ctimensec: attr.ctime.nsec as u32,
+        mode: mode_from_kind(attr.kind) | attr.perm as u32,
+        nlink: attr.nlink,
+        uid: attr.uid,
+        gid: attr.gid,
+        rdev: attr.rdev,
+        blksize: 0,
+        padding: 0,
+    }
+}
+
+fn entry_out(ttl: &Timespec, attr: &FileAttr, generation: u64) -> fuse_entry_out {
+    fuse_entry_out {
+        nodeid: attr.ino,
+        generation: generation,
+        entry_valid: ttl.sec as u64,
+        attr_valid: ttl.sec as u64,
+        entry_valid_nsec: ttl.nsec as u32,
+        attr_valid_nsec: ttl.nsec as u32,
+        attr: fuse_attr(attr),
+    }
+}
+
+fn mode_from_kind(kind: FileType) -> u32 {
+    match kind {
+        FileType::NamedPipe => libc::S_IFIFO,
+        FileType::CharDevice => libc::S_IFCHR,
+        FileType::BlockDevice => libc::S_IFBLK,
+        FileType::Directory => libc::S_IFDIR,
+        FileType::RegularFile => libc::S_IFREG,
+        FileType::Symlink => libc::S_IFLNK,
+        FileType::Socket => libc::S_IFSOCK,
+    }
+}
+
+// Append a directory entry, padded to 8 bytes.
+fn push_dirent<T: Copy>(buf: &mut Vec<u8>, dirent: &T, name: &[u8]) {
+    buf.extend_from_slice(as_bytes(dirent));
+    buf.extend_from_slice(name);
+    while buf.len() % 8 != 0 {
+        buf.push(0);
+    }
+}
+
+fn time_or_now(set: bool, now: bool, sec: u64, nsec: u32) -> Option<Timespec> {
+    if now {
+        Some(time::get_time())
+    } else if set {
+        Some(Timespec::new(sec as i64, nsec as i32))
+    } else {
+        None
+    }
+}
+
+/// Run request `opcode`, catching a panic, then tell the file system if it entered the errors
+/// state. Returns `None` if the request panicked.
+pub fn guard<'de, I, O, FS, R, F>(fs: &FS, opcode: u32, f: F) -> Option<R>
+where
+    FS: BentoFilesystem<'de, I, O>,
+    F: FnOnce() -> R,
+{
+    let ret = errors::guard(opcode, f);
+    report_errors(fs, opcode);
+    ret
+}
+
+/// Tell the file system if request `opcode` put it in the errors state.
+pub fn report_errors<'de, I, O, FS>(fs: &FS, opcode: u32)
+where
+    FS: BentoFilesystem<'de, I, O>,
+{
+    if errors::take_pending() {
+        if let Some(status) = errors::status() {
+            println!("bento: {}: file system error, now read-only: {}", fs.get_name(), status);
+            errors::guard(opcode, || fs.bento_error(&status));
+            errors::take_pending();
+        }
+    }
+}
+
+fn read_only<'de, I, O, FS: BentoFilesystem<'de, I, O>>(fs: &FS) -> bool {
+    errors::read_only() || fs.read_only()
+}
+
+/// Pass request `header`, with arguments `data`, to the file system and send its reply on
+/// `chan`. Init and destroy requests are handled by the session.
+pub fn dispatch<'de, I, O, FS>(fs: &FS, chan: &Arc<Channel>, header: &fuse_in_header, data: &[u8])
+where
+    FS: BentoFilesystem<'de, I, O>,
+{
+    let out = Out {
+        chan: Arc::clone(chan),
+        unique: header.unique,
+    };
+    // The kernel doesn't wait for a reply to a forget, and fails one that comes anyway.
+    let no_reply = header.opcode == FUSE_FORGET || header.opcode == FUSE_BATCH_FORGET;
+    if handle(fs, &out, header, data).is_none() && !no_reply {
+        out.error(EIO);
+    }
+}
+
+/// Encode `notification` and send it on `chan`. Fails with `ENODEV` once the session is over.
+pub fn notify(chan: &Weak<Channel>, notification: Notification) -> Result<(), c_int> {
+    let chan = chan.upgrade().ok_or(ENODEV)?;
+    let ret = match notification {
+        Notification::InvalInode { ino, offset, len } => {
+            let out = fuse_notify_inval_inode_out {
+                ino: ino,
+                off: offset,
+                len: len,
+            };
+            chan.notify(FUSE_NOTIFY_INVAL_INODE, &[as_bytes(&out)])
+        }
+        Notification::InvalEntry { parent, name } => {
+            let out = fuse_notify_inval_entry_out {
+                parent: parent,
+                namelen: name.len() as u32,
+                padding: 0,
+            };
+            chan.notify(FUSE_NOTIFY_INVAL_ENTRY, &[as_bytes(&out), name.as_bytes(), b"\0"])
+        }
+        Notification::Delete { parent, child, name } => {
+            let out = fuse_notify_delete_out {
+                parent: parent,
+                child: child,
+                namelen: name.len() as u32,
+                padding: 0,
+            };
+            chan.notify(FUSE_NOTIFY_DELETE, &[as_bytes(&out), name.as_bytes(), b"\0"])
+        }
+        Notification::Store { ino, offset, data } => {
+            let out = fuse_notify_store_out {
+                nodeid: ino,
+                offset: offset,
+                size: data.len() as u32,
+                padding: 0,
+            };
+            chan.notify(FUSE_NOTIFY_STORE, &[as_bytes(&out), data])
+        }
+    };
+    // The kernel fails writes to the connection with ENODEV once the file system is unmounted.
+    ret.map_err(|e| e.raw_os_error().unwrap_or(EIO))
+}
+
+// Returns `None`, without replying, if the arguments are too short. A batch forget with too few
+// entries still forgets the ones it has.
+fn handle<'de, I, O, FS>(fs: &FS, out: &Out, header: &fuse_in_header, data: &[u8]) -> Option<()>
+where
+    FS: BentoFilesystem<'de, I, O>,
+{
+    let req = Request::new(header.unique, header.uid, header.gid, header.pid);
+    let req = &req;
+    let ino = header.nodeid;
+    let mut args = ArgIter::new(data);
+    match header.opcode {
+        FUSE_LOOKUP => {
+            let name = OsStr::from_bytes(args.fetch_str()?);
+            guard(fs, opcode::LOOKUP, || fs.bento_lookup(req, ino, name, out.entry()));
+        }
+        FUSE_FORGET => {
+            let arg: fuse_forget_in = args.fetch()?;
+            guard(fs, opcode::FORGET, || fs.bento_forget(req, ino, arg.nlookup));
+        }
+        FUSE_BATCH_FORGET => {
+            let arg: fuse_batch_forget_in = args.fetch()?;
+            for _ in 0..arg.count {
+                let one: fuse_forget_one = args.fetch()?;
+                guard(fs, opcode::FORGET, || fs.bento_forget(req, one.nodeid, one.nlookup));
+            }
+        }
+        FUSE_GETATTR => {
+            guard(fs, opcode::GETATTR, || fs.bento_getattr(req, ino, out.attr()));
+        }
+        FUSE_SETATTR => {
+            let arg: fuse_setattr_in = args.fetch()?;
+            if read_only(fs) {
+                out.error(EROFS);
+                return Some(());
+            }
+            let valid = arg.valid;
+            let mode = if valid & FATTR_MODE != 0 { Some(arg.mode) } else { None };
+            let uid = if valid & FATTR_UID != 0 { Some(arg.uid) } else { None };
+            let gid = if valid & FATTR_GID != 0 { Some(arg.gid) } else { None };
+            let size = if valid & FATTR_SIZE != 0 { Some(arg.size) } else { None };
+            let atime = time_or_now(valid & FATTR_ATIME != 0, valid & FATTR_ATIME_NOW != 0,
+                                    arg.atime, arg.atimensec);
+            let mtime = time_or_now(valid & FATTR_MTIME != 0, valid & FATTR_MTIME_NOW != 0,
+                                    arg.mtime, arg.mtimensec);
+            let fh = if valid & FATTR_FH != 0 { Some(arg.fh) } else { None };
+            guard(fs, opcode::SETATTR, || {
+                fs.bento_setattr(req, ino, mode, uid, gid, size, atime, mtime, fh, None, None, None,
+                                 None, out.attr())
+            });
+        }
+        FUSE_READLINK => {
+            guard(fs, opcode::READLINK, || fs.bento_readlink(req, ino, out.data()));
+        }
+        FUSE_SYMLINK => {
+            let name = OsStr::from_bytes(args.fetch_str()?);
+            let link = Path::new(OsStr::from_bytes(args.fetch_str()?));
+            if read_only(fs) {
+                out.error(EROFS);
+                return Some(());
+            }
+            guard(fs, opcode::SYMLINK, || fs.bento_symlink(req, ino, name, link, out.entry()));
+        }
+        FUSE_MKNOD => {
+            let arg: fuse_mknod_in = args.fetch()?;
+            let name = OsStr::from_bytes(args.fetch_str()?);
+            if read_only(fs) {
+                out.error(EROFS);
+                return Some(());
+            }
+            guard(fs, opcode::MKNOD, || fs.bento_mknod(req, ino, name, arg.mode, arg.rdev, out.entry()));
+        }
+        FUSE_MKDIR => {
+            let arg: fuse_mkdir_in = args.fetch()?;
+            let name = OsStr::from_bytes(args.fetch_str()?);
+            if read_only(fs) {
+                out.error(EROFS);
+                return Some(());
+            }
+            guard(fs, opcode::MKDIR, || fs.bento_mkdir(req, ino, name, arg.mode, out.entry()));
+        }
+        FUSE_UNLINK => {
+            let name = OsStr::from_bytes(args.fetch_str()?);
+            if read_only(fs) {
+                out.error(EROFS);
+                return Some(());
+            }
+            guard(fs, opcode::UNLINK, || fs.bento_unlink(req, ino, name, out.empty()));
+        }
+        FUSE_RMDIR => {
+            let name = OsStr::from_bytes(args.fetch_str()?);
+            if read_only(fs) {
+                out.error(EROFS);
+                return Some(());
+            }
+            guard(fs, opcode::RMDIR, || fs.bento_rmdir(req, ino, name, out.empty()));
+        }
+        FUSE_RENAME | FUSE_RENAME2 => {
+            let (newdir, flags, op) = if header.opcode == FUSE_RENAME2 {
+                let arg: fuse_rename2_in = args.fetch()?;
+                (arg.newdir, arg.flags, opcode::RENAME2)
+            } else {
+                let arg: fuse_rename_in = args.fetch()?;
+                (arg.newdir, 0, opcode::RENAME)
+            };
+            let name = OsStr::from_bytes(args.fetch_str()?);
+            let newname = OsStr::from_bytes(args.fetch_str()?);
+            if read_only(fs) {
+                out.error(EROFS);
+                return Some(());
+            }
+            guard(fs, op, || fs.bento_rename(req, ino, name, newdir, newname, flags, out.empty()));
+        }
+        FUSE_LINK => {
+            let arg: fuse_link_in = args.fetch()?;
+            let newname = OsStr::from_bytes(args.fetch_str()?);
+            if read_only(fs) {
+                out.error(EROFS);
+                return Some(());
+            }
+            guard(fs, opcode::LINK, || fs.bento_link(req, arg.oldnodeid, ino, newname, out.entry()));
+        }
+        FUSE_OPEN => {
+            let arg: fuse_open_in = args.fetch()?;
+            if read_only(fs) && errors::open_modifies(arg.flags) {
+                out.error(EROFS);
+                return Some(());
+            }
+            guard(fs, opcode::OPEN, || fs.bento_open(req, ino, arg.flags, out.open()));
+        }
+        FUSE_READ => {
+            let arg: fuse_read_in = args.fetch()?;
+            guard(fs, opcode::READ, || {
+                fs.bento_read(req, ino, arg.fh, arg.offset as i64, arg.size, out.data())
+            });
+        }
+        FUSE_WRITE => {
+            let arg: fuse_write_in = args.fetch()?;
+            let data = args.fetch_bytes(arg.size as usize)?;
+            if read_only(fs) {
+                out.error(EROFS);
+                return Some(());
+            }
+            guard(fs, opcode::WRITE, || {
+                fs.bento_write(req, ino, arg.fh, arg.offset as i64, data, arg.write_flags, out.write())
+            });
+        }
+        FUSE_STATFS => {
+            guard(fs, opcode::STATFS, || fs.bento_statfs(req, ino, out.statfs()));
+        }
+        FUSE_RELEASE => {
+            let arg: fuse_release_in = args.fetch()?;
+            let flush = arg.release_flags & FUSE_RELEASE_FLUSH != 0;
+            guard(fs, opcode::RELEASE, || {
+                fs.bento_release(req, ino, arg.fh, arg.flags, arg.lock_owner, flush, out.empty())
+            });
+        }
+        FUSE_FSYNC => {
+            let arg: fuse_fsync_in = args.fetch()?;
+            let datasync = arg.fsync_flags & FUSE_FSYNC_FDATASYNC != 0;
+            guard(fs, opcode::FSYNC, || fs.bento_fsync(req, ino, arg.fh, datasync, out.empty()));
+        }
+        FUSE_SETXATTR => {
+            let arg: fuse_setxattr_in = args.fetch()?;
+            let name = OsStr::from_bytes(args.fetch_str()?);
+            let value = args.fetch_bytes(arg.size as usize)?;
+            if name == options::REMOUNT_XATTR {
+                remount(fs, req, out, value);
+                return Some(());
+            }
+            if read_only(fs) {
+                out.error(EROFS);
+                return Some(());
+            }
+            guard(fs, opcode::SETXATTR, || {
+                fs.bento_setxattr(req, ino, name, value, arg.flags, 0, out.empty())
+            });
+        }
+        FUSE_GETXATTR => {
+            let arg: fuse_getxattr_in = args.fetch()?;
+            let name = OsStr::from_bytes(args.fetch_str()?);
+            if name == errors::STATUS_XATTR {
+                let value = errors::status_value();
+                if arg.size == 0 {
+                    out.xattr().size(value.len() as u32);
+                } else if (arg.size as usize) < value.len() {
+                    out.error(ERANGE);
+                } else {
+                    out.xattr().data(value.as_bytes());
+                }
+                return Some(());
+            }
+            guard(fs, opcode::GETXATTR, || fs.bento_getxattr(req, ino, name, arg.size, out.xattr()));
+        }
+        FUSE_LISTXATTR => {
+            let arg: fuse_getxattr_in = args.fetch()?;
+            guard(fs, opcode::LISTXATTR, || fs.bento_listxattr(req, ino, arg.size, out.xattr()));
+        }
+        FUSE_REMOVEXATTR => {
+            let name = OsStr::from_bytes(args.fetch_str()?);
+            if read_only(fs) {
+                out.error(EROFS);
+                return Some(());
+            }
+            guard(fs, opcode::REMOVEXATTR, || fs.bento_removexattr(req, ino, name, out.empty()));
+        }
+        FUSE_FLUSH => {
+            let arg: fuse_flush_in = args.fetch()?;
+            guard(fs, opcode::FLUSH, || fs.bento_flush(req, ino, arg.fh, arg.lock_owner, out.empty()));
+        }
+        FUSE_OPENDIR => {
+            let arg: fuse_open_in = args.fetch()?;
+            guard(fs, opcode::OPENDIR, || fs.bento_opendir(req, ino, arg.flags, out.open()));
+        }
+        FUSE_READDIR => {
+            let arg: fuse_read_in = args.fetch()?;
+            guard(fs, opcode::READDIR, || {
+                fs.bento_readdir(req, ino, arg.fh, arg.offset as i64, out.directory(arg.size))
+            });
+        }
+        FUSE_READDIRPLUS => {
+            let arg: fuse_read_in = args.fetch()?;
+            guard(fs, opcode::READDIRPLUS, || {
+                fs.bento_readdirplus(req, ino, arg.fh, arg.offset as i64, out.directory_plus(arg.size))
+            });
+        }
+        FUSE_RELEASEDIR => {
+            let arg: fuse_release_in = args.fetch()?;
+            guard(fs, opcode::RELEASEDIR, || {
+                fs.bento_releasedir(req, ino, arg.fh, arg.flags, out.empty())
+            });
+        }
+        FUSE_FSYNCDIR => {
+            let arg: fuse_fsync_in = args.fetch()?;
+            let datasync = arg.fsync_flags & FUSE_FSYNC_FDATASYNC != 0;
+            guard(fs, opcode::FSYNCDIR, || fs.bento_fsyncdir(req, ino, arg.fh, datasync, out.empty()));
+        }
+        FUSE_GETLK => {
+            let arg: fuse_lk_in = args.fetch()?;
+            guard(fs, opcode::GETLK, || {
+                fs.bento_getlk(req, ino, arg.fh, arg.owner, arg.lk.start, arg.lk.end, arg.lk.typ,
+                               arg.lk.pid, out.lock())
+            });
+        }
+        FUSE_SETLK | FUSE_SETLKW => {
+            let arg: fuse_lk_in = args.fetch()?;
+            let sleep = header.opcode == FUSE_SETLKW;
+            let op = if sleep { opcode::SETLKW } else { opcode::SETLK };
+            guard(fs, op, || {
+                fs.bento_setlk(req, ino, arg.fh, arg.owner, arg.lk.start, arg.lk.end, arg.lk.typ,
+                               arg.lk.pid, sleep, out.empty())
+            });
+        }
+        FUSE_ACCESS => {
+            let arg: fuse_access_in = args.fetch()?;
+            guard(fs, opcode::ACCESS, || fs.bento_access(req, ino, arg.mask, out.empty()));
+        }
+        FUSE_CREATE => {
+            let arg: fuse_create_in = args.fetch()?;
+            let name = OsStr::from_bytes(args.fetch_str()?);
+            if read_only(fs) {
+                out.error(EROFS);
+                return Some(());
+            }
+            guard(fs, opcode::CREATE, || {
+                fs.bento_create(req, ino, name, arg.mode, arg.flags, out.create())
+            });
+        }
+        // The reply to the interrupted request is enough.
+        FUSE_INTERRUPT => {}
+        FUSE_BMAP => {
+            let arg: fuse_bmap_in = args.fetch()?;
+            guard(fs, opcode::BMAP, || fs.bento_bmap(req, ino, arg.blocksize, arg.block, out.bmap()));
+        }
+        FUSE_IOCTL => {
+            let arg: fuse_ioctl_in = args.fetch()?;
+            let in_data = args.fetch_bytes(arg.in_size as usize)?;
+            guard(fs, opcode::IOCTL, || {
+                fs.bento_ioctl(req, ino, arg.fh, arg.flags, arg.cmd, arg.arg, in_data, arg.out_size,
+                               out.ioctl())
+            });
+        }
+        FUSE_POLL => {
+            let arg: fuse_poll_in = args.fetch()?;
+            guard(fs, opcode::POLL, || {
+                fs.bento_poll(req, ino, arg.fh, arg.kh, arg.events, arg.flags, out.poll())
+            });
+        }
+        FUSE_FALLOCATE => {
+            let arg: fuse_fallocate_in = args.fetch()?;
+            if read_only(fs) {
+                out.error(EROFS);
+                return Some(());
+            }
+            guard(fs, opcode::FALLOCATE, || {
+                fs.bento_fallocate(req, ino, arg.fh, arg.offset as i64, arg.length as i64,
+                                   arg.mode as i32, out.empty())
+            });
+        }
+        FUSE_LSEEK => {
+            let arg: fuse_lseek_in = args.fetch()?;
+            guard(fs, opcode::LSEEK, || {
+                fs.bento_lseek(req, ino, arg.fh, arg.offset as i64, arg.whence as i32, out.lseek())
+            });
+        }
+        FUSE_COPY_FILE_RANGE => {
+            let arg: fuse_copy_file_range_in = args.fetch()?;
+            if read_only(fs) {
+                out.error(EROFS);
+                return Some(());
+            }
+            guard(fs, opcode::COPY_FILE_RANGE, || {
+                fs.bento_copy_file_range(req, ino, arg.fh_in, arg.off_in as i64, arg.nodeid_out, arg.fh_out,
+                                         arg.off_out as i64, arg.len, arg.flags as u32, out.write())
+            });
+        }
+        _ => out.error(ENOSYS),
+    }
+    Some(())
+}
+
+// Change the mount options to `value`, as a setxattr of `options::REMOUNT_XATTR`.
+fn remount<'de, I, O, FS>(fs: &FS, req: &Request, out: &Out, value: &[u8])
+where
+    FS: BentoFilesystem<'de, I, O>,
+{
+    let opts = match MountOptions::parse(OsStr::from_bytes(value), fs.mount_options()) {
+        Ok(opts) => opts,
+        Err(e) => {
+            println!("bento: {}: remount failed: {}", fs.get_name(), e);
+            return out.error(e.errno());
+        }
+    };
+    match guard(fs, opcode::SETXATTR, || fs.bento_remount(req, &opts)) {
+        Some(Ok(())) => out.ok(),
+        Some(Err(x)) => out.error(x),
+        None => out.error(EIO),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{OsStr, OsString};
+    use std::fs::File;
+    use std::io::Read;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::{Arc, Mutex};
+
+    use libc::{EIO, ENODEV, ENOENT};
+
+    use time::Timespec;
+
+    use fuse::*;
+    use BentoFilesystem;
+
+    use super::super::abi::*;
+    use super::super::channel::Channel;
+    use super::{dispatch, notify};
+
+    const TTL: Timespec = Timespec { sec: 1, nsec: 500 };
+
+    fn file_attr(ino: u64, kind: FileType) -> FileAttr {
+        FileAttr {
+            ino: ino,
+            size: 42,
+            blocks: 1,
+            atime: Timespec::new(10, 1),
+            mtime: Timespec::new(20, 2),
+            ctime: Timespec::new(30, 3),
+            crtime: Timespec::new(0, 0),
+            kind: kind,
+            perm: 0o644,
+            nlink: 1,
+            uid: 1000,
+            gid: 100,
+            rdev: 0,
+            flags: 0,
+        }
+    }
+
+    // Records the arguments it was called with. Inode 1 holds "file", inode 2.
+    #[derive(Default)]
+    struct TestFs {
+        writes: Mutex<Vec<(u64, u64, i64, Vec<u8>, u32)>>,
+        renames: Mutex<Vec<(u64, OsString, u64, OsString, u32)>>,
+        forgets: Mutex<Vec<(u64, u64)>>,
+    }
+
+    impl<'de> BentoFilesystem<'de> for TestFs {
+        fn get_name(&self) -> &'static str {
+            "testfs"
+        }
+
+        fn new_instance(&self) -> Box<Self> {
+            Box::new(TestFs::default())
+        }
+
+        fn bento_lookup(&self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+            if parent == 1 && name == "file" {
+                reply.entry(&TTL, &file_attr(2, FileType::RegularFile), 7);
+            } else {
+                reply.error(ENOENT);
+            }
+        }
+
+        fn bento_forget(&self, _req: &Request, ino: u64, nlookup: u64) {
+            self.forgets.lock().unwrap().push((ino, nlookup));
+        }
+
+        fn bento_write(&self, _req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8],
+                       flags: u32, reply: ReplyWrite) {
+            self.writes.lock().unwrap().push((ino, fh, offset, data.to_vec(), flags));
+            reply.written(data.len() as u32);
+        }
+
+        fn bento_rename(&self, _req: &Request, parent: u64, name: &OsStr, newparent: u64,
+                        newname: &OsStr, flags: u32, reply: ReplyEmpty) {
+            self.renames.lock().unwrap().push((parent, name.to_os_string(), newparent,
+                                               newname.to_os_string(), flags));
+            reply.ok();
+        }
+
+        fn bento_readdirplus(&self, _req: &Request, _ino: u64, _fh: u64, offset: i64,
+                             mut reply: ReplyDirectoryPlus) {
+            if offset == 0 {
+                reply.add(1, 1, &file_attr(1, FileType::Directory), &TTL, ".");
+                reply.add(2, 2, &file_attr(2, FileType::RegularFile), &TTL, "file");
+            }
+            reply.ok();
+        }
+    }
+
+    // Send request `opcode` on inode `nodeid`, made of the pieces in `args`, and return the
+    // header and body of the reply.
+    fn request(fs: &TestFs, opcode: u32, nodeid: u64, args: &[&[u8]]) -> (fuse_out_header, Vec<u8>) {
+        let (chan, mut kernel) = Channel::pair().unwrap();
+        let chan = Arc::new(chan);
+        let data: Vec<u8> = args.concat();
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + data.len()) as u32,
+            opcode: opcode,
+            unique: 99,
+            nodeid: nodeid,
+            uid: 1000,
+            gid: 100,
+            pid: 1,
+            padding: 0,
+        };
+        dispatch(fs, &chan, &header, &data);
+        reply(&mut kernel)
+    }
+
+    // Whether a reply is waiting on the kernel's end.
+    fn replied(kernel: &File) -> bool {
+        let mut buf = [0u8; 1];
+        let ret = unsafe {
+            libc::recv(kernel.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(),
+                       libc::MSG_DONTWAIT | libc::MSG_PEEK)
+        };
+        ret >= 0
+    }
+
+    fn reply(kernel: &mut File) -> (fuse_out_header, Vec<u8>) {
+        let mut buf = vec![0; 4096];
+        let len = kernel.read(&mut buf).unwrap();
+        let mut args = ArgIter::new(&buf[..len]);
+        let header: fuse_out_header = args.fetch().unwrap();
+        assert_eq!(header.len as usize, len);
+        assert_eq!(header.unique, 99);
+        (header, args.fetch_all().to_vec())
+    }
+
+    #[test]
+    fn lookup_replies_with_an_entry() {
+        let fs = TestFs::default();
+        let (header, body) = request(&fs, FUSE_LOOKUP, 1, &[b"file\0"]);
+        assert_eq!(header.error, 0);
+        assert_eq!(body.len(), mem::size_of::<fuse_entry_out>());
+        let entry: fuse_entry_out = ArgIter::new(&body).fetch().unwrap();
+        assert_eq!((entry.nodeid, entry.generation), (2, 7));
+        assert_eq!((entry.entry_valid, entry.entry_valid_nsec), (1, 500));
+        assert_eq!((entry.attr_valid, entry.attr_valid_nsec), (1, 500));
+        assert_eq!((entry.attr.ino, entry.attr.size, entry.attr.blocks), (2, 42, 1));
+        assert_eq!((entry.attr.mtime, entry.attr.mtimensec), (20, 2));
+        assert_eq!(entry.attr.mode, libc::S_IFREG | 0o644);
+        assert_eq!((entry.attr.uid, entry.attr.gid, entry.attr.nlink), (1000, 100, 1));
+
+        let (header, body) = request(&fs, FUSE_LOOKUP, 1, &[b"missing\0"]);
+        assert_eq!(header.error, -ENOENT);
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn write_passes_the_data_and_replies_with_its_size() {
+        let fs = TestFs::default();
+        let arg = fuse_write_in {
+            fh: 5,
+            offset: 4096,
+            size: 5,
+            write_flags: 1,
+            ..Default::default()
+        };
+        let (header, body) = request(&fs, FUSE_WRITE, 2, &[as_bytes(&arg), b"hello"]);
+        assert_eq!(header.error, 0);
+        let out: fuse_write_out = ArgIter::new(&body).fetch().unwrap();
+        assert_eq!(out.size, 5);
+        assert_eq!(*fs.writes.lock().unwrap(), vec![(2, 5, 4096, b"hello".to_vec(), 1)]);
+    }
+
+    #[test]
+    fn rename2_passes_the_flags() {
+        let fs = TestFs::default();
+        let arg = fuse_rename2_in {
+            newdir: 3,
+            flags: libc::RENAME_NOREPLACE as u32,
+            padding: 0,
+        };
+        let (header, body) = request(&fs, FUSE_RENAME2, 1, &[as_bytes(&arg), b"old\0", b"new\0"]);
+        assert_eq!(header.error, 0);
+        assert!(body.is_empty());
+        let renames = fs.renames.lock().unwrap();
+        assert_eq!(*renames, vec![(1, OsString::from("old"), 3, OsString::from("new"),
+                                   libc::RENAME_NOREPLACE as u32)]);
+    }
+
+    #[test]
+    fn readdirplus_entries_are_padded_to_8_bytes() {
+        let fs = TestFs::default();
+        let arg = fuse_read_in {
+            fh: 1,
+            offset: 0,
+            size: 4096,
+            ..Default::default()
+        };
+        let (header, body) = request(&fs, FUSE_READDIRPLUS, 1, &[as_bytes(&arg)]);
+        assert_eq!(header.error, 0);
+        // 152 bytes of header each, then "." padded to 8 bytes and "file" padded to 8 bytes.
+        assert_eq!(body.len(), 2 * mem::size_of::<fuse_direntplus>() + 8 + 8);
+
+        let mut args = ArgIter::new(&body);
+        let mut names = Vec::new();
+        for &(ino, off, kind) in &[(1, 1, libc::S_IFDIR), (2, 2, libc::S_IFREG)] {
+            let plus: fuse_direntplus = args.fetch().unwrap();
+            assert_eq!((plus.entry_out.nodeid, plus.entry_out.attr.ino), (ino, ino));
+            assert_eq!(plus.entry_out.attr.mode & libc::S_IFMT, kind);
+            assert_eq!((plus.dirent.ino, plus.dirent.off, plus.dirent.typ), (ino, off, kind >> 12));
+            let name = args.fetch_bytes(plus.dirent.namelen as usize).unwrap();
+            names.push(OsStr::from_bytes(name).to_os_string());
+            let padding = (8 - plus.dirent.namelen as usize % 8) % 8;
+            assert!(args.fetch_bytes(padding).unwrap().iter().all(|x| *x == 0));
+        }
+        assert_eq!(names, vec![OsString::from("."), OsString::from("file")]);
+        assert!(args.fetch_all().is_empty());
+
+        // Nothing past the end of the directory.
+        let arg = fuse_read_in { offset: 2, ..arg };
+        let (header, body) = request(&fs, FUSE_READDIRPLUS, 1, &[as_bytes(&arg)]);
+        assert_eq!(header.error, 0);
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn truncated_requests_fail_with_eio() {
+        let fs = TestFs::default();
+        // A name without its NUL.
+        let (header, body) = request(&fs, FUSE_LOOKUP, 1, &[b"file"]);
+        assert_eq!(header.error, -EIO);
+        assert!(body.is_empty());
+
+        // Less data than the write says.
+        let arg = fuse_write_in {
+            size: 100,
+            ..Default::default()
+        };
+        let (header, _) = request(&fs, FUSE_WRITE, 2, &[as_bytes(&arg), b"short"]);
+        assert_eq!(header.error, -EIO);
+        assert!(fs.writes.lock().unwrap().is_empty());
+
+        // Half an argument structure, and a rename missing its new name.
+        let arg = fuse_rename2_in::default();
+        let (header, _) = request(&fs, FUSE_RENAME2, 1, &[&as_bytes(&arg)[..8]]);
+        assert_eq!(header.error, -EIO);
+        let (header, _) = request(&fs, FUSE_RENAME2, 1, &[as_bytes(&arg), b"old\0"]);
+        assert_eq!(header.error, -EIO);
+        assert!(fs.renames.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn forgets_get_no_reply() {
+        let fs = TestFs::default();
+        let (chan, kernel) = Channel::pair().unwrap();
+        let chan = Arc::new(chan);
+        let header = |opcode| fuse_in_header {
+            opcode: opcode,
+            unique: 99,
+            nodeid: 2,
+            ..Default::default()
+        };
+
+        let arg = fuse_forget_in { nlookup: 3 };
+        dispatch(&fs, &chan, &header(FUSE_FORGET), as_bytes(&arg));
+        // Too short to forget anything.
+        dispatch(&fs, &chan, &header(FUSE_FORGET), &as_bytes(&arg)[..4]);
+
+        // Says three inodes, but has two and a half.
+        let mut data = as_bytes(&fuse_batch_forget_in { count: 3, dummy: 0 }).to_vec();
+        data.extend_from_slice(as_bytes(&fuse_forget_one { nodeid: 4, nlookup: 1 }));
+        data.extend_from_slice(as_bytes(&fuse_forget_one { nodeid: 5, nlookup: 2 }));
+        data.extend_from_slice(&as_bytes(&fuse_forget_one { nodeid: 6, nlookup: 3 })[..8]);
+        dispatch(&fs, &chan, &header(FUSE_BATCH_FORGET), &data);
+        dispatch(&fs, &chan, &header(FUSE_BATCH_FORGET), &data[..4]);
+
+        assert!(!replied(&kernel));
+        assert_eq!(*fs.forgets.lock().unwrap(), vec![(2, 3), (4, 1), (5, 2)]);
+    }
+
+    #[test]
+    fn notifications_are_encoded_with_unique_0() {
+        let (chan, mut kernel) = Channel::pair().unwrap();
+        let chan = Arc::new(chan);
+        let weak = Arc::downgrade(&chan);
+        let mut buf = vec![0; 4096];
+
+        notify(&weak, Notification::InvalInode { ino: 2, offset: 4096, len: -1 }).unwrap();
+        let len = kernel.read(&mut buf).unwrap();
+        let mut args = ArgIter::new(&buf[..len]);
+        let header: fuse_out_header = args.fetch().unwrap();
+        assert_eq!((header.len as usize, header.error, header.unique), (len, FUSE_NOTIFY_INVAL_INODE, 0));
+        let out: fuse_notify_inval_inode_out = args.fetch().unwrap();
+        assert_eq!((out.ino, out.off, out.len), (2, 4096, -1));
+        assert!(args.fetch_all().is_empty());
+
+        notify(&weak, Notification::Delete { parent: 1, child: 2, name: OsStr::new("file") }).unwrap();
+        let len = kernel.read(&mut buf).unwrap();
+        let mut args = ArgIter::new(&buf[..len]);
+        let header: fuse_out_header = args.fetch().unwrap();
+        assert_eq!((header.error, header.unique), (FUSE_NOTIFY_DELETE, 0));
+        let out: fuse_notify_delete_out = args.fetch().unwrap();
+        assert_eq!((out.parent, out.child, out.namelen), (1, 2, 4));
+        assert_eq!(args.fetch_all(), b"file\0");
+
+        notify(&weak, Notification::Store { ino: 3, offset: 512, data: b"data" }).unwrap();
+        let len = kernel.read(&mut buf).unwrap();
+        let mut args = ArgIter::new(&buf[..len]);
+        let header: fuse_out_header = args.fetch().unwrap();
+        assert_eq!(header.error, FUSE_NOTIFY_STORE);
+        let out: fuse_notify_store_out = args.fetch().unwrap();
+        assert_eq!((out.nodeid, out.offset, out.size), (3, 512, 4));
+        assert_eq!(args.fetch_all(), b"data");
+
+        // The session drops its channel when it ends.
+        drop(chan);
+        assert_eq!(notify(&weak, Notification::InvalEntry { parent: 1, name: OsStr::new("file") }),
+                   Err(ENODEV));
+    }
+}
@@ -0,0 +1,304 @@
+//! The userspace side of the FUSE protocol.
+//!
+//! A userspace Bento file system is served by reading requests from `/dev/fuse`, calling the
+//! `bento_*` methods with the `bento_utils::fuse` types, and writing the encoded replies back,
+//! as the kernel's `bento::fuse` dispatch does for a file system loaded into the kernel. The
+//! session speaks protocol version 7.31, so rename flags, readdirplus, lseek, the writeback
+//! cache and larger requests (`max_pages`) work in userspace too.
+//!
+//! `options::mount` serves requests on the thread that calls it. `session::mount` serves them
+//! from a pool of threads: after the init request, each worker thread reads requests from its
+//! own clone of the `/dev/fuse` file descriptor (`FUSE_DEV_IOC_CLONE`), calls the file system
+//! through `&self` and sends the reply from the thread that handled the request, so requests
+//! run in parallel as they do in the kernel. Only a destroy request, which needs `&mut self`,
+//! waits for the others to finish.
+//!
+//! Root mounts with mount(2); other users need fusermount.
+
+mod abi;
+mod channel;
+mod dispatch;
+
+use std::cmp;
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use errors::{self, opcode, ErrorState};
+use fuse::{Notifier, Request};
+use options::{self, MountOptions};
+use BentoFilesystem;
+use FuseConnInfo;
+
+use self::abi::*;
+use self::channel::{Channel, Mount};
+use self::dispatch::{dispatch, notify, report_errors};
+
+/// Largest write request the session accepts.
+pub const MAX_WRITE: u32 = 1024 * 1024;
+
+// Room for a write request and its headers. The kernel won't deliver requests into a buffer
+// smaller than this.
+const BUFFER_SIZE: usize = MAX_WRITE as usize + 4096;
+
+// Init flags a file system can ask for. `FUSE_DONT_MASK` is left out because requests don't
+// pass the umask on to the file system, and the splice flags only mean something to libfuse.
+const SUPPORTED_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_POSIX_LOCKS | FUSE_ATOMIC_O_TRUNC
+    | FUSE_EXPORT_SUPPORT | FUSE_BIG_WRITES | FUSE_FLOCK_LOCKS | FUSE_HAS_IOCTL_DIR
+    | FUSE_AUTO_INVAL_DATA | FUSE_DO_READDIRPLUS | FUSE_READDIRPLUS_AUTO | FUSE_ASYNC_DIO
+    | FUSE_WRITEBACK_CACHE | FUSE_NO_OPEN_SUPPORT | FUSE_PARALLEL_DIROPS | FUSE_HANDLE_KILLPRIV
+    | FUSE_POSIX_ACL | FUSE_ABORT_ERROR | FUSE_CACHE_SYMLINKS | FUSE_NO_OPENDIR_SUPPORT
+    | FUSE_EXPLICIT_INVAL_DATA;
+
+/// Mount a file system and serve it with `threads` worker threads until it is unmounted.
+///
+/// This takes the same arguments as `options::mount`, plus the number of threads. With
+/// `threads` 0, there is one thread per CPU.
+///
+/// Arguments:
+/// * `fs: FS` - The file system.
+/// * `mountpoint: &P` - Where to mount it.
+/// * `options: &[&OsStr]` - Arguments such as `-o fsname=disk,commit=10`.
+/// * `threads: usize` - Number of worker threads.
+pub fn mount<'de, I, O, FS, P>(fs: FS, mountpoint: &P, options: &[&OsStr], threads: usize) -> io::Result<()>
+where
+    FS: BentoFilesystem<'de, I, O> + Send + Sync,
+    P: AsRef<Path> + ?Sized,
+{
+    let (session, chan) = Session::start(fs, mountpoint.as_ref(), options)?;
+    let threads = match threads {
+        0 => thread::available_parallelism().map(|x| x.get()).unwrap_or(1),
+        x => x,
+    };
+    let ret = thread::scope(|scope| {
+        let session = &session;
+        let workers = (1..threads)
+            .map(|_| {
+                // Without clone support, threads share one queue.
+                let chan = chan.clone_fd().map(Arc::new).unwrap_or_else(|_| Arc::clone(&chan));
+                scope.spawn(move || session.run(chan))
+            })
+            .collect::<Vec<_>>();
+        let mut ret = session.run(Arc::clone(&chan));
+        for worker in workers {
+            // Panics in the file system are caught, so this is a panic in the session itself.
+            let worker_ret = worker.join().unwrap_or_else(|_| {
+                Err(io::Error::new(io::ErrorKind::Other, "session worker thread panicked"))
+            });
+            if ret.is_ok() {
+                ret = worker_ret;
+            }
+        }
+        ret
+    });
+    session.finish(ret)
+}
+
+// Mount a file system and serve it on this thread until it is unmounted.
+pub(crate) fn mount_local<'de, I, O, FS>(fs: FS, mountpoint: &Path, options: &[&OsStr]) -> io::Result<()>
+where
+    FS: BentoFilesystem<'de, I, O>,
+{
+    let (session, chan) = Session::start(fs, mountpoint, options)?;
+    let ret = session.run(chan);
+    session.finish(ret)
+}
+
+// Handle the init request, which comes before any other.
+fn init<'de, I, O, FS>(fs: &mut FS, chan: &Arc<Channel>, opts: &MountOptions, devname: &OsStr) -> io::Result<()>
+where
+    FS: BentoFilesystem<'de, I, O>,
+{
+    let mut buf = vec![0; BUFFER_SIZE];
+    loop {
+        let len = match chan.receive(&mut buf) {
+            Ok(len) => len,
+            Err(ref e) if retry(e) => continue,
+            Err(e) => return Err(e),
+        };
+        let mut args = ArgIter::new(&buf[..len]);
+        let header: fuse_in_header = match args.fetch() {
+            Some(header) => header,
+            None => continue,
+        };
+        if header.opcode != FUSE_INIT {
+            let _ = chan.reply(header.unique, Err(libc::EIO));
+            continue;
+        }
+        // Newer kernels send a longer fuse_init_in, but only its start is needed.
+        let arg: fuse_init_in = match args.fetch() {
+            Some(arg) => arg,
+            None => {
+                let _ = chan.reply(header.unique, Err(libc::EIO));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "short init request"));
+            }
+        };
+        if arg.major != FUSE_KERNEL_VERSION || arg.minor < FUSE_MIN_MINOR_VERSION {
+            let _ = chan.reply(header.unique, Err(libc::EPROTO));
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      format!("unsupported FUSE protocol {}.{}", arg.major, arg.minor)));
+        }
+
+        let mut fc_info = FuseConnInfo::new();
+        fc_info.proto_major = arg.major;
+        fc_info.proto_minor = arg.minor;
+        fc_info.max_readahead = arg.max_readahead;
+        fc_info.capable = arg.flags;
+        let weak = Arc::downgrade(chan);
+        fc_info.notifier = Notifier::new(move |notification| notify(&weak, notification));
+        let req = Request::new(header.unique, header.uid, header.gid, header.pid);
+        let ret = errors::guard(opcode::INIT, || fs.bento_init(&req, devname, opts, &mut fc_info));
+        report_errors(&*fs, opcode::INIT);
+        if let Err(err) = ret.unwrap_or(Err(libc::EIO)) {
+            let _ = chan.reply(header.unique, Err(err));
+            return Err(io::Error::from_raw_os_error(err));
+        }
+
+        let max_write = match fc_info.max_write {
+            0 => MAX_WRITE,
+            x => x.clamp(4096, MAX_WRITE),
+        };
+        let mut flags = fc_info.want & arg.flags & SUPPORTED_FLAGS;
+        // Without this, the kernel splits requests at 128 KiB whatever max_write is.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+        let max_pages = if arg.flags & FUSE_MAX_PAGES != 0 {
+            flags |= FUSE_MAX_PAGES;
+            max_write.div_ceil(page_size) as u16
+        } else {
+            0
+        };
+        let out = fuse_init_out {
+            major: FUSE_KERNEL_VERSION,
+            minor: cmp::min(arg.minor, FUSE_KERNEL_MINOR_VERSION),
+            max_readahead: cmp::min(fc_info.max_readahead, arg.max_readahead),
+            flags: flags,
+            max_background: fc_info.max_background as u16,
+            congestion_threshold: fc_info.congestion_threshold as u16,
+            max_write: max_write,
+            time_gran: fc_info.time_gran,
+            max_pages: max_pages,
+            ..Default::default()
+        };
+        let out = as_bytes(&out);
+        let out = if arg.minor < 23 { &out[..FUSE_COMPAT_22_INIT_OUT_SIZE] } else { out };
+        return chan.reply(header.unique, Ok(&[out]));
+    }
+}
+
+fn destroy<'de, I, O, FS>(fs: &mut FS, req: &Request)
+where
+    FS: BentoFilesystem<'de, I, O>,
+{
+    errors::guard(opcode::DESTROY, || fs.bento_destroy(req));
+    report_errors(&*fs, opcode::DESTROY);
+}
+
+// Whether a failed read of a request should be tried again: it was interrupted, or the request
+// was aborted before it could be read.
+fn retry(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EINTR) | Some(libc::EAGAIN) | Some(libc::ENOENT))
+}
+
+// State shared by the worker threads of a mount.
+struct Session<FS> {
+    fs: RwLock<FS>,
+    mount: Mount,
+    errors: Arc<ErrorState>,
+    // Whether the file system has handled a destroy request.
+    destroyed: AtomicBool,
+}
+
+impl<FS> Session<FS> {
+    // Mount the file system and handle its init request.
+    fn start<'de, I, O>(mut fs: FS, mountpoint: &Path, options: &[&OsStr]) -> io::Result<(Session<FS>, Arc<Channel>)>
+    where
+        FS: BentoFilesystem<'de, I, O>,
+    {
+        let (fuse_args, opts) = options::split_args(&fs, options)?;
+        let mut fuse_options: Vec<&[u8]> = Vec::new();
+        let mut args = fuse_args.iter();
+        while let Some(arg) = args.next() {
+            match (arg.as_bytes(), args.next()) {
+                (b"-o", Some(list)) => fuse_options.extend(list.as_bytes().split(|x| *x == b',')),
+                _ => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              format!("unsupported argument \"{}\"", arg.to_string_lossy())));
+                }
+            }
+        }
+        // `bento_init` gets the fsname option, usually the disk, as the device name.
+        let devname = fuse_options
+            .iter()
+            .rev()
+            .find(|x| x.starts_with(b"fsname="))
+            .map(|x| OsStr::from_bytes(&x[b"fsname=".len()..]))
+            .unwrap_or_else(|| OsStr::new(""));
+
+        let (chan, mount) = Mount::new(mountpoint, &fuse_options, fs.get_name())?;
+        let chan = Arc::new(chan);
+        let error_state = Arc::new(ErrorState::new());
+        let _errors = errors::enter(&error_state);
+        init(&mut fs, &chan, &opts, devname)?;
+        let session = Session {
+            fs: RwLock::new(fs),
+            mount: mount,
+            errors: Arc::clone(&error_state),
+            destroyed: AtomicBool::new(false),
+        };
+        Ok((session, chan))
+    }
+
+    // Destroy the file system if the kernel didn't, and unmount it.
+    fn finish<'de, I, O>(self, ret: io::Result<()>) -> io::Result<()>
+    where
+        FS: BentoFilesystem<'de, I, O>,
+    {
+        let _errors = errors::enter(&self.errors);
+        let mut fs = self.fs.into_inner().unwrap_or_else(|e| e.into_inner());
+        if !self.destroyed.load(Ordering::SeqCst) {
+            destroy(&mut fs, &Request::new(0, 0, 0, 0));
+        }
+        self.mount.unmount();
+        ret
+    }
+
+    // Serve requests from `chan` until the file system is unmounted.
+    fn run<'de, I, O>(&self, chan: Arc<Channel>) -> io::Result<()>
+    where
+        FS: BentoFilesystem<'de, I, O>,
+    {
+        let _errors = errors::enter(&self.errors);
+        let mut buf = vec![0; BUFFER_SIZE];
+        loop {
+            let len = match chan.receive(&mut buf) {
+                Ok(len) => len,
+                Err(ref e) if retry(e) => continue,
+                Err(ref e) if e.raw_os_error() == Some(libc::ENODEV) => return Ok(()),
+                Err(e) => {
+                    // Stop the other threads too.
+                    self.mount.unmount();
+                    return Err(e);
+                }
+            };
+            let mut args = ArgIter::new(&buf[..len]);
+            let header: fuse_in_header = match args.fetch() {
+                Some(header) => header,
+                None => continue,
+            };
+            if header.opcode == FUSE_DESTROY {
+                let mut fs = self.fs.write().unwrap_or_else(|e| e.into_inner());
+                let req = Request::new(header.unique, header.uid, header.gid, header.pid);
+                destroy(&mut *fs, &req);
+                self.destroyed.store(true, Ordering::SeqCst);
+                let _ = chan.reply(header.unique, Ok(&[]));
+            } else {
+                let fs = self.fs.read().unwrap_or_else(|e| e.into_inner());
+                dispatch(&*fs, &chan, &header, args.fetch_all());
+            }
+        }
+    }
+}
@@ -0,0 +1,828 @@
+//! Driving a `BentoFilesystem` in-process, without mounting it.
+//!
+//! A `Harness` owns a file system and calls its `bento_*` methods the way `session`
+//! does for requests from the kernel: panics are caught, the errors state is honoured, and
+//! requests that could modify a read-only file system fail with `EROFS`. Each reply is captured
+//! and returned as a value, so a test can check for `Ok(entry)` or `Err(libc::ENOENT)` without
+//! a FUSE mount, root or a block device. File systems that use `Disk` can be given one created
+//! with `Disk::new_ram` or `Disk::from_image` before the harness is built.
+//!
+//! Besides one method per request, the harness has a small POSIX-like layer (`open_path`,
+//! `read_fd`, `write_fd`, `readdir_path`, `rename_path`, `mkdir_path` and friends) that resolves
+//! paths by looking up each component from the root directory, so tests can be written in terms
+//! of paths and file descriptors.
+//!
+//! Each harness has its own errors state, as each mount does, so tests that put a file system
+//! in the errors state can run in parallel with other tests.
+//!
+//! The notifier a file system gets in `bento_init` records its notifications instead of
+//! sending them to a kernel. `take_notifications` returns them, and after `destroy` every
+//! notification fails with `ENODEV`, as it does once a mount is gone.
+
+use fuse::*;
+
+use errors::{self, opcode, ErrorState, ErrorStatus};
+use options::MountOptions;
+use BentoFilesystem;
+use FuseConnInfo;
+
+use libc::{c_int, EEXIST, EIO, ENODEV, ENOENT, ENOTDIR, EROFS};
+
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use time::Timespec;
+
+/// Inode number of the root directory.
+pub const ROOT_ID: u64 = 1;
+
+/// Size of the buffer given to readdir requests, one page as in the kernel.
+pub const READDIR_SIZE: usize = 4096;
+
+/// Result of a getxattr or listxattr request, with the value copied out of the reply.
+#[derive(Clone, Debug, PartialEq)]
+pub enum XattrReply {
+    /// Size of the value, when the request asked for size 0.
+    Size(u32),
+    /// The value.
+    Data(Vec<u8>),
+}
+
+/// A notification sent by the file system, with its name or data copied out.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SentNotification {
+    InvalInode { ino: u64, offset: i64, len: i64 },
+    InvalEntry { parent: u64, name: OsString },
+    Delete { parent: u64, child: u64, name: OsString },
+    Store { ino: u64, offset: u64, data: Vec<u8> },
+}
+
+impl<'a> From<Notification<'a>> for SentNotification {
+    fn from(notification: Notification<'a>) -> Self {
+        match notification {
+            Notification::InvalInode { ino, offset, len } => SentNotification::InvalInode {
+                ino: ino,
+                offset: offset,
+                len: len,
+            },
+            Notification::InvalEntry { parent, name } => SentNotification::InvalEntry {
+                parent: parent,
+                name: name.to_os_string(),
+            },
+            Notification::Delete { parent, child, name } => SentNotification::Delete {
+                parent: parent,
+                child: child,
+                name: name.to_os_string(),
+            },
+            Notification::Store { ino, offset, data } => SentNotification::Store {
+                ino: ino,
+                offset: offset,
+                data: data.to_vec(),
+            },
+        }
+    }
+}
+
+/// Attributes to change with `Harness::setattr`. Fields left as `None` are unchanged.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SetAttr {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub atime: Option<Timespec>,
+    pub mtime: Option<Timespec>,
+    pub fh: Option<u64>,
+}
+
+// Where a reply's callback leaves the result.
+struct Slot<T> {
+    result: Arc<Mutex<Option<Result<T, c_int>>>>,
+}
+
+impl<T: Send + 'static> Slot<T> {
+    fn new() -> Self {
+        Slot { result: Arc::new(Mutex::new(None)) }
+    }
+
+    fn callback(&self) -> impl FnOnce(Result<T, c_int>) + Send + 'static {
+        let result = Arc::clone(&self.result);
+        move |x| *result.lock().unwrap() = Some(x)
+    }
+
+    // A reply that is never sent is dropped, which sends `EIO`. A reply still held by the file
+    // system after the request returns gets the same.
+    fn take(self) -> Result<T, c_int> {
+        self.result.lock().unwrap().take().unwrap_or(Err(EIO))
+    }
+}
+
+// A file opened through the POSIX-like layer.
+struct OpenFile {
+    ino: u64,
+    fh: u64,
+    pos: u64,
+    append: bool,
+}
+
+/// Runs requests against a file system in-process.
+pub struct Harness<'de, I, O, FS> {
+    fs: FS,
+    uid: u32,
+    gid: u32,
+    pid: u32,
+    unique: u64,
+    files: BTreeMap<u64, OpenFile>,
+    next_fd: u64,
+    errors: Arc<ErrorState>,
+    notifications: Arc<Mutex<Vec<SentNotification>>>,
+    // Whether the notifier from the last `init` still has a mount to send to.
+    mounted: Arc<AtomicBool>,
+    transfer: PhantomData<(&'de (), I, O)>,
+}
+
+impl<'de, I, O, FS> Harness<'de, I, O, FS>
+where
+    FS: BentoFilesystem<'de, I, O>,
+{
+    /// Wrap a file system. Requests are sent as root, from pid 1, until changed with
+    /// `set_user` and `set_pid`.
+    pub fn new(fs: FS) -> Self {
+        Harness {
+            fs: fs,
+            uid: 0,
+            gid: 0,
+            pid: 1,
+            unique: 0,
+            files: BTreeMap::new(),
+            next_fd: 3,
+            errors: Arc::new(ErrorState::new()),
+            notifications: Arc::new(Mutex::new(Vec::new())),
+            mounted: Arc::new(AtomicBool::new(false)),
+            transfer: PhantomData,
+        }
+    }
+
+    /// The file system.
+    pub fn fs(&self) -> &FS {
+        &self.fs
+    }
+
+    /// The file system, mutably.
+    pub fn fs_mut(&mut self) -> &mut FS {
+        &mut self.fs
+    }
+
+    /// Take the file system back.
+    pub fn into_inner(self) -> FS {
+        self.fs
+    }
+
+    /// The errors state of the file system, or `None` if it hasn't hit an error.
+    pub fn error_status(&self) -> Option<ErrorStatus> {
+        self.errors.status()
+    }
+
+    /// Take the notifications the file system has sent since the last call, oldest first.
+    pub fn take_notifications(&mut self) -> Vec<SentNotification> {
+        let mut notifications = self.notifications.lock().unwrap();
+        notifications.drain(..).collect()
+    }
+
+    /// Send the following requests as user `uid` and group `gid`.
+    pub fn set_user(&mut self, uid: u32, gid: u32) {
+        self.uid = uid;
+        self.gid = gid;
+    }
+
+    /// Send the following requests from process `pid`.
+    pub fn set_pid(&mut self, pid: u32) {
+        self.pid = pid;
+    }
+
+    /// Create the request for the next call, with the current uid, gid and pid.
+    pub fn request(&mut self) -> Request {
+        self.unique += 1;
+        Request::new(self.unique, self.uid, self.gid, self.pid)
+    }
+
+    // Run request `opcode` like `session` does: catch a panic, then tell the file
+    // system if it entered the errors state.
+    fn run<R, F: FnOnce(&mut FS, &Request) -> R>(&mut self, opcode: u32, f: F) -> Option<R> {
+        let req = self.request();
+        let _errors = errors::enter(&self.errors);
+        let fs = &mut self.fs;
+        let ret = errors::guard(opcode, || f(fs, &req));
+        if errors::take_pending() {
+            if let Some(status) = errors::status() {
+                errors::guard(opcode, || fs.bento_error(&status));
+                errors::take_pending();
+            }
+        }
+        ret
+    }
+
+    fn check_writable(&self) -> Result<(), c_int> {
+        if self.errors.read_only() || self.fs.read_only() {
+            return Err(EROFS);
+        }
+        Ok(())
+    }
+
+    /// Initialize the file system, as when it is mounted.
+    ///
+    /// Arguments:
+    /// * `options: &str` - Mount options, such as `commit=10`, checked against
+    /// `mount_options`.
+    pub fn init(&mut self, options: &str) -> Result<(), c_int> {
+        let opts = MountOptions::parse(OsStr::new(options), self.fs.mount_options())
+            .map_err(|e| e.errno())?;
+        let mut fc_info = FuseConnInfo::new();
+        // Notifiers from an earlier mount stay unmounted.
+        self.mounted = Arc::new(AtomicBool::new(true));
+        let mounted = Arc::clone(&self.mounted);
+        let notifications = Arc::clone(&self.notifications);
+        fc_info.notifier = Notifier::new(move |notification| {
+            if !mounted.load(Ordering::SeqCst) {
+                return Err(ENODEV);
+            }
+            notifications.lock().unwrap().push(SentNotification::from(notification));
+            Ok(())
+        });
+        self.run(opcode::INIT, |fs, req| fs.bento_init(req, OsStr::new(""), &opts, &mut fc_info))
+            .unwrap_or(Err(EIO))
+    }
+
+    /// Change the mount options, as `mount -o remount` does.
+    pub fn remount(&mut self, options: &str) -> Result<(), c_int> {
+        let opts = MountOptions::parse(OsStr::new(options), self.fs.mount_options())
+            .map_err(|e| e.errno())?;
+        self.run(opcode::SETXATTR, |fs, req| fs.bento_remount(req, &opts))
+            .unwrap_or(Err(EIO))
+    }
+
+    /// Clean up the file system, as when it is unmounted.
+    pub fn destroy(&mut self) {
+        self.run(opcode::DESTROY, |fs, req| fs.bento_destroy(req));
+        self.mounted.store(false, Ordering::SeqCst);
+    }
+
+    pub fn lookup<N: AsRef<OsStr>>(&mut self, parent: u64, name: N) -> Result<Entry, c_int> {
+        let slot = Slot::new();
+        let reply = ReplyEntry::new(slot.callback());
+        self.run(opcode::LOOKUP, |fs, req| fs.bento_lookup(req, parent, name.as_ref(), reply));
+        slot.take()
+    }
+
+    pub fn forget(&mut self, ino: u64, nlookup: u64) {
+        self.run(opcode::FORGET, |fs, req| fs.bento_forget(req, ino, nlookup));
+    }
+
+    pub fn getattr(&mut self, ino: u64) -> Result<Attr, c_int> {
+        let slot = Slot::new();
+        let reply = ReplyAttr::new(slot.callback());
+        self.run(opcode::GETATTR, |fs, req| fs.bento_getattr(req, ino, reply));
+        slot.take()
+    }
+
+    pub fn setattr(&mut self, ino: u64, attr: SetAttr) -> Result<Attr, c_int> {
+        self.check_writable()?;
+        let slot = Slot::new();
+        let reply = ReplyAttr::new(slot.callback());
+        self.run(opcode::SETATTR, |fs, req| {
+            fs.bento_setattr(req, ino, attr.mode, attr.uid, attr.gid, attr.size, attr.atime,
+                             attr.mtime, attr.fh, None, None, None, None, reply)
+        });
+        slot.take()
+    }
+
+    pub fn readlink(&mut self, ino: u64) -> Result<Vec<u8>, c_int> {
+        let slot = Slot::new();
+        let callback = slot.callback();
+        let reply = ReplyData::new(move |x: Result<&[u8], c_int>| callback(x.map(|data| data.to_vec())));
+        self.run(opcode::READLINK, |fs, req| fs.bento_readlink(req, ino, reply));
+        slot.take()
+    }
+
+    pub fn mknod<N: AsRef<OsStr>>(&mut self, parent: u64, name: N, mode: u32, rdev: u32) -> Result<Entry, c_int> {
+        self.check_writable()?;
+        let slot = Slot::new();
+        let reply = ReplyEntry::new(slot.callback());
+        self.run(opcode::MKNOD, |fs, req| fs.bento_mknod(req, parent, name.as_ref(), mode, rdev, reply));
+        slot.take()
+    }
+
+    pub fn mkdir<N: AsRef<OsStr>>(&mut self, parent: u64, name: N, mode: u32) -> Result<Entry, c_int> {
+        self.check_writable()?;
+        let slot = Slot::new();
+        let reply = ReplyEntry::new(slot.callback());
+        self.run(opcode::MKDIR, |fs, req| fs.bento_mkdir(req, parent, name.as_ref(), mode, reply));
+        slot.take()
+    }
+
+    pub fn unlink<N: AsRef<OsStr>>(&mut self, parent: u64, name: N) -> Result<(), c_int> {
+        self.check_writable()?;
+        let slot = Slot::new();
+        let reply = ReplyEmpty::new(slot.callback());
+        self.run(opcode::UNLINK, |fs, req| fs.bento_unlink(req, parent, name.as_ref(), reply));
+        slot.take()
+    }
+
+    pub fn rmdir<N: AsRef<OsStr>>(&mut self, parent: u64, name: N) -> Result<(), c_int> {
+        self.check_writable()?;
+        let slot = Slot::new();
+        let reply = ReplyEmpty::new(slot.callback());
+        self.run(opcode::RMDIR, |fs, req| fs.bento_rmdir(req, parent, name.as_ref(), reply));
+        slot.take()
+    }
+
+    pub fn symlink<N: AsRef<OsStr>, P: AsRef<Path>>(&mut self, parent: u64, name: N, link: P) -> Result<Entry, c_int> {
+        self.check_writable()?;
+        let slot = Slot::new();
+        let reply = ReplyEntry::new(slot.callback());
+        self.run(opcode::SYMLINK, |fs, req| fs.bento_symlink(req, parent, name.as_ref(), link.as_ref(), reply));
+        slot.take()
+    }
+
+    pub fn rename<N: AsRef<OsStr>, M: AsRef<OsStr>>(
+        &mut self,
+        parent: u64,
+        name: N,
+        newparent: u64,
+        newname: M,
+        flags: u32,
+    ) -> Result<(), c_int> {
+        self.check_writable()?;
+        let slot = Slot::new();
+        let reply = ReplyEmpty::new(slot.callback());
+        self.run(opcode::RENAME, |fs, req| {
+            fs.bento_rename(req, parent, name.as_ref(), newparent, newname.as_ref(), flags, reply)
+        });
+        slot.take()
+    }
+
+    pub fn link<N: AsRef<OsStr>>(&mut self, ino: u64, newparent: u64, newname: N) -> Result<Entry, c_int> {
+        self.check_writable()?;
+        let slot = Slot::new();
+        let reply = ReplyEntry::new(slot.callback());
+        self.run(opcode::LINK, |fs, req| fs.bento_link(req, ino, newparent, newname.as_ref(), reply));
+        slot.take()
+    }
+
+    pub fn open(&mut self, ino: u64, flags: u32) -> Result<Open, c_int> {
+        if errors::open_modifies(flags) {
+            self.check_writable()?;
+        }
+        let slot = Slot::new();
+        let reply = ReplyOpen::new(slot.callback());
+        self.run(opcode::OPEN, |fs, req| fs.bento_open(req, ino, flags, reply));
+        slot.take()
+    }
+
+    pub fn read(&mut self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Vec<u8>, c_int> {
+        let slot = Slot::new();
+        let callback = slot.callback();
+        let reply = ReplyData::new(move |x: Result<&[u8], c_int>| callback(x.map(|data| data.to_vec())));
+        self.run(opcode::READ, |fs, req| fs.bento_read(req, ino, fh, offset, size, reply));
+        slot.take()
+    }
+
+    pub fn write(&mut self, ino: u64, fh: u64, offset: i64, data: &[u8], flags: u32) -> Result<u32, c_int> {
+        self.check_writable()?;
+        let slot = Slot::new();
+        let reply = ReplyWrite::new(slot.callback());
+        self.run(opcode::WRITE, |fs, req| fs.bento_write(req, ino, fh, offset, data, flags, reply));
+        slot.take()
+    }
+
+    pub fn copy_file_range(
+        &mut self,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+    ) -> Result<u32, c_int> {
+        self.check_writable()?;
+        let slot = Slot::new();
+        let reply = ReplyWrite::new(slot.callback());
+        self.run(opcode::COPY_FILE_RANGE, |fs, req| {
+            fs.bento_copy_file_range(req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, 0, reply)
+        });
+        slot.take()
+    }
+
+    pub fn flush(&mut self, ino: u64, fh: u64, lock_owner: u64) -> Result<(), c_int> {
+        let slot = Slot::new();
+        let reply = ReplyEmpty::new(slot.callback());
+        self.run(opcode::FLUSH, |fs, req| fs.bento_flush(req, ino, fh, lock_owner, reply));
+        slot.take()
+    }
+
+    pub fn release(&mut self, ino: u64, fh: u64, flags: u32) -> Result<(), c_int> {
+        let slot = Slot::new();
+        let reply = ReplyEmpty::new(slot.callback());
+        self.run(opcode::RELEASE, |fs, req| fs.bento_release(req, ino, fh, flags, 0, false, reply));
+        slot.take()
+    }
+
+    pub fn fsync(&mut self, ino: u64, fh: u64, datasync: bool) -> Result<(), c_int> {
+        let slot = Slot::new();
+        let reply = ReplyEmpty::new(slot.callback());
+        self.run(opcode::FSYNC, |fs, req| fs.bento_fsync(req, ino, fh, datasync, reply));
+        slot.take()
+    }
+
+    pub fn opendir(&mut self, ino: u64, flags: u32) -> Result<Open, c_int> {
+        let slot = Slot::new();
+        let reply = ReplyOpen::new(slot.callback());
+        self.run(opcode::OPENDIR, |fs, req| fs.bento_opendir(req, ino, flags, reply));
+        slot.take()
+    }
+
+    /// Read one buffer of `READDIR_SIZE` bytes of directory entries.
+    pub fn readdir(&mut self, ino: u64, fh: u64, offset: i64) -> Result<Vec<DirEntry>, c_int> {
+        let slot = Slot::new();
+        let reply = ReplyDirectory::new(READDIR_SIZE, slot.callback());
+        self.run(opcode::READDIR, |fs, req| fs.bento_readdir(req, ino, fh, offset, reply));
+        slot.take()
+    }
+
+    /// Read one buffer of `READDIR_SIZE` bytes of directory entries and their attributes.
+    pub fn readdirplus(&mut self, ino: u64, fh: u64, offset: i64) -> Result<Vec<DirEntryPlus>, c_int> {
+        let slot = Slot::new();
+        let reply = ReplyDirectoryPlus::new(READDIR_SIZE, slot.callback());
+        self.run(opcode::READDIR, |fs, req| fs.bento_readdirplus(req, ino, fh, offset, reply));
+        slot.take()
+    }
+
+    pub fn releasedir(&mut self, ino: u64, fh: u64, flags: u32) -> Result<(), c_int> {
+        let slot = Slot::new();
+        let reply = ReplyEmpty::new(slot.callback());
+        self.run(opcode::RELEASEDIR, |fs, req| fs.bento_releasedir(req, ino, fh, flags, reply));
+        slot.take()
+    }
+
+    pub fn fsyncdir(&mut self, ino: u64, fh: u64, datasync: bool) -> Result<(), c_int> {
+        let slot = Slot::new();
+        let reply = ReplyEmpty::new(slot.callback());
+        self.run(opcode::FSYNCDIR, |fs, req| fs.bento_fsyncdir(req, ino, fh, datasync, reply));
+        slot.take()
+    }
+
+    pub fn statfs(&mut self, ino: u64) -> Result<Statfs, c_int> {
+        let slot = Slot::new();
+        let reply = ReplyStatfs::new(slot.callback());
+        self.run(opcode::STATFS, |fs, req| fs.bento_statfs(req, ino, reply));
+        slot.take()
+    }
+
+    pub fn setxattr<N: AsRef<OsStr>>(&mut self, ino: u64, name: N, value: &[u8], flags: u32) -> Result<(), c_int> {
+        self.check_writable()?;
+        let slot = Slot::new();
+        let reply = ReplyEmpty::new(slot.callback());
+        self.run(opcode::SETXATTR, |fs, req| fs.bento_setxattr(req, ino, name.as_ref(), value, flags, 0, reply));
+        slot.take()
+    }
+
+    /// Get an extended attribute. `errors::STATUS_XATTR` is answered without calling the file
+    /// system, as `session` does.
+    pub fn getxattr<N: AsRef<OsStr>>(&mut self, ino: u64, name: N, size: u32) -> Result<XattrReply, c_int> {
+        if name.as_ref() == errors::STATUS_XATTR {
+            let value = {
+                let _errors = errors::enter(&self.errors);
+                errors::status_value()
+            };
+            if size == 0 {
+                return Ok(XattrReply::Size(value.len() as u32));
+            } else if (size as usize) < value.len() {
+                return Err(libc::ERANGE);
+            }
+            return Ok(XattrReply::Data(value.into_bytes()));
+        }
+        let slot = Slot::new();
+        let reply = ReplyXattr::new(xattr_callback(&slot));
+        self.run(opcode::GETXATTR, |fs, req| fs.bento_getxattr(req, ino, name.as_ref(), size, reply));
+        slot.take()
+    }
+
+    pub fn listxattr(&mut self, ino: u64, size: u32) -> Result<XattrReply, c_int> {
+        let slot = Slot::new();
+        let reply = ReplyXattr::new(xattr_callback(&slot));
+        self.run(opcode::LISTXATTR, |fs, req| fs.bento_listxattr(req, ino, size, reply));
+        slot.take()
+    }
+
+    pub fn removexattr<N: AsRef<OsStr>>(&mut self, ino: u64, name: N) -> Result<(), c_int> {
+        self.check_writable()?;
+        let slot = Slot::new();
+        let reply = ReplyEmpty::new(slot.callback());
+        self.run(opcode::REMOVEXATTR, |fs, req| fs.bento_removexattr(req, ino, name.as_ref(), reply));
+        slot.take()
+    }
+
+    pub fn access(&mut self, ino: u64, mask: u32) -> Result<(), c_int> {
+        let slot = Slot::new();
+        let reply = ReplyEmpty::new(slot.callback());
+        self.run(opcode::ACCESS, |fs, req| fs.bento_access(req, ino, mask, reply));
+        slot.take()
+    }
+
+    pub fn create<N: AsRef<OsStr>>(&mut self, parent: u64, name: N, mode: u32, flags: u32) -> Result<Created, c_int> {
+        self.check_writable()?;
+        let slot = Slot::new();
+        let reply = ReplyCreate::new(slot.callback());
+        self.run(opcode::CREATE, |fs, req| fs.bento_create(req, parent, name.as_ref(), mode, flags, reply));
+        slot.take()
+    }
+
+    // POSIX-like layer. Paths are relative to the root directory; "/" and "" name the root.
+
+    // Look up `path`. The caller owns the lookup reference on the result, unless it's the root.
+    fn lookup_path(&mut self, path: &str) -> Result<Entry, c_int> {
+        let mut entry = match self.getattr(ROOT_ID) {
+            Ok(attr) => Entry { ttl: attr.ttl, attr: attr.attr, generation: 0 },
+            Err(x) => return Err(x),
+        };
+        for name in path.split('/').filter(|x| !x.is_empty()) {
+            if entry.attr.kind != FileType::Directory {
+                self.put(entry.attr.ino);
+                return Err(ENOTDIR);
+            }
+            let ret = self.lookup(entry.attr.ino, name);
+            self.put(entry.attr.ino);
+            entry = ret?;
+        }
+        Ok(entry)
+    }
+
+    // Look up the parent directory of `path`, returning it with the last component.
+    fn lookup_parent<'a>(&mut self, path: &'a str) -> Result<(u64, &'a str), c_int> {
+        let path = path.trim_end_matches('/');
+        let (dir, name) = match path.rfind('/') {
+            Some(idx) => (&path[..idx], &path[idx + 1..]),
+            None => ("", path),
+        };
+        if name.is_empty() {
+            return Err(EEXIST);
+        }
+        let entry = self.lookup_path(dir)?;
+        if entry.attr.kind != FileType::Directory {
+            self.put(entry.attr.ino);
+            return Err(ENOTDIR);
+        }
+        Ok((entry.attr.ino, name))
+    }
+
+    // Drop a lookup reference taken by the POSIX-like layer.
+    fn put(&mut self, ino: u64) {
+        if ino != ROOT_ID {
+            self.forget(ino, 1);
+        }
+    }
+
+    fn file(&mut self, fd: u64) -> Result<&mut OpenFile, c_int> {
+        self.files.get_mut(&fd).ok_or(libc::EBADF)
+    }
+
+    /// Open `path` with `open(2)` flags, creating it with `mode` if `O_CREAT` is given. Returns
+    /// a file descriptor for `read`, `write` and `close`.
+    pub fn open_path(&mut self, path: &str, flags: i32, mode: u32) -> Result<u64, c_int> {
+        let (ino, fh) = match self.lookup_path(path) {
+            Ok(entry) => {
+                if flags & libc::O_CREAT != 0 && flags & libc::O_EXCL != 0 {
+                    self.put(entry.attr.ino);
+                    return Err(EEXIST);
+                }
+                let ino = entry.attr.ino;
+                let mut ret = Ok(());
+                if flags & libc::O_TRUNC != 0 && entry.attr.size != 0 {
+                    let attr = SetAttr { size: Some(0), ..Default::default() };
+                    ret = self.setattr(ino, attr).map(|_| ());
+                }
+                let opened = ret.and_then(|_| self.open(ino, (flags & !libc::O_CREAT) as u32));
+                match opened {
+                    Ok(open) => (ino, open.fh),
+                    Err(x) => {
+                        self.put(ino);
+                        return Err(x);
+                    }
+                }
+            }
+            Err(ENOENT) if flags & libc::O_CREAT != 0 => {
+                let (parent, name) = self.lookup_parent(path)?;
+                let ret = self.create(parent, name, mode | libc::S_IFREG, flags as u32);
+                self.put(parent);
+                let created = ret?;
+                (created.attr.ino, created.fh)
+            }
+            Err(x) => return Err(x),
+        };
+        let fd = self.next_fd;
+        self.next_fd += 1;
+        self.files.insert(fd, OpenFile {
+            ino: ino,
+            fh: fh,
+            pos: 0,
+            append: flags & libc::O_APPEND != 0,
+        });
+        Ok(fd)
+    }
+
+    /// Flush and release a file opened with `open_path`.
+    ///
+    /// As in the kernel, a flush that fails with `ENOSYS` counts as done and errors from
+    /// release are ignored.
+    pub fn close(&mut self, fd: u64) -> Result<(), c_int> {
+        let file = self.files.remove(&fd).ok_or(libc::EBADF)?;
+        let flushed = not_implemented_ok(self.flush(file.ino, file.fh, 0));
+        let _ = self.release(file.ino, file.fh, 0);
+        self.put(file.ino);
+        flushed
+    }
+
+    /// Read up to `size` bytes at the file position, advancing it.
+    pub fn read_fd(&mut self, fd: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        let (ino, fh, pos) = {
+            let file = self.file(fd)?;
+            (file.ino, file.fh, file.pos)
+        };
+        let data = self.read(ino, fh, pos as i64, size)?;
+        self.file(fd)?.pos += data.len() as u64;
+        Ok(data)
+    }
+
+    /// Write `data` at the file position, or at the end with `O_APPEND`, advancing it.
+    pub fn write_fd(&mut self, fd: u64, data: &[u8]) -> Result<usize, c_int> {
+        let (ino, fh, mut pos, append) = {
+            let file = self.file(fd)?;
+            (file.ino, file.fh, file.pos, file.append)
+        };
+        if append {
+            pos = self.getattr(ino)?.attr.size;
+        }
+        let written = self.write(ino, fh, pos as i64, data, 0)? as u64;
+        self.file(fd)?.pos = pos + written;
+        Ok(written as usize)
+    }
+
+    /// Set the file position.
+    pub fn seek(&mut self, fd: u64, pos: u64) -> Result<(), c_int> {
+        self.file(fd)?.pos = pos;
+        Ok(())
+    }
+
+    /// Sync a file opened with `open_path`. A file system without fsync counts as synced.
+    pub fn fsync_fd(&mut self, fd: u64, datasync: bool) -> Result<(), c_int> {
+        let (ino, fh) = {
+            let file = self.file(fd)?;
+            (file.ino, file.fh)
+        };
+        not_implemented_ok(self.fsync(ino, fh, datasync))
+    }
+
+    /// Get the attributes of `path`.
+    pub fn stat(&mut self, path: &str) -> Result<FileAttr, c_int> {
+        let entry = self.lookup_path(path)?;
+        self.put(entry.attr.ino);
+        Ok(entry.attr)
+    }
+
+    /// List the directory at `path`, including "." and ".." if the file system reports them.
+    pub fn readdir_path(&mut self, path: &str) -> Result<Vec<(OsString, FileType)>, c_int> {
+        let entry = self.lookup_path(path)?;
+        let ino = entry.attr.ino;
+        let ret = self.list_dir(ino);
+        self.put(ino);
+        ret
+    }
+
+    fn list_dir(&mut self, ino: u64) -> Result<Vec<(OsString, FileType)>, c_int> {
+        let fh = self.opendir(ino, libc::O_RDONLY as u32)?.fh;
+        let mut names = Vec::new();
+        let mut offset = 0;
+        let ret = loop {
+            match self.readdir(ino, fh, offset) {
+                Ok(ref entries) if entries.is_empty() => break Ok(()),
+                Ok(entries) => {
+                    offset = entries[entries.len() - 1].offset;
+                    names.extend(entries.into_iter().map(|x| (x.name, x.kind)));
+                }
+                Err(x) => break Err(x),
+            }
+        };
+        let _ = self.releasedir(ino, fh, 0);
+        ret.map(|_| names)
+    }
+
+    /// Create a directory.
+    pub fn mkdir_path(&mut self, path: &str, mode: u32) -> Result<FileAttr, c_int> {
+        let (parent, name) = self.lookup_parent(path)?;
+        let ret = self.mkdir(parent, name, mode);
+        self.put(parent);
+        let entry = ret?;
+        self.put(entry.attr.ino);
+        Ok(entry.attr)
+    }
+
+    /// Remove a file.
+    pub fn unlink_path(&mut self, path: &str) -> Result<(), c_int> {
+        let (parent, name) = self.lookup_parent(path)?;
+        let ret = self.unlink(parent, name);
+        self.put(parent);
+        ret
+    }
+
+    /// Remove an empty directory.
+    pub fn rmdir_path(&mut self, path: &str) -> Result<(), c_int> {
+        let (parent, name) = self.lookup_parent(path)?;
+        let ret = self.rmdir(parent, name);
+        self.put(parent);
+        ret
+    }
+
+    /// Rename `from` to `to`, replacing `to` if it exists.
+    pub fn rename_path(&mut self, from: &str, to: &str) -> Result<(), c_int> {
+        let (parent, name) = self.lookup_parent(from)?;
+        let (newparent, newname) = match self.lookup_parent(to) {
+            Ok(x) => x,
+            Err(x) => {
+                self.put(parent);
+                return Err(x);
+            }
+        };
+        let ret = self.rename(parent, name, newparent, newname, 0);
+        self.put(parent);
+        self.put(newparent);
+        ret
+    }
+
+    /// Create a symbolic link at `path` pointing to `target`.
+    pub fn symlink_path(&mut self, target: &str, path: &str) -> Result<FileAttr, c_int> {
+        let (parent, name) = self.lookup_parent(path)?;
+        let ret = self.symlink(parent, name, target);
+        self.put(parent);
+        let entry = ret?;
+        self.put(entry.attr.ino);
+        Ok(entry.attr)
+    }
+
+    /// Read the target of the symbolic link at `path`.
+    pub fn readlink_path(&mut self, path: &str) -> Result<Vec<u8>, c_int> {
+        let entry = self.lookup_path(path)?;
+        let ret = self.readlink(entry.attr.ino);
+        self.put(entry.attr.ino);
+        ret
+    }
+
+    /// Create a hard link at `path` to the file at `target`.
+    pub fn link_path(&mut self, target: &str, path: &str) -> Result<FileAttr, c_int> {
+        let entry = self.lookup_path(target)?;
+        let ino = entry.attr.ino;
+        let ret = match self.lookup_parent(path) {
+            Ok((parent, name)) => {
+                let ret = self.link(ino, parent, name);
+                self.put(parent);
+                ret
+            }
+            Err(x) => Err(x),
+        };
+        self.put(ino);
+        let entry = ret?;
+        self.put(entry.attr.ino);
+        Ok(entry.attr)
+    }
+
+    /// Set the size of the file at `path`.
+    pub fn truncate_path(&mut self, path: &str, size: u64) -> Result<(), c_int> {
+        let entry = self.lookup_path(path)?;
+        let attr = SetAttr { size: Some(size), ..Default::default() };
+        let ret = self.setattr(entry.attr.ino, attr);
+        self.put(entry.attr.ino);
+        ret.map(|_| ())
+    }
+}
+
+// The kernel treats `ENOSYS` from some requests as the file system having nothing to do.
+fn not_implemented_ok(ret: Result<(), c_int>) -> Result<(), c_int> {
+    match ret {
+        Err(libc::ENOSYS) => Ok(()),
+        x => x,
+    }
+}
+
+fn xattr_callback(slot: &Slot<XattrReply>) -> impl for<'a> FnOnce(Result<Xattr<'a>, c_int>) + Send + 'static {
+    let callback = slot.callback();
+    move |x: Result<Xattr, c_int>| {
+        callback(x.map(|value| match value {
+            Xattr::Size(size) => XattrReply::Size(size),
+            Xattr::Data(data) => XattrReply::Data(data.to_vec()),
+        }))
+    }
+}
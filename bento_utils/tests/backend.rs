@@ -0,0 +1,115 @@
+// Behaviour of the block backends on their own, without a `Disk` in front of them.
+
+extern crate bento_utils;
+extern crate libc;
+
+use std::env;
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bento_utils::{BlockBackend, CowBackend, FileBackend, RamBackend, SparseBackend};
+
+const BSIZE: u64 = 4096;
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+}
+
+#[test]
+fn sparse_holes_read_as_zeros() {
+    let sparse = SparseBackend::new(8 * BSIZE, BSIZE);
+    let mut buf = vec![0xff; 8 * BSIZE as usize];
+    sparse.read_at(&mut buf, 0).unwrap();
+    assert!(buf.iter().all(|x| *x == 0));
+    assert_eq!(sparse.allocated_blocks(), 0);
+
+    // A write spanning two blocks allocates only those two.
+    let data = pattern(100, 1);
+    sparse.write_at(&data, 3 * BSIZE - 50).unwrap();
+    assert_eq!(sparse.allocated_blocks(), 2);
+    sparse.read_at(&mut buf, 0).unwrap();
+    assert!(buf[..3 * BSIZE as usize - 50].iter().all(|x| *x == 0));
+    assert!(buf[3 * BSIZE as usize - 50..3 * BSIZE as usize + 50] == data[..]);
+    assert!(buf[3 * BSIZE as usize + 50..].iter().all(|x| *x == 0));
+}
+
+#[test]
+fn sparse_grows_on_write() {
+    let sparse = SparseBackend::new(2 * BSIZE, BSIZE);
+    let mut block = vec![0; BSIZE as usize];
+    assert_eq!(sparse.read_at(&mut block, 2 * BSIZE), Err(libc::EIO));
+
+    let data = pattern(BSIZE as usize, 2);
+    sparse.write_at(&data, 5 * BSIZE).unwrap();
+    assert_eq!(sparse.size(), 6 * BSIZE);
+    assert_eq!(sparse.allocated_blocks(), 1);
+    sparse.read_at(&mut block, 5 * BSIZE).unwrap();
+    assert!(block == data);
+
+    // The blocks skipped over are holes.
+    sparse.read_at(&mut block, 3 * BSIZE).unwrap();
+    assert!(block.iter().all(|x| *x == 0));
+    assert_eq!(sparse.read_at(&mut block, 6 * BSIZE), Err(libc::EIO));
+
+    // Writing inside the image doesn't change its size.
+    sparse.write_at(b"abc", 10).unwrap();
+    assert_eq!(sparse.size(), 6 * BSIZE);
+}
+
+#[test]
+fn cow_overlay_leaves_the_base_alone() {
+    let image = pattern(4 * BSIZE as usize, 3);
+    let cow = CowBackend::new(RamBackend::from_image(image.clone()), BSIZE);
+
+    // A partial-block write keeps the rest of the block from the base.
+    cow.write_at(b"overlay", BSIZE + 10).unwrap();
+    assert_eq!(cow.dirty_blocks(), 1);
+    let mut expected = image.clone();
+    expected[BSIZE as usize + 10..BSIZE as usize + 17].copy_from_slice(b"overlay");
+    let mut buf = vec![0; image.len()];
+    cow.read_at(&mut buf, 0).unwrap();
+    assert!(buf == expected);
+
+    // Partial reads, from the overlay and from the base.
+    let mut piece = vec![0; 20];
+    cow.read_at(&mut piece, BSIZE + 5).unwrap();
+    assert!(piece[..] == expected[BSIZE as usize + 5..BSIZE as usize + 25]);
+    cow.read_at(&mut piece, 2 * BSIZE + 5).unwrap();
+    assert!(piece[..] == image[2 * BSIZE as usize + 5..2 * BSIZE as usize + 25]);
+
+    assert!(cow.base().image() == image);
+    cow.reset();
+    assert_eq!(cow.dirty_blocks(), 0);
+    cow.read_at(&mut buf, 0).unwrap();
+    assert!(buf == image);
+}
+
+#[test]
+fn cow_partial_blocks_over_a_file() {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let path = env::temp_dir().join(format!("bento-backend-{}-{}.img", std::process::id(),
+                                            NEXT.fetch_add(1, Ordering::SeqCst)));
+    let image = pattern(4 * BSIZE as usize, 4);
+    fs::write(&path, &image).unwrap();
+    let base = match FileBackend::open_read_only(&path) {
+        Ok(base) => base,
+        // The file system holding the temporary directory doesn't do O_DIRECT.
+        Err(_) => return fs::remove_file(&path).unwrap(),
+    };
+    let cow = CowBackend::new(base, BSIZE);
+
+    // Neither of these is a whole aligned block, so both go through a bounce buffer.
+    let mut piece = vec![0; 100];
+    cow.read_at(&mut piece, BSIZE + 7).unwrap();
+    assert!(piece[..] == image[BSIZE as usize + 7..BSIZE as usize + 107]);
+    cow.write_at(b"xyz", 3 * BSIZE - 1).unwrap();
+    assert_eq!(cow.dirty_blocks(), 2);
+    let mut buf = vec![0; 8];
+    cow.read_at(&mut buf, 3 * BSIZE - 4).unwrap();
+    assert_eq!(&buf[..3], &image[3 * BSIZE as usize - 4..3 * BSIZE as usize - 1]);
+    assert_eq!(&buf[3..6], b"xyz");
+    assert_eq!(&buf[6..], &image[3 * BSIZE as usize + 2..3 * BSIZE as usize + 4]);
+
+    assert!(fs::read(&path).unwrap() == image);
+    fs::remove_file(&path).unwrap();
+}
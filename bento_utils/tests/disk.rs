@@ -0,0 +1,134 @@
+// The buffer cache in front of a `Disk`, on a RAM backend.
+
+extern crate bento_utils;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bento_utils::{BlockBackend, Disk, RamBackend};
+
+const BSIZE: u64 = 512;
+const BLOCKS: u64 = 16;
+
+fn ram_disk(capacity: usize) -> (Arc<RamBackend>, Disk) {
+    let backend = Arc::new(RamBackend::new(BLOCKS * BSIZE));
+    let disk = Disk::with_backend_and_cache_size(backend.clone(), BSIZE, capacity);
+    (backend, disk)
+}
+
+fn on_disk(backend: &RamBackend, blockno: u64) -> Vec<u8> {
+    let mut buf = vec![0; BSIZE as usize];
+    backend.read_at(&mut buf, blockno * BSIZE).unwrap();
+    buf
+}
+
+// Fill a cached block with `byte` and mark it dirty.
+fn dirty(disk: &Disk, blockno: u64, byte: u8) {
+    let mut bh = disk.bread(blockno).unwrap();
+    for x in bh.data_mut().iter_mut() {
+        *x = byte;
+    }
+    bh.mark_buffer_dirty();
+}
+
+#[test]
+fn released_blocks_stay_cached() {
+    let (_backend, disk) = ram_disk(8);
+    drop(disk.bread(3).unwrap());
+    let stats = disk.cache_stats();
+    assert_eq!((stats.hits, stats.misses, stats.cached), (0, 1, 1));
+
+    drop(disk.getblk(3).unwrap());
+    drop(disk.bread(3).unwrap());
+    let stats = disk.cache_stats();
+    assert_eq!((stats.hits, stats.misses, stats.cached, stats.capacity), (2, 1, 1, 8));
+}
+
+#[test]
+fn least_recently_used_blocks_are_evicted() {
+    let (_backend, disk) = ram_disk(4);
+    for blockno in 0..4 {
+        drop(disk.bread(blockno).unwrap());
+    }
+    drop(disk.bread(0).unwrap());
+    // Block 1 is now the least recently used.
+    drop(disk.bread(4).unwrap());
+    let stats = disk.cache_stats();
+    assert_eq!((stats.hits, stats.misses, stats.evictions, stats.cached), (1, 5, 1, 4));
+
+    for blockno in &[0, 2, 3] {
+        drop(disk.bread(*blockno).unwrap());
+    }
+    assert_eq!(disk.cache_stats().hits, 4);
+    // Reading 1 again is a miss, and evicts 4, which was used before 0, 2 and 3.
+    drop(disk.bread(1).unwrap());
+    drop(disk.bread(4).unwrap());
+    let stats = disk.cache_stats();
+    assert_eq!((stats.hits, stats.misses, stats.evictions, stats.cached), (4, 7, 3, 4));
+}
+
+#[test]
+fn blocks_in_use_are_not_evicted() {
+    let (_backend, disk) = ram_disk(2);
+    let held = [disk.bread(0).unwrap(), disk.bread(1).unwrap()];
+    drop(disk.bread(2).unwrap());
+    // Block 2 was in use too while it was added, so the cache goes over capacity.
+    let stats = disk.cache_stats();
+    assert_eq!((stats.evictions, stats.cached), (0, 3));
+
+    drop(held);
+    drop(disk.bread(3).unwrap());
+    let stats = disk.cache_stats();
+    assert_eq!((stats.evictions, stats.cached), (2, 2));
+    drop(disk.bread(2).unwrap());
+    assert_eq!(disk.cache_stats().hits, 1);
+}
+
+#[test]
+fn sync_all_writes_dirty_blocks() {
+    let (backend, disk) = ram_disk(8);
+    dirty(&disk, 5, 0x55);
+    dirty(&disk, 6, 0x66);
+    assert!(on_disk(&backend, 5).iter().all(|x| *x == 0));
+    disk.sync_all().unwrap();
+    assert!(on_disk(&backend, 5).iter().all(|x| *x == 0x55));
+    assert!(on_disk(&backend, 6).iter().all(|x| *x == 0x66));
+    assert_eq!(disk.cache_stats().writebacks, 2);
+
+    // Clean blocks aren't written again.
+    disk.sync_all().unwrap();
+    assert_eq!(disk.cache_stats().writebacks, 2);
+}
+
+#[test]
+fn the_flusher_writes_back_blocks_that_need_evicting() {
+    let (backend, disk) = ram_disk(1);
+    dirty(&disk, 0, 0x11);
+    // Going over capacity with a dirty block wakes the flusher, long before its next periodic
+    // writeback.
+    drop(disk.bread(1).unwrap());
+    // Block 0 is only evicted once it has been written back.
+    if disk.cache_stats().cached < 2 {
+        assert!(on_disk(&backend, 0).iter().all(|x| *x == 0x11));
+    }
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while !on_disk(&backend, 0).iter().all(|x| *x == 0x11) {
+        assert!(Instant::now() < deadline, "flusher didn't write the block back");
+        thread::sleep(Duration::from_millis(10));
+    }
+    while disk.cache_stats().cached > 1 {
+        assert!(Instant::now() < deadline, "flusher didn't evict the block");
+        thread::sleep(Duration::from_millis(10));
+    }
+    let stats = disk.cache_stats();
+    assert_eq!((stats.writebacks, stats.evictions), (1, 1));
+}
+
+#[test]
+fn dropping_the_disk_writes_dirty_blocks() {
+    let (backend, disk) = ram_disk(8);
+    dirty(&disk, 7, 0x77);
+    drop(disk);
+    assert!(on_disk(&backend, 7).iter().all(|x| *x == 0x77));
+}
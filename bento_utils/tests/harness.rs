@@ -0,0 +1,323 @@
+// A small in-memory file system driven through `testing::Harness`.
+
+extern crate bento_utils;
+extern crate libc;
+extern crate time;
+
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::sync::Mutex;
+use std::thread;
+
+use bento_utils::errors;
+use bento_utils::fuse::*;
+use bento_utils::options::MountOptions;
+use bento_utils::testing::{Harness, SentNotification, XattrReply, ROOT_ID};
+use bento_utils::{BentoFilesystem, FuseConnInfo};
+
+use libc::{c_int, EEXIST, EIO, ENAMETOOLONG, ENODEV, ENOENT, ENOTCONN, ENOTDIR, EROFS};
+
+use time::Timespec;
+
+const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
+
+struct Node {
+    attr: FileAttr,
+    data: Vec<u8>,
+    children: BTreeMap<OsString, u64>,
+}
+
+// Files and directories kept in a map from inode number. Looking up "corrupt" reports an error
+// with `errors::fs_error`, and making a directory called "panic" panics.
+struct MemFs {
+    nodes: Mutex<BTreeMap<u64, Node>>,
+    notifier: Mutex<Notifier>,
+}
+
+fn new_attr(ino: u64, kind: FileType, perm: u16) -> FileAttr {
+    FileAttr {
+        ino: ino,
+        size: 0,
+        blocks: 0,
+        atime: Timespec::new(0, 0),
+        mtime: Timespec::new(0, 0),
+        ctime: Timespec::new(0, 0),
+        crtime: Timespec::new(0, 0),
+        kind: kind,
+        perm: perm,
+        nlink: if kind == FileType::Directory { 2 } else { 1 },
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+    }
+}
+
+impl MemFs {
+    fn new() -> MemFs {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(ROOT_ID, Node {
+            attr: new_attr(ROOT_ID, FileType::Directory, 0o755),
+            data: Vec::new(),
+            children: BTreeMap::new(),
+        });
+        MemFs {
+            nodes: Mutex::new(nodes),
+            notifier: Mutex::new(Notifier::default()),
+        }
+    }
+
+    fn add(&self, parent: u64, name: &OsStr, kind: FileType, mode: u32) -> Result<FileAttr, c_int> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let ino = nodes.keys().next_back().unwrap() + 1;
+        let dir = nodes.get_mut(&parent).ok_or(ENOENT)?;
+        if dir.attr.kind != FileType::Directory {
+            return Err(ENOTDIR);
+        }
+        if dir.children.contains_key(name) {
+            return Err(EEXIST);
+        }
+        dir.children.insert(name.to_os_string(), ino);
+        let attr = new_attr(ino, kind, (mode & 0o7777) as u16);
+        nodes.insert(ino, Node { attr: attr, data: Vec::new(), children: BTreeMap::new() });
+        Ok(attr)
+    }
+}
+
+impl<'de> BentoFilesystem<'de> for MemFs {
+    fn get_name(&self) -> &'static str {
+        "memfs"
+    }
+
+    fn new_instance(&self) -> Box<Self> {
+        Box::new(MemFs::new())
+    }
+
+    fn bento_init(&mut self, _req: &Request, _devname: &OsStr, _opts: &MountOptions,
+                  fc_info: &mut FuseConnInfo) -> Result<(), i32> {
+        *self.notifier.lock().unwrap() = fc_info.notifier();
+        Ok(())
+    }
+
+    fn bento_lookup(&self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if name == "corrupt" {
+            errors::fs_error(EIO);
+            return reply.error(EIO);
+        }
+        let nodes = self.nodes.lock().unwrap();
+        match nodes.get(&parent).and_then(|dir| dir.children.get(name)) {
+            Some(ino) => reply.entry(&TTL, &nodes[ino].attr, 0),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn bento_getattr(&self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.nodes.lock().unwrap().get(&ino) {
+            Some(node) => reply.attr(&TTL, &node.attr),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn bento_setattr(&self, _req: &Request, ino: u64, _mode: Option<u32>, _uid: Option<u32>,
+                     _gid: Option<u32>, size: Option<u64>, _atime: Option<Timespec>,
+                     _mtime: Option<Timespec>, _fh: Option<u64>, _crtime: Option<Timespec>,
+                     _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>,
+                     _flags: Option<u32>, reply: ReplyAttr) {
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = match nodes.get_mut(&ino) {
+            Some(node) => node,
+            None => return reply.error(ENOENT),
+        };
+        if let Some(size) = size {
+            node.data.resize(size as usize, 0);
+            node.attr.size = size;
+        }
+        reply.attr(&TTL, &node.attr);
+    }
+
+    fn bento_mkdir(&self, _req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+        if name == "panic" {
+            panic!("memfs: asked to panic");
+        }
+        match self.add(parent, name, FileType::Directory, mode) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(x) => reply.error(x),
+        }
+    }
+
+    fn bento_create(&self, _req: &Request, parent: u64, name: &OsStr, mode: u32, _flags: u32,
+                    reply: ReplyCreate) {
+        match self.add(parent, name, FileType::RegularFile, mode) {
+            Ok(attr) => reply.created(&TTL, &attr, 0, 0, 0),
+            Err(x) => reply.error(x),
+        }
+    }
+
+    fn bento_unlink(&self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let mut nodes = self.nodes.lock().unwrap();
+        let ino = match nodes.get_mut(&parent).and_then(|dir| dir.children.remove(name)) {
+            Some(ino) => ino,
+            None => return reply.error(ENOENT),
+        };
+        nodes.remove(&ino);
+        reply.ok();
+    }
+
+    fn bento_open(&self, _req: &Request, _ino: u64, _flags: u32, reply: ReplyOpen) {
+        reply.opened(0, 0);
+    }
+
+    fn bento_read(&self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        let nodes = self.nodes.lock().unwrap();
+        let data = match nodes.get(&ino) {
+            Some(node) => &node.data,
+            None => return reply.error(ENOENT),
+        };
+        let start = std::cmp::min(offset as usize, data.len());
+        let end = std::cmp::min(start + size as usize, data.len());
+        reply.data(&data[start..end]);
+    }
+
+    fn bento_write(&self, _req: &Request, ino: u64, _fh: u64, offset: i64, data: &[u8], _flags: u32,
+                   reply: ReplyWrite) {
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = match nodes.get_mut(&ino) {
+            Some(node) => node,
+            None => return reply.error(ENOENT),
+        };
+        let end = offset as usize + data.len();
+        if node.data.len() < end {
+            node.data.resize(end, 0);
+        }
+        node.data[offset as usize..end].copy_from_slice(data);
+        node.attr.size = node.data.len() as u64;
+        reply.written(data.len() as u32);
+    }
+
+    fn bento_opendir(&self, _req: &Request, _ino: u64, _flags: u32, reply: ReplyOpen) {
+        reply.opened(0, 0);
+    }
+
+    fn bento_readdir(&self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let nodes = self.nodes.lock().unwrap();
+        let dir = match nodes.get(&ino) {
+            Some(dir) => dir,
+            None => return reply.error(ENOENT),
+        };
+        for (i, (name, child)) in dir.children.iter().enumerate().skip(offset as usize) {
+            if reply.add(*child, i as i64 + 1, nodes[child].attr.kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+fn mounted() -> Harness<'static, i32, i32, MemFs> {
+    let mut harness = Harness::new(MemFs::new());
+    harness.init("").unwrap();
+    harness
+}
+
+#[test]
+fn posix_layer() {
+    let mut harness = mounted();
+    harness.mkdir_path("/dir", 0o755).unwrap();
+    let fd = harness.open_path("/dir/file", libc::O_CREAT | libc::O_RDWR, 0o644).unwrap();
+    assert_eq!(harness.write_fd(fd, b"hello world").unwrap(), 11);
+    harness.seek(fd, 6).unwrap();
+    assert_eq!(harness.read_fd(fd, 100).unwrap(), b"world");
+    harness.close(fd).unwrap();
+
+    assert_eq!(harness.stat("/dir/file").unwrap().size, 11);
+    let names: Vec<OsString> = harness.readdir_path("/dir").unwrap().into_iter().map(|x| x.0).collect();
+    assert_eq!(names, vec![OsString::from("file")]);
+    assert_eq!(harness.open_path("/dir/file", libc::O_CREAT | libc::O_EXCL | libc::O_RDWR, 0o644),
+               Err(EEXIST));
+
+    harness.truncate_path("/dir/file", 5).unwrap();
+    let fd = harness.open_path("/dir/file", libc::O_RDONLY, 0).unwrap();
+    assert_eq!(harness.read_fd(fd, 100).unwrap(), b"hello");
+    harness.close(fd).unwrap();
+
+    harness.unlink_path("/dir/file").unwrap();
+    assert_eq!(harness.stat("/dir/file").err(), Some(ENOENT));
+    assert_eq!(harness.stat("/nothing/here").err(), Some(ENOENT));
+    harness.destroy();
+}
+
+#[test]
+fn fs_error_makes_the_harness_read_only() {
+    let mut harness = mounted();
+    let mut other = mounted();
+    harness.mkdir_path("/dir", 0o755).unwrap();
+
+    assert_eq!(harness.lookup(ROOT_ID, "corrupt").err(), Some(EIO));
+    let status = harness.error_status().unwrap();
+    assert_eq!(status.cause, errors::ErrorCause::Reported);
+    assert_eq!(status.op, "lookup");
+    assert_eq!(harness.mkdir_path("/dir2", 0o755).err(), Some(EROFS));
+    assert!(harness.stat("/dir").is_ok());
+    match harness.getxattr(ROOT_ID, errors::STATUS_XATTR, 4096).unwrap() {
+        XattrReply::Data(value) => assert!(value.starts_with(b"state=errors cause=reported")),
+        x => panic!("unexpected reply {:?}", x),
+    }
+
+    // Another mount of the same file system isn't affected.
+    assert!(other.error_status().is_none());
+    other.mkdir_path("/dir2", 0o755).unwrap();
+}
+
+#[test]
+fn panic_fails_the_request() {
+    let mut harness = mounted();
+    assert_eq!(harness.mkdir_path("/panic", 0o755).err(), Some(EIO));
+    assert_eq!(harness.error_status().unwrap().cause, errors::ErrorCause::Panic);
+    assert_eq!(harness.open_path("/file", libc::O_CREAT | libc::O_RDWR, 0o644).err(), Some(EROFS));
+
+    // Mounting again clears the state.
+    harness.init("").unwrap();
+    assert!(harness.error_status().is_none());
+    harness.mkdir_path("/dir", 0o755).unwrap();
+}
+
+#[test]
+fn notifications_are_recorded_until_destroy() {
+    let fs = MemFs::new();
+    assert_eq!(fs.notifier.lock().unwrap().notify_inval_inode(ROOT_ID, 0, 0), Err(ENOTCONN));
+    let mut harness = Harness::new(fs);
+    harness.init("").unwrap();
+    let notifier = harness.fs().notifier.lock().unwrap().clone();
+
+    notifier.notify_inval_inode(2, 4096, -1).unwrap();
+    notifier.notify_inval_entry(ROOT_ID, OsStr::new("file")).unwrap();
+    notifier.notify_delete(ROOT_ID, 2, OsStr::new("file")).unwrap();
+    notifier.notify_store(3, 512, b"data").unwrap();
+    let long = OsString::from("x".repeat(1025));
+    assert_eq!(notifier.notify_inval_entry(ROOT_ID, &long), Err(ENAMETOOLONG));
+    assert_eq!(harness.take_notifications(), vec![
+        SentNotification::InvalInode { ino: 2, offset: 4096, len: -1 },
+        SentNotification::InvalEntry { parent: ROOT_ID, name: OsString::from("file") },
+        SentNotification::Delete { parent: ROOT_ID, child: 2, name: OsString::from("file") },
+        SentNotification::Store { ino: 3, offset: 512, data: b"data".to_vec() },
+    ]);
+    assert!(harness.take_notifications().is_empty());
+
+    // Notifiers can be used from other threads.
+    thread::spawn(move || notifier.notify_inval_inode(4, 0, 0)).join().unwrap().unwrap();
+    assert_eq!(harness.take_notifications(), vec![
+        SentNotification::InvalInode { ino: 4, offset: 0, len: 0 },
+    ]);
+
+    let notifier = harness.fs().notifier.lock().unwrap().clone();
+    harness.destroy();
+    assert_eq!(notifier.notify_inval_inode(2, 0, 0), Err(ENODEV));
+    assert_eq!(notifier.notify_store(2, 0, b"data"), Err(ENODEV));
+    assert!(harness.take_notifications().is_empty());
+
+    // Mounting again gives a new notifier, and the old one stays unmounted.
+    harness.init("").unwrap();
+    harness.fs().notifier.lock().unwrap().notify_inval_inode(2, 0, 0).unwrap();
+    assert_eq!(notifier.notify_inval_inode(2, 0, 0), Err(ENODEV));
+    assert_eq!(harness.take_notifications().len(), 1);
+}
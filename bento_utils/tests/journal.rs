@@ -0,0 +1,327 @@
+// The jbd2 on-disk format, read and written by `journal::Journal` on a RAM disk.
+//
+// Most tests lay out a log by hand, as the kernel would leave it after a crash, and check
+// what recovery writes to the file system's blocks. fixtures/jbd2-ext4.journal is the start of
+// a real jbd2 journal, written by e2fsprogs 1.47 on an ext4 image with 1 KiB blocks:
+//
+//     mke2fs -t ext4 -b 1024 -O ^metadata_csum -J size=1 fs.img 8M
+//     debugfs -w fs.img: jo; jw -b 300,301 data; jw -r 301; jw -b 302 d302;
+//                        jw -b 303 -c d303; jc
+//     debugfs fs.img -R "dump <8> journal"; head -c 12288 journal
+//
+// where block N of data and d30x is "xv6 jbd2 fixture, block N, " repeated. e2fsprogs replays
+// 300 and 302 from it, and leaves 301 (revoked) and 303 (not committed) alone.
+
+extern crate bento_utils;
+extern crate libc;
+
+use std::sync::Arc;
+
+use bento_utils::journal::Journal;
+use bento_utils::{BlockBackend, Disk, RamBackend};
+
+const BSIZE: usize = 1024;
+// Blocks in the test disk. The log is at its start and the blocks it logs come after it.
+const DISK_BLOCKS: usize = 64;
+const LOG_BLOCKS: usize = 16;
+
+const MAGIC: u32 = 0xc03b3998;
+const DESCRIPTOR: u32 = 1;
+const COMMIT: u32 = 2;
+const SUPERBLOCK_V2: u32 = 4;
+const REVOKE: u32 = 5;
+const FEATURE_COMPAT_CHECKSUM: u32 = 1;
+const FEATURE_INCOMPAT_REVOKE: u32 = 1;
+
+fn put_be32(buf: &mut [u8], off: usize, val: u32) {
+    buf[off..off + 4].copy_from_slice(&val.to_be_bytes());
+}
+
+fn get_be32(buf: &[u8], off: usize) -> u32 {
+    let mut b = [0; 4];
+    b.copy_from_slice(&buf[off..off + 4]);
+    u32::from_be_bytes(b)
+}
+
+fn header(blocktype: u32, sequence: u32) -> Vec<u8> {
+    let mut block = vec![0; BSIZE];
+    put_be32(&mut block, 0, MAGIC);
+    put_be32(&mut block, 4, blocktype);
+    put_be32(&mut block, 8, sequence);
+    block
+}
+
+// A journal superblock for a log of `maxlen` blocks. The log holds transactions from
+// `sequence` on starting at block `start`, or none if `start` is 0.
+fn superblock(maxlen: u32, sequence: u32, start: u32, compat: u32) -> Vec<u8> {
+    let mut block = header(SUPERBLOCK_V2, 0);
+    put_be32(&mut block, 12, BSIZE as u32);
+    put_be32(&mut block, 16, maxlen);
+    put_be32(&mut block, 20, 1);
+    put_be32(&mut block, 24, sequence);
+    put_be32(&mut block, 28, start);
+    put_be32(&mut block, 36, compat);
+    put_be32(&mut block, 40, FEATURE_INCOMPAT_REVOKE);
+    block
+}
+
+// A descriptor block with 32-bit tags for the blocks that follow it in the log.
+fn descriptor(sequence: u32, blocks: &[u32]) -> Vec<u8> {
+    let mut block = header(DESCRIPTOR, sequence);
+    let mut off = 12;
+    for (i, blocknr) in blocks.iter().enumerate() {
+        let mut flags = if i == 0 { 0 } else { 2 };
+        if i == blocks.len() - 1 {
+            flags |= 8;
+        }
+        put_be32(&mut block, off, *blocknr);
+        put_be32(&mut block, off + 4, flags);
+        off += if i == 0 { 8 + 16 } else { 8 };
+    }
+    block
+}
+
+fn revoke(sequence: u32, blocks: &[u32]) -> Vec<u8> {
+    let mut block = header(REVOKE, sequence);
+    put_be32(&mut block, 12, 16 + 4 * blocks.len() as u32);
+    for (i, blocknr) in blocks.iter().enumerate() {
+        put_be32(&mut block, 16 + 4 * i, *blocknr);
+    }
+    block
+}
+
+fn commit(sequence: u32) -> Vec<u8> {
+    header(COMMIT, sequence)
+}
+
+fn data(blocknr: u32, version: u8) -> Vec<u8> {
+    format!("block {} version {}, ", blocknr, version).into_bytes().into_iter().cycle().take(BSIZE).collect()
+}
+
+// A RAM disk holding a log of LOG_BLOCKS blocks at block 0, described by `sb`, whose block `i`
+// is `log[i]` where given.
+fn disk_with_log(sb: Vec<u8>, log: &[(u32, Vec<u8>)]) -> Arc<RamBackend> {
+    let mut image = vec![0; DISK_BLOCKS * BSIZE];
+    image[..BSIZE].copy_from_slice(&sb);
+    for (blockno, block) in log {
+        let off = *blockno as usize * BSIZE;
+        image[off..off + BSIZE].copy_from_slice(block);
+    }
+    Arc::new(RamBackend::from_image(image))
+}
+
+fn open(backend: &Arc<RamBackend>, len: usize) -> Option<Journal> {
+    let disk = Arc::new(Disk::with_backend(backend.clone(), BSIZE as u64));
+    Journal::new_from_disk(disk.clone(), disk, 0, len as i32, BSIZE as i32)
+}
+
+fn block(backend: &RamBackend, blocknr: u32) -> Vec<u8> {
+    let mut buf = vec![0; BSIZE];
+    backend.read_at(&mut buf, blocknr as u64 * BSIZE as u64).unwrap();
+    buf
+}
+
+// Open the journal, which recovers it, and check that it is empty afterwards.
+fn recover(backend: &Arc<RamBackend>) {
+    drop(open(backend, LOG_BLOCKS).expect("journal doesn't open"));
+    assert_eq!(get_be32(&block(backend, 0), 28), 0, "log isn't empty after recovery");
+}
+
+#[test]
+fn committed_transactions_are_replayed() {
+    let backend = disk_with_log(superblock(LOG_BLOCKS as u32, 5, 1, 0), &[
+        (1, descriptor(5, &[40, 41])),
+        (2, data(40, 1)),
+        (3, data(41, 1)),
+        (4, commit(5)),
+        (5, descriptor(6, &[40])),
+        (6, data(40, 2)),
+        (7, commit(6)),
+    ]);
+    recover(&backend);
+    assert!(block(&backend, 40) == data(40, 2));
+    assert!(block(&backend, 41) == data(41, 1));
+    // The transaction after the ones replayed skips an ID, as in jbd2.
+    assert_eq!(get_be32(&block(&backend, 0), 24), 8);
+}
+
+#[test]
+fn transactions_without_a_commit_are_discarded() {
+    let backend = disk_with_log(superblock(LOG_BLOCKS as u32, 5, 1, 0), &[
+        (1, descriptor(5, &[40])),
+        (2, data(40, 1)),
+        (3, commit(5)),
+        (4, descriptor(6, &[40, 41])),
+        (5, data(40, 2)),
+        (6, data(41, 2)),
+        // A stale commit block from an older transaction doesn't count.
+        (7, commit(2)),
+    ]);
+    recover(&backend);
+    assert!(block(&backend, 40) == data(40, 1));
+    assert!(block(&backend, 41).iter().all(|x| *x == 0));
+}
+
+#[test]
+fn revoked_blocks_are_not_replayed() {
+    let backend = disk_with_log(superblock(LOG_BLOCKS as u32, 5, 1, 0), &[
+        (1, descriptor(5, &[40, 41, 42])),
+        (2, data(40, 1)),
+        (3, data(41, 1)),
+        (4, data(42, 1)),
+        (5, commit(5)),
+        (6, revoke(6, &[40, 41])),
+        (7, commit(6)),
+        // Logging a block again after it was revoked replays it.
+        (8, descriptor(7, &[41])),
+        (9, data(41, 3)),
+        (10, commit(7)),
+    ]);
+    recover(&backend);
+    assert!(block(&backend, 40).iter().all(|x| *x == 0));
+    assert!(block(&backend, 41) == data(41, 3));
+    assert!(block(&backend, 42) == data(42, 1));
+}
+
+#[test]
+fn transactions_wrap_around_the_end_of_the_log() {
+    // Log blocks run from 1 to LOG_BLOCKS - 1, so the transaction starting at 14 continues at 1.
+    let backend = disk_with_log(superblock(LOG_BLOCKS as u32, 9, 14, 0), &[
+        (14, descriptor(9, &[40, 41, 42])),
+        (15, data(40, 1)),
+        (1, data(41, 1)),
+        (2, data(42, 1)),
+        (3, commit(9)),
+        (4, descriptor(10, &[43])),
+        (5, data(43, 1)),
+        (6, commit(10)),
+    ]);
+    recover(&backend);
+    for blocknr in 40..44 {
+        assert!(block(&backend, blocknr) == data(blocknr, 1), "block {}", blocknr);
+    }
+}
+
+#[test]
+fn escaped_blocks_get_their_magic_number_back() {
+    let mut logged = data(40, 1);
+    let mut home = logged.clone();
+    put_be32(&mut home, 0, MAGIC);
+    put_be32(&mut logged, 0, 0);
+    let mut desc = descriptor(5, &[40]);
+    // JBD2_FLAG_ESCAPE with JBD2_FLAG_LAST_TAG.
+    put_be32(&mut desc, 16, 8 | 1);
+    let backend = disk_with_log(superblock(LOG_BLOCKS as u32, 5, 1, 0), &[
+        (1, desc),
+        (2, logged),
+        (3, commit(5)),
+    ]);
+    recover(&backend);
+    assert!(block(&backend, 40) == home);
+}
+
+// Commit a transaction writing `contents` to block 40 with the journal, then put the log back
+// the way a crash before the checkpoint would have left it, with block 40 still zero.
+fn crash_after_commit(compat: u32, contents: &[u8]) -> (Arc<RamBackend>, u32) {
+    let backend = disk_with_log(superblock(LOG_BLOCKS as u32, 1, 0, compat), &[]);
+    {
+        let disk = Arc::new(Disk::with_backend(backend.clone(), BSIZE as u64));
+        let journal = Journal::new_from_disk(disk.clone(), disk.clone(), 0, LOG_BLOCKS as i32, BSIZE as i32)
+            .unwrap();
+        {
+            let handle = journal.begin_op(1).unwrap();
+            let mut bh = disk.bread(40).unwrap();
+            assert_eq!(handle.get_write_access(&bh), 0);
+            bh.data_mut().copy_from_slice(contents);
+            assert_eq!(handle.journal_write(&mut bh), 0);
+        }
+        assert_eq!(journal.force_commit(), 0);
+    }
+    assert!(block(&backend, 40) == contents);
+
+    let commit_block = block(&backend, 3);
+    assert_eq!(get_be32(&commit_block, 0), MAGIC);
+    assert_eq!(get_be32(&commit_block, 4), COMMIT);
+    let sequence = get_be32(&commit_block, 8);
+    backend.write_at(&superblock(LOG_BLOCKS as u32, sequence, 1, compat), 0).unwrap();
+    backend.write_at(&vec![0; BSIZE], 40 * BSIZE as u64).unwrap();
+    (backend, sequence)
+}
+
+#[test]
+fn written_transactions_can_be_replayed() {
+    let (backend, sequence) = crash_after_commit(0, &data(40, 1));
+    let desc = block(&backend, 1);
+    assert_eq!(get_be32(&desc, 4), DESCRIPTOR);
+    assert_eq!(get_be32(&desc, 8), sequence);
+    assert_eq!(get_be32(&desc, 12), 40);
+    recover(&backend);
+    assert!(block(&backend, 40) == data(40, 1));
+}
+
+#[test]
+fn commit_checksums_are_checked() {
+    let (backend, _) = crash_after_commit(FEATURE_COMPAT_CHECKSUM, &data(40, 1));
+    // JBD2_CRC32_CHKSUM of 4 bytes.
+    assert_eq!(block(&backend, 3)[12..14], [1, 4]);
+    let replayed = backend.image();
+    recover(&backend);
+    assert!(block(&backend, 40) == data(40, 1));
+
+    // A data block that doesn't match the checksum means the transaction wasn't all written.
+    let backend = Arc::new(RamBackend::from_image(replayed));
+    let mut torn = block(&backend, 2);
+    torn[100] ^= 0xff;
+    backend.write_at(&torn, 2 * BSIZE as u64).unwrap();
+    recover(&backend);
+    assert!(block(&backend, 40).iter().all(|x| *x == 0));
+}
+
+#[test]
+fn aborted_journals_commit_nothing() {
+    let backend = disk_with_log(superblock(LOG_BLOCKS as u32, 1, 0, 0), &[]);
+    let disk = Arc::new(Disk::with_backend(backend.clone(), BSIZE as u64));
+    let journal = Journal::new_from_disk(disk.clone(), disk.clone(), 0, LOG_BLOCKS as i32, BSIZE as i32).unwrap();
+    {
+        let handle = journal.begin_op(1).unwrap();
+        let mut bh = disk.bread(40).unwrap();
+        bh.data_mut().copy_from_slice(&data(40, 1));
+        assert_eq!(handle.journal_write(&mut bh), 0);
+        journal.abort(libc::EIO);
+        assert_eq!(handle.journal_write(&mut bh), -libc::EROFS);
+    }
+    assert_eq!(journal.begin_op(1).err(), Some(libc::EROFS));
+    assert_eq!(journal.force_commit(), -libc::EIO);
+    drop(journal);
+    assert!(block(&backend, 1).iter().all(|x| *x == 0));
+    assert!(block(&backend, 3).iter().all(|x| *x == 0));
+    assert_eq!(get_be32(&block(&backend, 0), 28), 0);
+}
+
+#[test]
+fn kernel_journals_are_replayed() {
+    let fixture = include_bytes!("fixtures/jbd2-ext4.journal");
+    let log_blocks = get_be32(fixture, 16) as usize;
+    assert_eq!(get_be32(fixture, 12), BSIZE as u32);
+    // Superblock v2 with the revoke and 64-bit features, holding transactions from 1 at block 1.
+    assert_eq!(get_be32(fixture, 4), SUPERBLOCK_V2);
+    assert_eq!(get_be32(fixture, 40), 3);
+    assert_eq!((get_be32(fixture, 24), get_be32(fixture, 28)), (1, 1));
+
+    let mut image = fixture.to_vec();
+    image.resize(log_blocks * BSIZE, 0);
+    let log = Arc::new(Disk::with_backend(Arc::new(RamBackend::from_image(image)), BSIZE as u64));
+    let backend = Arc::new(RamBackend::new(304 * BSIZE as u64));
+    let fs = Arc::new(Disk::with_backend(backend.clone(), BSIZE as u64));
+    assert_eq!(Journal::needs_recovery(&log, 0, log_blocks as i32, BSIZE as i32), Ok(true));
+    drop(Journal::new_from_disk(log.clone(), fs, 0, log_blocks as i32, BSIZE as i32).unwrap());
+    assert_eq!(Journal::needs_recovery(&log, 0, log_blocks as i32, BSIZE as i32), Ok(false));
+
+    let expected = |blocknr: u32| -> Vec<u8> {
+        format!("xv6 jbd2 fixture, block {}, ", blocknr).into_bytes().into_iter().cycle().take(BSIZE).collect()
+    };
+    assert!(block(&backend, 300) == expected(300));
+    assert!(block(&backend, 301).iter().all(|x| *x == 0));
+    assert!(block(&backend, 302) == expected(302));
+    assert!(block(&backend, 303).iter().all(|x| *x == 0));
+}
@@ -34,9 +34,7 @@ pub mod hello_capnp {
     include!(concat!(env!("OUT_DIR"), "/src/hello_capnp.rs"));
 }
 
-pub static mut HELLO_FS: HelloFS = HelloFS {
-    socket: None,
-};
+pub static mut HELLO_FS: HelloFS = HelloFS::new();
 
 #[no_mangle]
 pub fn rust_main() {
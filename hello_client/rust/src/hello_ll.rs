@@ -18,15 +18,23 @@ use crate::time;
 //#[cfg(not(feature="user"))]
 //use crate::println;
 
+use alloc::boxed::Box;
 use alloc::vec::Vec;
 
 use bento_utils::*;
+use bento_utils::options::*;
 
 use core::str;
 
 use fuse::*;
 
 use std::ffi::OsStr;
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
 
 use time::Timespec;
 
@@ -38,12 +46,102 @@ use capnp::serialize;
 
 pub const PAGE_SIZE: usize = 4096;
 
+// Inode number of the hello file.
+const HELLO_INO: u64 = 2;
+
+// The connection to hello_srv. Replies are matched to requests by order, so only one request
+// is sent at a time.
+struct Server {
+    stream: Mutex<TcpStream>,
+}
+
+impl Server {
+    // Send `msg` and read the reply into `resp`.
+    fn call(&self, msg: &str, resp: &mut [u8]) -> std::io::Result<usize> {
+        let mut stream = self.stream.lock().unwrap();
+        let _ = stream.write(msg.as_bytes());
+        stream.read(resp)
+    }
+}
+
+// A thread that polls hello_srv for changes made by other clients.
+struct Watcher {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
 pub struct HelloFS {
-    pub socket: Option<TcpStream>,
+    server: Option<Arc<Server>>,
+    watcher: Option<Watcher>,
 }
 
 impl HelloFS {
     const NAME: &'static str = "hello_client\0";
+
+    // server=a.b.c.d:port: address of hello_srv, 127.0.0.1:1234 by default.
+    // refresh=N: check every N seconds whether the hello file changed on the server, and drop
+    // the kernel's cached copy if it did. Off by default.
+    const MOUNT_OPTIONS: &'static [MountOption] = &[
+        MountOption { name: "server", kind: MountOptionKind::String },
+        MountOption { name: "refresh", kind: MountOptionKind::Number },
+    ];
+
+    pub const fn new() -> Self {
+        HelloFS {
+            server: None,
+            watcher: None,
+        }
+    }
+}
+
+// Ask `server` for the size of the hello file every `refresh` seconds until `stop` is set, and
+// invalidate the kernel's cached data when the size changes. This runs on its own thread, so
+// the notification doesn't wait on a request this file system is handling.
+fn watch(server: Arc<Server>, notifier: Notifier, refresh: u64, stop: Arc<AtomicBool>) {
+    let mut last_size = None;
+    loop {
+        for _ in 0..refresh * 10 {
+            if stop.load(Ordering::SeqCst) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        let mut msg_resp = [0 as u8; 4096];
+        let size = match server.call(&format!("getattr {}", HELLO_INO), &mut msg_resp) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        let attr_msg = match str::from_utf8(&msg_resp[0..size]) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        let attr_vec: Vec<&str> = attr_msg.split(' ').collect();
+        if attr_vec.get(0) != Some(&"Ok") {
+            continue;
+        }
+        let file_size: Option<u64> = attr_vec.get(4).and_then(|x| x.parse().ok());
+        if last_size.is_some() && file_size != last_size {
+            let _ = notifier.notify_inval_inode(HELLO_INO, 0, 0);
+        }
+        last_size = file_size;
+    }
+}
+
+// Parse an address of the form a.b.c.d:port.
+fn parse_server(server: &str) -> Option<SocketAddrV4> {
+    let mut parts = server.splitn(2, ':');
+    let ip = parts.next()?;
+    let port = parts.next()?.parse().ok()?;
+    let mut octets = [0u8; 4];
+    let mut fields = ip.split('.');
+    for octet in octets.iter_mut() {
+        *octet = fields.next()?.parse().ok()?;
+    }
+    if fields.next().is_some() {
+        return None;
+    }
+    let ip = Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]);
+    Some(SocketAddrV4::new(ip, port))
 }
 
 impl BentoFilesystem<'_> for HelloFS {
@@ -51,10 +149,19 @@ impl BentoFilesystem<'_> for HelloFS {
         Self::NAME
     }
 
+    fn new_instance(&self) -> Box<Self> {
+        Box::new(HelloFS::new())
+    }
+
+    fn mount_options(&self) -> &'static [MountOption] {
+        Self::MOUNT_OPTIONS
+    }
+
     fn bento_init(
         &mut self,
         _req: &Request,
         _devname: &OsStr,
+        opts: &MountOptions,
         outarg: &mut FuseConnInfo,
     ) -> Result<(), i32> {
         outarg.proto_major = BENTO_KERNEL_VERSION;
@@ -70,7 +177,10 @@ impl BentoFilesystem<'_> for HelloFS {
         outarg.congestion_threshold = 0;
         outarg.time_gran = 1;
 
-        let srv_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1234);
+        let srv_addr = match opts.get_str("server") {
+            Some(server) => parse_server(server).ok_or(libc::EINVAL)?,
+            None => SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1234),
+        };
         let mut stream = match TcpStream::connect(SocketAddr::V4(srv_addr)) {
             Ok(x) => x,
             Err(_) => return Err(-1),
@@ -79,26 +189,45 @@ impl BentoFilesystem<'_> for HelloFS {
         let mut foo_msg = message.init_root::<foo::Builder>();
         foo_msg.set_msg("hello");
         serialize::write_message(&mut stream, &message);
-        self.socket = Some(stream);
+        let server = Arc::new(Server { stream: Mutex::new(stream) });
+        self.server = Some(Arc::clone(&server));
+
+        match opts.get_u64("refresh") {
+            Some(refresh) if refresh > 0 => {
+                let stop = Arc::new(AtomicBool::new(false));
+                let notifier = outarg.notifier();
+                let thread_stop = Arc::clone(&stop);
+                let thread = thread::spawn(move || watch(server, notifier, refresh, thread_stop));
+                self.watcher = Some(Watcher { stop: stop, thread: thread });
+            }
+            _ => {}
+        }
 
         return Ok(());
     }
 
     fn bento_destroy(&mut self, _req: &Request) {
+        if let Some(watcher) = self.watcher.take() {
+            watcher.stop.store(true, Ordering::SeqCst);
+            let _ = watcher.thread.join();
+        }
+        let server = match self.server.take() {
+            Some(x) => x,
+            None => return,
+        };
+        let mut stream = server.stream.lock().unwrap();
         let msg = "exit";
-        let _size = match self.socket.as_ref().unwrap().write(msg.as_bytes()) {
+        let _size = match stream.write(msg.as_bytes()) {
             Ok(x) => x,
             Err(_) => return,
         };
-        let _ = self.socket.as_ref().unwrap().shutdown(Shutdown::Both);
-        self.socket = None;
+        let _ = stream.shutdown(Shutdown::Both);
     }
 
     fn bento_statfs(&self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
         let msg = format!("statfs");
-        let _ = self.socket.as_ref().unwrap().write(msg.as_bytes());
         let mut msg_resp = [0 as u8; 4096];
-        let size = match self.socket.as_ref().unwrap().read(&mut msg_resp) {
+        let size = match self.server.as_ref().unwrap().call(&msg, &mut msg_resp) {
             Ok(x) => x,
             Err(_) => {
                 reply.error(libc::EIO);
@@ -148,9 +277,8 @@ impl BentoFilesystem<'_> for HelloFS {
         reply: ReplyOpen,
     ) {
         let msg = format!("open {}", nodeid);
-        let _ = self.socket.as_ref().unwrap().write(msg.as_bytes());
         let mut msg_resp = [0 as u8; 4096];
-        let size = match self.socket.as_ref().unwrap().read(&mut msg_resp) {
+        let size = match self.server.as_ref().unwrap().call(&msg, &mut msg_resp) {
             Ok(x) => x,
             Err(_) => {
                 reply.error(libc::EIO);
@@ -185,9 +313,8 @@ impl BentoFilesystem<'_> for HelloFS {
         reply: ReplyOpen,
     ) {
         let msg = format!("opendir {}", nodeid);
-        let _ = self.socket.as_ref().unwrap().write(msg.as_bytes());
         let mut msg_resp = [0 as u8; 4096];
-        let size = match self.socket.as_ref().unwrap().read(&mut msg_resp) {
+        let size = match self.server.as_ref().unwrap().call(&msg, &mut msg_resp) {
             Ok(x) => x,
             Err(_) => {
                 reply.error(libc::EIO);
@@ -216,9 +343,8 @@ impl BentoFilesystem<'_> for HelloFS {
 
     fn bento_getattr(&self, _req: &Request, nodeid: u64, reply: ReplyAttr) {
         let msg = format!("getattr {}", nodeid);
-        let _ = self.socket.as_ref().unwrap().write(msg.as_bytes());
         let mut msg_resp = [0 as u8; 4096];
-        let size = match self.socket.as_ref().unwrap().read(&mut msg_resp) {
+        let size = match self.server.as_ref().unwrap().call(&msg, &mut msg_resp) {
             Ok(x) => x,
             Err(_) => {
                 reply.error(libc::EIO);
@@ -299,9 +425,8 @@ impl BentoFilesystem<'_> for HelloFS {
     ) {
         let name_str = name.to_str().unwrap();
         let msg = format!("lookup {} {}", nodeid, name_str);
-        let _ = self.socket.as_ref().unwrap().write(msg.as_bytes());
         let mut msg_resp = [0 as u8; 4096];
-        let size = match self.socket.as_ref().unwrap().read(&mut msg_resp) {
+        let size = match self.server.as_ref().unwrap().call(&msg, &mut msg_resp) {
             Ok(x) => x,
             Err(_) => {
                 reply.error(libc::EIO);
@@ -384,9 +509,8 @@ impl BentoFilesystem<'_> for HelloFS {
         reply: ReplyData,
     ) {
         let msg = format!("read {} {}", nodeid, offset);
-        let _ = self.socket.as_ref().unwrap().write(msg.as_bytes());
         let mut msg_resp = [0 as u8; 4096];
-        let size = match self.socket.as_ref().unwrap().read(&mut msg_resp) {
+        let size = match self.server.as_ref().unwrap().call(&msg, &mut msg_resp) {
             Ok(x) => x,
             Err(_) => {
                 reply.error(libc::EIO);
@@ -419,9 +543,8 @@ impl BentoFilesystem<'_> for HelloFS {
         reply: ReplyWrite,
     ) {
         let msg = format!("write {} {} {}", nodeid, offset, str::from_utf8(data).unwrap());
-        let _ = self.socket.as_ref().unwrap().write(msg.as_bytes());
         let mut msg_resp = [0 as u8; 4096];
-        let size = match self.socket.as_ref().unwrap().read(&mut msg_resp) {
+        let size = match self.server.as_ref().unwrap().call(&msg, &mut msg_resp) {
             Ok(x) => x,
             Err(_) => {
                 reply.error(libc::EIO);
@@ -457,9 +580,8 @@ impl BentoFilesystem<'_> for HelloFS {
         mut reply: ReplyDirectory,
     ) {
         let msg = format!("readdir {} {}", nodeid, offset);
-        let _ = self.socket.as_ref().unwrap().write(msg.as_bytes());
         let mut msg_resp = [0 as u8; 4096];
-        let size = match self.socket.as_ref().unwrap().read(&mut msg_resp) {
+        let size = match self.server.as_ref().unwrap().call(&msg, &mut msg_resp) {
             Ok(x) => x,
             Err(_) => {
                 reply.error(libc::EIO);
@@ -512,9 +634,8 @@ impl BentoFilesystem<'_> for HelloFS {
         reply: ReplyEmpty,
     ) {
         let msg = format!("fsync");
-        let _ = self.socket.as_ref().unwrap().write(msg.as_bytes());
         let mut msg_resp = [0 as u8; 4096];
-        let size = match self.socket.as_ref().unwrap().read(&mut msg_resp) {
+        let size = match self.server.as_ref().unwrap().call(&msg, &mut msg_resp) {
             Ok(x) => x,
             Err(_) => {
                 reply.error(libc::EIO);
@@ -1,7 +1,5 @@
 extern crate alloc;
-#[macro_use]
 extern crate bento_utils;
-extern crate fuse;
 extern crate capnp;
 extern crate core;
 extern crate time;
@@ -10,16 +8,11 @@ pub mod hello_ll;
 
 use std::env;
 use std::ffi::OsStr;
-use std::path::Path;
-use time::Timespec;
+use std::process;
 
 use hello_ll::HelloFS;
 
-use fuse::*;
-use bento_utils::BentoFilesystem;
-use bento_utils::FuseConnInfo;
-
-impl_filesystem!(HelloFS);
+use bento_utils::fuse;
 
 pub mod hello_capnp {
     include!(concat!(env!("OUT_DIR"), "/src/hello_capnp.rs"));
@@ -27,12 +20,19 @@ pub mod hello_capnp {
 
 fn main() {
     env_logger::init();
-    let fs = HelloFS {
-        socket: None,
-    };
+    let fs = HelloFS::new();
 
     let mountpoint = env::args_os().nth(1).unwrap();
-    let options: Vec::<&OsStr> = Vec::<&OsStr>::new();
-
-    fuse::mount(fs, &mountpoint, &options).unwrap();
+    // Mount options such as "server=10.0.0.2:1234"
+    let mut options: Vec::<&OsStr> = Vec::<&OsStr>::new();
+    let mount_opts = env::args_os().nth(2);
+    if let Some(arg) = mount_opts.as_ref() {
+        options.push(OsStr::new("-o"));
+        options.push(arg);
+    }
+
+    if let Err(e) = bento_utils::options::mount(fs, &mountpoint, &options) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
 }
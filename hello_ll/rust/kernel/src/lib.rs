@@ -30,10 +30,7 @@ pub mod hello_ll;
 use hello_ll::HelloFS;
 use bento_utils::BentoFilesystem;
 
-pub static HELLO_FS: HelloFS = HelloFS {
-    disk: None,
-    diskname: None,
-};
+pub static HELLO_FS: HelloFS = HelloFS::new();
 
 #[no_mangle]
 pub fn rust_main() {
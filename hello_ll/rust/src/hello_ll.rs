@@ -18,6 +18,7 @@ use crate::time;
 //#[cfg(not(feature="user"))]
 //use crate::println;
 
+use alloc::boxed::Box;
 use alloc::string::String;
 use alloc::string::ToString;
 use alloc::vec::Vec;
@@ -38,7 +39,6 @@ use time::Timespec;
 
 pub const PAGE_SIZE: usize = 4096;
 
-static LEN: atomic::AtomicUsize = atomic::AtomicUsize::new(13);
 static HELLO_NAME: &str = "hello";
 
 #[derive(Serialize, Deserialize)]
@@ -47,15 +47,29 @@ pub struct HelloState {
     pub diskname: String, 
 }
 
+impl TransferState for HelloState {
+    const NAME: &'static str = "hello_ll::HelloState";
+    const VERSION: u32 = 1;
+}
+
 pub struct HelloFS {
     pub disk: Option<RwLock<Disk>>,
     pub diskname: Option<String>,
+    pub len: atomic::AtomicUsize,
 }
 
 impl HelloFS {
     const NAME: &'static str = "hello_ll\0";
 
-    fn hello_stat(ino: u64) -> Result<FileAttr, i32> {
+    pub const fn new() -> Self {
+        HelloFS {
+            disk: None,
+            diskname: None,
+            len: atomic::AtomicUsize::new(13),
+        }
+    }
+
+    fn hello_stat(&self, ino: u64) -> Result<FileAttr, i32> {
         if ino != 1 && ino != 2 {
             return Err(-1);
         }
@@ -71,7 +85,7 @@ impl HelloFS {
         };
         let size = match ino {
             1 => 0,
-            2 => LEN.load(atomic::Ordering::SeqCst) as u64,
+            2 => self.len.load(atomic::Ordering::SeqCst) as u64,
             _ => 0,
         };
         Ok(FileAttr {
@@ -98,10 +112,15 @@ impl BentoFilesystem<'_,i32,HelloState> for HelloFS {
         Self::NAME
     }
 
+    fn new_instance(&self) -> Box<Self> {
+        Box::new(HelloFS::new())
+    }
+
     fn bento_init(
         &mut self,
         _req: &Request,
         devname: &OsStr,
+        _opts: &options::MountOptions,
         outarg: &mut FuseConnInfo,
     ) -> Result<(), i32> {
         outarg.proto_major = BENTO_KERNEL_VERSION;
@@ -163,7 +182,7 @@ impl BentoFilesystem<'_,i32,HelloState> for HelloFS {
 
     fn bento_getattr(&self, _req: &Request, nodeid: u64, reply: ReplyAttr) {
         let attr_valid = Timespec::new(1, 999999999);
-        match HelloFS::hello_stat(nodeid) {
+        match self.hello_stat(nodeid) {
             Ok(attr) => reply.attr(&attr_valid, &attr),
             Err(_) => reply.error(libc::ENOENT),
         }
@@ -183,7 +202,7 @@ impl BentoFilesystem<'_,i32,HelloState> for HelloFS {
             let out_nodeid = 2;
             let generation = 0;
             let entry_valid = Timespec::new(1, 999999999);
-            match HelloFS::hello_stat(out_nodeid) {
+            match self.hello_stat(out_nodeid) {
                 Ok(attr) => reply.entry(&entry_valid, &attr, generation),
                 Err(_) => reply.error(libc::ENOENT),
             }
@@ -203,7 +222,7 @@ impl BentoFilesystem<'_,i32,HelloState> for HelloFS {
             reply.error(libc::ENOENT);
             return;
         }
-        let copy_len = LEN.load(atomic::Ordering::SeqCst) - offset as usize;
+        let copy_len = self.len.load(atomic::Ordering::SeqCst) - offset as usize;
 
         let disk = self.disk.as_ref().unwrap().read().unwrap();
         let mut bh = match disk.bread(0) {
@@ -256,7 +275,7 @@ impl BentoFilesystem<'_,i32,HelloState> for HelloFS {
             let write_region = &mut b_slice[offset..offset + copy_size];
             let data_region = &data[..copy_size];
             write_region.copy_from_slice(data_region);
-            LEN.store(total_len, atomic::Ordering::SeqCst);
+            self.len.store(total_len, atomic::Ordering::SeqCst);
         }
 
         bh.mark_buffer_dirty();
@@ -322,7 +341,7 @@ impl BentoFilesystem<'_,i32,HelloState> for HelloFS {
 
     fn bento_update_prepare(&mut self) -> Option<HelloState> {
         let state = HelloState {
-            len: LEN.load(atomic::Ordering::SeqCst),
+            len: self.len.load(atomic::Ordering::SeqCst),
             diskname: self.diskname.as_ref().unwrap().clone(),
         };
         return Some(state);
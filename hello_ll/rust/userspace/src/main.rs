@@ -1,7 +1,5 @@
 extern crate alloc;
-#[macro_use]
 extern crate bento_utils;
-extern crate fuse;
 extern crate core;
 extern crate serde;
 extern crate time;
@@ -10,18 +8,12 @@ pub mod hello_ll;
 
 use std::env;
 use std::ffi::OsStr;
-use std::path::Path;
 use std::sync::RwLock;
-use time::Timespec;
 
 use hello_ll::HelloFS;
 use bento_utils::Disk;
 
-use fuse::*;
-use bento_utils::BentoFilesystem;
-use bento_utils::FuseConnInfo;
-
-impl_filesystem!(HelloFS);
+use bento_utils::fuse;
 
 fn main() {
     env_logger::init();
@@ -32,6 +24,7 @@ fn main() {
     let fs = HelloFS {
         disk: Some(RwLock::new(disk)),
         diskname: Some(disk_name.to_str().unwrap().to_string()),
+        ..HelloFS::new()
     };
 
     let mountpoint = env::args_os().nth(2).unwrap();
@@ -43,5 +36,5 @@ fn main() {
     }
     let options = opts_arr.iter().map(OsStr::new).collect::<Vec<&OsStr>>();
 
-    fuse::mount(fs, &mountpoint, &options).unwrap();
+    bento_utils::options::mount(fs, &mountpoint, &options).unwrap();
 }
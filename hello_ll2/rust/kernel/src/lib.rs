@@ -30,9 +30,7 @@ pub mod hello_ll;
 use hello_ll::HelloFS;
 use bento_utils::BentoFilesystem;
 
-pub static mut HELLO_FS: HelloFS = HelloFS {
-    disk: None
-};
+pub static mut HELLO_FS: HelloFS = HelloFS::new();
 
 #[no_mangle]
 pub fn rust_main() {
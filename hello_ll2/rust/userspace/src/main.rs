@@ -1,7 +1,5 @@
 extern crate alloc;
-#[macro_use]
 extern crate bento_utils;
-extern crate fuse;
 extern crate core;
 extern crate serde;
 extern crate time;
@@ -10,18 +8,12 @@ pub mod hello_ll;
 
 use std::env;
 use std::ffi::OsStr;
-use std::path::Path;
 use std::sync::RwLock;
-use time::Timespec;
 
 use hello_ll::HelloFS;
 use bento_utils::Disk;
 
-use fuse::*;
-use bento_utils::BentoFilesystem;
-use bento_utils::FuseConnInfo;
-
-impl_filesystem!(HelloFS);
+use bento_utils::fuse;
 
 fn main() {
     env_logger::init();
@@ -30,7 +22,8 @@ fn main() {
     let fsname_arg = fsname_arg_str.as_str();
     let disk = Disk::new(disk_name.to_str().unwrap(), 4096);
     let fs = HelloFS {
-        disk: Some(RwLock::new(disk))
+        disk: Some(RwLock::new(disk)),
+        ..HelloFS::new()
     };
 
     let mountpoint = env::args_os().nth(2).unwrap();
@@ -42,5 +35,5 @@ fn main() {
     }
     let options = opts_arr.iter().map(OsStr::new).collect::<Vec<&OsStr>>();
 
-    fuse::mount(fs, &mountpoint, &options).unwrap();
+    bento_utils::options::mount(fs, &mountpoint, &options).unwrap();
 }
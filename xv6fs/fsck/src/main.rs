@@ -0,0 +1,1057 @@
+/*
+* SPDX-License-Identifier: GPL-2.0 OR MIT
+*
+* Copyright (C) 2020 Samantha Miller, Kaiyuan Zhang, Danyang Zhuo, Tom
+     Anderson, Ang Chen, University of Washington
+* Copyright (C) 2006-2018 Frans Kaashoek, Robert Morris, Russ Cox,
+*                      Massachusetts Institute of Technology
+*/
+
+// fsck.xv6fs: check and repair an xv6fs disk image.
+//
+// The image is checked in passes:
+// 1. The superblock layout is checked and committed transactions in the jbd2 log are replayed
+//    with the journal code the userspace file system uses.
+// 2. Every inode is read and the blocks it points to are claimed. Pointers outside the data
+//    area are cleared and blocks claimed twice are reported. Corrupt extended attributes are
+//    dropped.
+// 3. Directories are walked from the root. Every entry must point to an inode in use, and every
+//    name must be found through the htree by its calculate_hash value. Directories whose htree
+//    is broken are rebuilt in their existing blocks.
+// 4. Inodes that can't be reached from the root are freed, and link counts are corrected.
+// 5. The block bitmap is compared against the blocks that were claimed.
+// 6. The free block and free inode counts in the superblock are compared against the blocks
+//    and inodes in use.
+//
+// Problems are printed as they are found. With --report, they are also written to a file as
+// JSON.
+
+extern crate alloc;
+
+#[allow(dead_code)]
+mod xv6fs_htree;
+#[allow(dead_code)]
+mod xv6fs_utils;
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem;
+use std::ops::Range;
+use std::process;
+use std::str;
+use std::sync::Arc;
+
+use bento_utils::Disk;
+use bento_utils::journal::Journal;
+use datablock::DataBlock;
+
+use crate::xv6fs_htree::*;
+use crate::xv6fs_utils::*;
+
+const ROOTINO: u32 = 1;
+
+// Exit codes, as used by e2fsck.
+const EXIT_OK: i32 = 0;
+const EXIT_REPAIRED: i32 = 1;
+const EXIT_UNREPAIRED: i32 = 4;
+const EXIT_ERROR: i32 = 8;
+
+const USAGE: &str = "Usage: fsck.xv6fs [options] fs.img
+
+Check an xv6fs image. Without --repair the image is not modified, and a committed log is
+reported but not replayed.
+
+Options:
+  -r, --repair           replay the log and repair the problems found
+  -j, --report FILE      write a JSON report of the problems found to FILE
+  -q, --quiet            don't print problems
+  -h, --help             print this message
+
+Exit status is 0 if the image is clean, 1 if all problems were repaired, 4 if problems are
+left and 8 if the image could not be checked.";
+
+struct Options {
+    image: String,
+    repair: bool,
+    report: Option<String>,
+    quiet: bool,
+}
+
+fn usage_error(msg: &str) -> ! {
+    eprintln!("fsck.xv6fs: {}", msg);
+    eprintln!("{}", USAGE);
+    process::exit(EXIT_ERROR);
+}
+
+fn parse_args() -> Options {
+    let mut opts = Options {
+        image: String::new(),
+        repair: false,
+        report: None,
+        quiet: false,
+    };
+    let mut positional = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-r" | "--repair" => opts.repair = true,
+            "-j" | "--report" => match args.next() {
+                Some(x) => opts.report = Some(x),
+                None => usage_error(&format!("{} needs a value", arg)),
+            },
+            "-q" | "--quiet" => opts.quiet = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(EXIT_OK);
+            }
+            _ if arg.starts_with('-') => usage_error(&format!("unknown option {}", arg)),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() != 1 {
+        usage_error("expected an image");
+    }
+    opts.image = positional.swap_remove(0);
+    opts
+}
+
+// A problem found in the image.
+struct Problem {
+    kind: &'static str,
+    // Inode the problem is in, or 0.
+    inum: u32,
+    // First block the problem is about, and how many blocks from there, if any.
+    block: Option<(u32, u32)>,
+    detail: String,
+    repaired: bool,
+}
+
+fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl Problem {
+    fn to_json(&self) -> String {
+        let mut out = format!("{{\"kind\":{}", json_str(self.kind));
+        if self.inum != 0 {
+            out.push_str(&format!(",\"inode\":{}", self.inum));
+        }
+        if let Some((block, count)) = self.block {
+            out.push_str(&format!(",\"block\":{},\"count\":{}", block, count));
+        }
+        out.push_str(&format!(",\"detail\":{},\"repaired\":{}}}", json_str(&self.detail), self.repaired));
+        out
+    }
+}
+
+// What pass 2 learned about an inode in use.
+#[derive(Clone, Copy)]
+struct InodeInfo {
+    inode_type: u16,
+    nlink: u16,
+    // Entries naming this inode in reachable directories, not counting "." and "..".
+    refs: u32,
+    // Subdirectories of this directory.
+    subdirs: u32,
+    // For a directory, the directory it was reached from. Zero if it hasn't been reached.
+    parent: u32,
+}
+
+// A directory entry found in a leaf block, and where it was.
+struct FoundEntry {
+    de: Xv6fsDirent,
+    lblock: u32,
+    slot: usize,
+    keep: bool,
+}
+
+struct Fsck {
+    disk: Arc<Disk>,
+    sb: Xv6fsSB,
+    repair: bool,
+    quiet: bool,
+    nmeta: u32,
+    problems: Vec<Problem>,
+    inodes: BTreeMap<u32, InodeInfo>,
+    // Inode that claimed each block, or zero.
+    owner: Vec<u32>,
+}
+
+impl Fsck {
+    fn problem(&mut self, kind: &'static str, inum: u32, block: Option<(u32, u32)>, detail: String, repaired: bool) {
+        if !self.quiet {
+            let mut what = String::new();
+            if inum != 0 {
+                what.push_str(&format!("inode {}: ", inum));
+            }
+            if let Some((block, count)) = block {
+                if count > 1 {
+                    what.push_str(&format!("blocks {}-{}: ", block, block + count - 1));
+                } else {
+                    what.push_str(&format!("block {}: ", block));
+                }
+            }
+            eprintln!("{}{}: {}{}", what, kind, detail, if repaired { " (repaired)" } else { "" });
+        }
+        self.problems.push(Problem {
+            kind: kind,
+            inum: inum,
+            block: block,
+            detail: detail,
+            repaired: repaired,
+        });
+    }
+
+    fn read_block(&self, bno: u32) -> Result<Vec<u8>, libc::c_int> {
+        let bh = self.disk.bread(bno as u64)?;
+        Ok(bh.data().to_vec())
+    }
+
+    fn write_block(&self, bno: u32, data: &[u8]) -> Result<(), libc::c_int> {
+        let mut bh = self.disk.bread(bno as u64)?;
+        bh.data_mut().copy_from_slice(&data[..BSIZE]);
+        bh.mark_buffer_dirty();
+        bh.sync_dirty_buffer();
+        Ok(())
+    }
+
+    fn read_sb(&mut self) -> Result<(), libc::c_int> {
+        let buf = self.read_block(1)?;
+        self.sb.extract_from(&buf[0..mem::size_of::<Xv6fsSB>()]).map_err(|_| libc::EIO)
+    }
+
+    fn write_sb(&self) -> Result<(), libc::c_int> {
+        let mut buf = self.read_block(1)?;
+        self.sb.dump_into(&mut buf[0..mem::size_of::<Xv6fsSB>()]).map_err(|_| libc::EIO)?;
+        self.write_block(1, &buf)
+    }
+
+    fn ipb(&self) -> usize {
+        if self.sb.version == XV6FS_VERSION_ORIG {
+            IPB_V0
+        } else {
+            self.sb.ipb()
+        }
+    }
+
+    // Byte range of the extended attributes kept in inode idx of an inode table block. Empty if
+    // inodes have no room for them.
+    fn inode_xattr_range(&self, idx: usize) -> Range<usize> {
+        if self.sb.version == XV6FS_VERSION_ORIG {
+            return 0..0;
+        }
+        let off = idx * self.sb.inode_size();
+        off + mem::size_of::<Xv6fsInode>()..off + self.sb.inode_size()
+    }
+
+    fn decode_inode(&self, buf: &[u8], idx: usize) -> Result<Xv6fsInode, libc::c_int> {
+        let mut din = Xv6fsInode::new();
+        if self.sb.version == XV6FS_VERSION_ORIG {
+            let len = mem::size_of::<Xv6fsInodeV0>();
+            let mut din_v0 = Xv6fsInodeV0::new();
+            din_v0.extract_from(&buf[idx * len..(idx + 1) * len]).map_err(|_| libc::EIO)?;
+            din.inode_type = din_v0.inode_type;
+            din.major = din_v0.major;
+            din.minor = din_v0.minor;
+            din.nlink = din_v0.nlink;
+            din.size = din_v0.size;
+            din.addrs = din_v0.addrs;
+        } else {
+            let off = idx * self.sb.inode_size();
+            let len = mem::size_of::<Xv6fsInode>();
+            din.extract_from(&buf[off..off + len]).map_err(|_| libc::EIO)?;
+        }
+        Ok(din)
+    }
+
+    fn read_inode(&self, inum: u32) -> Result<Xv6fsInode, libc::c_int> {
+        let ipb = self.ipb();
+        let buf = self.read_block(inum / ipb as u32 + self.sb.inodestart)?;
+        self.decode_inode(&buf, inum as usize % ipb)
+    }
+
+    fn write_inode(&self, inum: u32, din: &Xv6fsInode) -> Result<(), libc::c_int> {
+        let ipb = self.ipb();
+        let idx = inum as usize % ipb;
+        let bno = inum / ipb as u32 + self.sb.inodestart;
+        let mut buf = self.read_block(bno)?;
+        if self.sb.version == XV6FS_VERSION_ORIG {
+            let len = mem::size_of::<Xv6fsInodeV0>();
+            let din_v0 = Xv6fsInodeV0 {
+                inode_type: din.inode_type,
+                major: din.major,
+                minor: din.minor,
+                nlink: din.nlink,
+                size: din.size,
+                addrs: din.addrs,
+            };
+            din_v0.dump_into(&mut buf[idx * len..(idx + 1) * len]).map_err(|_| libc::EIO)?;
+        } else {
+            let off = idx * self.sb.inode_size();
+            let len = mem::size_of::<Xv6fsInode>();
+            din.dump_into(&mut buf[off..off + len]).map_err(|_| libc::EIO)?;
+        }
+        self.write_block(bno, &buf)
+    }
+
+    fn data_block(&self, bno: u32) -> bool {
+        bno >= self.nmeta && bno < self.sb.size
+    }
+
+    fn cell(buf: &[u8], idx: usize) -> u32 {
+        let mut cell = [0; 4];
+        cell.copy_from_slice(&buf[idx * 4..(idx + 1) * 4]);
+        u32::from_ne_bytes(cell)
+    }
+
+    // Disk block holding block fbn of an inode, or zero if it's a hole or the pointer to it is
+    // outside the data area.
+    fn bmap(&self, din: &Xv6fsInode, fbn: u32) -> Result<u32, libc::c_int> {
+        let lookup = |bno: u32, idx: u32| -> Result<u32, libc::c_int> {
+            if !self.data_block(bno) {
+                return Ok(0);
+            }
+            let buf = self.read_block(bno)?;
+            Ok(Self::cell(&buf, idx as usize))
+        };
+        let bno = if fbn < NDIRECT {
+            din.addrs[fbn as usize]
+        } else if fbn - NDIRECT < NINDIRECT {
+            lookup(din.addrs[NDIRECT as usize], fbn - NDIRECT)?
+        } else if fbn < MAXFILE {
+            let idx = fbn - NDIRECT - NINDIRECT;
+            let ind = lookup(din.addrs[NDIRECT as usize + 1], idx / NINDIRECT)?;
+            lookup(ind, idx % NINDIRECT)?
+        } else {
+            0
+        };
+        Ok(if self.data_block(bno) { bno } else { 0 })
+    }
+
+    // Pass 1: check the superblock and replay the log.
+    fn check_sb(&mut self) -> Result<(), String> {
+        self.read_sb().map_err(|_| "can't read the superblock".to_string())?;
+        if self.sb.magic != XV6FS_MAGIC && (self.sb.magic != 0 || self.sb.version >= XV6FS_VERSION_FEATURES) {
+            return Err(format!("bad magic number {:#x}", self.sb.magic));
+        }
+        if self.sb.version > XV6FS_VERSION {
+            return Err(format!("unsupported format version {}", self.sb.version));
+        }
+        // Unknown ro_compat features are refused too, since checking may need to write.
+        let incompat = self.sb.feature_incompat & !XV6FS_FEATURE_INCOMPAT_SUPP;
+        let ro_compat = self.sb.feature_ro_compat & !XV6FS_FEATURE_RO_COMPAT_SUPP;
+        if incompat != 0 || ro_compat != 0 {
+            return Err(format!("unsupported features: incompat {:#x} ro_compat {:#x}", incompat, ro_compat));
+        }
+
+        let (logstart, nlog) = (self.sb.logstart as u64, self.sb.nlog as i32);
+        match Journal::needs_recovery(&self.disk, logstart, nlog, BSIZE as i32) {
+            Err(libc::EINVAL) => {
+                self.problem("log_header", 0, Some((self.sb.logstart, 1)),
+                    "the jbd2 journal superblock is missing or unsupported".to_string(), false);
+            }
+            Err(_) => return Err("can't read the journal superblock".to_string()),
+            Ok(false) => {}
+            Ok(true) if self.repair => {
+                let disk = Arc::clone(&self.disk);
+                let disk2 = Arc::clone(&self.disk);
+                // Dropping the journal right away stops it after recovery.
+                match Journal::new_from_disk(disk, disk2, logstart, nlog, BSIZE as i32) {
+                    Some(_) => {}
+                    None => return Err("can't replay the log".to_string()),
+                }
+                self.problem("log_pending", 0, None, "replayed committed transactions from the log".to_string(), true);
+                self.read_sb().map_err(|_| "can't read the superblock".to_string())?;
+            }
+            Ok(true) => {
+                self.problem("log_pending", 0, None,
+                    "the log has transactions that are not installed, results may be stale".to_string(), false);
+            }
+        }
+
+        if self.sb.version == XV6FS_VERSION_ORIG && self.sb.upgrade_next != 0 {
+            return Err("an inode table upgrade is in progress, mount the file system to finish it".to_string());
+        }
+        if self.sb.feature_incompat & XV6FS_FEATURE_INCOMPAT_INODE_SIZE != 0 {
+            let isize = self.sb.inode_size as usize;
+            if !isize.is_power_of_two() || isize < mem::size_of::<Xv6fsInode>() || isize > BSIZE {
+                return Err(format!("bad inode size {}", isize));
+            }
+        }
+        let ninodeblocks = self.sb.ninodes / self.ipb() as u32 + 1;
+        let nbitmap = self.sb.size / BPB as u32 + 1;
+        let layout_ok = self.sb.logstart == 2
+            && self.sb.inodestart as u64 == self.sb.logstart as u64 + self.sb.nlog as u64
+            && self.sb.bmapstart as u64 == self.sb.inodestart as u64 + ninodeblocks as u64
+            && (self.sb.bmapstart as u64 + nbitmap as u64) < self.sb.size as u64;
+        if !layout_ok {
+            return Err(format!(
+                "bad layout: size {} ninodes {} nlog {} logstart {} inodestart {} bmapstart {}",
+                self.sb.size, self.sb.ninodes, self.sb.nlog, self.sb.logstart, self.sb.inodestart, self.sb.bmapstart
+            ));
+        }
+        self.nmeta = self.sb.bmapstart + nbitmap;
+        if self.sb.nblocks != self.sb.size - self.nmeta {
+            let detail = format!("nblocks is {}, should be {}", self.sb.nblocks, self.sb.size - self.nmeta);
+            if self.repair {
+                self.sb.nblocks = self.sb.size - self.nmeta;
+                self.write_sb().map_err(|_| "can't write the superblock".to_string())?;
+            }
+            let repaired = self.repair;
+            self.problem("superblock_nblocks", 0, Some((1, 1)), detail, repaired);
+        }
+        self.owner = vec![0; self.sb.size as usize];
+        Ok(())
+    }
+
+    // Claim block bno for inode inum. Returns false if the pointer is outside the data area and
+    // should be cleared.
+    fn claim(&mut self, inum: u32, bno: u32) -> bool {
+        if !self.data_block(bno) {
+            let repaired = self.repair;
+            self.problem("bad_block_pointer", inum, Some((bno, 1)), "points outside the data area".to_string(), repaired);
+            return false;
+        }
+        let other = self.owner[bno as usize];
+        if other != 0 {
+            self.problem("duplicate_block", inum, Some((bno, 1)), format!("also used by inode {}", other), false);
+        } else {
+            self.owner[bno as usize] = inum;
+        }
+        true
+    }
+
+    // Claim the blocks listed in indirect block bno, and if depth is 2 the blocks they list.
+    fn claim_indirect(&mut self, inum: u32, bno: u32, depth: u32) -> Result<(), libc::c_int> {
+        let mut buf = self.read_block(bno)?;
+        let mut changed = false;
+        for idx in 0..NINDIRECT as usize {
+            let cell = Self::cell(&buf, idx);
+            if cell == 0 {
+                continue;
+            }
+            if !self.claim(inum, cell) {
+                buf[idx * 4..(idx + 1) * 4].copy_from_slice(&0u32.to_ne_bytes());
+                changed = true;
+            } else if depth > 1 {
+                self.claim_indirect(inum, cell, depth - 1)?;
+            }
+        }
+        if changed && self.repair {
+            self.write_block(bno, &buf)?;
+        }
+        Ok(())
+    }
+
+    // Claim every block of inode inum.
+    fn claim_blocks(&mut self, inum: u32, din: &mut Xv6fsInode) -> Result<bool, libc::c_int> {
+        let mut changed = false;
+        for i in 0..NDIRECT as usize + 2 {
+            let bno = din.addrs[i];
+            if bno == 0 {
+                continue;
+            }
+            if !self.claim(inum, bno) {
+                din.addrs[i] = 0;
+                changed = true;
+            } else if i == NDIRECT as usize {
+                self.claim_indirect(inum, bno, 1)?;
+            } else if i == NDIRECT as usize + 1 {
+                self.claim_indirect(inum, bno, 2)?;
+            }
+        }
+        Ok(changed)
+    }
+
+    // Give back the blocks claimed by inode inum.
+    fn release_blocks(&mut self, inum: u32) {
+        for owner in self.owner.iter_mut() {
+            if *owner == inum {
+                *owner = 0;
+            }
+        }
+    }
+
+    fn clear_inode(&mut self, inum: u32) -> Result<(), libc::c_int> {
+        self.write_inode(inum, &Xv6fsInode::new())?;
+        self.clear_inode_xattrs(inum)
+    }
+
+    fn clear_inode_xattrs(&mut self, inum: u32) -> Result<(), libc::c_int> {
+        let range = self.inode_xattr_range(inum as usize % self.ipb());
+        if range.is_empty() {
+            return Ok(());
+        }
+        let bno = inum / self.ipb() as u32 + self.sb.inodestart;
+        let mut buf = self.read_block(bno)?;
+        for byte in buf[range].iter_mut() {
+            *byte = 0;
+        }
+        self.write_block(bno, &buf)
+    }
+
+    // Check the extended attributes of inode inum, whose inode table block is buf, and claim its
+    // xattr block. Returns true if din was changed.
+    fn check_xattrs(&mut self, inum: u32, din: &mut Xv6fsInode, buf: &[u8], idx: usize) -> Result<bool, libc::c_int> {
+        let mut attrs = Vec::new();
+        let range = self.inode_xattr_range(idx);
+        if !range.is_empty() && xattr_decode(&buf[range], &mut attrs).is_err() {
+            let repaired = self.repair;
+            self.problem("bad_xattr", inum, None, "extended attributes in the inode are corrupt".to_string(), repaired);
+            if self.repair {
+                self.clear_inode_xattrs(inum)?;
+            }
+        }
+
+        let bno = din.xattr;
+        if bno == 0 {
+            return Ok(false);
+        }
+        if !self.claim(inum, bno) {
+            din.xattr = 0;
+            return Ok(true);
+        }
+        let block = self.read_block(bno)?;
+        if xattr_decode(&block, &mut attrs).is_ok() {
+            return Ok(false);
+        }
+        let repaired = self.repair;
+        self.problem("bad_xattr", inum, Some((bno, 1)), "extended attribute block is corrupt".to_string(), repaired);
+        if !self.repair {
+            return Ok(false);
+        }
+        // The block is freed when the bitmap is checked.
+        if self.owner[bno as usize] == inum {
+            self.owner[bno as usize] = 0;
+        }
+        din.xattr = 0;
+        Ok(true)
+    }
+
+    // Pass 2: read every inode and claim its blocks.
+    fn check_inodes(&mut self) -> Result<(), libc::c_int> {
+        let ipb = self.ipb();
+        let mut inum = 0;
+        while inum < self.sb.ninodes {
+            let buf = self.read_block(inum / ipb as u32 + self.sb.inodestart)?;
+            for idx in 0..ipb {
+                if inum >= self.sb.ninodes {
+                    break;
+                }
+                let mut din = self.decode_inode(&buf, idx)?;
+                let this = inum;
+                inum += 1;
+                if this == 0 || din.inode_type == 0 {
+                    continue;
+                }
+                match din.inode_type {
+                    T_DIR | T_FILE | T_DEV | T_LNK | T_BLK | T_FIFO | T_SOCK => {}
+                    x => {
+                        let repaired = self.repair;
+                        self.problem("bad_inode_type", this, None, format!("type {}", x), repaired);
+                        if self.repair {
+                            self.clear_inode(this)?;
+                        }
+                        continue;
+                    }
+                }
+                let mut changed = self.claim_blocks(this, &mut din)?;
+                if self.check_xattrs(this, &mut din, &buf, idx)? {
+                    changed = true;
+                }
+                if changed && self.repair {
+                    self.write_inode(this, &din)?;
+                }
+                self.inodes.insert(this, InodeInfo {
+                    inode_type: din.inode_type,
+                    nlink: din.nlink,
+                    refs: 0,
+                    subdirs: 0,
+                    parent: 0,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Read the leaves of directory inum through its htree. Returns the entries found, and
+    // whether the htree has to be rebuilt for every entry to be found by dirlookup.
+    fn read_htree(&mut self, inum: u32, din: &Xv6fsInode, root: &Htree_root, root_buf: &[u8])
+        -> Result<(Vec<FoundEntry>, bool), libc::c_int>
+    {
+        let hroot_len = mem::size_of::<Htree_root>();
+        let hindex_len = mem::size_of::<Htree_index>();
+        let hentry_len = mem::size_of::<Htree_entry>();
+        let de_len = mem::size_of::<Xv6fsDirent>();
+        let nlblocks = ((din.size + BSIZE as u64 - 1) / BSIZE as u64) as u32;
+        let mut rebuild = false;
+        let mut found = Vec::new();
+
+        let root_max = (BSIZE - hroot_len) / hentry_len;
+        let mut nindex = root.ind_entries as usize;
+        if nindex > root_max {
+            self.problem("htree_index", inum, None, format!("root lists {} index blocks", nindex), false);
+            nindex = root_max;
+            rebuild = true;
+        }
+
+        let mut root_entries = Vec::with_capacity(nindex);
+        for idx in 0..nindex {
+            let mut rie = Htree_entry::new();
+            let off = hroot_len + idx * hentry_len;
+            rie.extract_from(&root_buf[off..off + hentry_len]).map_err(|_| libc::EIO)?;
+            if rie.block == 0 {
+                break;
+            }
+            root_entries.push(rie);
+        }
+
+        // Leaf blocks reachable through each index block, in the order dirlookup searches them.
+        let mut seen = BTreeSet::new();
+        seen.insert(0);
+        let mut index_entries: Vec<Vec<Htree_entry>> = Vec::with_capacity(root_entries.len());
+        let mut max_lblock = 0;
+        for (idx, rie) in root_entries.iter().enumerate() {
+            if idx > 0 && root_entries[idx - 1].name_hash > rie.name_hash {
+                self.problem("htree_order", inum, None, format!("root entry {} is out of order", idx), false);
+                rebuild = true;
+            }
+            let bno = if rie.block < nlblocks && seen.insert(rie.block) { self.bmap(din, rie.block)? } else { 0 };
+            if bno == 0 {
+                self.problem("htree_index", inum, None,
+                    format!("root entry {} points to bad block {}", idx, rie.block), false);
+                rebuild = true;
+                index_entries.push(Vec::new());
+                continue;
+            }
+            max_lblock = std::cmp::max(max_lblock, rie.block);
+            let buf = self.read_block(bno)?;
+            let mut index = Htree_index::new();
+            index.extract_from(&buf[0..hindex_len]).map_err(|_| libc::EIO)?;
+            let index_max = (BSIZE - hindex_len) / hentry_len;
+            if index.entries as usize > index_max {
+                self.problem("htree_index", inum, None,
+                    format!("index block {} lists {} leaves", rie.block, index.entries), false);
+                rebuild = true;
+            }
+            let mut leaves = Vec::new();
+            for lidx in 0..std::cmp::min(index.entries as usize, index_max) {
+                let mut ine = Htree_entry::new();
+                let off = hindex_len + lidx * hentry_len;
+                ine.extract_from(&buf[off..off + hentry_len]).map_err(|_| libc::EIO)?;
+                if ine.block == 0 {
+                    break;
+                }
+                if let Some(prev) = leaves.last() {
+                    let prev: &Htree_entry = prev;
+                    if prev.name_hash > ine.name_hash {
+                        self.problem("htree_order", inum, None,
+                            format!("entry {} of index block {} is out of order", lidx, rie.block), false);
+                        rebuild = true;
+                    }
+                }
+                leaves.push(ine);
+            }
+            index_entries.push(leaves);
+        }
+
+        for (idx, leaves) in index_entries.iter().enumerate() {
+            for ine in leaves {
+                let bno = if ine.block < nlblocks && seen.insert(ine.block) { self.bmap(din, ine.block)? } else { 0 };
+                if bno == 0 {
+                    self.problem("htree_index", inum, None,
+                        format!("index block {} points to bad leaf {}", root_entries[idx].block, ine.block), false);
+                    rebuild = true;
+                    continue;
+                }
+                max_lblock = std::cmp::max(max_lblock, ine.block);
+                let buf = self.read_block(bno)?;
+                for slot in 0..BSIZE / de_len {
+                    let mut de = Xv6fsDirent::new();
+                    de.extract_from(&buf[slot * de_len..(slot + 1) * de_len]).map_err(|_| libc::EIO)?;
+                    if de.inum == 0 {
+                        continue;
+                    }
+                    // The entry must be in the leaf dirlookup would search for its name.
+                    let hash = dirent_hash(&de);
+                    let found_leaf = find_lowerbound(&root_entries, root_entries.len(), hash)
+                        .and_then(|r| {
+                            let l = &index_entries[r];
+                            find_lowerbound(l, l.len(), hash).map(|x| l[x].block)
+                        });
+                    if found_leaf != Some(ine.block) {
+                        self.problem("htree_hash", inum, None,
+                            format!("{} (hash {:#x}) is in the wrong leaf", dirent_name(&de), hash), false);
+                        rebuild = true;
+                    }
+                    found.push(FoundEntry { de: de, lblock: ine.block, slot: slot, keep: true });
+                }
+            }
+        }
+
+        if !root_entries.is_empty() && root.blocks <= max_lblock {
+            self.problem("htree_blocks", inum, None,
+                format!("root says the htree has {} blocks but it uses block {}", root.blocks, max_lblock), false);
+            rebuild = true;
+        }
+        Ok((found, rebuild))
+    }
+
+    // Rewrite directory inum with entries, in the blocks it already has. Returns false if
+    // there isn't enough room.
+    fn rebuild_dir(&mut self, inum: u32, parent: u32, din: &Xv6fsInode, entries: Vec<Xv6fsDirent>)
+        -> Result<bool, libc::c_int>
+    {
+        let blocks = match htree_build(inum, parent, entries) {
+            Ok(x) => x,
+            Err(libc::ENOSPC) => return Ok(false),
+            Err(x) => return Err(x),
+        };
+        let nlblocks = ((din.size + BSIZE as u64 - 1) / BSIZE as u64) as u32;
+        let mut bnos = Vec::with_capacity(nlblocks as usize);
+        for lblock in 0..nlblocks {
+            bnos.push(self.bmap(din, lblock)?);
+        }
+        if blocks.len() > bnos.len() || bnos[..blocks.len()].contains(&0) {
+            return Ok(false);
+        }
+        // dirlink expects blocks past the end of the htree to be zero.
+        let zeroes = vec![0; BSIZE];
+        for (lblock, bno) in bnos.iter().enumerate() {
+            if *bno == 0 {
+                continue;
+            }
+            let data = match blocks.get(lblock) {
+                Some(x) => x,
+                None => &zeroes,
+            };
+            self.write_block(*bno, data)?;
+        }
+        Ok(true)
+    }
+
+    // Check directory inum, reached from parent. Returns the subdirectories to check next.
+    fn check_dir(&mut self, inum: u32, parent: u32) -> Result<Vec<u32>, libc::c_int> {
+        let hroot_len = mem::size_of::<Htree_root>();
+        let de_len = mem::size_of::<Xv6fsDirent>();
+        let din = self.read_inode(inum)?;
+        let root_bno = self.bmap(&din, 0)?;
+        if din.size < hroot_len as u64 || root_bno == 0 {
+            self.problem("dir_empty", inum, None, "directory has no root block".to_string(), false);
+            return Ok(Vec::new());
+        }
+        let mut root_buf = self.read_block(root_bno)?;
+        let mut root = Htree_root::new();
+        root.extract_from(&root_buf[0..hroot_len]).map_err(|_| libc::EIO)?;
+
+        let mut root_changed = false;
+        if root.dot.inum != inum {
+            let repaired = self.repair;
+            self.problem("bad_dot", inum, None, format!("\".\" points to {}", root.dot.inum), repaired);
+            root.dot.inum = inum;
+            root_changed = true;
+        }
+        if root.dotdot.inum != parent {
+            let repaired = self.repair;
+            self.problem("bad_dotdot", inum, None,
+                format!("\"..\" points to {}, should be {}", root.dotdot.inum, parent), repaired);
+            root.dotdot.inum = parent;
+            root_changed = true;
+        }
+
+        let first_problem = self.problems.len();
+        let (mut found, rebuild) = self.read_htree(inum, &din, &root, &root_buf)?;
+
+        let mut names = BTreeSet::new();
+        let mut subdirs = Vec::new();
+        for entry in found.iter_mut() {
+            let name = dirent_name(&entry.de);
+            let child = entry.de.inum;
+            let bad = if name.is_empty() || name == "." || name == ".." {
+                Some(("bad_name", format!("entry named \"{}\"", name)))
+            } else if !names.insert(entry.de.name.to_vec()) {
+                Some(("duplicate_name", format!("{} appears more than once", name)))
+            } else if !self.inodes.contains_key(&child) {
+                Some(("dangling_entry", format!("{} points to free inode {}", name, child)))
+            } else {
+                None
+            };
+            if let Some((kind, detail)) = bad {
+                let repaired = self.repair;
+                self.problem(kind, inum, None, detail, repaired);
+                entry.keep = false;
+                continue;
+            }
+
+            let info = self.inodes.get_mut(&child).unwrap();
+            if info.inode_type == T_DIR {
+                if info.parent != 0 || child == ROOTINO {
+                    let repaired = self.repair;
+                    self.problem("directory_hardlink", inum, None,
+                        format!("{} links to directory {} which is already linked", name, child), repaired);
+                    entry.keep = false;
+                    continue;
+                }
+                info.parent = inum;
+                subdirs.push(child);
+            } else {
+                info.refs += 1;
+            }
+        }
+        self.inodes.get_mut(&inum).unwrap().subdirs = subdirs.len() as u32;
+
+        if !self.repair {
+            return Ok(subdirs);
+        }
+
+        if rebuild {
+            let entries = found.iter()
+                .filter(|e| e.keep)
+                .map(|e| Xv6fsDirent { inum: e.de.inum, name: e.de.name })
+                .collect();
+            if self.rebuild_dir(inum, parent, &din, entries)? {
+                // The rebuild fixed everything read_htree found, and the entries dropped above.
+                for p in self.problems[first_problem..].iter_mut() {
+                    p.repaired = true;
+                }
+                self.problem("htree_rebuilt", inum, None, "rebuilt the directory htree".to_string(), true);
+                return Ok(subdirs);
+            }
+            self.problem("htree_rebuilt", inum, None, "not enough room to rebuild the directory htree".to_string(), false);
+            // Fall back to fixing what can be fixed in place.
+        }
+        if root_changed {
+            root.dump_into(&mut root_buf[0..hroot_len]).map_err(|_| libc::EIO)?;
+            self.write_block(root_bno, &root_buf)?;
+        }
+        for entry in found.iter().filter(|e| !e.keep) {
+            let bno = self.bmap(&din, entry.lblock)?;
+            let mut buf = self.read_block(bno)?;
+            let off = entry.slot * de_len;
+            Xv6fsDirent::new().dump_into(&mut buf[off..off + de_len]).map_err(|_| libc::EIO)?;
+            self.write_block(bno, &buf)?;
+        }
+        Ok(subdirs)
+    }
+
+    // Pass 3: walk the directory tree from the root.
+    fn check_dirs(&mut self) -> Result<(), String> {
+        match self.inodes.get_mut(&ROOTINO) {
+            Some(info) if info.inode_type == T_DIR => info.parent = ROOTINO,
+            _ => return Err("the root inode is not a directory".to_string()),
+        }
+        let mut queue = VecDeque::new();
+        queue.push_back((ROOTINO, ROOTINO));
+        while let Some((inum, parent)) = queue.pop_front() {
+            let subdirs = self.check_dir(inum, parent)
+                .map_err(|x| format!("inode {}: {}", inum, io::Error::from_raw_os_error(x)))?;
+            for child in subdirs {
+                queue.push_back((child, inum));
+            }
+        }
+        Ok(())
+    }
+
+    // Pass 4: free inodes that can't be reached and fix link counts.
+    fn check_links(&mut self) -> Result<(), libc::c_int> {
+        let inums: Vec<u32> = self.inodes.keys().cloned().collect();
+        for inum in inums {
+            let info = self.inodes[&inum];
+            let reached = if info.inode_type == T_DIR { info.parent != 0 } else { info.refs > 0 };
+            if !reached {
+                let repaired = self.repair;
+                self.problem("unreachable_inode", inum, None,
+                    format!("type {} with {} links is not in any directory", info.inode_type, info.nlink),
+                    repaired);
+                if self.repair {
+                    self.clear_inode(inum)?;
+                    self.release_blocks(inum);
+                    self.inodes.remove(&inum);
+                }
+                continue;
+            }
+            let expected = if info.inode_type == T_DIR { 1 + info.subdirs } else { info.refs };
+            if info.nlink as u32 != expected {
+                let repaired = self.repair;
+                self.problem("bad_nlink", inum, None, format!("nlink is {}, should be {}", info.nlink, expected), repaired);
+                if self.repair {
+                    let mut din = self.read_inode(inum)?;
+                    din.nlink = expected as u16;
+                    self.write_inode(inum, &din)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Pass 5: compare the bitmap with the blocks in use.
+    fn check_bitmap(&mut self) -> Result<(), libc::c_int> {
+        // Runs of blocks that are wrongly marked free or in use: (kind, first, count).
+        let mut runs: Vec<(&'static str, u32, u32)> = Vec::new();
+        let mut b = 0;
+        while b < self.sb.size {
+            let bno = bblock(b as usize, &self.sb) as u32;
+            let mut buf = self.read_block(bno)?;
+            let mut changed = false;
+            for bi in 0..BPB as u32 {
+                let block = b + bi;
+                if block >= self.sb.size {
+                    break;
+                }
+                let used = block < self.nmeta || self.owner[block as usize] != 0;
+                let m = 1 << (bi % 8);
+                let marked = buf[bi as usize / 8] & m != 0;
+                if used == marked {
+                    continue;
+                }
+                let kind = if used { "block_not_marked" } else { "block_leaked" };
+                match runs.last_mut() {
+                    Some((k, first, count)) if *k == kind && *first + *count == block => *count += 1,
+                    _ => runs.push((kind, block, 1)),
+                }
+                buf[bi as usize / 8] ^= m;
+                changed = true;
+            }
+            if changed && self.repair {
+                self.write_block(bno, &buf)?;
+            }
+            b += BPB as u32;
+        }
+        for (kind, first, count) in runs {
+            let detail = if kind == "block_leaked" {
+                "marked in use but not used by any inode"
+            } else {
+                "used but marked free"
+            };
+            let repaired = self.repair;
+            self.problem(kind, 0, Some((first, count)), detail.to_string(), repaired);
+        }
+        Ok(())
+    }
+
+    // Pass 6: compare the free counts in the superblock with the blocks and inodes in use.
+    fn check_free_counts(&mut self) -> Result<(), libc::c_int> {
+        if self.sb.version < XV6FS_VERSION_FREE_COUNTS {
+            return Ok(());
+        }
+        let blocks_used = self.owner.iter().filter(|o| **o != 0).count() as u32 + self.nmeta;
+        let nfree = self.sb.size - blocks_used;
+        let nifree = self.sb.ninodes - 1 - self.inodes.len() as u32;
+        if self.sb.nfree == nfree && self.sb.nifree == nifree {
+            return Ok(());
+        }
+        let detail = format!(
+            "free blocks {} and free inodes {}, should be {} and {}",
+            self.sb.nfree, self.sb.nifree, nfree, nifree
+        );
+        if self.repair {
+            self.sb.nfree = nfree;
+            self.sb.nifree = nifree;
+            self.write_sb()?;
+        }
+        let repaired = self.repair;
+        self.problem("superblock_free_counts", 0, Some((1, 1)), detail, repaired);
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<(), String> {
+        self.check_sb()?;
+        let ioerr = |x: libc::c_int| io::Error::from_raw_os_error(x).to_string();
+        self.check_inodes().map_err(ioerr)?;
+        self.check_dirs()?;
+        self.check_links().map_err(ioerr)?;
+        self.check_bitmap().map_err(ioerr)?;
+        self.check_free_counts().map_err(ioerr)?;
+        if self.repair {
+            self.disk.sync_all().map_err(ioerr)?;
+        }
+        Ok(())
+    }
+
+    fn report(&self, image: &str, error: Option<&str>) -> String {
+        let problems: Vec<String> = self.problems.iter().map(|p| p.to_json()).collect();
+        let blocks_used = self.owner.iter().filter(|o| **o != 0).count() as u64 + self.nmeta as u64;
+        format!(
+            "{{\"image\":{},\"error\":{},\"repair\":{},\"clean\":{},\"inodes_used\":{},\"blocks_used\":{},\"problems\":[{}]}}\n",
+            json_str(image),
+            error.map(json_str).unwrap_or_else(|| "null".to_string()),
+            self.repair,
+            error.is_none() && self.problems.is_empty(),
+            self.inodes.len(),
+            blocks_used,
+            problems.join(",")
+        )
+    }
+}
+
+// Name of a directory entry, for messages.
+fn dirent_name(de: &Xv6fsDirent) -> String {
+    let len = de.name.iter().position(|c| *c == 0).unwrap_or(de.name.len());
+    String::from_utf8_lossy(&de.name[..len]).into_owned()
+}
+
+fn main() {
+    let opts = parse_args();
+    if let Err(e) = File::open(&opts.image) {
+        eprintln!("fsck.xv6fs: {}: {}", opts.image, e);
+        process::exit(EXIT_ERROR);
+    }
+
+    let mut fsck = Fsck {
+        disk: Arc::new(Disk::new(&opts.image, BSIZE as u64)),
+        sb: Xv6fsSB::new(),
+        repair: opts.repair,
+        quiet: opts.quiet,
+        nmeta: 0,
+        problems: Vec::new(),
+        inodes: BTreeMap::new(),
+        owner: Vec::new(),
+    };
+
+    let result = fsck.run();
+    if let Err(e) = &result {
+        eprintln!("fsck.xv6fs: {}: {}", opts.image, e);
+    }
+    if let Some(path) = &opts.report {
+        let report = fsck.report(&opts.image, result.as_ref().err().map(|e| e.as_str()));
+        if let Err(e) = File::create(path).and_then(|mut f| f.write_all(report.as_bytes())) {
+            eprintln!("fsck.xv6fs: {}: {}", path, e);
+            process::exit(EXIT_ERROR);
+        }
+    }
+
+    let unrepaired = fsck.problems.iter().filter(|p| !p.repaired).count();
+    let code = if result.is_err() {
+        EXIT_ERROR
+    } else if unrepaired > 0 {
+        EXIT_UNREPAIRED
+    } else if !fsck.problems.is_empty() {
+        EXIT_REPAIRED
+    } else {
+        EXIT_OK
+    };
+    if !opts.quiet {
+        eprintln!(
+            "{}: {} inodes, {} problems, {} left",
+            opts.image,
+            fsck.inodes.len(),
+            fsck.problems.len(),
+            unrepaired
+        );
+    }
+    process::exit(code);
+}
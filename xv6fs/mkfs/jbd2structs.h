@@ -1,91 +0,0 @@
-#include <stdint.h>
-
-#define JBD2_MAGIC_NUMBER 0xc03b3998U /* The first 4 bytes of /dev/random! */
-
-#define JBD2_DESCRIPTOR_BLOCK	1
-#define JBD2_COMMIT_BLOCK	2
-#define JBD2_SUPERBLOCK_V1	3
-#define JBD2_SUPERBLOCK_V2	4
-#define JBD2_REVOKE_BLOCK	5
-
-#define JBD2_FEATURE_COMPAT_CHECKSUM		0x00000001
-
-#define JBD2_FEATURE_INCOMPAT_REVOKE		0x00000001
-#define JBD2_FEATURE_INCOMPAT_64BIT		0x00000002
-#define JBD2_FEATURE_INCOMPAT_ASYNC_COMMIT	0x00000004
-#define JBD2_FEATURE_INCOMPAT_CSUM_V2		0x00000008
-#define JBD2_FEATURE_INCOMPAT_CSUM_V3		0x00000010
-
-/*
- * Checksum types.
- */
-#define JBD2_CRC32_CHKSUM   1
-#define JBD2_MD5_CHKSUM     2
-#define JBD2_SHA1_CHKSUM    3
-#define JBD2_CRC32C_CHKSUM  4
-
-#define JBD2_CRC32_CHKSUM_SIZE 4
-
-
-typedef uint32_t __be32;
-typedef uint32_t __u32;
-typedef uint8_t __u8;
-
-typedef struct journal_header_s
-{
-	__be32		h_magic;
-	__be32		h_blocktype;
-	__be32		h_sequence;
-} journal_header_t;
-
-
-/*
- * The journal superblock.  All fields are in big-endian byte order.
- */
-typedef struct journal_superblock_s
-{
-/* 0x0000 */
-	journal_header_t s_header;
-
-/* 0x000C */
-	/* Static information describing the journal */
-	__be32	s_blocksize;		/* journal device blocksize */
-	__be32	s_maxlen;		/* total blocks in journal file */
-	__be32	s_first;		/* first block of log information */
-
-/* 0x0018 */
-	/* Dynamic information describing the current state of the log */
-	__be32	s_sequence;		/* first commit ID expected in log */
-	__be32	s_start;		/* blocknr of start of log */
-
-/* 0x0020 */
-	/* Error value, as set by jbd2_journal_abort(). */
-	__be32	s_errno;
-
-/* 0x0024 */
-	/* Remaining fields are only valid in a version-2 superblock */
-	__be32	s_feature_compat;	/* compatible feature set */
-	__be32	s_feature_incompat;	/* incompatible feature set */
-	__be32	s_feature_ro_compat;	/* readonly-compatible feature set */
-/* 0x0030 */
-	__u8	s_uuid[16];		/* 128-bit uuid for journal */
-
-/* 0x0040 */
-	__be32	s_nr_users;		/* Nr of filesystems sharing log */
-
-	__be32	s_dynsuper;		/* Blocknr of dynamic superblock copy*/
-
-/* 0x0048 */
-	__be32	s_max_transaction;	/* Limit of journal blocks per trans.*/
-	__be32	s_max_trans_data;	/* Limit of data blocks per trans. */
-
-/* 0x0050 */
-	__u8	s_checksum_type;	/* checksum type */
-	__u8	s_padding2[3];
-	__u32	s_padding[42];
-	__be32	s_checksum;		/* crc32c(superblock) */
-
-/* 0x0100 */
-	__u8	s_users[16*48];		/* ids of all fs'es sharing the log */
-/* 0x0400 */
-} journal_superblock_t;
\ No newline at end of file
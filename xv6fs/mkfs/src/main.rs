@@ -0,0 +1,686 @@
+/*
+* SPDX-License-Identifier: GPL-2.0 OR MIT
+*
+* Copyright (C) 2020 Samantha Miller, Kaiyuan Zhang, Danyang Zhuo, Tom
+     Anderson, Ang Chen, University of Washington
+* Copyright (C) 2006-2018 Frans Kaashoek, Robert Morris, Russ Cox,
+*                      Massachusetts Institute of Technology
+*/
+
+// mkfs.xv6fs: create an xv6fs disk image.
+//
+// The on-disk structures come from xv6fs_utils.rs and xv6fs_htree.rs, which the Makefile copies
+// from ../rust/src, so the image always matches the format the file system reads.
+
+extern crate alloc;
+
+#[allow(dead_code)]
+mod xv6fs_htree;
+#[allow(dead_code)]
+mod xv6fs_utils;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::process;
+use std::str;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use datablock::DataBlock;
+
+use crate::xv6fs_htree::*;
+use crate::xv6fs_utils::*;
+
+// Defaults for images used with the kernel module.
+const FSSIZE: u32 = 3500000;
+const NINODES: u32 = 900000;
+const NLOG: u32 = 262144;
+
+// Defaults for images used with the userspace file system.
+const USER_FSSIZE: u32 = 2000000;
+const USER_NINODES: u32 = 50000;
+const USER_NLOG: u32 = 1024;
+
+const ROOTINO: u32 = 1;
+
+// Leaves room for small extended attributes in the inode.
+const DEF_INODE_SIZE: u32 = 256;
+
+// jbd2 journal superblock, written at the start of the log for the journal in both the
+// kernel and userspace builds.
+// All fields are big endian.
+const JBD2_MAGIC_NUMBER: u32 = 0xc03b3998;
+const JBD2_SUPERBLOCK_V2: u32 = 4;
+const JBD2_FEATURE_COMPAT_CHECKSUM: u32 = 0x1;
+const JBD2_FIRST: u32 = 8;
+
+const USAGE: &str = "Usage: mkfs.xv6fs [options] fs.img [dir]
+
+Create an xv6fs image in fs.img, copying the contents of dir into it if given.
+
+Options:
+  -s, --size BLOCKS      size of the image in blocks (default 3500000)
+  -i, --inodes N         number of inodes (default 900000)
+  -l, --log BLOCKS       number of log blocks (default 262144)
+  -u, --user             image for the userspace file system: defaults of 2000000 blocks,
+                         50000 inodes and 1024 log blocks
+  -F, --format N         on-disk format version to write (default: latest)
+  -I, --inode-size BYTES size of an inode, a power of two from 128 to 4096; the space past
+                         the first 128 bytes holds extended attributes (default 256). Older
+                         formats have fixed-size inodes: 64 bytes in format 0, which xv6fs2
+                         and xv6fs_prov read, and 128 bytes in formats 1 and 2
+  -t, --time SECS        timestamp for inodes not copied from dir (default: now)
+  -L, --label LABEL      volume label, up to 16 bytes
+  -U, --uuid UUID        file system UUID (default: random)
+  -h, --help             print this message";
+
+struct Options {
+    image: String,
+    dir: Option<String>,
+    size: Option<u32>,
+    ninodes: Option<u32>,
+    nlog: Option<u32>,
+    user: bool,
+    version: u32,
+    inode_size: Option<u32>,
+    time: Option<u64>,
+    label: Option<String>,
+    uuid: Option<[u8; 16]>,
+}
+
+fn usage_error(msg: &str) -> ! {
+    eprintln!("mkfs.xv6fs: {}", msg);
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn parse_num<T: str::FromStr>(opt: &str, val: Option<String>) -> T {
+    let val = match val {
+        Some(x) => x,
+        None => usage_error(&format!("{} needs a value", opt)),
+    };
+    match val.parse() {
+        Ok(x) => x,
+        Err(_) => usage_error(&format!("invalid value for {}: {}", opt, val)),
+    }
+}
+
+fn parse_uuid(opt: &str, val: Option<String>) -> [u8; 16] {
+    let val = match val {
+        Some(x) => x,
+        None => usage_error(&format!("{} needs a value", opt)),
+    };
+    let hex: String = val.chars().filter(|c| *c != '-').collect();
+    let mut uuid = [0; 16];
+    if hex.len() != 32 || !hex.is_ascii() {
+        usage_error(&format!("invalid value for {}: {}", opt, val));
+    }
+    for (i, byte) in uuid.iter_mut().enumerate() {
+        *byte = match u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16) {
+            Ok(x) => x,
+            Err(_) => usage_error(&format!("invalid value for {}: {}", opt, val)),
+        };
+    }
+    uuid
+}
+
+// A random version 4 UUID.
+fn random_uuid() -> io::Result<[u8; 16]> {
+    let mut uuid = [0; 16];
+    File::open("/dev/urandom")?.read_exact(&mut uuid)?;
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    Ok(uuid)
+}
+
+fn parse_args() -> Options {
+    let mut opts = Options {
+        image: String::new(),
+        dir: None,
+        size: None,
+        ninodes: None,
+        nlog: None,
+        user: false,
+        version: XV6FS_VERSION,
+        inode_size: None,
+        time: None,
+        label: None,
+        uuid: None,
+    };
+    let mut positional = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-s" | "--size" => opts.size = Some(parse_num(&arg, args.next())),
+            "-i" | "--inodes" => opts.ninodes = Some(parse_num(&arg, args.next())),
+            "-l" | "--log" => opts.nlog = Some(parse_num(&arg, args.next())),
+            "-u" | "--user" => opts.user = true,
+            "-F" | "--format" => opts.version = parse_num(&arg, args.next()),
+            "-I" | "--inode-size" => opts.inode_size = Some(parse_num(&arg, args.next())),
+            "-t" | "--time" => opts.time = Some(parse_num(&arg, args.next())),
+            "-L" | "--label" => opts.label = Some(parse_num(&arg, args.next())),
+            "-U" | "--uuid" => opts.uuid = Some(parse_uuid(&arg, args.next())),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if arg.starts_with('-') => usage_error(&format!("unknown option {}", arg)),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.is_empty() || positional.len() > 2 {
+        usage_error("expected an image and an optional directory");
+    }
+    opts.dir = positional.get(1).cloned();
+    opts.image = positional.swap_remove(0);
+    opts
+}
+
+fn other_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+struct Mkfs {
+    img: File,
+    sb: Xv6fsSB,
+    version: u32,
+    time: u64,
+    // Next inode and block to allocate. Both are handed out in order, like the C mkfs did.
+    freeinode: u32,
+    freeblock: u32,
+    // Inodes of files with more than one link on the host, keyed by host device and inode.
+    links: BTreeMap<(u64, u64), u32>,
+}
+
+impl Mkfs {
+    fn wsect(&self, bno: u32, buf: &[u8]) -> io::Result<()> {
+        self.img.write_all_at(&buf[..BSIZE], bno as u64 * BSIZE as u64)
+    }
+
+    fn rsect(&self, bno: u32, buf: &mut [u8]) -> io::Result<()> {
+        self.img.read_exact_at(&mut buf[..BSIZE], bno as u64 * BSIZE as u64)
+    }
+
+    fn ipb(&self) -> usize {
+        if self.version == XV6FS_VERSION_ORIG {
+            IPB_V0
+        } else {
+            self.sb.ipb()
+        }
+    }
+
+    // Block and byte offset of inode inum.
+    fn inode_pos(&self, inum: u32) -> (u32, usize) {
+        let ipb = self.ipb();
+        let isize = BSIZE / ipb;
+        let bno = inum / ipb as u32 + self.sb.inodestart;
+        (bno, (inum as usize % ipb) * isize)
+    }
+
+    fn rinode(&self, inum: u32) -> io::Result<Xv6fsInode> {
+        let (bno, off) = self.inode_pos(inum);
+        let mut buf = vec![0; BSIZE];
+        self.rsect(bno, &mut buf)?;
+        let mut din = Xv6fsInode::new();
+        if self.version == XV6FS_VERSION_ORIG {
+            let mut din_v0 = Xv6fsInodeV0::new();
+            let len = mem::size_of::<Xv6fsInodeV0>();
+            din_v0.extract_from(&buf[off..off + len]).map_err(|_| other_error("bad inode".to_string()))?;
+            din.inode_type = din_v0.inode_type;
+            din.major = din_v0.major;
+            din.minor = din_v0.minor;
+            din.nlink = din_v0.nlink;
+            din.size = din_v0.size;
+            din.addrs = din_v0.addrs;
+        } else {
+            let len = mem::size_of::<Xv6fsInode>();
+            din.extract_from(&buf[off..off + len]).map_err(|_| other_error("bad inode".to_string()))?;
+        }
+        Ok(din)
+    }
+
+    // Write an inode. Version 0 images only keep the fields that exist in that format.
+    fn winode(&self, inum: u32, din: &Xv6fsInode) -> io::Result<()> {
+        let (bno, off) = self.inode_pos(inum);
+        let mut buf = vec![0; BSIZE];
+        self.rsect(bno, &mut buf)?;
+        if self.version == XV6FS_VERSION_ORIG {
+            let din_v0 = Xv6fsInodeV0 {
+                inode_type: din.inode_type,
+                major: din.major,
+                minor: din.minor,
+                nlink: din.nlink,
+                size: din.size,
+                addrs: din.addrs,
+            };
+            let len = mem::size_of::<Xv6fsInodeV0>();
+            din_v0.dump_into(&mut buf[off..off + len]).map_err(|_| other_error("bad inode".to_string()))?;
+        } else {
+            let len = mem::size_of::<Xv6fsInode>();
+            din.dump_into(&mut buf[off..off + len]).map_err(|_| other_error("bad inode".to_string()))?;
+        }
+        self.wsect(bno, &buf)
+    }
+
+    fn ialloc(&mut self, i_type: u16, mode: u32) -> io::Result<u32> {
+        if self.freeinode >= self.sb.ninodes {
+            return Err(other_error("out of inodes".to_string()));
+        }
+        let inum = self.freeinode;
+        self.freeinode += 1;
+
+        let mut din = Xv6fsInode::new();
+        din.inode_type = i_type;
+        din.nlink = 1;
+        din.mode = mode;
+        din.atime = self.time;
+        din.mtime = self.time;
+        din.ctime = self.time;
+        din.crtime = self.time;
+        self.winode(inum, &din)?;
+        Ok(inum)
+    }
+
+    fn balloc(&mut self) -> io::Result<u32> {
+        if self.freeblock >= self.sb.size {
+            return Err(other_error("out of blocks".to_string()));
+        }
+        let bno = self.freeblock;
+        self.freeblock += 1;
+        Ok(bno)
+    }
+
+    // Look up entry idx of indirect block bno, allocating a block for it if it's empty.
+    fn indirect(&mut self, bno: u32, idx: usize) -> io::Result<u32> {
+        let mut buf = vec![0; BSIZE];
+        self.rsect(bno, &mut buf)?;
+        let mut cell = [0; 4];
+        cell.copy_from_slice(&buf[idx * 4..(idx + 1) * 4]);
+        let mut addr = u32::from_le_bytes(cell);
+        if addr == 0 {
+            addr = self.balloc()?;
+            buf[idx * 4..(idx + 1) * 4].copy_from_slice(&addr.to_le_bytes());
+            self.wsect(bno, &buf)?;
+        }
+        Ok(addr)
+    }
+
+    // Return the disk block holding block fbn of the file, allocating it if needed.
+    fn bmap(&mut self, din: &mut Xv6fsInode, fbn: u32) -> io::Result<u32> {
+        if fbn >= MAXFILE {
+            return Err(other_error("file too large".to_string()));
+        }
+        if fbn < NDIRECT {
+            if din.addrs[fbn as usize] == 0 {
+                din.addrs[fbn as usize] = self.balloc()?;
+            }
+            return Ok(din.addrs[fbn as usize]);
+        }
+
+        let idx = fbn - NDIRECT;
+        if idx < NINDIRECT {
+            if din.addrs[NDIRECT as usize] == 0 {
+                din.addrs[NDIRECT as usize] = self.balloc()?;
+            }
+            return self.indirect(din.addrs[NDIRECT as usize], idx as usize);
+        }
+
+        let idx = idx - NINDIRECT;
+        if din.addrs[NDIRECT as usize + 1] == 0 {
+            din.addrs[NDIRECT as usize + 1] = self.balloc()?;
+        }
+        let ind = self.indirect(din.addrs[NDIRECT as usize + 1], (idx / NINDIRECT) as usize)?;
+        self.indirect(ind, (idx % NINDIRECT) as usize)
+    }
+
+    // Append data to the end of inode inum.
+    fn iappend(&mut self, inum: u32, data: &[u8]) -> io::Result<()> {
+        let mut din = self.rinode(inum)?;
+        let mut off = din.size;
+        let mut p = data;
+        let mut buf = vec![0; BSIZE];
+        while !p.is_empty() {
+            let fbn = (off / BSIZE as u64) as u32;
+            let bno = self.bmap(&mut din, fbn)?;
+            let boff = (off % BSIZE as u64) as usize;
+            let n = std::cmp::min(p.len(), BSIZE - boff);
+            self.rsect(bno, &mut buf)?;
+            buf[boff..boff + n].copy_from_slice(&p[..n]);
+            self.wsect(bno, &buf)?;
+            off += n as u64;
+            p = &p[n..];
+        }
+        din.size = off;
+        self.winode(inum, &din)
+    }
+
+    // Write the contents of directory inum, whose parent is parent_inum.
+    fn write_dir(&mut self, path: &Path, inum: u32, parent_inum: u32, entries: Vec<Xv6fsDirent>) -> io::Result<()> {
+        let blocks = htree_build(inum, parent_inum, entries).map_err(|x| {
+            other_error(format!("{}: {}", path.display(), io::Error::from_raw_os_error(x)))
+        })?;
+        for block in blocks {
+            self.iappend(inum, &block)?;
+        }
+        Ok(())
+    }
+
+    // Allocate an inode with the mode, ownership and times of a host file.
+    fn ialloc_host(&mut self, i_type: u16, md: &fs::Metadata) -> io::Result<u32> {
+        let inum = self.ialloc(i_type, md.mode() & 0o7777)?;
+        let mut din = self.rinode(inum)?;
+        din.uid = md.uid();
+        din.gid = md.gid();
+        din.atime = md.atime() as u64;
+        din.atime_nsec = md.atime_nsec() as u32;
+        din.mtime = md.mtime() as u64;
+        din.mtime_nsec = md.mtime_nsec() as u32;
+        din.ctime = md.ctime() as u64;
+        din.ctime_nsec = md.ctime_nsec() as u32;
+        self.winode(inum, &din)?;
+        Ok(inum)
+    }
+
+    // Copy the contents of host directory path into directory inum.
+    fn add_dir(&mut self, path: &Path, inum: u32, parent_inum: u32) -> io::Result<()> {
+        let mut names: Vec<_> = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+        names.sort_by_key(|e| e.file_name());
+
+        let mut entries = Vec::with_capacity(names.len());
+        let dirent = |name: &OsStr, inum: u32| {
+            let mut de = Xv6fsDirent::new();
+            de.inum = inum;
+            de.name[..name.len()].copy_from_slice(name.as_bytes());
+            de
+        };
+        for entry in names {
+            let name = entry.file_name();
+            let child_path = entry.path();
+            if name.as_bytes().len() > DIRSIZ as usize {
+                return Err(other_error(format!("{}: name too long", child_path.display())));
+            }
+            let md = fs::symlink_metadata(&child_path)?;
+            let ft = md.file_type();
+
+            let child = if ft.is_dir() {
+                let child = self.ialloc_host(T_DIR, &md)?;
+                self.add_dir(&child_path, child, inum)?;
+                let mut din = self.rinode(inum)?;
+                din.nlink += 1;
+                self.winode(inum, &din)?;
+                child
+            } else if ft.is_file() {
+                if md.nlink() > 1 {
+                    if let Some(&child) = self.links.get(&(md.dev(), md.ino())) {
+                        let mut din = self.rinode(child)?;
+                        din.nlink += 1;
+                        self.winode(child, &din)?;
+                        entries.push(dirent(&name, child));
+                        continue;
+                    }
+                }
+                let child = self.ialloc_host(T_FILE, &md)?;
+                let mut file = File::open(&child_path)?;
+                let mut buf = vec![0; BSIZE];
+                loop {
+                    let n = file.read(&mut buf)?;
+                    if n == 0 {
+                        break;
+                    }
+                    self.iappend(child, &buf[..n])?;
+                }
+                if md.nlink() > 1 {
+                    self.links.insert((md.dev(), md.ino()), child);
+                }
+                child
+            } else if ft.is_symlink() {
+                // Symlinks hold the length of the target including a terminating nul, then the
+                // target, as written by bento_symlink.
+                let target = fs::read_link(&child_path)?;
+                let target = target.as_os_str().as_bytes();
+                let child = self.ialloc_host(T_LNK, &md)?;
+                let mut data = Vec::with_capacity(mem::size_of::<u32>() + target.len());
+                data.extend_from_slice(&(target.len() as u32 + 1).to_ne_bytes());
+                data.extend_from_slice(target);
+                self.iappend(child, &data)?;
+                child
+            } else if ft.is_char_device() || ft.is_block_device() || ft.is_fifo() || ft.is_socket() {
+                let i_type = if ft.is_char_device() {
+                    T_DEV
+                } else if ft.is_block_device() {
+                    T_BLK
+                } else if ft.is_fifo() {
+                    T_FIFO
+                } else {
+                    T_SOCK
+                };
+                let child = self.ialloc_host(i_type, &md)?;
+                if i_type == T_DEV || i_type == T_BLK {
+                    let rdev = md.rdev();
+                    let (major, minor) = match rdev_split(rdev as u32) {
+                        Some(x) if rdev >> 32 == 0 => x,
+                        _ => {
+                            return Err(other_error(format!("{}: device number too large",
+                                                           child_path.display())));
+                        }
+                    };
+                    let mut din = self.rinode(child)?;
+                    din.major = major;
+                    din.minor = minor;
+                    self.winode(child, &din)?;
+                }
+                child
+            } else {
+                eprintln!("mkfs.xv6fs: skipping {}: unsupported file type", child_path.display());
+                continue;
+            };
+            entries.push(dirent(&name, child));
+        }
+
+        self.write_dir(path, inum, parent_inum, entries)
+    }
+
+    // Mark blocks 0..used as in use in the bitmap.
+    fn write_bitmap(&self, used: u32) -> io::Result<()> {
+        let mut buf = vec![0; BSIZE];
+        let mut b = 0;
+        while b < used {
+            let n = std::cmp::min(BPB as u32, used - b);
+            for byte in buf.iter_mut() {
+                *byte = 0;
+            }
+            for i in 0..n as usize {
+                buf[i / 8] |= 1 << (i % 8);
+            }
+            self.wsect(bblock(b as usize, &self.sb) as u32, &buf)?;
+            b += BPB as u32;
+        }
+        Ok(())
+    }
+
+    fn write_journal_sb(&self) -> io::Result<()> {
+        let mut buf = vec![0; BSIZE];
+        let fields = [
+            (0, JBD2_MAGIC_NUMBER),
+            (4, JBD2_SUPERBLOCK_V2),
+            (12, BSIZE as u32),
+            (16, self.sb.nlog),
+            (20, JBD2_FIRST),
+            (24, 1),
+            (36, JBD2_FEATURE_COMPAT_CHECKSUM),
+            (64, 1),
+        ];
+        for (off, val) in fields.iter() {
+            buf[*off..*off + 4].copy_from_slice(&val.to_be_bytes());
+        }
+        self.wsect(self.sb.logstart, &buf)
+    }
+
+    fn write_sb(&self) -> io::Result<()> {
+        let mut buf = vec![0; BSIZE];
+        self.sb.dump_into(&mut buf[..mem::size_of::<Xv6fsSB>()])
+            .map_err(|_| other_error("bad superblock".to_string()))?;
+        self.wsect(1, &buf)
+    }
+
+    fn run(&mut self, dir: Option<&Path>) -> io::Result<()> {
+        // Every block is zero unless written below.
+        let meta = self.img.metadata()?;
+        if meta.file_type().is_file() {
+            self.img.set_len(self.sb.size as u64 * BSIZE as u64)?;
+        } else {
+            let zeroes = vec![0; BSIZE];
+            for bno in 0..self.sb.size {
+                self.wsect(bno, &zeroes)?;
+            }
+        }
+        self.write_sb()?;
+
+        let rootino = self.ialloc(T_DIR, 0o755)?;
+        assert_eq!(rootino, ROOTINO);
+        match dir {
+            Some(dir) => self.add_dir(dir, rootino, rootino)?,
+            None => self.write_dir(Path::new("/"), rootino, rootino, Vec::new())?,
+        }
+
+        self.write_bitmap(self.freeblock)?;
+        // Every block and inode past the ones handed out is free.
+        self.sb.nfree = self.sb.size - self.freeblock;
+        self.sb.nifree = self.sb.ninodes - self.freeinode;
+        if self.version >= XV6FS_VERSION_FREE_COUNTS {
+            self.sb.state = XV6FS_STATE_CLEAN;
+        }
+        self.write_sb()?;
+        self.write_journal_sb()?;
+        self.img.sync_all()
+    }
+}
+
+fn main() {
+    let opts = parse_args();
+    if opts.version > XV6FS_VERSION {
+        usage_error(&format!("format version {} is newer than {}", opts.version, XV6FS_VERSION));
+    }
+    let (def_size, def_ninodes, def_nlog) = if opts.user {
+        (USER_FSSIZE, USER_NINODES, USER_NLOG)
+    } else {
+        (FSSIZE, NINODES, NLOG)
+    };
+    let size = opts.size.unwrap_or(def_size);
+    let ninodes = opts.ninodes.unwrap_or(def_ninodes);
+    let nlog = opts.nlog.unwrap_or(def_nlog);
+    if ninodes < 2 {
+        usage_error("need at least 2 inodes");
+    }
+    if nlog <= JBD2_FIRST {
+        usage_error(&format!("the jbd2 journal needs more than {} log blocks", JBD2_FIRST));
+    }
+    let label = opts.label.clone().unwrap_or_default();
+    if label.len() > XV6FS_LABEL_LEN {
+        usage_error(&format!("label {} is longer than {} bytes", label, XV6FS_LABEL_LEN));
+    }
+    if opts.version < XV6FS_VERSION_FEATURES && (opts.label.is_some() || opts.uuid.is_some()) {
+        usage_error(&format!("format version {} has no label or uuid", opts.version));
+    }
+
+    // Older formats have fixed-size inodes.
+    let inode_size = if opts.version >= XV6FS_VERSION_FEATURES {
+        let inode_size = opts.inode_size.unwrap_or(DEF_INODE_SIZE);
+        if !inode_size.is_power_of_two() || (inode_size as usize) < mem::size_of::<Xv6fsInode>()
+            || inode_size as usize > BSIZE {
+            usage_error(&format!("invalid inode size {}", inode_size));
+        }
+        inode_size
+    } else {
+        let fixed = if opts.version == XV6FS_VERSION_ORIG {
+            mem::size_of::<Xv6fsInodeV0>()
+        } else {
+            mem::size_of::<Xv6fsInode>()
+        } as u32;
+        match opts.inode_size {
+            Some(x) if x != fixed => {
+                usage_error(&format!("format version {} only has {}-byte inodes", opts.version, fixed))
+            }
+            _ => fixed,
+        }
+    };
+
+    let ipb = BSIZE as u32 / inode_size;
+    let ninodeblocks = ninodes / ipb + 1;
+    let nbitmap = size / BPB as u32 + 1;
+    let nmeta = 2u64 + nlog as u64 + ninodeblocks as u64 + nbitmap as u64;
+    if nmeta >= size as u64 {
+        usage_error(&format!("{} blocks is too small, the metadata alone needs {}", size, nmeta + 1));
+    }
+    let nmeta = nmeta as u32;
+
+    let time = match opts.time {
+        Some(x) => x,
+        None => SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+    };
+
+    let uuid = match opts.uuid {
+        Some(x) => x,
+        None => match random_uuid() {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("mkfs.xv6fs: can't generate a uuid: {}", e);
+                process::exit(1);
+            }
+        },
+    };
+
+    let img = match OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&opts.image) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("mkfs.xv6fs: {}: {}", opts.image, e);
+            process::exit(1);
+        }
+    };
+
+    let mut mkfs = Mkfs {
+        img: img,
+        sb: Xv6fsSB {
+            size: size,
+            nblocks: size - nmeta,
+            ninodes: ninodes,
+            nlog: nlog,
+            logstart: 2,
+            inodestart: 2 + nlog,
+            bmapstart: 2 + nlog + ninodeblocks,
+            version: opts.version,
+            ..Xv6fsSB::new()
+        },
+        version: opts.version,
+        time: time,
+        freeinode: ROOTINO,
+        freeblock: nmeta,
+        links: BTreeMap::new(),
+    };
+    if opts.version >= XV6FS_VERSION_FEATURES {
+        mkfs.sb.magic = XV6FS_MAGIC;
+        mkfs.sb.uuid = uuid;
+        mkfs.sb.label[..label.len()].copy_from_slice(label.as_bytes());
+        if inode_size as usize != mem::size_of::<Xv6fsInode>() {
+            mkfs.sb.feature_incompat = XV6FS_FEATURE_INCOMPAT_INODE_SIZE;
+            mkfs.sb.inode_size = inode_size;
+        }
+        println!("uuid {}", Uuid(&mkfs.sb.uuid));
+    }
+
+    println!(
+        "nmeta {} (boot, super, log blocks {} inode blocks {}, bitmap blocks {}) blocks {} total {}",
+        nmeta, nlog, ninodeblocks, nbitmap, size - nmeta, size
+    );
+
+    if let Err(e) = mkfs.run(opts.dir.as_ref().map(Path::new)) {
+        eprintln!("mkfs.xv6fs: {}", e);
+        process::exit(1);
+    }
+}
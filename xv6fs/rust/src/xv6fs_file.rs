@@ -7,14 +7,22 @@
  *                      Massachusetts Institute of Technology
  */
 
+#[cfg(not(feature = "user"))]
+use crate::fuse;
 #[cfg(not(feature = "user"))]
 use crate::std;
+#[cfg(not(feature = "user"))]
+use crate::time;
 
 use crate::xv6fs_utils::*;
 use crate::xv6fs_ll::*;
 
+use fuse::FileType;
+
 use std::sync::RwLock;
 
+use time::Timespec;
+
 pub struct CachedInode<'a> {
     pub idx: usize,
     pub inum: u32,
@@ -54,6 +62,14 @@ pub struct InodeInternal {
     pub nlink: u16,
     pub size: u64,
     pub addrs: [u32; NDIRECT as usize + 2],
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub xattr: u32,
+    pub atime: Timespec,
+    pub mtime: Timespec,
+    pub ctime: Timespec,
+    pub crtime: Timespec,
 }
 
 impl InodeInternal {
@@ -66,6 +82,34 @@ impl InodeInternal {
             nlink: 0,
             size: 0,
             addrs: [0; NDIRECT as usize + 2],
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            xattr: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            crtime: Timespec { sec: 0, nsec: 0 },
+        }
+    }
+    // The file type to report for the inode.
+    pub fn kind(&self) -> FileType {
+        match self.inode_type {
+            T_DIR => FileType::Directory,
+            T_LNK => FileType::Symlink,
+            T_DEV => FileType::CharDevice,
+            T_BLK => FileType::BlockDevice,
+            T_FIFO => FileType::NamedPipe,
+            T_SOCK => FileType::Socket,
+            _ => FileType::RegularFile,
+        }
+    }
+
+    // The device number to report for the inode, which is 0 unless it is a device.
+    pub fn rdev(&self) -> u32 {
+        match self.inode_type {
+            T_DEV | T_BLK => rdev_join(self.major, self.minor),
+            _ => 0,
         }
     }
 }
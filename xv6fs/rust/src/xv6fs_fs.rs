@@ -7,6 +7,8 @@
 *                      Massachusetts Institute of Technology
 */
 
+#[cfg(not(feature = "user"))]
+use crate::bento_utils;
 #[cfg(not(feature = "user"))]
 use crate::fuse;
 #[cfg(not(feature = "user"))]
@@ -22,14 +24,14 @@ use alloc::collections::btree_map::BTreeMap;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
-use core::cmp::min;
+use core::cmp::{max, min};
 use core::mem;
-use core::str;
-use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::sync::atomic::Ordering;
 
 use datablock::DataBlock;
 
-use fuse::{FileAttr, FileType};
+use bento_utils::errors;
+use fuse::FileAttr;
 
 use crate::xv6fs_file::*;
 use crate::xv6fs_htree::*;
@@ -39,18 +41,15 @@ use crate::xv6fs_utils::*;
 #[cfg(not(feature = "user"))]
 use bento::kernel::journal::*;
 #[cfg(feature = "user")]
-use crate::xv6fs_log::*;
+use bento_utils::journal::*;
 
 use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::AsRawFd;
 use std::sync::*;
 
 use time::Timespec;
 
-static LAST_BLOCK: AtomicUsize = AtomicUsize::new(0);
-static LAST_INODE: AtomicUsize = AtomicUsize::new(0);
-static FIRST_I_LOOP: AtomicBool = AtomicBool::new(true);
-
 impl Xv6FileSystem {
     // Read xv6 superblock from disk
     fn readsb(&mut self) -> Result<(), libc::c_int> {
@@ -60,6 +59,304 @@ impl Xv6FileSystem {
         let b_slice = bh.data();
         sb.extract_from(&b_slice[0..mem::size_of::<Xv6fsSB>()])
             .map_err(|_| libc::EIO)?;
+        if !sb.is_valid() {
+            println!("xv6fs: can't find an xv6fs file system");
+            return Err(libc::EINVAL);
+        }
+        if sb.version > XV6FS_VERSION {
+            println!("xv6fs: unsupported file system version {}", sb.version);
+            return Err(libc::EINVAL);
+        }
+        let incompat = sb.feature_incompat & !XV6FS_FEATURE_INCOMPAT_SUPP;
+        if incompat != 0 {
+            println!("xv6fs: unsupported incompat features {:#x}", incompat);
+            return Err(libc::EINVAL);
+        }
+        let ro_compat = sb.feature_ro_compat & !XV6FS_FEATURE_RO_COMPAT_SUPP;
+        if ro_compat != 0 {
+            println!("xv6fs: unsupported ro_compat features {:#x}, mounting read-only", ro_compat);
+            self.read_only.store(true, Ordering::SeqCst);
+        }
+        let xattr = sb.feature_ro_compat & XV6FS_FEATURE_RO_COMPAT_XATTR != 0;
+        self.xattr_feature.store(xattr, Ordering::SeqCst);
+        return Ok(());
+    }
+
+    // Write the in-memory superblock back to disk
+    fn writesb(&self, handle: &Handle) -> Result<(), libc::c_int> {
+        let sb = self.sb.as_ref().unwrap();
+        let disk = self.disk.as_ref().unwrap();
+        let mut bh = disk.bread(1)?;
+        handle.get_write_access(&bh);
+        let b_slice = bh.data_mut();
+        sb.dump_into(&mut b_slice[0..mem::size_of::<Xv6fsSB>()])
+            .map_err(|_| libc::EIO)?;
+        handle.journal_write(&mut bh);
+        return Ok(());
+    }
+
+    // Change the free counts with f and write them to the superblock as part of handle, so they
+    // commit together with the bitmap or inode change they account for.
+    fn update_free_counts<F>(&self, handle: &Handle, f: F) -> Result<(), libc::c_int>
+    where
+        F: FnOnce(&mut FreeCounts) -> Result<(), libc::c_int>,
+    {
+        let mut counts = self.free_counts.as_ref().unwrap().write().map_err(|_| libc::EIO)?;
+        f(&mut counts)?;
+
+        let disk = self.disk.as_ref().unwrap();
+        let mut bh = disk.bread(1)?;
+        handle.get_write_access(&bh);
+        let b_slice = bh.data_mut();
+        let mut sb = Xv6fsSB::new();
+        sb.extract_from(&b_slice[0..mem::size_of::<Xv6fsSB>()])
+            .map_err(|_| libc::EIO)?;
+        sb.nfree = counts.nfree;
+        sb.nifree = counts.nifree;
+        sb.dump_into(&mut b_slice[0..mem::size_of::<Xv6fsSB>()])
+            .map_err(|_| libc::EIO)?;
+        handle.journal_write(&mut bh);
+        return Ok(());
+    }
+
+    // Set the xattr feature in the superblock as part of handle, before the first extended
+    // attribute is written. File systems that never had one stay mountable by older drivers.
+    fn set_xattr_feature(&self, handle: &Handle) -> Result<(), libc::c_int> {
+        if self.xattr_feature.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        // update_free_counts changes the same buffer, so both hold the free_counts lock.
+        let _counts = self.free_counts.as_ref().unwrap().write().map_err(|_| libc::EIO)?;
+        if self.xattr_feature.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let disk = self.disk.as_ref().unwrap();
+        let mut bh = disk.bread(1)?;
+        handle.get_write_access(&bh);
+        let b_slice = bh.data_mut();
+        let mut sb = Xv6fsSB::new();
+        sb.extract_from(&b_slice[0..mem::size_of::<Xv6fsSB>()])
+            .map_err(|_| libc::EIO)?;
+        sb.feature_ro_compat |= XV6FS_FEATURE_RO_COMPAT_XATTR;
+        sb.dump_into(&mut b_slice[0..mem::size_of::<Xv6fsSB>()])
+            .map_err(|_| libc::EIO)?;
+        handle.journal_write(&mut bh);
+        self.xattr_feature.store(true, Ordering::SeqCst);
+        return Ok(());
+    }
+
+    // Count the free blocks in the bitmap and the free inodes in the inode table.
+    fn count_free(&self) -> Result<FreeCounts, libc::c_int> {
+        let sb = self.sb.as_ref().unwrap();
+        let disk = self.disk.as_ref().unwrap();
+        let mut counts = FreeCounts { nfree: 0, nifree: 0 };
+
+        let mut b = 0;
+        while b < sb.size as usize {
+            let bh = disk.bread(bblock(b, &sb) as u64)?;
+            let bitmap_slice = bh.data();
+            for bi in 0..min(BPB, sb.size as usize - b) {
+                if bitmap_slice[bi / 8] & (1 << (bi % 8)) == 0 {
+                    counts.nfree += 1;
+                }
+            }
+            b += BPB;
+        }
+
+        let inode_len = mem::size_of::<Xv6fsInode>();
+        let ipb = sb.ipb();
+        let mut inum = 1;
+        while inum < sb.ninodes as usize {
+            let bh = disk.bread(iblock(inum, &sb) as u64)?;
+            let data_slice = bh.data();
+            let block_end = min(sb.ninodes as usize, inum - inum % ipb + ipb);
+            while inum < block_end {
+                let inode_offset = (inum % ipb) * sb.inode_size();
+                let mut dinode = Xv6fsInode::new();
+                dinode
+                    .extract_from(&data_slice[inode_offset..inode_offset + inode_len])
+                    .map_err(|_| libc::EIO)?;
+                if dinode.inode_type == 0 {
+                    counts.nifree += 1;
+                }
+                inum += 1;
+            }
+        }
+        return Ok(counts);
+    }
+
+    // Load the free counts from the superblock. The counts are rebuilt if the file system wasn't
+    // cleanly unmounted or predates them.
+    fn load_free_counts(&mut self) -> Result<(), libc::c_int> {
+        let counts = {
+            let sb = self.sb.as_ref().unwrap();
+            let clean = sb.version >= XV6FS_VERSION_FREE_COUNTS
+                && sb.state == XV6FS_STATE_CLEAN
+                && sb.nfree <= sb.size
+                && sb.nifree < sb.ninodes;
+            if clean {
+                FreeCounts { nfree: sb.nfree, nifree: sb.nifree }
+            } else {
+                println!("xv6fs: not cleanly unmounted, rebuilding free counts");
+                self.count_free()?
+            }
+        };
+
+        {
+            let sb = self.sb.as_mut().unwrap();
+            sb.nfree = counts.nfree;
+            sb.nifree = counts.nifree;
+        }
+        self.free_counts = Some(RwLock::new(counts));
+        return Ok(());
+    }
+
+    // Mark the file system as mounted read-write and bring the superblock up to the current
+    // version. Until it is marked clean again, the free counts are rebuilt on the next mount.
+    fn mark_mounted(&mut self) -> Result<(), libc::c_int> {
+        let now = current_time();
+        {
+            let sb = self.sb.as_mut().unwrap();
+            sb.version = XV6FS_VERSION;
+            sb.magic = XV6FS_MAGIC;
+            sb.state = XV6FS_STATE_DIRTY;
+            sb.mnt_count = sb.mnt_count.wrapping_add(1);
+            sb.mount_time = now.sec as u64;
+        }
+        let handle = self.log.as_ref().unwrap().begin_op(1)?;
+        self.writesb(&handle)
+    }
+
+    // Whether nothing may be written, because the file system is mounted read-only or is in the
+    // errors state and its journal has been aborted.
+    fn writes_blocked(&self) -> bool {
+        return errors::read_only() || self.read_only.load(Ordering::SeqCst);
+    }
+
+    // Write the free counts to the superblock and mark the file system as cleanly unmounted.
+    pub fn mark_clean(&mut self) -> Result<(), libc::c_int> {
+        if self.log.is_none() || self.free_counts.is_none() || self.writes_blocked() {
+            return Ok(());
+        }
+        let counts = *self.free_counts.as_ref().unwrap().read().map_err(|_| libc::EIO)?;
+        {
+            let sb = self.sb.as_mut().unwrap();
+            sb.nfree = counts.nfree;
+            sb.nifree = counts.nifree;
+            sb.state = XV6FS_STATE_CLEAN;
+            // Set on disk since the mount by the first extended attribute written.
+            if self.xattr_feature.load(Ordering::SeqCst) {
+                sb.feature_ro_compat |= XV6FS_FEATURE_RO_COMPAT_XATTR;
+            }
+        }
+        let handle = self.log.as_ref().unwrap().begin_op(1)?;
+        self.writesb(&handle)
+    }
+
+    // Convert a version 0 inode table to the current inode format.
+    //
+    // Current inodes are twice the size of version 0 inodes, so the converted table holds half
+    // as many inodes in the same blocks and every inode past that must be free. Blocks are
+    // converted from the end of the table towards the start. New block b is built from the
+    // second half of old block b / 2 if b is odd and the first half if it's even, so the old
+    // blocks that are still needed are never overwritten. Each transaction records how many
+    // blocks are left in the superblock so an interrupted conversion resumes on the next mount.
+    fn upgrade_inodes(&mut self) -> Result<(), libc::c_int> {
+        let (inodestart, ninodes, mut next) = {
+            let sb = self.sb.as_ref().unwrap();
+            (sb.inodestart as usize, sb.ninodes as usize, sb.upgrade_next as usize)
+        };
+        let inode_blocks = self.sb.as_ref().unwrap().bmapstart as usize - inodestart;
+        let new_ninodes = min(ninodes, inode_blocks * IPB);
+        let disk = Arc::clone(self.disk.as_ref().unwrap());
+        let v0_len = mem::size_of::<Xv6fsInodeV0>();
+        let inode_len = mem::size_of::<Xv6fsInode>();
+
+        if next == 0 {
+            // Not started yet, so make sure nothing is lost by shrinking the table.
+            let mut inum = new_ninodes;
+            while inum < ninodes {
+                let bh = disk.bread((inum / IPB_V0 + inodestart) as u64)?;
+                let data_slice = bh.data();
+                let block_end = min(ninodes, inum - inum % IPB_V0 + IPB_V0);
+                while inum < block_end {
+                    let inode_offset = (inum % IPB_V0) * v0_len;
+                    let mut old_inode = Xv6fsInodeV0::new();
+                    old_inode
+                        .extract_from(&data_slice[inode_offset..inode_offset + v0_len])
+                        .map_err(|_| libc::EIO)?;
+                    if old_inode.inode_type != 0 {
+                        println!("xv6fs: can't upgrade, inode {} is past the end of the new inode table", inum);
+                        return Err(libc::ENOSPC);
+                    }
+                    inum += 1;
+                }
+            }
+            next = (new_ninodes + IPB - 1) / IPB;
+        }
+        println!("xv6fs: upgrading inode table, {} blocks left", next);
+
+        let now = current_time();
+        while next > 0 {
+            let log = self.log.as_ref().unwrap();
+            let handle = log.begin_op(MAXOPBLOCKS as u32)?;
+            let mut converted = 0;
+            while next > 0 && converted < MAXOPBLOCKS - 2 {
+                let b = next - 1;
+                let mut new_block: Vec<u8> = vec![0; BSIZE];
+                {
+                    let bh = disk.bread((b / 2 + inodestart) as u64)?;
+                    let data_slice = bh.data();
+                    for i in 0..IPB {
+                        let old_offset = ((b % 2) * IPB + i) * v0_len;
+                        let mut old_inode = Xv6fsInodeV0::new();
+                        old_inode
+                            .extract_from(&data_slice[old_offset..old_offset + v0_len])
+                            .map_err(|_| libc::EIO)?;
+
+                        let mut new_inode = Xv6fsInode::new();
+                        if old_inode.inode_type != 0 {
+                            new_inode.inode_type = old_inode.inode_type;
+                            new_inode.major = old_inode.major;
+                            new_inode.minor = old_inode.minor;
+                            new_inode.nlink = old_inode.nlink;
+                            new_inode.size = old_inode.size;
+                            new_inode.addrs.copy_from_slice(&old_inode.addrs);
+                            new_inode.mode = match old_inode.inode_type {
+                                T_DIR => 0o755,
+                                T_LNK => 0o777,
+                                _ => 0o644,
+                            };
+                            new_inode.atime = now.sec as u64;
+                            new_inode.mtime = now.sec as u64;
+                            new_inode.ctime = now.sec as u64;
+                            new_inode.crtime = now.sec as u64;
+                        }
+                        new_inode
+                            .dump_into(&mut new_block[i * inode_len..(i + 1) * inode_len])
+                            .map_err(|_| libc::EIO)?;
+                    }
+                }
+
+                let mut bh = disk.bread((b + inodestart) as u64)?;
+                handle.get_write_access(&bh);
+                bh.data_mut().copy_from_slice(new_block.as_slice());
+                handle.journal_write(&mut bh);
+
+                next -= 1;
+                converted += 1;
+            }
+
+            {
+                let sb = self.sb.as_mut().unwrap();
+                sb.upgrade_next = next as u32;
+                if next == 0 {
+                    sb.ninodes = new_ninodes as u32;
+                    sb.version = XV6FS_VERSION_INODE_ATTRS;
+                }
+            }
+            self.writesb(&handle)?;
+        }
         return Ok(());
     }
 
@@ -101,12 +398,22 @@ impl Xv6FileSystem {
     // Allocate a block on disk, using a slightly different alloc strategy from xv6.
     // xv6 scans from 0th block and allocates the first available block, we scan from the latest used block since last boot.
     fn balloc(&self, handle: &Handle) -> Result<u32, libc::c_int> {
+        // Take the block from the free count first, so a full disk fails without scanning the
+        // bitmap.
+        self.update_free_counts(handle, |counts| {
+            if counts.nfree == 0 {
+                return Err(libc::ENOSPC);
+            }
+            counts.nfree -= 1;
+            return Ok(());
+        })?;
+
         let sb = self.sb.as_ref().unwrap();
         let fs_size = sb.size;
         let mut allocated_block = None;
 
         // Bitmap operations on bitmap blocks
-        let most_recent = LAST_BLOCK.load(Ordering::SeqCst);
+        let most_recent = self.last_block.load(Ordering::SeqCst);
         let mut first = true;
         // last_segment is the bitmap block ID and block_offset is the offset for 'most_recent'
         let last_segment = most_recent - most_recent % BPB;
@@ -150,7 +457,7 @@ impl Xv6FileSystem {
             }
             // extract new block ID x
             if let Some(x) = allocated_block {
-                LAST_BLOCK.store(x as usize, Ordering::SeqCst);
+                self.last_block.store(x as usize, Ordering::SeqCst);
                 self.bzero(x as usize, &handle)?;
                 return Ok(x);
             }
@@ -163,7 +470,10 @@ impl Xv6FileSystem {
                 first = false;
             }
         }
-        return Err(libc::EIO);
+        // The free count was too high. It stays decremented, and is rebuilt on the next mount
+        // after an unclean unmount.
+        println!("xv6fs: free block count is wrong, no free block in the bitmap");
+        return Err(libc::ENOSPC);
     }
 
     fn bfree(&self, block_id: usize, handle: &Handle) -> Result<(), libc::c_int> {
@@ -191,12 +501,16 @@ impl Xv6FileSystem {
         // Write buffer
         handle.journal_write(&mut bh);
 
-        return Ok(());
+        return self.update_free_counts(handle, |counts| {
+            counts.nfree += 1;
+            return Ok(());
+        });
     }
 
-    pub fn iinit(&mut self) {
-        if self.readsb().is_err() {
+    pub fn iinit(&mut self) -> Result<(), libc::c_int> {
+        if let Err(x) = self.readsb() {
             println!("Unable to read super block from disk.");
+            return Err(x);
         }
 
         let mut inode_vec: Vec<RwLock<Inode>> = Vec::with_capacity(NINODE);
@@ -214,28 +528,59 @@ impl Xv6FileSystem {
         if self.log.is_none() {
             let disk_ref = Arc::clone(self.disk.as_ref().unwrap());
             let disk_ref2 = Arc::clone(self.disk.as_ref().unwrap());
-            let log = Journal::new_from_disk(disk_ref, disk_ref2, sb.logstart as u64, sb.nlog as i32, BSIZE as i32).unwrap();
+            let log = Journal::new_from_disk(disk_ref, disk_ref2, sb.logstart as u64, sb.nlog as i32, BSIZE as i32)
+                .ok_or(libc::EIO)?;
             self.log = Some(log);
         }
+
+        if sb.version == XV6FS_VERSION_ORIG {
+            self.upgrade_inodes()?;
+        }
+        self.load_free_counts()?;
+        if !self.read_only.load(Ordering::SeqCst) {
+            self.mark_mounted()?;
+        }
+        let sb = self.sb.as_ref().unwrap();
+        println!(
+            "xv6fs: uuid {}, label \"{}\", mount count {}",
+            Uuid(&sb.uuid),
+            core::str::from_utf8(sb.label()).unwrap_or("?"),
+            sb.mnt_count
+            );
         println!(
-            "sb: size {}, nblocks {}, ninodes {}, nlog {}, logstart {} inodestart {}, bmap start {}",
+            "sb: size {}, nblocks {}, ninodes {}, nlog {}, logstart {} inodestart {}, bmap start {}, free blocks {}, free inodes {}",
             sb.size,
             sb.nblocks,
             sb.ninodes,
             sb.nlog,
             sb.logstart,
             sb.inodestart,
-            sb.bmapstart
+            sb.bmapstart,
+            sb.nfree,
+            sb.nifree
             );
+        return Ok(());
     }
 
     pub fn ialloc<'a>(&'a self, i_type: u16, handle: &Handle) -> Result<CachedInode<'a>, libc::c_int> {
+        // Take the inode from the free count first, so running out of inodes fails without
+        // scanning the inode table.
+        self.update_free_counts(handle, |counts| {
+            if counts.nifree == 0 {
+                return Err(libc::ENOSPC);
+            }
+            counts.nifree -= 1;
+            return Ok(());
+        })?;
+
         let sb = self.sb.as_ref().unwrap();
         let num_inodes = sb.ninodes;
 
-        let most_recent = LAST_INODE.load(Ordering::SeqCst);
+        let ipb = sb.ipb();
+        let isize = sb.inode_size();
+        let most_recent = self.last_inode.load(Ordering::SeqCst);
         let mut first = true;
-        let last_segment = most_recent - most_recent % IPB;
+        let last_segment = most_recent - most_recent % ipb;
         let mut block_inum = last_segment;
 
         while first || block_inum < last_segment {
@@ -243,9 +588,9 @@ impl Xv6FileSystem {
             let disk = self.disk.as_ref().unwrap();
             let iblock_new = iblock(block_inum, &sb) as u64;
             /* TODO: not actually correct for reusing blocks */
-            let is_first_loop = FIRST_I_LOOP.load(Ordering::SeqCst);
-            let curr_most_recent = LAST_INODE.load(Ordering::SeqCst);
-            let curr_last_segment = curr_most_recent - curr_most_recent % IPB;
+            let is_first_loop = self.first_i_loop.load(Ordering::SeqCst);
+            let curr_most_recent = self.last_inode.load(Ordering::SeqCst);
+            let curr_last_segment = curr_most_recent - curr_most_recent % ipb;
             let new_blk = (iblock_new > iblock(curr_last_segment, &sb) as u64) && is_first_loop;
             if new_blk {
                 let mut bh = disk.getblk(iblock_new)?;
@@ -255,44 +600,47 @@ impl Xv6FileSystem {
                 if new_blk {
                     data_slice.fill(0);
                 }
-                for inum_idx in (block_inum % IPB)..IPB {
+                for inum_idx in (block_inum % ipb)..ipb {
                     let inum = block_inum + inum_idx;
                     if inum == 0 {
                         continue;
                     }
+                    if inum >= num_inodes as usize {
+                        break;
+                    }
                     // Get the specific inode offset
-                    let inode_offset = (inum as usize % IPB) * mem::size_of::<Xv6fsInode>();
+                    let inode_offset = (inum as usize % ipb) * isize;
 
-                    let inode_slice =
-                        &mut data_slice[inode_offset..inode_offset + mem::size_of::<Xv6fsInode>()];
+                    let inode_slice = &mut data_slice[inode_offset..inode_offset + isize];
 
                     let mut dinode = Xv6fsInode::new();
-                    dinode.extract_from(inode_slice).map_err(|_| libc::EIO)?;
+                    dinode
+                        .extract_from(&inode_slice[..mem::size_of::<Xv6fsInode>()])
+                        .map_err(|_| libc::EIO)?;
                     // Check if inode is free
                     if dinode.inode_type == 0 {
-                        dinode.major = 0;
-                        dinode.minor = 0;
-                        dinode.size = 0;
-                        for addr_mut in dinode.addrs.iter_mut() {
-                            *addr_mut = 0;
-                        }
+                        dinode = Xv6fsInode::new();
                         dinode.inode_type = i_type;
                         dinode.nlink = 1;
-                        dinode.dump_into(inode_slice).map_err(|_| libc::EIO)?;
+                        // Clear the extended attributes left in the inode by its last user.
+                        inode_slice.fill(0);
+                        dinode
+                            .dump_into(&mut inode_slice[..mem::size_of::<Xv6fsInode>()])
+                            .map_err(|_| libc::EIO)?;
                         locked_bh.set_buffer_uptodate();
                         core::mem::drop(locked_bh);
                         handle.journal_write(&mut bh);
                         if !first || inum > curr_most_recent {
-                            LAST_INODE.store(inum as usize, Ordering::SeqCst);
+                            self.last_inode.store(inum as usize, Ordering::SeqCst);
                         }
                         return self.iget(inum as u64);
                     }
                 }
-                block_inum += IPB;
+                block_inum += ipb;
                 if block_inum >= num_inodes as usize {
                     block_inum = 0;
                     first = false;
-                    FIRST_I_LOOP.store(false, Ordering::SeqCst);
+                    self.first_i_loop.store(false, Ordering::SeqCst);
                 }
             } else {
                 let mut bh = disk.bread(iblock_new)?;
@@ -301,46 +649,51 @@ impl Xv6FileSystem {
                 if new_blk {
                     data_slice.fill(0);
                 }
-                for inum_idx in (block_inum % IPB)..IPB {
+                for inum_idx in (block_inum % ipb)..ipb {
                     let inum = block_inum + inum_idx;
                     if inum == 0 {
                         continue;
                     }
+                    if inum >= num_inodes as usize {
+                        break;
+                    }
                     // Get the specific inode offset
-                    let inode_offset = (inum as usize % IPB) * mem::size_of::<Xv6fsInode>();
+                    let inode_offset = (inum as usize % ipb) * isize;
 
-                    let inode_slice =
-                        &mut data_slice[inode_offset..inode_offset + mem::size_of::<Xv6fsInode>()];
+                    let inode_slice = &mut data_slice[inode_offset..inode_offset + isize];
 
                     let mut dinode = Xv6fsInode::new();
-                    dinode.extract_from(inode_slice).map_err(|_| libc::EIO)?;
+                    dinode
+                        .extract_from(&inode_slice[..mem::size_of::<Xv6fsInode>()])
+                        .map_err(|_| libc::EIO)?;
                     // Check if inode is free
                     if dinode.inode_type == 0 {
-                        dinode.major = 0;
-                        dinode.minor = 0;
-                        dinode.size = 0;
-                        for addr_mut in dinode.addrs.iter_mut() {
-                            *addr_mut = 0;
-                        }
+                        dinode = Xv6fsInode::new();
                         dinode.inode_type = i_type;
                         dinode.nlink = 1;
-                        dinode.dump_into(inode_slice).map_err(|_| libc::EIO)?;
+                        // Clear the extended attributes left in the inode by its last user.
+                        inode_slice.fill(0);
+                        dinode
+                            .dump_into(&mut inode_slice[..mem::size_of::<Xv6fsInode>()])
+                            .map_err(|_| libc::EIO)?;
                         handle.journal_write(&mut bh);
                         if !first || inum > curr_most_recent {
-                            LAST_INODE.store(inum as usize, Ordering::SeqCst);
+                            self.last_inode.store(inum as usize, Ordering::SeqCst);
                         }
                         return self.iget(inum as u64);
                     }
                 }
-                block_inum += IPB;
+                block_inum += ipb;
                 if block_inum >= num_inodes as usize {
                     block_inum = 0;
                     first = false;
-                    FIRST_I_LOOP.store(false, Ordering::SeqCst);
+                    self.first_i_loop.store(false, Ordering::SeqCst);
                 }
             };
         }
-        return Err(libc::EIO);
+        // The free count was too high, as in balloc.
+        println!("xv6fs: free inode count is wrong, no free inode in the inode table");
+        return Err(libc::ENOSPC);
     }
 
     pub fn iupdate(&self, internals: &InodeInternal, inum: u32, handle: &Handle) -> Result<(), libc::c_int> {
@@ -352,7 +705,7 @@ impl Xv6FileSystem {
         let data_slice = bh.data_mut();
 
         // Get the specific inode offset
-        let inode_offset = (inum as usize % IPB) * mem::size_of::<Xv6fsInode>();
+        let inode_offset = (inum as usize % sb.ipb()) * sb.inode_size();
         let inode_slice =
             &mut data_slice[inode_offset..inode_offset + mem::size_of::<Xv6fsInode>()];
 
@@ -366,6 +719,18 @@ impl Xv6FileSystem {
         disk_inode.nlink = internals.nlink;
         disk_inode.size = internals.size;
         disk_inode.addrs.copy_from_slice(&internals.addrs);
+        disk_inode.mode = internals.mode as u32;
+        disk_inode.uid = internals.uid;
+        disk_inode.gid = internals.gid;
+        disk_inode.xattr = internals.xattr;
+        disk_inode.atime = internals.atime.sec as u64;
+        disk_inode.atime_nsec = internals.atime.nsec as u32;
+        disk_inode.mtime = internals.mtime.sec as u64;
+        disk_inode.mtime_nsec = internals.mtime.nsec as u32;
+        disk_inode.ctime = internals.ctime.sec as u64;
+        disk_inode.ctime_nsec = internals.ctime.nsec as u32;
+        disk_inode.crtime = internals.crtime.sec as u64;
+        disk_inode.crtime_nsec = internals.crtime.nsec as u32;
         disk_inode.dump_into(inode_slice).map_err(|_| libc::EIO)?;
 
         handle.journal_write(&mut bh);
@@ -376,14 +741,14 @@ impl Xv6FileSystem {
         let mut final_idx = None;
 
         let icache = self.ilock_cache.as_ref().unwrap();
-        let mut map = self.icache_map.as_ref().unwrap().write().unwrap();
+        let mut map = self.icache_map.as_ref().unwrap().write().map_err(|_| libc::EIO)?;
         if map.contains_key(&inum) {
-            let idx = map.get(&inum).unwrap();
-            let inode_lock = icache.get(*idx).unwrap();
-            let inode = inode_lock.read().unwrap();
+            let idx = map.get(&inum).ok_or(libc::EIO)?;
+            let inode_lock = icache.get(*idx).ok_or(libc::EIO)?;
+            let inode = inode_lock.read().map_err(|_| libc::EIO)?;
             let disk = self.disk.as_ref().unwrap();
             let dev_id = disk.as_raw_fd();
-            let mut inode_nref = inode.nref.write().unwrap();
+            let mut inode_nref = inode.nref.write().map_err(|_| libc::EIO)?;
             if *inode_nref > 0 && inode.dev == dev_id as u32 && inode.inum == inum as u32 {
                 *inode_nref += 1;
 
@@ -401,14 +766,14 @@ impl Xv6FileSystem {
             };
             let disk = self.disk.as_ref().unwrap();
             let dev_id = disk.as_raw_fd();
-            if final_idx.is_none() && *inode.nref.read().unwrap() == 0 {
+            if final_idx.is_none() && *inode.nref.read().map_err(|_| libc::EIO)? == 0 {
                 {
                     let mut new_inode_int = inode.internals.write().map_err(|_| libc::EIO)?;
                     new_inode_int.valid = 0;
                 }
                 inode.dev = dev_id as u32;
                 inode.inum = inum as u32;
-                *inode.nref.write().unwrap() = 1;
+                *inode.nref.write().map_err(|_| libc::EIO)? = 1;
                 final_idx = Some(idx);
                 map.insert(inum, idx);
                 break;
@@ -442,7 +807,7 @@ impl Xv6FileSystem {
                 let data_slice = bh.data();
 
                 // Get the specific inode offset
-                let inode_offset = (inum as usize % IPB) * mem::size_of::<Xv6fsInode>();
+                let inode_offset = (inum as usize % sb.ipb()) * sb.inode_size();
 
                 let inode_slice =
                     &data_slice[inode_offset..inode_offset + mem::size_of::<Xv6fsInode>()];
@@ -458,6 +823,14 @@ impl Xv6FileSystem {
                 internals.nlink = disk_inode.nlink;
                 internals.size = disk_inode.size;
                 internals.addrs.copy_from_slice(&disk_inode.addrs);
+                internals.mode = disk_inode.mode as u16;
+                internals.uid = disk_inode.uid;
+                internals.gid = disk_inode.gid;
+                internals.xattr = disk_inode.xattr;
+                internals.atime = Timespec::new(disk_inode.atime as i64, disk_inode.atime_nsec as i32);
+                internals.mtime = Timespec::new(disk_inode.mtime as i64, disk_inode.mtime_nsec as i32);
+                internals.ctime = Timespec::new(disk_inode.ctime as i64, disk_inode.ctime_nsec as i32);
+                internals.crtime = Timespec::new(disk_inode.crtime as i64, disk_inode.crtime_nsec as i32);
                 internals.valid = 1;
                 if internals.inode_type == 0 {
                     return Err(libc::EIO);
@@ -472,27 +845,37 @@ impl Xv6FileSystem {
         {
             let inode_guard = self.ilock(inode.idx, &icache, inode.inum)?;
             let mut internals = inode_guard.internals.write().map_err(|_| libc::EIO)?;
-            if internals.valid != 0 && internals.nlink == 0 {
+            // An unlinked inode is freed on its last put, unless nothing may be written. Then it
+            // stays allocated until fsck frees it.
+            if internals.valid != 0 && internals.nlink == 0 && !self.writes_blocked() {
                 let r;
                 {
                     let dinode_lock = icache.get(inode.idx).ok_or(libc::EIO)?;
                     let dinode = dinode_lock.read().map_err(|_| { libc::EIO })?;
-                    r = *dinode.nref.read().unwrap();
+                    r = *dinode.nref.read().map_err(|_| libc::EIO)?;
                 }
                 if r == 1 {
-                    let handle = self.log.as_ref().unwrap().begin_op(MAXOPBLOCKS as u32);
+                    let handle = self.log.as_ref().unwrap().begin_op(MAXOPBLOCKS as u32)?;
                     self.itrunc(inode, &mut internals, &handle)?;
+                    if internals.xattr != 0 {
+                        self.bfree(internals.xattr as usize, &handle)?;
+                        internals.xattr = 0;
+                    }
                     internals.inode_type = 0;
                     self.iupdate(&internals, inode.inum, &handle)?;
+                    self.update_free_counts(&handle, |counts| {
+                        counts.nifree += 1;
+                        return Ok(());
+                    })?;
                     internals.valid = 0;
                 }
             }
         }
 
         let dinode_lock = icache.get(inode.idx).ok_or(libc::EIO)?;
-        let mut map = self.icache_map.as_ref().unwrap().write().unwrap();
+        let mut map = self.icache_map.as_ref().unwrap().write().map_err(|_| libc::EIO)?;
         let dinode = dinode_lock.read().map_err(|_| {libc::EIO})?;
-        let mut dinode_nref = dinode.nref.write().unwrap();
+        let mut dinode_nref = dinode.nref.write().map_err(|_| libc::EIO)?;
         *dinode_nref -= 1;
         if *dinode_nref == 0 {
             map.remove(&(inode.inum as u64));
@@ -512,7 +895,12 @@ impl Xv6FileSystem {
             if *addr == 0 {
                 let h = match handle {
                     Some(_) => handle.unwrap(),
-                    None => new_tx.get_or_insert_with(|| self.log.as_ref().unwrap().begin_op(2)),
+                    None => {
+                        if new_tx.is_none() {
+                            new_tx = Some(self.log.as_ref().unwrap().begin_op(3)?);
+                        }
+                        new_tx.as_ref().unwrap()
+                    }
                 };
                 return self.balloc(h).map(|blk_id| {
                     *addr = blk_id;
@@ -529,7 +917,12 @@ impl Xv6FileSystem {
             if *ind_blk_id == 0 {
                 let h = match handle {
                     Some(_) => handle.unwrap(),
-                    None => new_tx.get_or_insert_with(|| self.log.as_ref().unwrap().begin_op(5)),
+                    None => {
+                        if new_tx.is_none() {
+                            new_tx = Some(self.log.as_ref().unwrap().begin_op(6)?);
+                        }
+                        new_tx.as_ref().unwrap()
+                    }
                 };
                 self.balloc(h).map(|blk_id| {
                     *ind_blk_id = blk_id;
@@ -549,7 +942,12 @@ impl Xv6FileSystem {
                 // need to allocate blk
                 let h = match handle {
                     Some(_) => handle.unwrap(),
-                    None => new_tx.get_or_insert_with(|| self.log.as_ref().unwrap().begin_op(3)),
+                    None => {
+                        if new_tx.is_none() {
+                            new_tx = Some(self.log.as_ref().unwrap().begin_op(4)?);
+                        }
+                        new_tx.as_ref().unwrap()
+                    }
                 };
                 h.get_write_access(&bh);
                 let b_data = bh.data_mut();
@@ -574,7 +972,12 @@ impl Xv6FileSystem {
             if *dind_blk_id == 0 {
                 let h = match handle {
                     Some(_) => handle.unwrap(),
-                    None => new_tx.get_or_insert_with(|| self.log.as_ref().unwrap().begin_op(2)),
+                    None => {
+                        if new_tx.is_none() {
+                            new_tx = Some(self.log.as_ref().unwrap().begin_op(3)?);
+                        }
+                        new_tx.as_ref().unwrap()
+                    }
                 };
                 self.balloc(h).map(|blk_id| {
                     *dind_blk_id = blk_id;
@@ -594,7 +997,12 @@ impl Xv6FileSystem {
             if cell == 0 {
                 let h = match handle {
                     Some(_) => handle.unwrap(),
-                    None => new_tx.get_or_insert_with(|| self.log.as_ref().unwrap().begin_op(6)),
+                    None => {
+                        if new_tx.is_none() {
+                            new_tx = Some(self.log.as_ref().unwrap().begin_op(7)?);
+                        }
+                        new_tx.as_ref().unwrap()
+                    }
                 };
                 h.get_write_access(&bh);
                 let b_data = bh.data_mut();
@@ -619,7 +1027,12 @@ impl Xv6FileSystem {
             if dcell == 0 {
                 let h = match handle {
                     Some(_) => handle.unwrap(),
-                    None => new_tx.get_or_insert_with(|| self.log.as_ref().unwrap().begin_op(3)),
+                    None => {
+                        if new_tx.is_none() {
+                            new_tx = Some(self.log.as_ref().unwrap().begin_op(4)?);
+                        }
+                        new_tx.as_ref().unwrap()
+                    }
                 };
                 h.get_write_access(&dbh);
                 let db_data = dbh.data_mut();
@@ -790,30 +1203,55 @@ impl Xv6FileSystem {
         if internals.inode_type == 0 {
             return Err(libc::ENOENT);
         }
-        let file_kind = match internals.inode_type {
-            T_DIR => FileType::Directory,
-            T_LNK => FileType::Symlink,
-            _ => FileType::RegularFile,
-        };
         let attr = FileAttr {
             ino: ino,
             size: internals.size,
             blocks: 0,
-            atime: Timespec::new(0, 0),
-            mtime: Timespec::new(0, 0),
-            ctime: Timespec::new(0, 0),
-            crtime: Timespec::new(0, 0),
-            kind: file_kind,
-            perm: 0o077,
+            atime: internals.atime,
+            mtime: internals.mtime,
+            ctime: internals.ctime,
+            crtime: internals.crtime,
+            kind: internals.kind(),
+            perm: internals.mode,
             nlink: internals.nlink as u32,
-            uid: 0,
-            gid: 0,
-            rdev: 0,
+            uid: internals.uid,
+            gid: internals.gid,
+            rdev: internals.rdev(),
             flags: 0,
         };
         return Ok(attr);
     }
 
+    // Check whether a caller with the given uid and gid may access an inode.
+    // mask is a combination of R_OK, W_OK and X_OK, or F_OK to only check existence. Only the
+    // caller's primary group is known, so supplementary groups aren't considered.
+    pub fn iaccess(&self, internals: &InodeInternal, uid: u32, gid: u32, mask: i32) -> Result<(), libc::c_int> {
+        if internals.inode_type == 0 {
+            return Err(libc::ENOENT);
+        }
+        let mask = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u32;
+        let mode = internals.mode as u32;
+        if uid == 0 {
+            // root can read and write anything, but can only execute files with an execute bit set
+            if mask & libc::X_OK as u32 != 0 && internals.inode_type != T_DIR && mode & 0o111 == 0 {
+                return Err(libc::EACCES);
+            }
+            return Ok(());
+        }
+
+        let perm = if uid == internals.uid {
+            (mode >> 6) & 0o7
+        } else if gid == internals.gid {
+            (mode >> 3) & 0o7
+        } else {
+            mode & 0o7
+        };
+        if perm & mask != mask {
+            return Err(libc::EACCES);
+        }
+        return Ok(());
+    }
+
     pub fn readi(
         &self,
         buf: &mut [u8],
@@ -941,13 +1379,301 @@ impl Xv6FileSystem {
             end_size = off;
         }
 
-        if n > 0 && end_size > i_size {
-            internals.size = end_size as u64;
+        if n > 0 {
+            if end_size > i_size {
+                internals.size = end_size as u64;
+            }
+            let now = current_time();
+            internals.mtime = now;
+            internals.ctime = now;
             self.iupdate(internals, inum, handle)?;
         }
         return Ok(n);
     }
 
+    // Copy n bytes at off_in of src to off_out of dst block by block, without going through a
+    // user buffer. src is None when copying within dst. Unmapped source blocks read as zeros.
+    // Copies at most as many blocks as fit in one transaction and returns the number of bytes
+    // copied, so callers should start a new transaction and call again for the rest.
+    // The ranges must not overlap, and off_out must not be past the end of dst.
+    pub fn icopy(
+        &self,
+        src: Option<&InodeInternal>,
+        off_in: usize,
+        dst: &mut InodeInternal,
+        inum: u32,
+        off_out: usize,
+        n: usize,
+        handle: &Handle,
+    ) -> Result<usize, libc::c_int> {
+        let i_size = dst.size as usize;
+        if off_out > i_size {
+            return Err(libc::EIO);
+        }
+        match off_out.checked_add(n) {
+            Some(x) if x <= (MAXFILE as usize) * BSIZE => {},
+            _ => return Err(libc::EFBIG),
+        }
+
+        let max_blocks = (MAXOPBLOCKS - 1 - 1 - 2) / 2;
+        let disk = self.disk.as_ref().unwrap();
+        let mut buf = vec![0; BSIZE];
+        let mut written_blocks = 0;
+        let mut tot = 0;
+
+        while tot < n && written_blocks < max_blocks {
+            let src_off = off_in + tot;
+            let dst_off = off_out + tot;
+            let m = min(n - tot, min(BSIZE - src_off % BSIZE, BSIZE - dst_off % BSIZE));
+
+            let src_blk = match src {
+                Some(x) => self.bmap_noalloc(x, src_off / BSIZE),
+                None => self.bmap_noalloc(dst, src_off / BSIZE),
+            };
+            match src_blk {
+                Ok(block_no) => {
+                    let bh = disk.bread(block_no as u64)?;
+                    let data_off = src_off % BSIZE;
+                    buf[..m].copy_from_slice(&bh.data()[data_off..data_off + m]);
+                },
+                Err(libc::ENOENT) => {
+                    for b in buf[..m].iter_mut() {
+                        *b = 0;
+                    }
+                },
+                Err(x) => return Err(x),
+            }
+
+            let block_no = self.bmap(dst, dst_off / BSIZE, Some(handle))?;
+            let mut bh = disk.bread(block_no as u64)?;
+            handle.get_write_access(&bh);
+            let data_off = dst_off % BSIZE;
+            bh.data_mut()[data_off..data_off + m].copy_from_slice(&buf[..m]);
+            handle.journal_write(&mut bh);
+            written_blocks += 1;
+
+            tot += m;
+        }
+
+        if tot > 0 {
+            if off_out + tot > i_size {
+                dst.size = (off_out + tot) as u64;
+            }
+            let now = current_time();
+            dst.mtime = now;
+            dst.ctime = now;
+            self.iupdate(dst, inum, handle)?;
+        }
+        return Ok(tot);
+    }
+
+    // Allocate the blocks backing [off, end) without changing the file size.
+    // Allocates at most as many blocks as fit in one transaction and returns the offset it got
+    // up to, so callers should start a new transaction and call again until that reaches end.
+    // The caller is responsible for calling iupdate.
+    pub fn iprealloc(
+        &self,
+        off: usize,
+        end: usize,
+        internals: &mut InodeInternal,
+        handle: &Handle,
+    ) -> Result<usize, libc::c_int> {
+        if end > (MAXFILE as usize) * BSIZE {
+            return Err(libc::EFBIG);
+        }
+
+        let max_blocks = (MAXOPBLOCKS - 1 - 1 - 2) / 2;
+        let mut allocated_blocks = 0;
+        let mut blk_idx = off / BSIZE;
+        let last_blk = (end + BSIZE - 1) / BSIZE;
+
+        while blk_idx < last_blk && allocated_blocks < max_blocks {
+            match self.bmap_noalloc(internals, blk_idx) {
+                Ok(_) => {},
+                Err(libc::ENOENT) => {
+                    self.bmap(internals, blk_idx, Some(handle))?;
+                    allocated_blocks += 1;
+                },
+                Err(x) => return Err(x),
+            }
+            blk_idx += 1;
+        }
+        return Ok(min(blk_idx * BSIZE, end));
+    }
+
+    // Find the first offset at or after off that is backed by a block (data == true) or that
+    // isn't (data == false), for SEEK_DATA and SEEK_HOLE.
+    // The end of the file counts as a hole.
+    pub fn iseek(&self, off: usize, data: bool, internals: &InodeInternal) -> Result<usize, libc::c_int> {
+        let i_size = internals.size as usize;
+        if off >= i_size {
+            return Err(libc::ENXIO);
+        }
+
+        let mut blk_idx = off / BSIZE;
+        let last_blk = (i_size + BSIZE - 1) / BSIZE;
+        while blk_idx < last_blk {
+            let mapped = match self.bmap_noalloc(internals, blk_idx) {
+                Ok(_) => true,
+                Err(libc::ENOENT) => false,
+                Err(x) => return Err(x),
+            };
+            if mapped == data {
+                return Ok(max(off, blk_idx * BSIZE));
+            }
+            blk_idx += 1;
+        }
+
+        if data {
+            return Err(libc::ENXIO);
+        }
+        return Ok(i_size);
+    }
+
+    // Read all of an inode's extended attributes, sorted by name.
+    fn xattr_load(&self, internals: &InodeInternal, inum: u32) -> Result<Vec<Xattr>, libc::c_int> {
+        let disk = self.disk.as_ref().unwrap();
+        let sb = self.sb.as_ref().unwrap();
+        let mut attrs = Vec::new();
+
+        let inode_len = mem::size_of::<Xv6fsInode>();
+        if sb.inode_size() > inode_len {
+            let bh = disk.bread(iblock(inum as usize, &sb) as u64)?;
+            let inode_offset = (inum as usize % sb.ipb()) * sb.inode_size();
+            let area = &bh.data()[inode_offset + inode_len..inode_offset + sb.inode_size()];
+            xattr_decode(area, &mut attrs).map_err(|_| libc::EIO)?;
+        }
+        if internals.xattr != 0 {
+            let bh = disk.bread(internals.xattr as u64)?;
+            xattr_decode(bh.data(), &mut attrs).map_err(|_| libc::EIO)?;
+        }
+        attrs.sort_by(|a, b| a.name.cmp(&b.name));
+        return Ok(attrs);
+    }
+
+    // Write an inode's extended attributes, which must be sorted by name. Attributes go in the
+    // inode while they fit and the rest go in the xattr block, which is allocated or freed as
+    // needed. Fails with ENOSPC before changing anything if they don't all fit.
+    // The caller is responsible for calling iupdate.
+    fn xattr_store(
+        &self,
+        attrs: Vec<Xattr>,
+        internals: &mut InodeInternal,
+        inum: u32,
+        handle: &Handle,
+    ) -> Result<(), libc::c_int> {
+        let disk = self.disk.as_ref().unwrap();
+        let sb = self.sb.as_ref().unwrap();
+        let inode_len = mem::size_of::<Xv6fsInode>();
+        let inode_space = sb.inode_size() - inode_len;
+
+        let mut in_inode = Vec::new();
+        let mut in_block = Vec::new();
+        let mut inode_used = mem::size_of::<Xv6fsXattrHeader>();
+        for attr in attrs {
+            if inode_used + attr.disk_len() <= inode_space {
+                inode_used += attr.disk_len();
+                in_inode.push(attr);
+            } else {
+                in_block.push(attr);
+            }
+        }
+        if xattr_area_len(&in_block) > BSIZE {
+            return Err(libc::ENOSPC);
+        }
+
+        if !in_block.is_empty() {
+            if internals.xattr == 0 {
+                internals.xattr = self.balloc(handle)?;
+            }
+            let mut bh = disk.bread(internals.xattr as u64)?;
+            handle.get_write_access(&bh);
+            xattr_encode(&in_block, bh.data_mut()).map_err(|_| libc::EIO)?;
+            handle.journal_write(&mut bh);
+        } else if internals.xattr != 0 {
+            self.bfree(internals.xattr as usize, handle)?;
+            internals.xattr = 0;
+        }
+
+        if inode_space > 0 {
+            let mut bh = disk.bread(iblock(inum as usize, &sb) as u64)?;
+            handle.get_write_access(&bh);
+            let inode_offset = (inum as usize % sb.ipb()) * sb.inode_size();
+            let area = &mut bh.data_mut()[inode_offset + inode_len..inode_offset + sb.inode_size()];
+            xattr_encode(&in_inode, area).map_err(|_| libc::EIO)?;
+            handle.journal_write(&mut bh);
+        }
+        return Ok(());
+    }
+
+    // Get the value of the extended attribute called name.
+    pub fn getxattr(&self, internals: &InodeInternal, inum: u32, name: &[u8]) -> Result<Vec<u8>, libc::c_int> {
+        let attrs = self.xattr_load(internals, inum)?;
+        match attrs.into_iter().find(|x| x.name == name) {
+            Some(attr) => return Ok(attr.value),
+            None => return Err(libc::ENODATA),
+        }
+    }
+
+    // List the names of an inode's extended attributes, each followed by a NUL.
+    pub fn listxattr(&self, internals: &InodeInternal, inum: u32) -> Result<Vec<u8>, libc::c_int> {
+        let attrs = self.xattr_load(internals, inum)?;
+        let mut names = Vec::new();
+        for attr in attrs {
+            names.extend_from_slice(&attr.name);
+            names.push(0);
+        }
+        return Ok(names);
+    }
+
+    // Set the extended attribute called name to value, or remove it if value is None.
+    // flags are the XATTR_CREATE and XATTR_REPLACE flags of setxattr.
+    // The caller is responsible for calling iupdate.
+    pub fn setxattr(
+        &self,
+        internals: &mut InodeInternal,
+        inum: u32,
+        name: &[u8],
+        value: Option<&[u8]>,
+        flags: i32,
+        handle: &Handle,
+    ) -> Result<(), libc::c_int> {
+        if name.is_empty() || name.len() > XATTR_NAME_MAX {
+            return Err(libc::ERANGE);
+        }
+        if let Some(value) = value {
+            // Too big for even an empty xattr block.
+            let attr_len = (mem::size_of::<Xv6fsXattrEntry>() + name.len() + value.len() + 3) & !3;
+            if mem::size_of::<Xv6fsXattrHeader>() + attr_len > BSIZE {
+                return Err(libc::E2BIG);
+            }
+        }
+        let mut attrs = self.xattr_load(internals, inum)?;
+        let pos = attrs.binary_search_by(|x| x.name.as_slice().cmp(name));
+        match (pos, value) {
+            (Ok(_), Some(_)) if flags & libc::XATTR_CREATE != 0 => return Err(libc::EEXIST),
+            (Err(_), Some(_)) if flags & libc::XATTR_REPLACE != 0 => return Err(libc::ENODATA),
+            (Err(_), None) => return Err(libc::ENODATA),
+            (Ok(i), Some(value)) => attrs[i].value = value.to_vec(),
+            (Err(i), Some(value)) => {
+                let attr = Xattr {
+                    name: name.to_vec(),
+                    value: value.to_vec(),
+                };
+                attrs.insert(i, attr);
+            },
+            (Ok(i), None) => {
+                attrs.remove(i);
+            },
+        }
+        if value.is_some() {
+            self.set_xattr_feature(handle)?;
+        }
+        self.xattr_store(attrs, internals, inum, handle)?;
+        internals.ctime = current_time();
+        return Ok(());
+    }
+
     // entry lookup
     pub fn dirlookup<'a>(
         &'a self,
@@ -965,22 +1691,16 @@ impl Xv6FileSystem {
         let de_len = mem::size_of::<Xv6fsDirent>();
         let disk = self.disk.as_ref().unwrap();
 
-        let search_name = match name.to_str() {
-            Some(s) => s,
-            None => {
-                return Err(libc::ENOENT);
-            },
-        };
-        let search_name_bytes = search_name.as_bytes();
+        let search_name_bytes = name.as_bytes();
 
         let mut de = Xv6fsDirent::new();
         let mut de_vec: Vec<u8> = vec![0; de_len];
         let de_slice = de_vec.as_mut_slice();
         de.extract_from(de_slice).map_err(|_| libc::EIO)?;
 
-        let name_slice = name.to_str().unwrap().as_bytes();
+        let name_slice = name.as_bytes();
         if name_slice.len() > DIRSIZ as usize {
-            return Err(libc::EIO);
+            return Err(libc::ENAMETOOLONG);
         }
 
         for (idx, ch) in de.name.iter_mut().enumerate() {
@@ -991,12 +1711,7 @@ impl Xv6FileSystem {
         }
 
         // get hash of target entry
-        let tmp_name = match str::from_utf8(&de.name) {
-            Ok(x) => x,
-            Err(_) => return Err(libc::EIO),
-        };
-        let osstr_name = OsStr::new(tmp_name);
-        let target_hash = calculate_hash(&osstr_name);
+        let target_hash = calculate_hash(OsStr::from_bytes(&de.name));
 
         // read in entire root block
         let root_block_no = self.bmap_noalloc(internals, 0)?;
@@ -1009,13 +1724,13 @@ impl Xv6FileSystem {
         root.extract_from(root_slice).map_err(|_| libc::EIO)?;
 
         // '.' and '..' are always the first two entries in a directory
-        if search_name == "." {
+        if search_name_bytes == b"." {
             let mut de = Xv6fsDirent::new();
             let de_slice = &mut root_slice[0..de_len];
             de.extract_from(de_slice).map_err(|_| libc::EIO)?;
             *poff = 0;
             return self.iget(de.inum as u64);
-        } else if search_name == ".." {
+        } else if search_name_bytes == b".." {
             let mut de = Xv6fsDirent::new();
             let de_slice = &mut root_slice[de_len..2 * de_len];
             de.extract_from(de_slice).map_err(|_| libc::EIO)?;
@@ -1132,12 +1847,7 @@ impl Xv6FileSystem {
         let de_len = mem::size_of::<Xv6fsDirent>();
         let disk = self.disk.as_ref().unwrap();
 
-        let search_name = match name.to_str() {
-            Some(s) => s,
-            None => {
-                return Err(libc::ENOENT);
-            }
-        };
+        let search_name_bytes = name.as_bytes();
 
         let root_block_no = self.bmap(internals, 0, None)?;
         let mut root_bh = disk.bread(root_block_no as u64)?;
@@ -1156,9 +1866,9 @@ impl Xv6FileSystem {
         let de_slice = de_vec.as_mut_slice();
         de.extract_from(de_slice).map_err(|_| libc::EIO)?;
 
-        let name_slice = name.to_str().unwrap().as_bytes();
+        let name_slice = name.as_bytes();
         if name_slice.len() > DIRSIZ as usize {
-            return Err(libc::EIO);
+            return Err(libc::ENAMETOOLONG);
         }
 
         for (idx, ch) in de.name.iter_mut().enumerate() {
@@ -1171,15 +1881,10 @@ impl Xv6FileSystem {
         de.inum = child_inum as u32;
 
         // get hash of target entry
-        let tmp_name = match str::from_utf8(&de.name) {
-            Ok(x) => x,
-            Err(_) => return Err(libc::EIO),
-        };
-        let osstr_name = OsStr::new(tmp_name);
-        let target_hash = calculate_hash(&osstr_name);
+        let target_hash = calculate_hash(OsStr::from_bytes(&de.name));
 
         // new directory, create root node
-        if search_name == "." {
+        if search_name_bytes == b"." {
             root.ind_entries = 0;
             root.blocks = 1;
             root.dump_into(root_slice).map_err(|_| libc::EIO)?;
@@ -1191,7 +1896,7 @@ impl Xv6FileSystem {
             }
 
             return Ok(0);
-        } else if search_name == ".." {
+        } else if search_name_bytes == b".." {
             let root_de_slice = &mut root_slice[de_len..2 * de_len];
             de.dump_into(root_de_slice).map_err(|_| libc::EIO)?;
             if self.writei(root_de_slice, de_len, de_len, internals, parent_inum, handle, true)? != de_len {
@@ -1445,8 +2150,7 @@ impl Xv6FileSystem {
             }
 
             // there is enough space in the leaf node
-            if final_off.is_some() {
-                let final_off = final_off.unwrap();
+            if let Some(final_off) = final_off {
                 if self.writei(de_slice, final_off as usize, de_len, internals, parent_inum, handle, true)?
                     != de_len
                 {
@@ -1469,12 +2173,7 @@ impl Xv6FileSystem {
             if de_temp.inum == 0 {
                 continue;
             }
-            let de_name = match str::from_utf8(&de_temp.name) {
-                Ok(x) => x,
-                Err(_) => return Err(libc::EIO),
-            };
-            let de_name_osstr = OsStr::new(de_name);
-            let de_hash = calculate_hash(&de_name_osstr);
+            let de_hash = calculate_hash(OsStr::from_bytes(&de_temp.name));
 
             if !de_map.contains_key(&de_hash) {
                 de_map.insert(de_hash, Vec::with_capacity(3));
@@ -1587,7 +2286,7 @@ impl Xv6FileSystem {
         new_ie.name_hash = leaf2_lower;
         new_ie.block = num_blocks as u32;
         leaf_vec.push(new_ie);
-        leaf_vec.sort_unstable_by(|a, b| b.name_hash.partial_cmp(&a.name_hash).unwrap());
+        leaf_vec.sort_unstable_by(|a, b| b.name_hash.cmp(&a.name_hash));
 
         // enough space in current index node
         if num_entries < ((BSIZE - hindex_len) / hentry_len) {
@@ -10,14 +10,19 @@
 #[cfg(not(feature = "user"))]
 use crate::hash32::{Hash, Hasher};
 #[cfg(not(feature = "user"))]
+use crate::libc;
+#[cfg(not(feature = "user"))]
 use crate::std;
 
+use alloc::vec::Vec;
+
 use core::mem;
 use datablock::DataBlock;
 
 use crate::xv6fs_utils::*;
 
 use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
 #[cfg(feature = "user")]
 use std::hash::{Hash, Hasher};
 
@@ -120,6 +125,114 @@ pub fn calculate_hash(target: &OsStr) -> u32 {
     s.finish() as u32
 }
 
+// Hash of the name in a directory entry. The whole name field, including the trailing zeroes,
+// is hashed, as dirlink does.
+pub fn dirent_hash(de: &Xv6fsDirent) -> u32 {
+    calculate_hash(OsStr::from_bytes(&de.name))
+}
+
+// Lay out the directory inum, whose parent is parent_inum and which holds entries, as an htree
+// and return its blocks in logical block order.
+//
+// Block 0 holds the root with "." and "..". The other entries are sorted by hash and packed into
+// leaf blocks, and each index block is followed by the leaves it points to. Entries with equal
+// hashes always share a leaf, since dirlookup only searches the leaf whose lower bound matches.
+pub fn htree_build(
+    inum: u32,
+    parent_inum: u32,
+    entries: Vec<Xv6fsDirent>,
+) -> Result<Vec<Vec<u8>>, libc::c_int> {
+    let hroot_len = mem::size_of::<Htree_root>();
+    let hindex_len = mem::size_of::<Htree_index>();
+    let hentry_len = mem::size_of::<Htree_entry>();
+    let de_len = mem::size_of::<Xv6fsDirent>();
+    let leaf_max = BSIZE / de_len;
+    let index_max = (BSIZE - hindex_len) / hentry_len;
+    let root_max = (BSIZE - hroot_len) / hentry_len;
+
+    let mut hashed = Vec::with_capacity(entries.len());
+    for de in entries {
+        let hash = dirent_hash(&de);
+        hashed.push((hash, de));
+    }
+    hashed.sort_by_key(|(hash, _)| *hash);
+
+    // group entries into leaves, keyed by their lowest hash
+    let mut leaves: Vec<(u32, Vec<Xv6fsDirent>)> = Vec::new();
+    while !hashed.is_empty() {
+        let hash = hashed[0].0;
+        let n = hashed.iter().take_while(|(h, _)| *h == hash).count();
+        if n > leaf_max {
+            return Err(libc::ENOSPC);
+        }
+        let new_leaf = match leaves.last() {
+            Some((_, leaf)) => leaf.len() + n > leaf_max,
+            None => true,
+        };
+        if new_leaf {
+            leaves.push((hash, Vec::with_capacity(leaf_max)));
+        }
+        let leaf = &mut leaves.last_mut().ok_or(libc::EIO)?.1;
+        for (_, de) in hashed.drain(..n) {
+            leaf.push(de);
+        }
+    }
+    let nindex = (leaves.len() + index_max - 1) / index_max;
+    if nindex > root_max {
+        return Err(libc::ENOSPC);
+    }
+
+    let mut root = Htree_root::new();
+    root.dot.inum = inum;
+    root.dot.name[0] = b'.';
+    root.dotdot.inum = parent_inum;
+    root.dotdot.name[0] = b'.';
+    root.dotdot.name[1] = b'.';
+    if nindex > 0 {
+        root.depth = 2;
+        root.blocks = (1 + nindex + leaves.len()) as u32;
+        root.ind_entries = nindex as u32;
+    }
+
+    let mut blocks = Vec::with_capacity(1 + nindex + leaves.len());
+    blocks.push(vec![0; BSIZE]);
+    for (idx, chunk) in leaves.chunks(index_max).enumerate() {
+        let index_lblock = blocks.len() as u32;
+        let rie = Htree_entry {
+            name_hash: chunk[0].0,
+            block: index_lblock,
+        };
+        let off = hroot_len + idx * hentry_len;
+        rie.dump_into(&mut blocks[0][off..off + hentry_len]).map_err(|_| libc::EIO)?;
+
+        let mut index_block = vec![0; BSIZE];
+        let mut index = Htree_index::new();
+        index.entries = chunk.len() as u32;
+        index.dump_into(&mut index_block[0..hindex_len]).map_err(|_| libc::EIO)?;
+        for (lidx, (hash, _)) in chunk.iter().enumerate() {
+            let ine = Htree_entry {
+                name_hash: *hash,
+                block: index_lblock + 1 + lidx as u32,
+            };
+            let off = hindex_len + lidx * hentry_len;
+            ine.dump_into(&mut index_block[off..off + hentry_len]).map_err(|_| libc::EIO)?;
+        }
+        blocks.push(index_block);
+
+        for (_, leaf) in chunk {
+            let mut leaf_block = vec![0; BSIZE];
+            for (didx, de) in leaf.iter().enumerate() {
+                de.dump_into(&mut leaf_block[didx * de_len..(didx + 1) * de_len])
+                    .map_err(|_| libc::EIO)?;
+            }
+            blocks.push(leaf_block);
+        }
+    }
+    root.dump_into(&mut blocks[0][0..hroot_len]).map_err(|_| libc::EIO)?;
+
+    Ok(blocks)
+}
+
 pub struct SliceHasher {
     state: u32,
 }
@@ -131,7 +244,7 @@ impl SliceHasher {
 
     // djb2_hash
     pub fn write_u8(&mut self, i: u8) {
-        self.state = ((self.state << 5) + self.state) + i as u32;
+        self.state = (self.state << 5).wrapping_add(self.state).wrapping_add(i as u32);
     }
 }
 
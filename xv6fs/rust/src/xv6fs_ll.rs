@@ -14,10 +14,13 @@ use crate::fuse;
 #[cfg(not(feature = "user"))]
 use crate::libc;
 #[cfg(not(feature = "user"))]
+use crate::println;
+#[cfg(not(feature = "user"))]
 use crate::std;
 #[cfg(not(feature = "user"))]
 use crate::time;
 
+use alloc::boxed::Box;
 use alloc::collections::btree_map::BTreeMap;
 
 use alloc::string::String;
@@ -25,8 +28,9 @@ use alloc::string::ToString;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
+use core::cmp::min;
 use core::mem;
-use core::str;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use bento_utils::BentoFilesystem;
 
@@ -34,6 +38,7 @@ use datablock::DataBlock;
 
 use bento_utils::consts::*;
 use bento_utils::*;
+use bento_utils::options::*;
 use fuse::consts::*;
 
 use fuse::*;
@@ -41,9 +46,10 @@ use fuse::*;
 #[cfg(not(feature = "user"))]
 use bento::kernel::journal::*;
 #[cfg(feature = "user")]
-use crate::xv6fs_log::*;
+use bento_utils::journal::*;
 
 use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use std::sync::RwLock;
 
@@ -61,6 +67,11 @@ pub struct Xv6State {
     log: Option<Journal>,
 }
 
+impl TransferState for Xv6State {
+    const NAME: &'static str = "xv6fs::Xv6State";
+    const VERSION: u32 = 1;
+}
+
 pub struct Xv6FileSystem {
     pub log: Option<Journal>,
     pub sb: Option<Xv6fsSB>,
@@ -69,7 +80,18 @@ pub struct Xv6FileSystem {
     pub icache_map: Option<RwLock<BTreeMap<u64, usize>>>,
     pub ialloc_lock: Option<RwLock<usize>>,
     pub balloc_lock: Option<RwLock<usize>>,
+    // Free block and inode counts, kept in step with the superblock.
+    pub free_counts: Option<RwLock<FreeCounts>>,
+    // Set when the superblock has ro_compat features this version doesn't know.
+    pub read_only: AtomicBool,
+    // Whether the superblock has the xattr feature, which the first extended attribute sets.
+    pub xattr_feature: AtomicBool,
     pub diskname: Option<String>,
+    // Allocation hints: the last block and inode allocated, and whether the inode search has
+    // yet to wrap around.
+    pub last_block: AtomicUsize,
+    pub last_inode: AtomicUsize,
+    pub first_i_loop: AtomicBool,
 }
 
 impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
@@ -77,17 +99,40 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         Xv6FileSystem::NAME
     }
 
+    fn new_instance(&self) -> Box<Self> {
+        Box::new(Xv6FileSystem::new())
+    }
+
+    fn mount_options(&self) -> &'static [MountOption] {
+        Xv6FileSystem::MOUNT_OPTIONS
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
     fn bento_destroy(&mut self, _req: &Request) {
+        if let Err(x) = self.mark_clean() {
+            println!("xv6fs: failed to mark the file system clean: {}", x);
+        }
         // Allow log and disk to be dropped
         self.log = None;
         self.disk = None;
     }
 
+    fn bento_error(&self, _status: &errors::ErrorStatus) {
+        // Like ext4, abort the journal so an update interrupted by the error is never
+        // committed. Everything since the last commit is lost.
+        if let Some(log) = self.log.as_ref() {
+            log.abort(libc::EIO);
+        }
+    }
 
     fn bento_init(
         &mut self,
         _req: &Request,
         devname: &OsStr,
+        opts: &MountOptions,
         fc_info: &mut FuseConnInfo,
     ) -> Result<(), i32> {
         fc_info.proto_major = BENTO_KERNEL_VERSION;
@@ -101,7 +146,7 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         }
 
         if self.disk.is_none() {
-            let devname_str = devname.to_str().unwrap();
+            let devname_str = devname.to_str().ok_or(libc::EINVAL)?;
             let disk = Disk::new(devname_str, BSIZE as u64);
             let mut disk_string = devname_str.to_string();
             disk_string.push('\0');
@@ -109,23 +154,17 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
             self.disk = Some(Arc::new(disk));
         }
 
-        let sb_lock = Xv6fsSB {
-            size: 0,
-            nblocks: 0,
-            ninodes: 0,
-            nlog: 0,
-            logstart: 0,
-            inodestart: 0,
-            bmapstart: 0,
-        };
+        let sb_lock = Xv6fsSB::new();
         self.sb = Some(sb_lock);
 
-        self.iinit();
+        self.iinit()?;
+        self.set_options(opts)?;
 
         fc_info.want |= FUSE_BIG_WRITES;
         fc_info.want |= FUSE_ATOMIC_O_TRUNC;
         fc_info.want |= FUSE_WRITEBACK_CACHE;
         fc_info.want |= FUSE_PARALLEL_DIROPS;
+        fc_info.want |= FUSE_DO_READDIRPLUS;
 
         fc_info.max_readahead = max_readahead;
         fc_info.max_background = 0;
@@ -135,10 +174,30 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         return Ok(());
     }
 
+    fn bento_remount(&self, _req: &Request, opts: &MountOptions) -> Result<(), i32> {
+        self.set_options(opts)
+    }
+
     fn bento_statfs(&self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
         let sb_lock = self.sb.as_ref().unwrap();
         let fs_size = sb_lock.size;
-        reply.statfs(fs_size as u64, 0, 0, 0, 0, BSIZE as u32, DIRSIZ as u32, 0);
+        let counts = match self.free_counts.as_ref().unwrap().read() {
+            Ok(x) => *x,
+            Err(_) => {
+                reply.error(libc::EIO);
+                return;
+            }
+        };
+        reply.statfs(
+            fs_size as u64,
+            counts.nfree as u64,
+            counts.nfree as u64,
+            sb_lock.ninodes as u64,
+            counts.nifree as u64,
+            BSIZE as u32,
+            DIRSIZ as u32,
+            0,
+        );
     }
 
     fn bento_open(&self, _req: &Request, nodeid: u64, flags: u32, reply: ReplyOpen) {
@@ -174,7 +233,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         }
 
         if flags & libc::O_TRUNC as u32 != 0 {
-            let handle = log.begin_op(2);
+            let handle = match log.begin_op(2) {
+                Ok(x) => x,
+                Err(x) => {
+                    reply.error(x);
+                    return;
+                }
+            };
             internals.size = 0;
             if let Err(x) = self.iupdate(&internals, inode.inum, &handle) {
                 reply.error(x);
@@ -258,17 +323,17 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
 
     fn bento_setattr(
         &self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
-        _mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
         size: Option<u64>,
-        _atime: Option<Timespec>,
-        _mtime: Option<Timespec>,
+        atime: Option<Timespec>,
+        mtime: Option<Timespec>,
         _fh: Option<u64>,
         _crtime: Option<Timespec>,
-        _chgtime: Option<Timespec>,
+        chgtime: Option<Timespec>,
         _bkuptime: Option<Timespec>,
         _flags: Option<u32>,
         reply: ReplyAttr,
@@ -297,10 +362,59 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
                 return;
             }
         };
-        if let Some(fsize) = size {
+
+        // Only root can give a file away, and only the owner can change its group or mode.
+        let caller = req.uid();
+        let is_owner = caller == 0 || caller == internals.uid;
+        if let Some(new_uid) = uid {
+            if caller != 0 && new_uid != internals.uid {
+                reply.error(libc::EPERM);
+                return;
+            }
+        }
+        if let Some(new_gid) = gid {
+            if !is_owner || (caller != 0 && new_gid != internals.gid && new_gid != req.gid()) {
+                reply.error(libc::EPERM);
+                return;
+            }
+        }
+        if mode.is_some() && !is_owner {
+            reply.error(libc::EPERM);
+            return;
+        }
+
+        if mode.is_some() || uid.is_some() || gid.is_some() || size.is_some()
+            || atime.is_some() || mtime.is_some() {
+            let now = current_time();
+            if let Some(new_mode) = mode {
+                internals.mode = (new_mode & 0o7777) as u16;
+            }
+            if let Some(new_uid) = uid {
+                internals.uid = new_uid;
+            }
+            if let Some(new_gid) = gid {
+                internals.gid = new_gid;
+            }
+            if let Some(fsize) = size {
+                internals.size = fsize;
+                internals.mtime = now;
+            }
+            if let Some(new_atime) = atime {
+                internals.atime = new_atime;
+            }
+            if let Some(new_mtime) = mtime {
+                internals.mtime = new_mtime;
+            }
+            internals.ctime = chgtime.unwrap_or(now);
+
             let log = self.log.as_ref().unwrap();
-            let handle = log.begin_op(2);
-            internals.size = fsize;
+            let handle = match log.begin_op(2) {
+                Ok(x) => x,
+                Err(x) => {
+                    reply.error(x);
+                    return;
+                }
+            };
             if let Err(x) = self.iupdate(&internals, inode.inum, &handle) {
                 reply.error(x);
                 return;
@@ -484,7 +598,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
             }
             let data_region = &data[file_off..];
             //let handle = log.begin_op(3 as u32);
-            let handle = log.begin_op(MAXOPBLOCKS as u32);
+            let handle = match log.begin_op(MAXOPBLOCKS as u32) {
+                Ok(x) => x,
+                Err(x) => {
+                    reply.error(x);
+                    return;
+                }
+            };
             let r = match self.writei(data_region, off, n1, &mut internals, inode.inum, &handle, true) {
                 Ok(x) => x,
                 Err(x) => {
@@ -509,240 +629,54 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        // Get inode number nodeid
-        let inode = match self.iget(nodeid) {
-            Ok(x) => x,
-            Err(x) => {
-                reply.error(x);
-                return;
-            }
-        };
-
-        let icache = self.ilock_cache.as_ref().unwrap();
-        let inode_guard = match self.ilock(inode.idx, &icache, inode.inum) {
-            Ok(x) => x,
-            Err(x) => {
-                reply.error(x);
-                return;
-            }
-        };
-        //let mut internals = match inode_guard.internals.write() {
-        let internals = match inode_guard.internals.read() {
-            Ok(x) => x,
-            Err(_) => {
-                reply.error(libc::EIO);
-                return;
-            }
-        };
-
-        // Check if inode is directory
-        if internals.inode_type != T_DIR {
-            reply.error(libc::ENOTDIR);
-            return;
-        }
-        let hroot_len = mem::size_of::<Htree_root>();
-        let hindex_len = mem::size_of::<Htree_index>();
-        let hentry_len = mem::size_of::<Htree_entry>();
-        let de_len = mem::size_of::<Xv6fsDirent>();
-        let mut hroot_vec: Vec<u8> = vec![0; hroot_len];
-        let mut buf_off = 1;
-        let mut inarg_offset = offset as usize;
-        let hroot_slice = hroot_vec.as_mut_slice();
-
-        // try reading directory root
-        let mut root = Htree_root::new();
-        match self.readi(hroot_slice, 0, hroot_len, &internals) {
-            Ok(x) if x != hroot_len => {
-                reply.error(1);
-                return;
-            }
-            Err(x) => {
-                reply.error(x);
-                return;
-            }
-            _ => {}
-        };
-        if root.extract_from(hroot_slice).is_err() {
-            reply.error(libc::EIO);
-            return;
-        }
-
-        let num_indeces = root.ind_entries;
-        if num_indeces == 0 {
-            reply.ok();
-            return;
+        let res = self.readdir_internal(nodeid, offset, |inum, off, internals, name| {
+            Ok(reply.add(inum, off, internals.kind(), name))
+        });
+        match res {
+            Ok(()) => reply.ok(),
+            Err(x) => reply.error(x),
         }
+    }
 
-        let mut hie_vec: Vec<u8> = vec![0; hentry_len];
-        let hie_slice = hie_vec.as_mut_slice();
-
-        // check the index pointers stored in the root node
-        for off in (hroot_len..(num_indeces as usize * hentry_len) + hroot_len).step_by(hentry_len)
-        {
-            if off >= BSIZE {
-                break;
-            }
-            let mut hie = Htree_entry::new();
-            match self.readi(hie_slice, off as usize, hentry_len, &internals) {
-                Ok(x) if x != hentry_len => {
-                    reply.error(1);
-                    return;
-                }
-                Err(x) => {
-                    reply.error(x);
-                    return;
-                }
-                _ => {}
-            }
-            if hie.extract_from(hie_slice).is_err() {
-                reply.error(libc::EIO);
-                return;
-            }
-
-            // check the index block for entries
-            let mut ind_arr_vec: Vec<u8> = vec![0; BSIZE];
-            let ind_arr_slice = ind_arr_vec.as_mut_slice();
-            match self.readi(
-                ind_arr_slice,
-                BSIZE * hie.block as usize,
-                BSIZE,
-                &internals,
-            ) {
-                Ok(x) if x != BSIZE => {
-                    reply.error(1);
-                    return;
-                }
-                Err(x) => {
-                    reply.error(x);
-                    return;
-                }
-                _ => {}
-            }
-
-            let ind_header_slice = &mut ind_arr_slice[0..hindex_len];
-            let mut index = Htree_index::new();
-            if index.extract_from(ind_header_slice).is_err() {
-                reply.error(libc::EIO);
-                return;
-            }
-
-            let num_entries = index.entries;
-
-            if num_entries == 0 {
-                continue;
-            }
-
-            // check entries in index node
-            for ine_idx in
-                (hindex_len..hindex_len + (hentry_len * index.entries as usize)).step_by(hentry_len)
-            {
-                let ine_slice = &mut ind_arr_slice[ine_idx..ine_idx + hentry_len];
-                let mut ine = Htree_entry::new();
-                if ine.extract_from(ine_slice).is_err() {
-                    reply.error(libc::EIO);
-                    return;
-                }
-                let dblock_off = ine.block;
-                if dblock_off == 0 {
-                    continue;
-                }
-                let mut de_block_vec: Vec<u8> = vec![0; BSIZE];
-                let de_block_slice = de_block_vec.as_mut_slice();
-
-                match self.readi(
-                    de_block_slice,
-                    BSIZE * dblock_off as usize,
-                    BSIZE,
-                    &internals,
-                ) {
-                    Err(x) => {
-                        reply.error(x);
-                        return;
-                    }
-                    _ => {}
-                }
-
-                // check dirents in leaf node
-                for de_off in (0..BSIZE).step_by(de_len) {
-                    let de_slice = &mut de_block_slice[de_off..de_off + de_len];
-                    let mut de = Xv6fsDirent::new();
-                    if de.extract_from(de_slice).is_err() {
-                        reply.error(libc::EIO);
-                        return;
-                    }
-
-                    if de.inum == 0 {
-                        continue;
-                    }
-                    if inarg_offset >= 1 {
-                        inarg_offset -= 1;
-                        buf_off += 1;
-                        continue;
-                    }
-
-                    let i_type;
-                    if de.inum as u64 == nodeid {
-                        i_type = FileType::Directory;
-                    } else {
-                        let entry = match self.iget(de.inum as u64) {
-                            Ok(x) => x,
-                            Err(x) => {
-                                reply.error(x);
-                                return;
-                            }
-                        };
-
-                        let entry_inode_guard = match self.ilock(entry.idx, &icache, de.inum) {
-                            Ok(x) => x,
-                            Err(x) => {
-                                reply.error(x);
-                                return;
-                            }
-                        };
-                        let entry_internals = match entry_inode_guard.internals.read() {
-                            Ok(x) => x,
-                            Err(_) => {
-                                reply.error(libc::EIO);
-                                return;
-                            }
-                        };
-
-                        i_type = match entry_internals.inode_type {
-                            T_DIR => FileType::Directory,
-                            T_LNK => FileType::Symlink,
-                            _ => FileType::RegularFile,
-                        };
-                    }
-
-                    let name_str = match str::from_utf8(&de.name) {
-                        Ok(x) => x,
-                        Err(_) => "",
-                    };
-
-                    if reply.add(de.inum as u64, buf_off, i_type, name_str) {
-                        reply.ok();
-                        return;
-                    }
-                    buf_off += 1;
-                }
-            }
+    #[allow(unused_mut)]
+    fn bento_readdirplus(
+        &self,
+        _req: &Request,
+        nodeid: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        let attr_valid = Timespec::new(1, 999999999);
+        let res = self.readdir_internal(nodeid, offset, |inum, off, internals, name| {
+            let attr = self.stati(inum, internals)?;
+            Ok(reply.add(inum, off, &attr, &attr_valid, name))
+        });
+        match res {
+            Ok(()) => reply.ok(),
+            Err(x) => reply.error(x),
         }
-        reply.ok();
     }
 
     fn bento_create(
         &self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
-        _mode: u32,
+        mode: u32,
         _flags: u32,
         reply: ReplyCreate,
     ) {
         // Check if the file already exists
         let log = self.log.as_ref().unwrap();
-        let handle = log.begin_op(32);
-        let child = match self.create_internal(parent, T_FILE, name, &handle) {
+        let handle = match log.begin_op(32) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
+        let child = match self.create_internal(req, parent, T_FILE, mode, 0, name, &handle) {
             Ok(x) => x,
             Err(x) => {
                 reply.error(x);
@@ -783,17 +717,37 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
 
     fn bento_mknod(
         &self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
-        _mode: u32,
-        _rdev: u32,
+        mode: u32,
+        rdev: u32,
         reply: ReplyEntry,
     ) {
+        let itype = match mode & libc::S_IFMT as u32 {
+            0 | libc::S_IFREG => T_FILE,
+            libc::S_IFCHR => T_DEV,
+            libc::S_IFBLK => T_BLK,
+            libc::S_IFIFO => T_FIFO,
+            libc::S_IFSOCK => T_SOCK,
+            _ => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+        // Only devices have a device number.
+        let rdev = if itype == T_DEV || itype == T_BLK { rdev } else { 0 };
+
         // Check if the file already exists
         let log = self.log.as_ref().unwrap();
-        let handle = log.begin_op(16);
-        let child = match self.create_internal(parent, T_FILE, name, &handle) {
+        let handle = match log.begin_op(16) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
+        let child = match self.create_internal(req, parent, itype, mode, rdev, name, &handle) {
             Ok(x) => x,
             Err(x) => {
                 reply.error(x);
@@ -832,24 +786,30 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
 
     fn bento_mkdir(
         &self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
-        _mode: u32,
+        mode: u32,
         reply: ReplyEntry,
     ) {
         let log = self.log.as_ref().unwrap();
-        let handle = log.begin_op(MAXOPBLOCKS as u32);
-        //let handle = log.begin_op(16);
-        let child = match self.create_internal(parent, T_DIR, &name, &handle) {
+        let handle = match log.begin_op(MAXOPBLOCKS as u32) {
             Ok(x) => x,
             Err(x) => {
                 reply.error(x);
                 return;
             }
         };
-
-        let icache = self.ilock_cache.as_ref().unwrap();
+        //let handle = log.begin_op(16);
+        let child = match self.create_internal(req, parent, T_DIR, mode, 0, &name, &handle) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
+
+        let icache = self.ilock_cache.as_ref().unwrap();
         let inode_guard = match self.ilock(child.idx, &icache, child.inum) {
             Ok(x) => x,
             Err(x) => {
@@ -882,7 +842,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
 
     fn bento_rmdir(&self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let log = self.log.as_ref().unwrap();
-        let handle = log.begin_op(MAXOPBLOCKS as u32);
+        let handle = match log.begin_op(MAXOPBLOCKS as u32) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
         //let handle = log.begin_op(16);
         match self.dounlink(parent, name, &handle) {
             Ok(_) => reply.ok(),
@@ -892,7 +858,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
 
     fn bento_unlink(&self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let log = self.log.as_ref().unwrap();
-        let handle = log.begin_op(MAXOPBLOCKS as u32);
+        let handle = match log.begin_op(MAXOPBLOCKS as u32) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
         //let handle = log.begin_op(16);
         match self.dounlink(parent, name, &handle) {
             Ok(_) => {
@@ -902,6 +874,34 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         }
     }
 
+    fn bento_link(
+        &self,
+        _req: &Request,
+        nodeid: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        let log = self.log.as_ref().unwrap();
+        let handle = match log.begin_op(MAXOPBLOCKS as u32) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
+        let attr = match self.dolink(nodeid, newparent, newname, &handle) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
+        let generation = 0;
+        let attr_valid = Timespec::new(1, 999999999);
+        reply.entry(&attr_valid, &attr, generation);
+    }
+
     fn bento_fsync(&self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
         let log = self.log.as_ref().unwrap();
         log.force_commit();
@@ -916,17 +916,23 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
 
     fn bento_symlink(
         &self,
-        _req: &Request,
+        req: &Request,
         nodeid: u64,
         name: &OsStr,
         linkname: &Path,
         reply: ReplyEntry,
     ) {
         let log = self.log.as_ref().unwrap();
-        let handle = log.begin_op(MAXOPBLOCKS as u32);
+        let handle = match log.begin_op(MAXOPBLOCKS as u32) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
         //let handle = log.begin_op(16);
         // Create new file
-        let child = match self.create_internal(nodeid, T_LNK, name, &handle) {
+        let child = match self.create_internal(req, nodeid, T_LNK, 0o777, 0, name, &handle) {
             Ok(x) => x,
             Err(x) => {
                 reply.error(x);
@@ -951,8 +957,8 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         };
 
         let mut len_slice = [0; mem::size_of::<u32>()];
-        let linkname_str = linkname.to_str().unwrap();
-        let str_length: u32 = linkname_str.len() as u32 + 1;
+        let linkname_bytes = linkname.as_os_str().as_bytes();
+        let str_length: u32 = linkname_bytes.len() as u32 + 1;
         let strlen_slice = str_length.to_ne_bytes();
         len_slice.copy_from_slice(&strlen_slice);
         if let Err(x) = self.writei(
@@ -969,9 +975,9 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         };
 
         if let Err(x) = self.writei(
-            linkname_str.as_bytes(),
+            linkname_bytes,
             mem::size_of::<u32>(),
-            linkname_str.len(),
+            linkname_bytes.len(),
             &mut internals,
             child.inum,
             &handle,
@@ -1068,7 +1074,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         reply: ReplyEmpty,
     ) {
         let log = self.log.as_ref().unwrap();
-        let handle = log.begin_op(MAXOPBLOCKS as u32);
+        let handle = match log.begin_op(MAXOPBLOCKS as u32) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
         //let handle = log.begin_op(16);
         let no_replace = (flags & libc::RENAME_NOREPLACE as u32) > 0;
         let exchange = (flags & libc::RENAME_EXCHANGE as u32) > 0;
@@ -1122,8 +1134,8 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
                 },
             };
             let mut old_poff = 0;
-            let old_name_str = name.to_str().unwrap();
-            if old_name_str == "." || old_name_str == ".." {
+            let old_name_bytes = name.as_bytes();
+            if old_name_bytes == b"." || old_name_bytes == b".." {
                 reply.error(libc::EIO);
                 return;
             }
@@ -1156,16 +1168,25 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
             }
 
             let mut new_poff = 0;
-            let new_name_str = newname.to_str().unwrap();
-            if new_name_str == "." || new_name_str == ".." {
+            let new_name_bytes = newname.as_bytes();
+            if new_name_bytes == b"." || new_name_bytes == b".." {
                 reply.error(libc::EIO);
                 return;
             }
             let new_inode_res = self.dirlookup(&mut new_parent_internals, newname, &mut new_poff);
+            if new_inode_res.is_err() && inode_internals.inode_type == T_DIR
+                && new_parent_internals.nlink >= MAXLINK {
+                reply.error(libc::EMLINK);
+                return;
+            }
             if let Ok(new_inode) = new_inode_res {
                 if no_replace {
                     reply.error(libc::EEXIST);
                     return;
+                } else if new_inode.inum == inode.inum {
+                    // Both names are links to the same inode, so there is nothing to do.
+                    reply.ok();
+                    return;
                 } else if exchange {
                     let de_arr = [0; mem::size_of::<Xv6fsDirent>()];
                     let buf_len = mem::size_of::<Xv6fsDirent>();
@@ -1229,13 +1250,21 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
                             return;
                         },
                     };
-                    let new_inode_internals = match new_inode_guard.internals.read() {
+                    let mut new_inode_internals = match new_inode_guard.internals.write() {
                         Ok(x) => x,
                         Err(_) => {
                             reply.error(libc::EIO);
                             return;
                         },
                     };
+                    if new_inode_internals.inode_type == T_DIR && inode_internals.inode_type != T_DIR {
+                        reply.error(libc::EISDIR);
+                        return;
+                    }
+                    if new_inode_internals.inode_type != T_DIR && inode_internals.inode_type == T_DIR {
+                        reply.error(libc::ENOTDIR);
+                        return;
+                    }
                     if new_inode_internals.inode_type == T_DIR {
                         match self.isdirempty(&new_inode_internals) {
                             Ok(true) => {}
@@ -1262,6 +1291,21 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
                             return;
                         },
                     };
+                    // The replaced inode loses the link, and the parent loses the replaced
+                    // directory's "..". The inode is freed once its last link is gone.
+                    if new_inode_internals.inode_type == T_DIR {
+                        new_parent_internals.nlink -= 1;
+                        if self.iupdate(&new_parent_internals, new_parent.inum, &handle).is_err() {
+                            reply.error(libc::EIO);
+                            return;
+                        }
+                    }
+                    new_inode_internals.nlink -= 1;
+                    new_inode_internals.ctime = current_time();
+                    if self.iupdate(&new_inode_internals, new_inode.inum, &handle).is_err() {
+                        reply.error(libc::EIO);
+                        return;
+                    }
                 }
             }
 
@@ -1341,8 +1385,8 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
                 },
             };
             let mut old_poff = 0;
-            let old_name_str = name.to_str().unwrap();
-            if old_name_str == "." || old_name_str == ".." {
+            let old_name_bytes = name.as_bytes();
+            if old_name_bytes == b"." || old_name_bytes == b".." {
                 reply.error(libc::EIO);
                 return;
             }
@@ -1374,8 +1418,8 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
             }
 
             let mut new_poff = 0;
-            let new_name_str = newname.to_str().unwrap();
-            if new_name_str == "." || new_name_str == ".." {
+            let new_name_bytes = newname.as_bytes();
+            if new_name_bytes == b"." || new_name_bytes == b".." {
                 reply.error(libc::EIO);
                 return;
             }
@@ -1384,6 +1428,10 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
                 if no_replace {
                     reply.error(libc::EEXIST);
                     return;
+                } else if new_inode.inum == inode.inum {
+                    // Both names are links to the same inode, so there is nothing to do.
+                    reply.ok();
+                    return;
                 } else if exchange {
                     let de_arr = [0; mem::size_of::<Xv6fsDirent>()];
                     let buf_len = mem::size_of::<Xv6fsDirent>();
@@ -1414,13 +1462,21 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
                             return;
                         },
                     };
-                    let new_inode_internals = match new_inode_guard.internals.read() {
+                    let mut new_inode_internals = match new_inode_guard.internals.write() {
                         Ok(x) => x,
                         Err(_) => {
                             reply.error(libc::EIO);
                             return;
                         },
                     };
+                    if new_inode_internals.inode_type == T_DIR && inode_internals.inode_type != T_DIR {
+                        reply.error(libc::EISDIR);
+                        return;
+                    }
+                    if new_inode_internals.inode_type != T_DIR && inode_internals.inode_type == T_DIR {
+                        reply.error(libc::ENOTDIR);
+                        return;
+                    }
                     if new_inode_internals.inode_type == T_DIR {
                         match self.isdirempty(&new_inode_internals) {
                             Ok(true) => {}
@@ -1447,6 +1503,21 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
                             return;
                         },
                     };
+                    // The replaced inode loses the link, and the parent loses the replaced
+                    // directory's "..". The inode is freed once its last link is gone.
+                    if new_inode_internals.inode_type == T_DIR {
+                        parent_internals.nlink -= 1;
+                        if self.iupdate(&parent_internals, parent.inum, &handle).is_err() {
+                            reply.error(libc::EIO);
+                            return;
+                        }
+                    }
+                    new_inode_internals.nlink -= 1;
+                    new_inode_internals.ctime = current_time();
+                    if self.iupdate(&new_inode_internals, new_inode.inum, &handle).is_err() {
+                        reply.error(libc::EIO);
+                        return;
+                    }
                 }
             }
             let de_arr = [0; mem::size_of::<Xv6fsDirent>()];
@@ -1475,6 +1546,238 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
     
     }
 
+    fn bento_fallocate(
+        &self,
+        _req: &Request,
+        nodeid: u64,
+        _fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        // xv6fs can only free blocks by truncating the whole file, so punching holes and
+        // zeroing ranges aren't supported.
+        if mode & !libc::FALLOC_FL_KEEP_SIZE != 0 {
+            reply.error(libc::EOPNOTSUPP);
+            return;
+        }
+        if offset < 0 || length <= 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let end = match (offset as usize).checked_add(length as usize) {
+            Some(x) if x <= (MAXFILE as usize) * BSIZE => x,
+            _ => {
+                reply.error(libc::EFBIG);
+                return;
+            }
+        };
+
+        let mut off = offset as usize;
+        while off < end {
+            let log = self.log.as_ref().unwrap();
+            let inode = match self.iget(nodeid) {
+                Ok(x) => x,
+                Err(x) => {
+                    reply.error(x);
+                    return;
+                }
+            };
+
+            let icache = self.ilock_cache.as_ref().unwrap();
+            let inode_guard = match self.ilock(inode.idx, &icache, inode.inum) {
+                Ok(x) => x,
+                Err(x) => {
+                    reply.error(x);
+                    return;
+                }
+            };
+            let mut internals = match inode_guard.internals.write() {
+                Ok(x) => x,
+                Err(_) => {
+                    reply.error(libc::EIO);
+                    return;
+                }
+            };
+
+            if internals.inode_type == T_DIR {
+                reply.error(libc::EISDIR);
+                return;
+            } else if internals.inode_type != T_FILE {
+                reply.error(libc::ENODEV);
+                return;
+            }
+
+            let handle = match log.begin_op(MAXOPBLOCKS as u32) {
+                Ok(x) => x,
+                Err(x) => {
+                    reply.error(x);
+                    return;
+                }
+            };
+            off = match self.iprealloc(off, end, &mut internals, &handle) {
+                Ok(x) => x,
+                Err(x) => {
+                    reply.error(x);
+                    return;
+                }
+            };
+            if mode & libc::FALLOC_FL_KEEP_SIZE == 0 && off as u64 > internals.size {
+                internals.size = off as u64;
+            }
+            if let Err(x) = self.iupdate(&internals, inode.inum, &handle) {
+                reply.error(x);
+                return;
+            }
+        }
+        reply.ok();
+    }
+
+    fn bento_lseek(
+        &self,
+        _req: &Request,
+        nodeid: u64,
+        _fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        // The kernel handles SEEK_SET, SEEK_CUR and SEEK_END itself.
+        let data = match whence {
+            libc::SEEK_DATA => true,
+            libc::SEEK_HOLE => false,
+            _ => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+        if offset < 0 {
+            reply.error(libc::ENXIO);
+            return;
+        }
+
+        let inode = match self.iget(nodeid) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
+
+        let icache = self.ilock_cache.as_ref().unwrap();
+        let inode_guard = match self.ilock(inode.idx, &icache, inode.inum) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
+        let internals = match inode_guard.internals.read() {
+            Ok(x) => x,
+            Err(_) => {
+                reply.error(libc::EIO);
+                return;
+            }
+        };
+
+        match self.iseek(offset as usize, data, &internals) {
+            Ok(x) => reply.offset(x as i64),
+            Err(x) => reply.error(x),
+        }
+    }
+
+    fn bento_copy_file_range(
+        &self,
+        _req: &Request,
+        ino_in: u64,
+        _fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        _fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        if offset_in < 0 || offset_out < 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        match self.copy_internal(ino_in, offset_in as usize, ino_out, offset_out as usize, len as usize) {
+            Ok(x) => reply.written(x as u32),
+            Err(x) => reply.error(x),
+        }
+    }
+
+    fn bento_setxattr(
+        &self,
+        _req: &Request,
+        nodeid: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        match self.setxattr_internal(nodeid, name, Some(value), flags as i32) {
+            Ok(()) => reply.ok(),
+            Err(x) => reply.error(x),
+        }
+    }
+
+    fn bento_getxattr(&self, _req: &Request, nodeid: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        match self.getxattr_internal(nodeid, Some(name)) {
+            Ok(value) => Self::reply_xattr(&value, size, reply),
+            Err(x) => reply.error(x),
+        }
+    }
+
+    fn bento_listxattr(&self, _req: &Request, nodeid: u64, size: u32, reply: ReplyXattr) {
+        match self.getxattr_internal(nodeid, None) {
+            Ok(names) => Self::reply_xattr(&names, size, reply),
+            Err(x) => reply.error(x),
+        }
+    }
+
+    fn bento_removexattr(&self, _req: &Request, nodeid: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.setxattr_internal(nodeid, name, None, 0) {
+            Ok(()) => reply.ok(),
+            Err(x) => reply.error(x),
+        }
+    }
+
+    fn bento_access(&self, req: &Request, nodeid: u64, mask: u32, reply: ReplyEmpty) {
+        let inode = match self.iget(nodeid) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
+
+        let icache = self.ilock_cache.as_ref().unwrap();
+        let inode_guard = match self.ilock(inode.idx, &icache, inode.inum) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
+        let internals = match inode_guard.internals.read() {
+            Ok(x) => x,
+            Err(_) => {
+                reply.error(libc::EIO);
+                return;
+            }
+        };
+
+        match self.iaccess(&internals, req.uid(), req.gid(), mask as i32) {
+            Ok(()) => reply.ok(),
+            Err(x) => reply.error(x),
+        }
+    }
+
     fn bento_update_prepare(&mut self) -> Option<Xv6State> {
         let mut state = Xv6State {
             diskname: self.diskname.as_ref().unwrap().clone(),
@@ -1489,19 +1792,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
             let disk = Arc::new(Disk::new(state.diskname.as_str(), 4096));
             self.disk = Some(disk);
             self.diskname = Some(state.diskname);
-            let sb_lock = Xv6fsSB {
-                size: 0,
-                nblocks: 0,
-                ninodes: 0,
-                nlog: 0,
-                logstart: 0,
-                inodestart: 0,
-                bmapstart: 0,
-            };
+            let sb_lock = Xv6fsSB::new();
             self.sb = Some(sb_lock);
             mem::swap(&mut self.log, &mut state.log);
 
-            self.iinit();
+            if self.iinit().is_err() {
+                println!("xv6fs: failed to initialize after upgrade");
+            }
         }
     }
 }
@@ -1509,13 +1806,250 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
 impl Xv6FileSystem {
     const NAME: &'static str = "xv6fs_ll\0";
 
+    pub const fn new() -> Self {
+        Xv6FileSystem {
+            log: None,
+            sb: None,
+            disk: None,
+            ilock_cache: None,
+            icache_map: None,
+            ialloc_lock: None,
+            balloc_lock: None,
+            free_counts: None,
+            read_only: AtomicBool::new(false),
+            xattr_feature: AtomicBool::new(false),
+            diskname: None,
+            last_block: AtomicUsize::new(0),
+            last_inode: AtomicUsize::new(0),
+            first_i_loop: AtomicBool::new(true),
+        }
+    }
+
+    // commit=N: commit the journal every N seconds rather than every 5.
+    const MOUNT_OPTIONS: &'static [MountOption] = &[
+        MountOption { name: "commit", kind: MountOptionKind::Number },
+    ];
+
+    fn set_options(&self, opts: &MountOptions) -> Result<(), libc::c_int> {
+        if let Some(secs) = opts.get_u64("commit") {
+            if secs > u32::MAX as u64 {
+                return Err(libc::EINVAL);
+            }
+            let log = self.log.as_ref().ok_or(libc::EIO)?;
+            log.set_commit_interval(secs as u32);
+        }
+        Ok(())
+    }
+
+    // Walk the entries of directory nodeid that come after offset, calling filler with each
+    // entry's inode number, directory offset, inode internals and name. Stops early if filler
+    // returns true because the reply buffer is full.
+    fn readdir_internal<F>(&self, nodeid: u64, offset: i64, mut filler: F) -> Result<(), libc::c_int>
+    where
+        F: FnMut(u64, i64, &InodeInternal, &OsStr) -> Result<bool, libc::c_int>,
+    {
+        // Get inode number nodeid
+        let inode = self.iget(nodeid)?;
+
+        let icache = self.ilock_cache.as_ref().unwrap();
+        let inode_guard = self.ilock(inode.idx, &icache, inode.inum)?;
+        let internals = inode_guard.internals.read().map_err(|_| libc::EIO)?;
+
+        // Check if inode is directory
+        if internals.inode_type != T_DIR {
+            return Err(libc::ENOTDIR);
+        }
+        let hroot_len = mem::size_of::<Htree_root>();
+        let hindex_len = mem::size_of::<Htree_index>();
+        let hentry_len = mem::size_of::<Htree_entry>();
+        let de_len = mem::size_of::<Xv6fsDirent>();
+        let mut hroot_vec: Vec<u8> = vec![0; hroot_len];
+        let mut buf_off = 1;
+        let mut inarg_offset = offset as usize;
+        let hroot_slice = hroot_vec.as_mut_slice();
+
+        // try reading directory root
+        let mut root = Htree_root::new();
+        if self.readi(hroot_slice, 0, hroot_len, &internals)? != hroot_len {
+            return Err(1);
+        }
+        root.extract_from(hroot_slice).map_err(|_| libc::EIO)?;
+
+        let num_indeces = root.ind_entries;
+        if num_indeces == 0 {
+            return Ok(());
+        }
+
+        let mut hie_vec: Vec<u8> = vec![0; hentry_len];
+        let hie_slice = hie_vec.as_mut_slice();
+
+        // check the index pointers stored in the root node
+        for off in (hroot_len..(num_indeces as usize * hentry_len) + hroot_len).step_by(hentry_len)
+        {
+            if off >= BSIZE {
+                break;
+            }
+            let mut hie = Htree_entry::new();
+            if self.readi(hie_slice, off as usize, hentry_len, &internals)? != hentry_len {
+                return Err(1);
+            }
+            hie.extract_from(hie_slice).map_err(|_| libc::EIO)?;
+
+            // check the index block for entries
+            let mut ind_arr_vec: Vec<u8> = vec![0; BSIZE];
+            let ind_arr_slice = ind_arr_vec.as_mut_slice();
+            if self.readi(ind_arr_slice, BSIZE * hie.block as usize, BSIZE, &internals)? != BSIZE {
+                return Err(1);
+            }
+
+            let ind_header_slice = &mut ind_arr_slice[0..hindex_len];
+            let mut index = Htree_index::new();
+            index.extract_from(ind_header_slice).map_err(|_| libc::EIO)?;
+
+            let num_entries = index.entries;
+
+            if num_entries == 0 {
+                continue;
+            }
+
+            // check entries in index node
+            for ine_idx in
+                (hindex_len..hindex_len + (hentry_len * index.entries as usize)).step_by(hentry_len)
+            {
+                let ine_slice = &mut ind_arr_slice[ine_idx..ine_idx + hentry_len];
+                let mut ine = Htree_entry::new();
+                ine.extract_from(ine_slice).map_err(|_| libc::EIO)?;
+                let dblock_off = ine.block;
+                if dblock_off == 0 {
+                    continue;
+                }
+                let mut de_block_vec: Vec<u8> = vec![0; BSIZE];
+                let de_block_slice = de_block_vec.as_mut_slice();
+
+                self.readi(de_block_slice, BSIZE * dblock_off as usize, BSIZE, &internals)?;
+
+                // check dirents in leaf node
+                for de_off in (0..BSIZE).step_by(de_len) {
+                    let de_slice = &mut de_block_slice[de_off..de_off + de_len];
+                    let mut de = Xv6fsDirent::new();
+                    de.extract_from(de_slice).map_err(|_| libc::EIO)?;
+
+                    if de.inum == 0 {
+                        continue;
+                    }
+                    if inarg_offset >= 1 {
+                        inarg_offset -= 1;
+                        buf_off += 1;
+                        continue;
+                    }
+
+                    // Names are padded with zeroes, and can be any other bytes.
+                    let name_len = de.name.iter().position(|c| *c == 0).unwrap_or(de.name.len());
+                    let name = OsStr::from_bytes(&de.name[..name_len]);
+
+                    let full = if de.inum as u64 == nodeid {
+                        filler(de.inum as u64, buf_off, &*internals, name)?
+                    } else {
+                        let entry = self.iget(de.inum as u64)?;
+                        let entry_inode_guard = self.ilock(entry.idx, &icache, de.inum)?;
+                        let entry_internals = entry_inode_guard
+                            .internals
+                            .read()
+                            .map_err(|_| libc::EIO)?;
+                        filler(de.inum as u64, buf_off, &*entry_internals, name)?
+                    };
+                    if full {
+                        return Ok(());
+                    }
+                    buf_off += 1;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    // Copy up to len bytes at offset_in of ino_in to offset_out of ino_out for copy_file_range.
+    // The data is copied block to block in a single transaction, so the copy may be short.
+    // If offset_out is past the end of ino_out, the gap is zero-filled first.
+    fn copy_internal(
+        &self,
+        ino_in: u64,
+        offset_in: usize,
+        ino_out: u64,
+        offset_out: usize,
+        len: usize,
+    ) -> Result<usize, libc::c_int> {
+        let log = self.log.as_ref().unwrap();
+        let inode_in = self.iget(ino_in)?;
+        let inode_out = self.iget(ino_out)?;
+        let same = inode_in.inum == inode_out.inum;
+
+        let icache = self.ilock_cache.as_ref().unwrap();
+        let in_guard = if same {
+            None
+        } else {
+            Some(self.ilock(inode_in.idx, &icache, inode_in.inum)?)
+        };
+        let out_guard = self.ilock(inode_out.idx, &icache, inode_out.inum)?;
+
+        // Lock the inodes in inode number order, so copies in opposite directions can't deadlock.
+        let in_first = inode_in.inum < inode_out.inum;
+        let mut in_internals = match in_guard {
+            Some(ref x) if in_first => Some(x.internals.read().map_err(|_| libc::EIO)?),
+            _ => None,
+        };
+        let mut out_internals = out_guard.internals.write().map_err(|_| libc::EIO)?;
+        if let (Some(ref x), false) = (&in_guard, in_first) {
+            in_internals = Some(x.internals.read().map_err(|_| libc::EIO)?);
+        }
+
+        let (in_type, size_in) = match in_internals {
+            Some(ref x) => (x.inode_type, x.size as usize),
+            None => (out_internals.inode_type, out_internals.size as usize),
+        };
+        if in_type == T_DIR || out_internals.inode_type == T_DIR {
+            return Err(libc::EISDIR);
+        } else if in_type != T_FILE || out_internals.inode_type != T_FILE {
+            return Err(libc::EINVAL);
+        }
+
+        if offset_in >= size_in {
+            return Ok(0);
+        }
+        let n = min(len, size_in - offset_in);
+        if same && offset_in < offset_out + n && offset_out < offset_in + n {
+            return Err(libc::EINVAL);
+        }
+        match offset_out.checked_add(n) {
+            Some(x) if x <= (MAXFILE as usize) * BSIZE => {},
+            _ => return Err(libc::EFBIG),
+        }
+
+        let max = ((MAXOPBLOCKS - 1 - 1 - 2) / 2) * BSIZE;
+        let zeros: Vec<u8> = vec![0; max];
+        while (out_internals.size as usize) < offset_out {
+            let off = out_internals.size as usize;
+            let handle = log.begin_op(MAXOPBLOCKS as u32)?;
+            self.writei(&zeros, off, min(offset_out - off, max), &mut out_internals, inode_out.inum,
+                        &handle, true)?;
+        }
+
+        let handle = log.begin_op(MAXOPBLOCKS as u32)?;
+        let src = in_internals.as_deref();
+        return self.icopy(src, offset_in, &mut out_internals, inode_out.inum, offset_out, n, &handle);
+    }
+
     fn create_internal<'a>(
         &'a self,
+        req: &Request,
         nodeid: u64,
         itype: u16,
+        mode: u32,
+        rdev: u32,
         name: &OsStr,
         handle: &Handle
     ) -> Result<CachedInode<'a>, libc::c_int> {
+        let (major, minor) = rdev_split(rdev).ok_or(libc::EINVAL)?;
         // Get inode for parent directory
         let parent = self.iget(nodeid)?;
         let icache = self.ilock_cache.as_ref().unwrap();
@@ -1526,6 +2060,9 @@ impl Xv6FileSystem {
             .write()
             .map_err(|_| libc::EIO)?;
 
+        if itype == T_DIR && parent_internals.nlink >= MAXLINK {
+            return Err(libc::EMLINK);
+        }
         let inode = self.ialloc(itype, handle)?;
         if (parent_internals.size as usize + mem::size_of::<Xv6fsDirent>())
             > (MAXFILE as usize * BSIZE)
@@ -1536,9 +2073,25 @@ impl Xv6FileSystem {
         let inode_guard = self.ilock(inode.idx, &icache, inode.inum)?;
         let mut internals = inode_guard.internals.write().map_err(|_| libc::EIO)?;
 
-        internals.major = parent_internals.major;
-        internals.minor = parent_internals.minor;
+        internals.major = major;
+        internals.minor = minor;
         internals.nlink = 1;
+        internals.mode = (mode & 0o7777) as u16;
+        internals.uid = req.uid();
+        internals.gid = req.gid();
+        // Files created in a set-group-ID directory take the directory's group, and
+        // subdirectories inherit the flag.
+        if parent_internals.mode & 0o2000 != 0 {
+            internals.gid = parent_internals.gid;
+            if itype == T_DIR {
+                internals.mode |= 0o2000;
+            }
+        }
+        let now = current_time();
+        internals.atime = now;
+        internals.mtime = now;
+        internals.ctime = now;
+        internals.crtime = now;
     
         self.iupdate(&internals, inode.inum, handle)?;
     
@@ -1673,6 +2226,61 @@ impl Xv6FileSystem {
         return Ok(true);
     }
     
+    fn dolink(&self, nodeid: u64, newparent: u64, newname: &OsStr, handle: &Handle) -> Result<FileAttr, libc::c_int> {
+        let name_bytes = newname.as_bytes();
+        if name_bytes == b"." || name_bytes == b".." {
+            return Err(libc::EEXIST);
+        }
+        let parent = self.iget(newparent)?;
+        let inode = self.iget(nodeid)?;
+        let icache = self.ilock_cache.as_ref().unwrap();
+        let parent_inode_guard = self.ilock(parent.idx, &icache, parent.inum)?;
+        let mut parent_internals = parent_inode_guard
+            .internals
+            .write()
+            .map_err(|_| libc::EIO)?;
+        if parent_internals.inode_type != T_DIR {
+            return Err(libc::ENOTDIR);
+        }
+        // The directory was removed while still open.
+        if parent_internals.nlink < 1 {
+            return Err(libc::ENOENT);
+        }
+        let mut poff = 0;
+        match self.dirlookup(&parent_internals, newname, &mut poff) {
+            Ok(_) => return Err(libc::EEXIST),
+            Err(libc::ENOENT) => {}
+            Err(x) => return Err(x),
+        }
+        if (parent_internals.size as usize + mem::size_of::<Xv6fsDirent>())
+            > (MAXFILE as usize * BSIZE)
+        {
+            return Err(libc::EIO);
+        }
+
+        let inode_guard = self.ilock(inode.idx, &icache, inode.inum)?;
+        let mut inode_internals = inode_guard.internals.write().map_err(|_| libc::EIO)?;
+        // Directories can't have hard links, they would make the tree a graph and ".." ambiguous.
+        if inode_internals.inode_type == T_DIR {
+            return Err(libc::EPERM);
+        }
+        // The file was unlinked while still open.
+        if inode_internals.nlink < 1 {
+            return Err(libc::ENOENT);
+        }
+        if inode_internals.nlink >= MAXLINK {
+            return Err(libc::EMLINK);
+        }
+
+        self.dirlink(&mut parent_internals, newname, inode.inum, parent.inum, handle)?;
+
+        inode_internals.nlink += 1;
+        inode_internals.ctime = current_time();
+        self.iupdate(&inode_internals, inode.inum, handle)?;
+
+        return self.stati(nodeid, &inode_internals);
+    }
+
     fn dounlink(&self, nodeid: u64, name: &OsStr, handle: &Handle) -> Result<usize, libc::c_int> {
         let parent = self.iget(nodeid)?;
         let icache = self.ilock_cache.as_ref().unwrap();
@@ -1682,8 +2290,8 @@ impl Xv6FileSystem {
             .write()
             .map_err(|_| libc::EIO)?;
         let mut poff = 0;
-        let name_str = name.to_str().unwrap();
-        if name_str == "." || name_str == ".." {
+        let name_bytes = name.as_bytes();
+        if name_bytes == b"." || name_bytes == b".." {
             return Err(libc::EIO);
         }
         let inode = self.dirlookup(&mut parent_internals, name, &mut poff)?;
@@ -1726,8 +2334,54 @@ impl Xv6FileSystem {
         }
 
         inode_internals.nlink -= 1;
+        inode_internals.ctime = current_time();
         self.iupdate(&inode_internals, inode.inum, handle)?;
     
         return Ok(0);
     }
+
+    // Set the extended attribute name of nodeid to value, or remove it if value is None.
+    fn setxattr_internal(
+        &self,
+        nodeid: u64,
+        name: &OsStr,
+        value: Option<&[u8]>,
+        flags: i32,
+    ) -> Result<(), libc::c_int> {
+        let log = self.log.as_ref().unwrap();
+        let inode = self.iget(nodeid)?;
+        let icache = self.ilock_cache.as_ref().unwrap();
+        let inode_guard = self.ilock(inode.idx, &icache, inode.inum)?;
+        let mut internals = inode_guard.internals.write().map_err(|_| libc::EIO)?;
+
+        // The inode block, the xattr block, a bitmap block and the superblock.
+        let handle = log.begin_op(4)?;
+        self.setxattr(&mut internals, inode.inum, name.as_bytes(), value, flags, &handle)?;
+        return self.iupdate(&internals, inode.inum, &handle);
+    }
+
+    // Get the value of the extended attribute name of nodeid, or the list of its attribute
+    // names if name is None.
+    fn getxattr_internal(&self, nodeid: u64, name: Option<&OsStr>) -> Result<Vec<u8>, libc::c_int> {
+        let inode = self.iget(nodeid)?;
+        let icache = self.ilock_cache.as_ref().unwrap();
+        let inode_guard = self.ilock(inode.idx, &icache, inode.inum)?;
+        let internals = inode_guard.internals.read().map_err(|_| libc::EIO)?;
+
+        match name {
+            Some(name) => return self.getxattr(&internals, inode.inum, name.as_bytes()),
+            None => return self.listxattr(&internals, inode.inum),
+        }
+    }
+
+    // Reply to getxattr or listxattr. A size of 0 asks how big a buffer the data needs.
+    fn reply_xattr(data: &[u8], size: u32, reply: ReplyXattr) {
+        if size == 0 {
+            reply.size(data.len() as u32);
+        } else if data.len() > size as usize {
+            reply.error(libc::ERANGE);
+        } else {
+            reply.data(data);
+        }
+    }
 }
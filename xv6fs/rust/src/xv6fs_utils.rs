@@ -7,16 +7,69 @@
 *                      Massachusetts Institute of Technology
 */
 
+#[cfg(not(feature = "user"))]
+use crate::std;
+#[cfg(not(feature = "user"))]
+use crate::time;
+
+use alloc::vec::Vec;
+
+use core::fmt;
 use core::mem;
 use datablock::DataBlock;
 
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use time::Timespec;
+
 pub const BSIZE: usize = 4096;
 
+// On-disk format versions, stored in the superblock.
+// Version 0 is the original format with 64 byte inodes and no attributes.
+pub const XV6FS_VERSION_ORIG: u32 = 0;
+// Version 1 adds permissions, ownership and timestamps to the inode.
+pub const XV6FS_VERSION_INODE_ATTRS: u32 = 1;
+// Version 2 keeps free block and free inode counts in the superblock.
+pub const XV6FS_VERSION_FREE_COUNTS: u32 = 2;
+// Version 3 adds the magic number, UUID, label, mount tracking and feature flags. Later format
+// changes are feature flags rather than new versions.
+pub const XV6FS_VERSION_FEATURES: u32 = 3;
+pub const XV6FS_VERSION: u32 = XV6FS_VERSION_FEATURES;
+
+// "xv6f", at byte 48 of the superblock (byte 4144 of the image). Zero on file systems made
+// before version 3.
+pub const XV6FS_MAGIC: u32 = 0x66367678;
+
+// Feature flags, as in ext4. A file system that doesn't know a compat feature can still mount
+// read-write, one that doesn't know a ro_compat feature can only mount read-only, and one that
+// doesn't know an incompat feature can't mount at all. The SUPP masks are the features this
+// version knows.
+pub const XV6FS_FEATURE_COMPAT_SUPP: u32 = 0;
+pub const XV6FS_FEATURE_RO_COMPAT_SUPP: u32 = XV6FS_FEATURE_RO_COMPAT_XATTR;
+pub const XV6FS_FEATURE_INCOMPAT_SUPP: u32 = XV6FS_FEATURE_INCOMPAT_INODE_SIZE;
+
+// Inodes may have extended attributes, in the inode and in the block their xattr field points
+// to. Drivers that don't know about them would leak those blocks, and fsck would free them.
+pub const XV6FS_FEATURE_RO_COMPAT_XATTR: u32 = 0x1;
+// Inodes are inode_size bytes rather than the size of Xv6fsInode. The space after the inode
+// holds extended attributes.
+pub const XV6FS_FEATURE_INCOMPAT_INODE_SIZE: u32 = 0x1;
+
+pub const XV6FS_LABEL_LEN: usize = 16;
+
+// Superblock states. The free counts can only be trusted if the file system was cleanly
+// unmounted, otherwise they are rebuilt from the bitmap and inode table on mount.
+pub const XV6FS_STATE_DIRTY: u32 = 0;
+pub const XV6FS_STATE_CLEAN: u32 = 1;
+
 pub const T_DIR: u16 = 1;
 pub const T_FILE: u16 = 2;
-#[allow(dead_code)]
+// Character device. Device inodes keep the device number in major and minor.
 pub const T_DEV: u16 = 3;
 pub const T_LNK: u16 = 4;
+pub const T_BLK: u16 = 5;
+pub const T_FIFO: u16 = 6;
+pub const T_SOCK: u16 = 7;
 
 pub const DIRSIZ: u16 = 124;
 pub const NDIRECT: u32 = 10;
@@ -24,7 +77,10 @@ pub const NINDIRECT: u32 = (BSIZE / mem::size_of::<u32>()) as u32;
 pub const NDINDIRECT: u32 = NINDIRECT * NINDIRECT;
 pub const MAXFILE: u32 = NDIRECT + NINDIRECT + NDINDIRECT;
 
+// Inodes per block for inodes without extra space. Use Xv6fsSB::ipb for the inode table of a
+// file system.
 pub const IPB: usize = BSIZE / mem::size_of::<Xv6fsInode>();
+pub const IPB_V0: usize = BSIZE / mem::size_of::<Xv6fsInodeV0>();
 #[allow(dead_code)]
 pub const DPB: usize = BSIZE / mem::size_of::<Xv6fsDirent>();
 
@@ -33,17 +89,45 @@ pub const BPB: usize = BSIZE * 8;
 pub const NINODE: usize = 300;
 
 pub const MAXOPBLOCKS: usize = 32;
+// Largest link count of an inode, as in ext4. A directory's link count grows with its
+// subdirectories.
+pub const MAXLINK: u16 = 65000;
 #[allow(dead_code)]
 pub const LOGSIZE: usize = 1023;
 
 pub fn iblock(i: usize, sb: &Xv6fsSB) -> usize {
-    i / IPB + sb.inodestart as usize
+    i / sb.ipb() + sb.inodestart as usize
 }
 
 pub fn bblock(b: usize, sb: &Xv6fsSB) -> usize {
     b / BPB + sb.bmapstart as usize
 }
 
+// Split a device number in the kernel's encoding, as FUSE passes it to mknod, into the major
+// and minor numbers of a device inode. Fails if the minor number doesn't fit in 16 bits.
+pub fn rdev_split(rdev: u32) -> Option<(u16, u16)> {
+    let major = (rdev >> 8) & 0xfff;
+    let minor = (rdev & 0xff) | ((rdev >> 12) & 0xfff00);
+    if minor > u16::MAX as u32 {
+        return None;
+    }
+    Some((major as u16, minor as u16))
+}
+
+// Device number in the kernel's encoding for the major and minor numbers of a device inode.
+pub fn rdev_join(major: u16, minor: u16) -> u32 {
+    let (major, minor) = (major as u32 & 0xfff, minor as u32);
+    (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+}
+
+// Current wall clock time, for inode timestamps.
+pub fn current_time() -> Timespec {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(dur) => Timespec::new(dur.as_secs() as i64, dur.subsec_nanos() as i32),
+        Err(_) => Timespec::new(0, 0),
+    }
+}
+
 #[repr(C)]
 #[derive(DataBlock, Copy, Clone)]
 pub struct Xv6fsInode {
@@ -53,9 +137,59 @@ pub struct Xv6fsInode {
     pub nlink: u16,
     pub size: u64,
     pub addrs: [u32; NDIRECT as usize + 2],
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    // Block holding the extended attributes that don't fit in the inode, or 0.
+    pub xattr: u32,
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+    pub crtime: u64,
+    pub atime_nsec: u32,
+    pub mtime_nsec: u32,
+    pub ctime_nsec: u32,
+    pub crtime_nsec: u32,
 }
 
 impl Xv6fsInode {
+    pub const fn new() -> Self {
+        Self {
+            inode_type: 0,
+            major: 0,
+            minor: 0,
+            nlink: 0,
+            size: 0,
+            addrs: [0; NDIRECT as usize + 2],
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            xattr: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+            crtime: 0,
+            atime_nsec: 0,
+            mtime_nsec: 0,
+            ctime_nsec: 0,
+            crtime_nsec: 0,
+        }
+    }
+}
+
+// Inode layout used by version 0 file systems. Only used to upgrade them.
+#[repr(C)]
+#[derive(DataBlock, Copy, Clone)]
+pub struct Xv6fsInodeV0 {
+    pub inode_type: u16,
+    pub major: u16,
+    pub minor: u16,
+    pub nlink: u16,
+    pub size: u64,
+    pub addrs: [u32; NDIRECT as usize + 2],
+}
+
+impl Xv6fsInodeV0 {
     pub const fn new() -> Self {
         Self {
             inode_type: 0,
@@ -78,6 +212,122 @@ pub struct Xv6fsSB {
     pub logstart: u32,
     pub inodestart: u32,
     pub bmapstart: u32,
+    // On-disk format version. Zero on file systems made before the field existed.
+    pub version: u32,
+    // While converting a version 0 inode table, the number of inode blocks left to convert.
+    pub upgrade_next: u32,
+    // Number of free blocks and free inodes. Version 2 and later.
+    pub nfree: u32,
+    pub nifree: u32,
+    // XV6FS_STATE_CLEAN if unmounted cleanly, XV6FS_STATE_DIRTY while mounted.
+    pub state: u32,
+    // Version 3 and later.
+    pub magic: u32,
+    pub feature_compat: u32,
+    pub feature_incompat: u32,
+    pub feature_ro_compat: u32,
+    pub uuid: [u8; 16],
+    // Volume label, padded with zeros. Not terminated if all 16 bytes are used.
+    pub label: [u8; XV6FS_LABEL_LEN],
+    // Number of times the file system was mounted read-write, and when it last was, in seconds
+    // since the epoch.
+    pub mnt_count: u32,
+    // Size of an inode in the inode table if XV6FS_FEATURE_INCOMPAT_INODE_SIZE is set.
+    pub inode_size: u32,
+    pub mount_time: u64,
+}
+
+impl Xv6fsSB {
+    pub const fn new() -> Self {
+        Self {
+            size: 0,
+            nblocks: 0,
+            ninodes: 0,
+            nlog: 0,
+            logstart: 0,
+            inodestart: 0,
+            bmapstart: 0,
+            version: 0,
+            upgrade_next: 0,
+            nfree: 0,
+            nifree: 0,
+            state: XV6FS_STATE_DIRTY,
+            magic: 0,
+            feature_compat: 0,
+            feature_incompat: 0,
+            feature_ro_compat: 0,
+            uuid: [0; 16],
+            label: [0; XV6FS_LABEL_LEN],
+            mnt_count: 0,
+            inode_size: 0,
+            mount_time: 0,
+        }
+    }
+
+    // Whether this looks like an xv6fs superblock. Images made before version 3 have no magic
+    // number, so for those only the layout is checked.
+    pub fn is_valid(&self) -> bool {
+        if self.magic != XV6FS_MAGIC && (self.magic != 0 || self.version >= XV6FS_VERSION_FEATURES) {
+            return false;
+        }
+        let nbitmap = self.size as u64 / BPB as u64 + 1;
+        if self.feature_incompat & XV6FS_FEATURE_INCOMPAT_INODE_SIZE != 0 {
+            let isize = self.inode_size as usize;
+            if !isize.is_power_of_two() || isize < mem::size_of::<Xv6fsInode>() || isize > BSIZE {
+                return false;
+            }
+        }
+        self.logstart == 2
+            && self.inodestart as u64 == self.logstart as u64 + self.nlog as u64
+            && self.inodestart < self.bmapstart
+            && self.ninodes > 1
+            && self.bmapstart as u64 + nbitmap < self.size as u64
+            && self.nblocks < self.size
+    }
+
+    // Size of an inode in the inode table, including the space for extended attributes.
+    pub fn inode_size(&self) -> usize {
+        if self.feature_incompat & XV6FS_FEATURE_INCOMPAT_INODE_SIZE != 0 {
+            self.inode_size as usize
+        } else {
+            mem::size_of::<Xv6fsInode>()
+        }
+    }
+
+    // Inodes per block of the inode table. Version 0 inode tables are only read while upgrading
+    // them, which uses IPB_V0.
+    pub fn ipb(&self) -> usize {
+        BSIZE / self.inode_size()
+    }
+
+    // The label, without the zero padding.
+    pub fn label(&self) -> &[u8] {
+        let len = self.label.iter().position(|c| *c == 0).unwrap_or(XV6FS_LABEL_LEN);
+        &self.label[..len]
+    }
+}
+
+// Formats a UUID in the usual 8-4-4-4-12 form.
+pub struct Uuid<'a>(pub &'a [u8; 16]);
+
+impl fmt::Display for Uuid<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i == 4 || i == 6 || i == 8 || i == 10 {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+// In-memory free block and free inode counts, written to the superblock in the same
+// transaction as the bitmap or inode that changed them.
+#[derive(Copy, Clone)]
+pub struct FreeCounts {
+    pub nfree: u32,
+    pub nifree: u32,
 }
 
 #[repr(C)]
@@ -95,3 +345,117 @@ impl Xv6fsDirent {
         }
     }
 }
+
+// Extended attributes are kept in the space after the inode in large inodes and in the block the
+// inode's xattr field points to. Each of these xattr areas starts with an Xv6fsXattrHeader,
+// followed by count entries sorted by name. An entry is an Xv6fsXattrEntry, then the name, then
+// the value, padded to a multiple of 4 bytes. An area that is all zeros has no attributes.
+
+// "xatr"
+pub const XV6FS_XATTR_MAGIC: u32 = 0x72746178;
+// Longest attribute name, as in Linux.
+pub const XATTR_NAME_MAX: usize = 255;
+
+#[repr(C)]
+#[derive(DataBlock)]
+pub struct Xv6fsXattrHeader {
+    pub magic: u32,
+    pub count: u32,
+}
+
+#[repr(C)]
+#[derive(DataBlock)]
+pub struct Xv6fsXattrEntry {
+    pub name_len: u16,
+    pub value_len: u16,
+}
+
+pub struct Xattr {
+    pub name: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl Xattr {
+    // Bytes the attribute takes up in an xattr area.
+    pub fn disk_len(&self) -> usize {
+        (mem::size_of::<Xv6fsXattrEntry>() + self.name.len() + self.value.len() + 3) & !3
+    }
+}
+
+// Append the attributes in an xattr area to attrs. Fails if the area is corrupt.
+pub fn xattr_decode(area: &[u8], attrs: &mut Vec<Xattr>) -> Result<(), ()> {
+    let hlen = mem::size_of::<Xv6fsXattrHeader>();
+    let elen = mem::size_of::<Xv6fsXattrEntry>();
+    let mut header = Xv6fsXattrHeader { magic: 0, count: 0 };
+    header.extract_from(area.get(..hlen).ok_or(())?)?;
+    if header.magic == 0 && header.count == 0 {
+        return Ok(());
+    }
+    if header.magic != XV6FS_XATTR_MAGIC {
+        return Err(());
+    }
+
+    let first = attrs.len();
+    let mut off = hlen;
+    for _ in 0..header.count {
+        let mut entry = Xv6fsXattrEntry { name_len: 0, value_len: 0 };
+        entry.extract_from(area.get(off..off + elen).ok_or(())?)?;
+        let name_off = off + elen;
+        let value_off = name_off + entry.name_len as usize;
+        let end = value_off + entry.value_len as usize;
+        if entry.name_len == 0 || entry.name_len as usize > XATTR_NAME_MAX || end > area.len() {
+            return Err(());
+        }
+        let attr = Xattr {
+            name: area[name_off..value_off].to_vec(),
+            value: area[value_off..end].to_vec(),
+        };
+        if attrs.len() > first && attrs[attrs.len() - 1].name >= attr.name {
+            return Err(());
+        }
+        off += attr.disk_len();
+        attrs.push(attr);
+    }
+    return Ok(());
+}
+
+// Bytes an xattr area holding attrs needs.
+pub fn xattr_area_len(attrs: &[Xattr]) -> usize {
+    mem::size_of::<Xv6fsXattrHeader>() + attrs.iter().map(|x| x.disk_len()).sum::<usize>()
+}
+
+// Write attrs, which must be sorted by name, into an xattr area at least xattr_area_len bytes
+// long. An empty list leaves the area all zeros.
+pub fn xattr_encode(attrs: &[Xattr], area: &mut [u8]) -> Result<(), ()> {
+    for byte in area.iter_mut() {
+        *byte = 0;
+    }
+    if attrs.is_empty() {
+        return Ok(());
+    }
+    if xattr_area_len(attrs) > area.len() {
+        return Err(());
+    }
+
+    let hlen = mem::size_of::<Xv6fsXattrHeader>();
+    let elen = mem::size_of::<Xv6fsXattrEntry>();
+    let header = Xv6fsXattrHeader {
+        magic: XV6FS_XATTR_MAGIC,
+        count: attrs.len() as u32,
+    };
+    header.dump_into(&mut area[..hlen])?;
+    let mut off = hlen;
+    for attr in attrs {
+        let entry = Xv6fsXattrEntry {
+            name_len: attr.name.len() as u16,
+            value_len: attr.value.len() as u16,
+        };
+        entry.dump_into(&mut area[off..off + elen])?;
+        let name_off = off + elen;
+        let value_off = name_off + attr.name.len();
+        area[name_off..value_off].copy_from_slice(&attr.name);
+        area[value_off..value_off + attr.value.len()].copy_from_slice(&attr.value);
+        off += attr.disk_len();
+    }
+    return Ok(());
+}
@@ -1,11 +1,9 @@
 #![feature(slice_fill)]
 
 extern crate alloc;
-//#[macro_use]
 extern crate bento_utils;
 extern crate core;
 extern crate datablock;
-extern crate fuse;
 extern crate serde;
 extern crate time;
 
@@ -14,9 +12,11 @@ pub mod xv6fs_ll;
 pub mod xv6fs_file;
 pub mod xv6fs_fs;
 pub mod xv6fs_htree;
-pub mod xv6fs_log;
 pub mod xv6fs_utils;
 
+#[cfg(test)]
+mod tests;
+
 use alloc::sync::Arc;
 
 use std::env;
@@ -25,11 +25,8 @@ use xv6fs_ll::Xv6FileSystem;
 use xv6fs_utils::BSIZE;
 
 use bento_utils::*;
-use fuse::*;
-use std::path::Path;
-use time::Timespec;
-
-impl_filesystem!(Xv6FileSystem);
+use bento_utils::fuse;
+use std::process;
 
 fn main() {
     env_logger::init();
@@ -38,24 +35,24 @@ fn main() {
     let fsname_arg = fsname_arg_str.as_str();
     let disk = Disk::new(disk_name.to_str().unwrap(), BSIZE as u64);
     let fs = Xv6FileSystem {
-        log: None,
-        sb: None,
         disk: Some(Arc::new(disk)),
-        ilock_cache: None,
-        icache_map: None,
-        ialloc_lock: None,
-        balloc_lock: None,
         diskname: Some(disk_name.to_str().unwrap().to_string()),
+        ..Xv6FileSystem::new()
     };
 
     let mountpoint = env::args_os().nth(2).unwrap();
-    let mut opts_arr = vec!["-o", fsname_arg];
-    if let Some(arg) = env::args_os().nth(3) {
-        if arg.to_str().unwrap() == "blkdev" {
-            opts_arr.append(&mut vec!["-o", "blkdev"]);
-        }
+    // Serve requests from one thread per CPU, as the kernel build does. A "threads=N" in the
+    // mount options below overrides this.
+    let mut opts_arr = vec!["-o", fsname_arg, "-o", "threads=0"];
+    // Mount options such as "blkdev" or "commit=10"
+    let mount_opts = env::args().nth(3);
+    if let Some(arg) = mount_opts.as_ref() {
+        opts_arr.append(&mut vec!["-o", arg.as_str()]);
     }
     let options = opts_arr.iter().map(OsStr::new).collect::<Vec<&OsStr>>();
 
-    fuse::mount(fs, &mountpoint, &options).unwrap();
+    if let Err(e) = bento_utils::options::mount(fs, &mountpoint, &options) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
 }
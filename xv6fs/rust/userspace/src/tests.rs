@@ -0,0 +1,326 @@
+// Tests of xv6fs on a RAM disk, driven in-process through `bento_utils::testing::Harness`.
+//
+// Images are made by mkfs.xv6fs, which `make` in xv6fs/mkfs builds. Set MKFS_XV6FS to use
+// another binary.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use bento_utils::crash::{self, CrashConfig, RecordingBackend};
+use bento_utils::fuse::FileType;
+use bento_utils::testing::{Harness, XattrReply, ROOT_ID};
+use bento_utils::{BlockBackend, Disk, RamBackend};
+
+use datablock::DataBlock;
+
+use xv6fs_ll::{Xv6FileSystem, Xv6State};
+use xv6fs_utils::{Xv6fsSB, BSIZE, XV6FS_FEATURE_RO_COMPAT_XATTR};
+
+pub type Xv6Harness = Harness<'static, Xv6State, Xv6State, Xv6FileSystem>;
+
+// Blocks in a test image, 16 MiB.
+const IMAGE_BLOCKS: usize = 4096;
+
+fn mkfs_path() -> PathBuf {
+    match env::var_os("MKFS_XV6FS") {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../mkfs/mkfs.xv6fs"),
+    }
+}
+
+/// Make an image with mkfs.xv6fs, passing it `args` as well as the size.
+pub fn mkfs_image(args: &[&str]) -> Vec<u8> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let path = env::temp_dir().join(format!("xv6fs-test-{}-{}.img", std::process::id(),
+                                            NEXT.fetch_add(1, Ordering::SeqCst)));
+    let mkfs = mkfs_path();
+    let output = Command::new(&mkfs)
+        .args(&["-s", &IMAGE_BLOCKS.to_string(), "-i", "256", "-l", "256"])
+        .args(args)
+        .arg(&path)
+        .output()
+        .unwrap_or_else(|e| panic!("can't run {} (build it with make in xv6fs/mkfs): {}", mkfs.display(), e));
+    assert!(output.status.success(), "mkfs.xv6fs failed: {}", String::from_utf8_lossy(&output.stderr));
+    let image = fs::read(&path).unwrap();
+    let _ = fs::remove_file(&path);
+    image
+}
+
+/// Mount xv6fs on a RAM disk holding `disk`.
+pub fn mount_disk(disk: Arc<Disk>, options: &str) -> Xv6Harness {
+    let fs = Xv6FileSystem {
+        disk: Some(disk),
+        diskname: Some("ram\0".to_string()),
+        ..Xv6FileSystem::new()
+    };
+    let mut harness = Harness::new(fs);
+    harness.init(options).unwrap();
+    harness
+}
+
+/// Mount a new file system on a RAM disk.
+pub fn mounted() -> Xv6Harness {
+    mount_disk(Arc::new(Disk::from_image(mkfs_image(&[]), BSIZE as u64)), "")
+}
+
+#[test]
+fn files_and_directories() {
+    let mut harness = mounted();
+    harness.mkdir_path("/dir", 0o755).unwrap();
+
+    // Large enough to need indirect blocks.
+    let data: Vec<u8> = (0..BSIZE * 20).map(|i| (i % 251) as u8).collect();
+    let fd = harness.open_path("/dir/file", libc::O_CREAT | libc::O_RDWR, 0o644).unwrap();
+    assert_eq!(harness.write_fd(fd, &data).unwrap(), data.len());
+    harness.fsync_fd(fd, false).unwrap();
+    harness.close(fd).unwrap();
+
+    let attr = harness.stat("/dir/file").unwrap();
+    assert_eq!(attr.size, data.len() as u64);
+    assert_eq!(attr.perm, 0o644);
+    let fd = harness.open_path("/dir/file", libc::O_RDONLY, 0).unwrap();
+    let mut read = Vec::new();
+    loop {
+        let chunk = harness.read_fd(fd, 3 * BSIZE as u32).unwrap();
+        if chunk.is_empty() {
+            break;
+        }
+        read.extend(chunk);
+    }
+    harness.close(fd).unwrap();
+    assert!(read == data);
+
+    harness.rename_path("/dir/file", "/moved").unwrap();
+    assert_eq!(harness.stat("/dir/file").err(), Some(libc::ENOENT));
+    let names: Vec<_> = harness.readdir_path("/").unwrap().into_iter().map(|x| x.0).collect();
+    assert!(names.iter().any(|x| x == "moved"));
+    assert!(names.iter().any(|x| x == "dir"));
+
+    harness.symlink_path("moved", "/link").unwrap();
+    assert_eq!(harness.readlink_path("/link").unwrap().split(|x| *x == 0).next().unwrap(), b"moved");
+
+    harness.unlink_path("/moved").unwrap();
+    harness.unlink_path("/link").unwrap();
+    harness.rmdir_path("/dir").unwrap();
+    assert!(harness.readdir_path("/").unwrap().iter().all(|x| x.0 == "." || x.0 == ".."));
+    harness.destroy();
+}
+
+#[test]
+fn data_survives_a_remount() {
+    let disk = Arc::new(Disk::from_image(mkfs_image(&[]), BSIZE as u64));
+    let mut harness = mount_disk(Arc::clone(&disk), "");
+    harness.mkdir_path("/a", 0o700).unwrap();
+    let fd = harness.open_path("/a/b", libc::O_CREAT | libc::O_WRONLY, 0o600).unwrap();
+    harness.write_fd(fd, b"persistent").unwrap();
+    harness.close(fd).unwrap();
+    harness.destroy();
+    drop(harness);
+
+    let mut harness = mount_disk(disk, "");
+    let fd = harness.open_path("/a/b", libc::O_RDONLY, 0).unwrap();
+    assert_eq!(harness.read_fd(fd, 100).unwrap(), b"persistent");
+    harness.close(fd).unwrap();
+    harness.destroy();
+}
+
+#[test]
+fn crash_states_recover() {
+    let image = mkfs_image(&[]);
+    let base: Arc<dyn BlockBackend> = Arc::new(RamBackend::from_image(image.clone()));
+    let recording = Arc::new(RecordingBackend::new(RamBackend::from_image(image)));
+    let mut harness = mount_disk(Arc::new(Disk::with_backend(recording.clone(), BSIZE as u64)), "");
+
+    harness.mkdir_path("/dir", 0o755).unwrap();
+    let fd = harness.open_path("/dir/file", libc::O_CREAT | libc::O_WRONLY, 0o644).unwrap();
+    harness.write_fd(fd, b"durable").unwrap();
+    harness.fsync_fd(fd, false).unwrap();
+    recording.mark("fsync");
+    harness.close(fd).unwrap();
+    harness.rename_path("/dir/file", "/file").unwrap();
+    harness.destroy();
+
+    let log = recording.log();
+    let config = CrashConfig { max_exhaustive: 4, max_states: 500 };
+    let report = crash::check(base, BSIZE as u64, &log, &config, "", |disk| Xv6FileSystem {
+        disk: Some(disk),
+        diskname: Some("ram\0".to_string()),
+        ..Xv6FileSystem::new()
+    }, |harness, state| {
+        if !state.passed("fsync") {
+            return Ok(());
+        }
+        // The rename may or may not have happened, but the data must be in one place.
+        let data = crash::read_file(harness, "/dir/file")
+            .or_else(|_| crash::read_file(harness, "/file"))
+            .map_err(|x| format!("fsynced file is missing: errno {}", x))?;
+        if data != b"durable" {
+            return Err(format!("fsynced file holds {:?}", data));
+        }
+        Ok(())
+    });
+    assert!(report.states > 1 && !report.truncated, "{}", report);
+    assert!(report.is_ok(), "{}", report);
+}
+
+// Copy `len` bytes with copy_file_range, repeating short copies as the kernel does.
+fn copy_range(harness: &mut Xv6Harness, ino_in: u64, offset_in: usize, ino_out: u64, offset_out: usize,
+              len: usize) -> usize {
+    let mut done = 0;
+    while done < len {
+        let n = harness.copy_file_range(ino_in, 0, (offset_in + done) as i64, ino_out, 0,
+                                        (offset_out + done) as i64, (len - done) as u64).unwrap();
+        if n == 0 {
+            break;
+        }
+        done += n as usize;
+    }
+    done
+}
+
+#[test]
+fn copy_ranges_across_blocks() {
+    let mut harness = mounted();
+    let data: Vec<u8> = (0..BSIZE * 7 / 2).map(|i| (i % 253) as u8).collect();
+    let fd = harness.open_path("/src", libc::O_CREAT | libc::O_WRONLY, 0o644).unwrap();
+    harness.write_fd(fd, &data).unwrap();
+    harness.close(fd).unwrap();
+    let fd = harness.open_path("/dst", libc::O_CREAT | libc::O_WRONLY, 0o644).unwrap();
+    harness.close(fd).unwrap();
+    let src = harness.stat("/src").unwrap().ino;
+    let dst = harness.stat("/dst").unwrap().ino;
+
+    // Unaligned at both ends and on a different offset within the block on each side, into a
+    // file that has to be extended with zeros first.
+    let (off_in, off_out, len) = (BSIZE - 100, 50, 2 * BSIZE + 300);
+    assert_eq!(copy_range(&mut harness, src, off_in, dst, off_out, len), len);
+    let mut expected = vec![0; off_out];
+    expected.extend_from_slice(&data[off_in..off_in + len]);
+    assert!(crash::read_file(&mut harness, "/dst").unwrap() == expected);
+
+    // Over the middle of existing data, from a range running past the end of the source.
+    let off_out = BSIZE / 2;
+    let copied = copy_range(&mut harness, src, 3 * BSIZE, dst, off_out, BSIZE);
+    assert_eq!(copied, BSIZE / 2);
+    expected[off_out..off_out + copied].copy_from_slice(&data[3 * BSIZE..]);
+    assert!(crash::read_file(&mut harness, "/dst").unwrap() == expected);
+
+    // Within one file, to a range that doesn't overlap.
+    assert_eq!(copy_range(&mut harness, src, 10, src, 2 * BSIZE + 10, BSIZE), BSIZE);
+    let mut expected = data.clone();
+    expected.copy_within(10..BSIZE + 10, 2 * BSIZE + 10);
+    assert!(crash::read_file(&mut harness, "/src").unwrap() == expected);
+    assert_eq!(harness.copy_file_range(src, 0, 0, src, 0, 100, 200), Err(libc::EINVAL));
+    harness.destroy();
+}
+
+#[test]
+fn mknod_keeps_the_type_and_device_number() {
+    let disk = Arc::new(Disk::from_image(mkfs_image(&[]), BSIZE as u64));
+    let mut harness = mount_disk(Arc::clone(&disk), "");
+    let chr = libc::makedev(136, 300) as u32;
+    let blk = libc::makedev(8, 17) as u32;
+    let nodes = [
+        ("chr", libc::S_IFCHR, chr, FileType::CharDevice),
+        ("blk", libc::S_IFBLK, blk, FileType::BlockDevice),
+        ("fifo", libc::S_IFIFO, 0, FileType::NamedPipe),
+        ("sock", libc::S_IFSOCK, 0, FileType::Socket),
+    ];
+    for &(name, kind, rdev, _) in nodes.iter() {
+        harness.mknod(ROOT_ID, name, kind | 0o640, rdev).unwrap();
+    }
+    // The minor number is stored in 16 bits.
+    assert_eq!(harness.mknod(ROOT_ID, "big", libc::S_IFCHR | 0o640, libc::makedev(1, 0x10000) as u32).err(),
+               Some(libc::EINVAL));
+    harness.destroy();
+    drop(harness);
+
+    let mut harness = mount_disk(disk, "");
+    let entries = harness.readdir_path("/").unwrap();
+    for &(name, _, rdev, file_type) in nodes.iter() {
+        let attr = harness.stat(&format!("/{}", name)).unwrap();
+        assert_eq!(attr.kind, file_type, "{}", name);
+        assert_eq!(attr.rdev, rdev, "{}", name);
+        assert_eq!(attr.perm, 0o640, "{}", name);
+        assert!(entries.iter().any(|x| x.0 == name && x.1 == file_type), "{}", name);
+    }
+    harness.destroy();
+}
+
+// The ro_compat features in the superblock on disk.
+fn ro_compat_features(disk: &Disk) -> u32 {
+    let bh = disk.bread(1).unwrap();
+    let mut sb = Xv6fsSB::new();
+    sb.extract_from(&bh.data()[..std::mem::size_of::<Xv6fsSB>()]).unwrap();
+    sb.feature_ro_compat
+}
+
+#[test]
+fn xattrs_overflow_into_the_xattr_block() {
+    let disk = Arc::new(Disk::from_image(mkfs_image(&[]), BSIZE as u64));
+    let mut harness = mount_disk(Arc::clone(&disk), "");
+    let fd = harness.open_path("/file", libc::O_CREAT | libc::O_RDWR, 0o644).unwrap();
+    harness.close(fd).unwrap();
+    let ino = harness.stat("/file").unwrap().ino;
+
+    // The feature is only set by the first attribute written, not by the mount or a failed
+    // removal.
+    assert_eq!(harness.removexattr(ino, "user.none"), Err(libc::ENODATA));
+    assert_eq!(ro_compat_features(&disk) & XV6FS_FEATURE_RO_COMPAT_XATTR, 0);
+    assert!(!harness.fs().xattr_feature.load(Ordering::SeqCst));
+
+    harness.setxattr(ino, "user.small", b"value", 0).unwrap();
+    assert!(harness.fs().xattr_feature.load(Ordering::SeqCst));
+    assert_eq!(harness.setxattr(ino, "user.small", b"again", libc::XATTR_CREATE as u32),
+               Err(libc::EEXIST));
+    assert_eq!(harness.setxattr(ino, "user.none", b"value", libc::XATTR_REPLACE as u32),
+               Err(libc::ENODATA));
+    harness.setxattr(ino, "user.small", b"replaced", libc::XATTR_REPLACE as u32).unwrap();
+    assert_eq!(harness.getxattr(ino, "user.small", 0), Ok(XattrReply::Size(8)));
+    assert_eq!(harness.getxattr(ino, "user.small", 7), Err(libc::ERANGE));
+    assert_eq!(harness.getxattr(ino, "user.small", 8), Ok(XattrReply::Data(b"replaced".to_vec())));
+    assert_eq!(harness.getxattr(ino, "user.none", 100), Err(libc::ENODATA));
+
+    // More than the inode has room for, so some go in a block of their own.
+    let free = harness.statfs(ROOT_ID).unwrap().bfree;
+    let values: Vec<Vec<u8>> = (0..8u8).map(|i| vec![b'a' + i; 300]).collect();
+    for (i, value) in values.iter().enumerate() {
+        harness.setxattr(ino, format!("user.big{}", i), value, 0).unwrap();
+    }
+    assert_eq!(harness.statfs(ROOT_ID).unwrap().bfree, free - 1);
+    assert_eq!(harness.setxattr(ino, "user.huge", &vec![0; BSIZE], 0), Err(libc::E2BIG));
+    harness.destroy();
+    drop(harness);
+    assert_ne!(ro_compat_features(&disk) & XV6FS_FEATURE_RO_COMPAT_XATTR, 0);
+
+    let mut harness = mount_disk(disk, "");
+    assert!(harness.fs().xattr_feature.load(Ordering::SeqCst));
+    for (i, value) in values.iter().enumerate() {
+        assert_eq!(harness.getxattr(ino, format!("user.big{}", i), 300),
+                   Ok(XattrReply::Data(value.clone())));
+    }
+    let names = match harness.listxattr(ino, 4096).unwrap() {
+        XattrReply::Data(names) => names,
+        reply => panic!("{:?}", reply),
+    };
+    let mut names: Vec<&[u8]> = names.split(|x| *x == 0).filter(|x| !x.is_empty()).collect();
+    names.sort();
+    let mut expected: Vec<String> = (0..8).map(|i| format!("user.big{}", i)).collect();
+    expected.push("user.small".to_string());
+    assert!(names.iter().zip(expected.iter()).all(|(a, b)| *a == b.as_bytes()));
+    assert_eq!(names.len(), expected.len());
+
+    // Removing the attributes that overflowed frees the xattr block again.
+    let free = harness.statfs(ROOT_ID).unwrap().bfree;
+    for i in 0..8 {
+        harness.removexattr(ino, format!("user.big{}", i)).unwrap();
+    }
+    assert_eq!(harness.statfs(ROOT_ID).unwrap().bfree, free + 1);
+    assert_eq!(harness.getxattr(ino, "user.big0", 0), Err(libc::ENODATA));
+    assert_eq!(harness.getxattr(ino, "user.small", 0), Ok(XattrReply::Size(8)));
+    harness.destroy();
+}
@@ -35,16 +35,7 @@ use xv6fs_ll::Xv6FileSystem;
 
 pub static FS_NAME: &'static str = "xv6fs_ll\0";
 
-pub static XV6FS: Xv6FileSystem = Xv6FileSystem {
-    log: None,
-    sb: None,
-    disk: None,
-    ilock_cache: None,
-    icache_map: None,
-    ialloc_lock: None,
-    balloc_lock: None,
-    diskname: None,
-};
+pub static XV6FS: Xv6FileSystem = Xv6FileSystem::new();
 
 
 #[no_mangle]
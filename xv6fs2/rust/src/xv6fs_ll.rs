@@ -18,6 +18,7 @@ use crate::std;
 #[cfg(not(feature = "user"))]
 use crate::time;
 
+use alloc::boxed::Box;
 use alloc::collections::btree_map::BTreeMap;
 
 use alloc::string::String;
@@ -26,6 +27,7 @@ use alloc::sync::Arc;
 use alloc::vec::Vec;
 
 use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicUsize};
 use core::str;
 
 use bento_utils::BentoFilesystem;
@@ -34,6 +36,7 @@ use datablock::DataBlock;
 
 use bento_utils::consts::*;
 use bento_utils::*;
+use bento_utils::options::*;
 use fuse::consts::*;
 
 use fuse::*;
@@ -61,6 +64,11 @@ pub struct Xv6State {
     log: Option<Journal>,
 }
 
+impl TransferState for Xv6State {
+    const NAME: &'static str = "xv6fs::Xv6State";
+    const VERSION: u32 = 1;
+}
+
 pub struct Xv6FileSystem {
     pub log: Option<Journal>,
     pub sb: Option<Xv6fsSB>,
@@ -70,6 +78,11 @@ pub struct Xv6FileSystem {
     pub ialloc_lock: Option<RwLock<usize>>,
     pub balloc_lock: Option<RwLock<usize>>,
     pub diskname: Option<String>,
+    // Allocation hints: the last block and inode allocated, and whether the inode search has
+    // yet to wrap around.
+    pub last_block: AtomicUsize,
+    pub last_inode: AtomicUsize,
+    pub first_i_loop: AtomicBool,
 }
 
 impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
@@ -77,6 +90,10 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         Xv6FileSystem::NAME
     }
 
+    fn new_instance(&self) -> Box<Self> {
+        Box::new(Xv6FileSystem::new())
+    }
+
     fn bento_destroy(&mut self, _req: &Request) {
         // Allow log and disk to be dropped
         self.log = None;
@@ -88,6 +105,7 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         &mut self,
         _req: &Request,
         devname: &OsStr,
+        _opts: &MountOptions,
         fc_info: &mut FuseConnInfo,
     ) -> Result<(), i32> {
         fc_info.proto_major = BENTO_KERNEL_VERSION;
@@ -173,7 +191,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         }
 
         if flags & libc::O_TRUNC as u32 != 0 {
-            let handle = log.begin_op(2);
+            let handle = match log.begin_op(2) {
+                Ok(x) => x,
+                Err(x) => {
+                    reply.error(x);
+                    return;
+                }
+            };
             internals.size = 0;
             if let Err(x) = self.iupdate(&internals, inode.inum, &handle) {
                 reply.error(x);
@@ -438,7 +462,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         let mut file_off = 0;
         while i < n {
             let log = self.log.as_ref().unwrap();
-            let handle = log.begin_op(MAXOPBLOCKS as u32);
+            let handle = match log.begin_op(MAXOPBLOCKS as u32) {
+                Ok(x) => x,
+                Err(x) => {
+                    reply.error(x);
+                    return;
+                }
+            };
             let inode = match self.iget(nodeid) {
                 Ok(x) => x,
                 Err(x) => {
@@ -729,7 +759,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
     ) {
         // Check if the file already exists
         let log = self.log.as_ref().unwrap();
-        let handle = log.begin_op(16);
+        let handle = match log.begin_op(16) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
         let child = match self.create_internal(parent, T_FILE, name, &handle) {
             Ok(x) => x,
             Err(x) => {
@@ -778,7 +814,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         reply: ReplyEntry,
     ) {
         let log = self.log.as_ref().unwrap();
-        let handle = log.begin_op(MAXOPBLOCKS as u32);
+        let handle = match log.begin_op(MAXOPBLOCKS as u32) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
         let child = match self.create_internal(parent, T_DIR, &name, &handle) {
             Ok(x) => x,
             Err(x) => {
@@ -820,7 +862,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
 
     fn bento_rmdir(&self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let log = self.log.as_ref().unwrap();
-        let handle = log.begin_op(MAXOPBLOCKS as u32);
+        let handle = match log.begin_op(MAXOPBLOCKS as u32) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
         match self.dounlink(parent, name, &handle) {
             Ok(_) => reply.ok(),
             Err(x) => reply.error(x),
@@ -829,7 +877,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
 
     fn bento_unlink(&self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let log = self.log.as_ref().unwrap();
-        let handle = log.begin_op(MAXOPBLOCKS as u32);
+        let handle = match log.begin_op(MAXOPBLOCKS as u32) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
         match self.dounlink(parent, name, &handle) {
             Ok(_) => {
                 reply.ok();
@@ -859,7 +913,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         reply: ReplyEntry,
     ) {
         let log = self.log.as_ref().unwrap();
-        let handle = log.begin_op(MAXOPBLOCKS as u32);
+        let handle = match log.begin_op(MAXOPBLOCKS as u32) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
         // Create new file
         let child = match self.create_internal(nodeid, T_LNK, name, &handle) {
             Ok(x) => x,
@@ -1024,6 +1084,22 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
 impl Xv6FileSystem {
     const NAME: &'static str = "xv6fs_ll\0";
 
+    pub const fn new() -> Self {
+        Xv6FileSystem {
+            log: None,
+            sb: None,
+            disk: None,
+            ilock_cache: None,
+            icache_map: None,
+            ialloc_lock: None,
+            balloc_lock: None,
+            diskname: None,
+            last_block: AtomicUsize::new(0),
+            last_inode: AtomicUsize::new(0),
+            first_i_loop: AtomicBool::new(true),
+        }
+    }
+
     fn create_internal<'a>(
         &'a self,
         nodeid: u64,
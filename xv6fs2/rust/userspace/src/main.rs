@@ -4,7 +4,6 @@ extern crate alloc;
 extern crate bento_utils;
 extern crate core;
 extern crate datablock;
-extern crate fuse;
 extern crate serde;
 extern crate time;
 
@@ -23,12 +22,9 @@ use std::ffi::OsStr;
 use xv6fs_ll::Xv6FileSystem;
 use xv6fs_utils::BSIZE;
 
-use fuse::*;
+use bento_utils::fuse;
 use bento_utils::*;
-use time::Timespec;
-use std::path::Path;
-
-impl_filesystem!(Xv6FileSystem);
+use std::process;
 
 fn main() {
     env_logger::init();
@@ -37,24 +33,22 @@ fn main() {
     let fsname_arg = fsname_arg_str.as_str();
     let disk = Disk::new(disk_name.to_str().unwrap(), BSIZE as u64);
     let fs = Xv6FileSystem {
-        log: None,
-        sb: None,
         disk: Some(Arc::new(disk)),
-        ilock_cache: None,
-        icache_map: None,
-        ialloc_lock: None,
-        balloc_lock: None,
         diskname: Some(disk_name.to_str().unwrap().to_string()),
+        ..Xv6FileSystem::new()
     };
 
     let mountpoint = env::args_os().nth(2).unwrap();
     let mut opts_arr = vec!["-o", fsname_arg];
-    if let Some(arg) = env::args_os().nth(3) {
-        if arg.to_str().unwrap() == "blkdev" {
-            opts_arr.append(&mut vec!["-o", "blkdev"]);
-        }
+    // Mount options such as "blkdev"
+    let mount_opts = env::args().nth(3);
+    if let Some(arg) = mount_opts.as_ref() {
+        opts_arr.append(&mut vec!["-o", arg.as_str()]);
     }
     let options = opts_arr.iter().map(OsStr::new).collect::<Vec<&OsStr>>();
 
-    fuse::mount(fs, &mountpoint, &options).unwrap();
+    if let Err(e) = bento_utils::options::mount(fs, &mountpoint, &options) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
 }
@@ -25,7 +25,7 @@ use alloc::vec::Vec;
 use core::cmp::min;
 use core::mem;
 use core::str;
-use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::sync::atomic::Ordering;
 
 use datablock::DataBlock;
 
@@ -47,10 +47,6 @@ use std::sync::*;
 
 use time::Timespec;
 
-static LAST_BLOCK: AtomicUsize = AtomicUsize::new(0);
-static LAST_INODE: AtomicUsize = AtomicUsize::new(0);
-static FIRST_I_LOOP: AtomicBool = AtomicBool::new(true);
-
 impl Xv6FileSystem {
     // Read xv6 superblock from disk
     fn readsb(&mut self) -> Result<(), libc::c_int> {
@@ -105,7 +101,7 @@ impl Xv6FileSystem {
         let mut allocated_block = None;
 
         // Bitmap operations on bitmap blocks
-        let most_recent = LAST_BLOCK.load(Ordering::SeqCst);
+        let most_recent = self.last_block.load(Ordering::SeqCst);
         let mut first = true;
         // last_segment is the bitmap block ID and block_offset is the offset for 'most_recent'
         let last_segment = most_recent - most_recent % BPB;
@@ -149,7 +145,7 @@ impl Xv6FileSystem {
             }
             // extract new block ID x
             if let Some(x) = allocated_block {
-                LAST_BLOCK.store(x as usize, Ordering::SeqCst);
+                self.last_block.store(x as usize, Ordering::SeqCst);
                 self.bzero(x as usize, &handle)?;
                 return Ok(x);
             }
@@ -232,7 +228,7 @@ impl Xv6FileSystem {
         let sb = self.sb.as_ref().unwrap();
         let num_inodes = sb.ninodes;
 
-        let most_recent = LAST_INODE.load(Ordering::SeqCst);
+        let most_recent = self.last_inode.load(Ordering::SeqCst);
         let mut first = true;
         let last_segment = most_recent - most_recent % IPB;
         let mut block_inum = last_segment;
@@ -242,8 +238,8 @@ impl Xv6FileSystem {
             let disk = self.disk.as_ref().unwrap();
             let iblock_new = iblock(block_inum, &sb) as u64;
             /* TODO: not actually correct for reusing blocks */
-            let is_first_loop = FIRST_I_LOOP.load(Ordering::SeqCst);
-            let curr_most_recent = LAST_INODE.load(Ordering::SeqCst);
+            let is_first_loop = self.first_i_loop.load(Ordering::SeqCst);
+            let curr_most_recent = self.last_inode.load(Ordering::SeqCst);
             let curr_last_segment = curr_most_recent - curr_most_recent % IPB;
             let new_blk = (iblock_new > iblock(curr_last_segment, &sb) as u64) && is_first_loop;
             if new_blk {
@@ -282,7 +278,7 @@ impl Xv6FileSystem {
                         core::mem::drop(locked_bh);
                         handle.journal_write(&mut bh);
                         if !first || inum > curr_most_recent {
-                            LAST_INODE.store(inum as usize, Ordering::SeqCst);
+                            self.last_inode.store(inum as usize, Ordering::SeqCst);
                         }
                         return self.iget(inum as u64);
                     }
@@ -291,7 +287,7 @@ impl Xv6FileSystem {
                 if block_inum >= num_inodes as usize {
                     block_inum = 0;
                     first = false;
-                    FIRST_I_LOOP.store(false, Ordering::SeqCst);
+                    self.first_i_loop.store(false, Ordering::SeqCst);
                 }
             } else {
                 let mut bh = disk.bread(iblock_new)?;
@@ -326,7 +322,7 @@ impl Xv6FileSystem {
                         dinode.dump_into(inode_slice).map_err(|_| libc::EIO)?;
                         handle.journal_write(&mut bh);
                         if !first || inum > curr_most_recent {
-                            LAST_INODE.store(inum as usize, Ordering::SeqCst);
+                            self.last_inode.store(inum as usize, Ordering::SeqCst);
                         }
                         return self.iget(inum as u64);
                     }
@@ -335,7 +331,7 @@ impl Xv6FileSystem {
                 if block_inum >= num_inodes as usize {
                     block_inum = 0;
                     first = false;
-                    FIRST_I_LOOP.store(false, Ordering::SeqCst);
+                    self.first_i_loop.store(false, Ordering::SeqCst);
                 }
             };
         }
@@ -480,7 +476,7 @@ impl Xv6FileSystem {
                     r = *dinode.nref.read().unwrap();
                 }
                 if r == 1 {
-                    let handle = self.log.as_ref().unwrap().begin_op(MAXOPBLOCKS as u32);
+                    let handle = self.log.as_ref().unwrap().begin_op(MAXOPBLOCKS as u32)?;
                     self.itrunc(inode, &mut internals, &handle)?;
                     internals.inode_type = 0;
                     self.iupdate(&internals, inode.inum, &handle)?;
@@ -512,7 +508,12 @@ impl Xv6FileSystem {
             if *addr == 0 {
                 let h = match handle {
                     Some(_) => handle.unwrap(),
-                    None => new_tx.get_or_insert_with(|| self.log.as_ref().unwrap().begin_op(2)),
+                    None => {
+                        if new_tx.is_none() {
+                            new_tx = Some(self.log.as_ref().unwrap().begin_op(2)?);
+                        }
+                        new_tx.as_ref().unwrap()
+                    }
                 };
                 return self.balloc(h).map(|blk_id| {
                     *addr = blk_id;
@@ -529,7 +530,12 @@ impl Xv6FileSystem {
             if *ind_blk_id == 0 {
                 let h = match handle {
                     Some(_) => handle.unwrap(),
-                    None => new_tx.get_or_insert_with(|| self.log.as_ref().unwrap().begin_op(5)),
+                    None => {
+                        if new_tx.is_none() {
+                            new_tx = Some(self.log.as_ref().unwrap().begin_op(5)?);
+                        }
+                        new_tx.as_ref().unwrap()
+                    }
                 };
                 self.balloc(h).map(|blk_id| {
                     *ind_blk_id = blk_id;
@@ -549,7 +555,12 @@ impl Xv6FileSystem {
                 // need to allocate blk
                 let h = match handle {
                     Some(_) => handle.unwrap(),
-                    None => new_tx.get_or_insert_with(|| self.log.as_ref().unwrap().begin_op(3)),
+                    None => {
+                        if new_tx.is_none() {
+                            new_tx = Some(self.log.as_ref().unwrap().begin_op(3)?);
+                        }
+                        new_tx.as_ref().unwrap()
+                    }
                 };
                 h.get_write_access(&bh);
                 let b_data = bh.data_mut();
@@ -574,7 +585,12 @@ impl Xv6FileSystem {
             if *dind_blk_id == 0 {
                 let h = match handle {
                     Some(_) => handle.unwrap(),
-                    None => new_tx.get_or_insert_with(|| self.log.as_ref().unwrap().begin_op(2)),
+                    None => {
+                        if new_tx.is_none() {
+                            new_tx = Some(self.log.as_ref().unwrap().begin_op(2)?);
+                        }
+                        new_tx.as_ref().unwrap()
+                    }
                 };
                 self.balloc(h).map(|blk_id| {
                     *dind_blk_id = blk_id;
@@ -594,7 +610,12 @@ impl Xv6FileSystem {
             if cell == 0 {
                 let h = match handle {
                     Some(_) => handle.unwrap(),
-                    None => new_tx.get_or_insert_with(|| self.log.as_ref().unwrap().begin_op(6)),
+                    None => {
+                        if new_tx.is_none() {
+                            new_tx = Some(self.log.as_ref().unwrap().begin_op(6)?);
+                        }
+                        new_tx.as_ref().unwrap()
+                    }
                 };
                 h.get_write_access(&bh);
                 let b_data = bh.data_mut();
@@ -619,7 +640,12 @@ impl Xv6FileSystem {
             if dcell == 0 {
                 let h = match handle {
                     Some(_) => handle.unwrap(),
-                    None => new_tx.get_or_insert_with(|| self.log.as_ref().unwrap().begin_op(3)),
+                    None => {
+                        if new_tx.is_none() {
+                            new_tx = Some(self.log.as_ref().unwrap().begin_op(3)?);
+                        }
+                        new_tx.as_ref().unwrap()
+                    }
                 };
                 h.get_write_access(&dbh);
                 let db_data = dbh.data_mut();
@@ -18,6 +18,7 @@ use crate::std;
 #[cfg(not(feature = "user"))]
 use crate::time;
 
+use alloc::boxed::Box;
 use alloc::collections::btree_map::BTreeMap;
 
 use alloc::string::String;
@@ -27,6 +28,7 @@ use alloc::vec::Vec;
 
 use core::mem;
 use core::str;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use bento_utils::BentoFilesystem;
 
@@ -34,6 +36,7 @@ use datablock::DataBlock;
 
 use bento_utils::consts::*;
 use bento_utils::*;
+use bento_utils::options::*;
 use fuse::consts::*;
 
 use fuse::*;
@@ -63,6 +66,11 @@ pub struct Xv6State {
     log: Option<Journal>,
 }
 
+impl TransferState for Xv6State {
+    const NAME: &'static str = "xv6fs::Xv6State";
+    const VERSION: u32 = 1;
+}
+
 pub struct Xv6FileSystem {
     pub log: Option<Journal>,
     pub sb: Option<Xv6fsSB>,
@@ -74,6 +82,13 @@ pub struct Xv6FileSystem {
     pub diskname: Option<String>,
     pub provino: Option<u64>,
     pub provino_mtime: Option<RwLock<Timespec>>,
+    // Whether operations are recorded in the provenance file, set by the prov mount option.
+    pub prov_enabled: AtomicBool,
+    // Allocation hints: the last block and inode allocated, and whether the inode search has
+    // yet to wrap around.
+    pub last_block: AtomicUsize,
+    pub last_inode: AtomicUsize,
+    pub first_i_loop: AtomicBool,
 }
 
 impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
@@ -81,6 +96,14 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         Xv6FileSystem::NAME
     }
 
+    fn new_instance(&self) -> Box<Self> {
+        Box::new(Xv6FileSystem::new())
+    }
+
+    fn mount_options(&self) -> &'static [MountOption] {
+        Xv6FileSystem::MOUNT_OPTIONS
+    }
+
     fn bento_destroy(&mut self, _req: &Request) {
         // Allow log and disk to be dropped
         self.log = None;
@@ -92,6 +115,7 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         &mut self,
         _req: &Request,
         devname: &OsStr,
+        opts: &MountOptions,
         fc_info: &mut FuseConnInfo,
     ) -> Result<(), i32> {
         fc_info.proto_major = BENTO_KERNEL_VERSION;
@@ -125,6 +149,7 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         self.sb = Some(sb_lock);
 
         self.iinit();
+        self.set_options(opts);
 
         fc_info.want |= FUSE_BIG_WRITES;
         fc_info.want |= FUSE_ATOMIC_O_TRUNC;
@@ -140,6 +165,11 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         return Ok(());
     }
 
+    fn bento_remount(&self, _req: &Request, opts: &MountOptions) -> Result<(), i32> {
+        self.set_options(opts);
+        Ok(())
+    }
+
     fn bento_statfs(&self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
         let sb_lock = self.sb.as_ref().unwrap();
         let fs_size = sb_lock.size;
@@ -180,7 +210,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
             }
 
             if flags & libc::O_TRUNC as u32 != 0 {
-                let handle = log.begin_op(2);
+                let handle = match log.begin_op(2) {
+                    Ok(x) => x,
+                    Err(x) => {
+                        reply.error(x);
+                        return;
+                    }
+                };
                 internals.size = 0;
                 if let Err(x) = self.iupdate(&internals, inode.inum, &handle) {
                     reply.error(x);
@@ -196,7 +232,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
             0
         };
         {
-            let handle = log.begin_op(6 as u32);
+            let handle = match log.begin_op(6 as u32) {
+                Ok(x) => x,
+                Err(x) => {
+                    reply.error(x);
+                    return;
+                }
+            };
 
             let msg = format!(
                 "op: open, pid: {}, flags: {}, inode: {}\n",
@@ -246,7 +288,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         reply: ReplyEmpty,
     ) {
         let log = self.log.as_ref().unwrap();
-        let handle = log.begin_op(6 as u32);
+        let handle = match log.begin_op(6 as u32) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
         let msg = format!(
             "op: close, pid: {}, inode: {}\n",
             req.pid(),
@@ -371,7 +419,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         };
         if let Some(fsize) = size {
             let log = self.log.as_ref().unwrap();
-            let handle = log.begin_op(2);
+            let handle = match log.begin_op(2) {
+                Ok(x) => x,
+                Err(x) => {
+                    reply.error(x);
+                    return;
+                }
+            };
             internals.size = fsize;
             if let Err(x) = self.iupdate(&internals, inode.inum, &handle) {
                 reply.error(x);
@@ -520,7 +574,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         let mut file_off = 0;
         while i < n {
             let log = self.log.as_ref().unwrap();
-            let handle = log.begin_op(MAXOPBLOCKS as u32);
+            let handle = match log.begin_op(MAXOPBLOCKS as u32) {
+                Ok(x) => x,
+                Err(x) => {
+                    reply.error(x);
+                    return;
+                }
+            };
             let inode = match self.iget(nodeid) {
                 Ok(x) => x,
                 Err(x) => {
@@ -811,7 +871,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
     ) {
         // Check if the file already exists
         let log = self.log.as_ref().unwrap();
-        let handle = log.begin_op(10);
+        let handle = match log.begin_op(10) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
         let child = match self.create_internal(parent, T_FILE, name, &handle) {
             Ok(x) => x,
             Err(x) => {
@@ -880,7 +946,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         reply: ReplyEntry,
     ) {
         let log = self.log.as_ref().unwrap();
-        let handle = log.begin_op(MAXOPBLOCKS as u32);
+        let handle = match log.begin_op(MAXOPBLOCKS as u32) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
         let child = match self.create_internal(parent, T_DIR, &name, &handle) {
             Ok(x) => x,
             Err(x) => {
@@ -941,7 +1013,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
 
     fn bento_rmdir(&self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let log = self.log.as_ref().unwrap();
-        let handle = log.begin_op(MAXOPBLOCKS as u32);
+        let handle = match log.begin_op(MAXOPBLOCKS as u32) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
         match self.dounlink(req, parent, name, &handle) {
             Ok(_) => reply.ok(),
             Err(x) => reply.error(x),
@@ -950,7 +1028,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
 
     fn bento_unlink(&self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let log = self.log.as_ref().unwrap();
-        let handle = log.begin_op(MAXOPBLOCKS as u32);
+        let handle = match log.begin_op(MAXOPBLOCKS as u32) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
         match self.dounlink(req, parent, name, &handle) {
             Ok(_) => {
                 reply.ok();
@@ -980,7 +1064,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         reply: ReplyEntry,
     ) {
         let log = self.log.as_ref().unwrap();
-        let handle = log.begin_op(MAXOPBLOCKS as u32);
+        let handle = match log.begin_op(MAXOPBLOCKS as u32) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
         // Create new file
         let child = match self.create_internal(nodeid, T_LNK, name, &handle) {
             Ok(x) => x,
@@ -1141,7 +1231,13 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
         reply: ReplyEmpty,
     ) {
         let log = self.log.as_ref().unwrap();
-        let handle = log.begin_op(MAXOPBLOCKS as u32);
+        let handle = match log.begin_op(MAXOPBLOCKS as u32) {
+            Ok(x) => x,
+            Err(x) => {
+                reply.error(x);
+                return;
+            }
+        };
         let no_replace = (flags & libc::RENAME_NOREPLACE as u32) > 0;
         let exchange = (flags & libc::RENAME_EXCHANGE as u32) > 0;
         let moved_ino;
@@ -1601,6 +1697,36 @@ impl BentoFilesystem<'_, Xv6State,Xv6State> for Xv6FileSystem {
 impl Xv6FileSystem {
     const NAME: &'static str = "xv6fs_ll\0";
 
+    pub const fn new() -> Self {
+        Xv6FileSystem {
+            log: None,
+            sb: None,
+            disk: None,
+            ilock_cache: None,
+            icache_map: None,
+            ialloc_lock: None,
+            balloc_lock: None,
+            diskname: None,
+            provino: None,
+            provino_mtime: None,
+            prov_enabled: AtomicBool::new(true),
+            last_block: AtomicUsize::new(0),
+            last_inode: AtomicUsize::new(0),
+            first_i_loop: AtomicBool::new(true),
+        }
+    }
+
+    // prov=on|off: record operations in the provenance file. On by default.
+    const MOUNT_OPTIONS: &'static [MountOption] = &[
+        MountOption { name: "prov", kind: MountOptionKind::Bool },
+    ];
+
+    fn set_options(&self, opts: &MountOptions) {
+        if let Some(prov) = opts.get_bool("prov") {
+            self.prov_enabled.store(prov, Ordering::SeqCst);
+        }
+    }
+
     fn create_internal<'a>(
         &'a self,
         nodeid: u64,
@@ -1893,7 +2019,7 @@ impl Xv6FileSystem {
 
         {
             let log = self.log.as_ref().unwrap();
-            let handle = log.begin_op(16);
+            let handle = log.begin_op(16).unwrap();
             let child = self.create_internal(1, T_FILE, name, &handle).unwrap();
             provino = Some(child.inum as u64);
         }
@@ -1907,6 +2033,9 @@ impl Xv6FileSystem {
     }
 
     fn write_prov_file(&self, msg: String, handle: &Handle) -> Result<(), libc::c_int> {
+        if !self.prov_enabled.load(Ordering::SeqCst) {
+            return Ok(());
+        }
         let icache = self.ilock_cache.as_ref().unwrap();
         let inode = self.iget(self.provino.unwrap())?;
 
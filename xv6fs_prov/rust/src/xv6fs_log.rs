@@ -6,6 +6,7 @@ use crate::std;
 use crate::println;
 #[cfg(not(feature = "user"))]
 use crate::bento_utils;
+use bento_utils::errors;
 
 use alloc::sync::Arc;
 
@@ -134,15 +135,12 @@ pub struct Log {
     lh: logheader,
 }
 
-pub static BLOCKER: AtomicBool = AtomicBool::new(false);
-
-fn wait_cont(_l: &mut Log) -> bool {
-    return BLOCKER.load(Ordering::SeqCst);
-}
-
 pub struct Journal {
     log_globl: Mutex<Log>,
     wait_q: Condvar,
+    blocker: AtomicBool,
+    // Set when a transaction went wrong. Nothing is committed after that.
+    aborted: AtomicBool,
     disk: Arc<Disk>,
 }
 
@@ -162,6 +160,8 @@ impl Journal {
                 },
             }),
             wait_q: Condvar::new(),
+            blocker: AtomicBool::new(false),
+            aborted: AtomicBool::new(false),
             disk: disk,
         };
         new_journal.initlog(start, len, bsize);
@@ -180,17 +180,21 @@ impl Journal {
 
     // Begin of a tx, must call begin_op in a filesystem syscall
     #[allow(dead_code)]
-    pub fn begin_op<'log>(&'log self, _size: u32) -> Handle<'log> {
+    // Fails with EROFS once the journal is aborted, like bento::kernel::journal::Journal::begin_op.
+    pub fn begin_op<'log>(&'log self, _size: u32) -> Result<Handle<'log>, libc::c_int> {
+        if self.aborted.load(Ordering::SeqCst) {
+            return Err(libc::EROFS);
+        }
         let mut waiting = false;
         loop {
             let mut guard = self.log_globl.lock().unwrap();
             if waiting {
                 // Wait on condvar
-                guard = self.wait_q.wait_while(guard, wait_cont).unwrap();
+                guard = self.wait_q.wait_while(guard, |_| self.blocker.load(Ordering::SeqCst)).unwrap();
             }
             let log: &mut Log = &mut *guard;
             if log.lh.n as usize + (log.outstanding as usize + 1) * MAXOPBLOCKS > LOGSIZE {
-                BLOCKER.store(false, Ordering::SeqCst);
+                self.blocker.store(false, Ordering::SeqCst);
                 waiting = true;
                 continue;
             } else {
@@ -199,9 +203,9 @@ impl Journal {
             }
         }
     
-        Handle {
+        Ok(Handle {
             xv6_log: self
-        }
+        })
     }
 
     #[allow(dead_code)]
@@ -212,26 +216,36 @@ impl Journal {
     
         let _com_out = self.commit(log);
         log.committing = 0;
-        BLOCKER.store(true, Ordering::SeqCst);
+        self.blocker.store(true, Ordering::SeqCst);
         self.wait_q.notify_one();
         self.disk.sync_all();
     }
 
+    // Put the file system in the errors state and drop everything not yet committed.
+    fn abort(&self, errno: libc::c_int) {
+        self.aborted.store(true, Ordering::SeqCst);
+        errors::fs_error(errno);
+    }
+
     // Only writes to buffer cache, does not persist; only install_trans will persist data.
+    // Fails with EIO and aborts the journal if the block can't be added to the transaction.
     #[allow(dead_code)]
-    pub fn log_write(&self, blk_no: u32) {
+    pub fn log_write(&self, blk_no: u32) -> Result<(), libc::c_int> {
         let mut guard = self.log_globl.lock().unwrap();
         let log: &mut Log = &mut *guard;
+        if self.aborted.load(Ordering::SeqCst) {
+            return Err(libc::EROFS);
+        }
         if log.lh.n as usize >= LOGSIZE || log.lh.n >= log.size {
-            // TODO: panic
-            println!("log_write: panic: too big transaction {}", log.lh.n);
-            loop {}
+            println!("log_write: too big transaction {}", log.lh.n);
+            self.abort(libc::EIO);
+            return Err(libc::EIO);
         }
     
         if log.outstanding < 1 {
-            // TODO: panic
-            println!("log_write: panic: log_write outside of transaction");
-            loop {}
+            println!("log_write: log_write outside of transaction");
+            self.abort(libc::EIO);
+            return Err(libc::EIO);
         }
     
         let mut i: usize = 0;
@@ -248,6 +262,7 @@ impl Journal {
         if i == log.lh.n as usize {
             log.lh.n += 1;
         }
+        return Ok(());
     }
 
     fn read_head(&self,log: &mut Log) -> Result<(), libc::c_int> {
@@ -378,8 +393,10 @@ impl Handle<'_> {
 
     pub fn journal_write(&self, bh: &mut BufferHead) -> i32 {
         bh.mark_buffer_dirty();
-        self.xv6_log.log_write(bh.blk_no as u32);
-        0
+        match self.xv6_log.log_write(bh.blk_no as u32) {
+            Ok(()) => 0,
+            Err(x) => -x,
+        }
     }
 }
 
@@ -391,22 +408,31 @@ impl Drop for Handle<'_> {
             let log: &mut Log = &mut *guard;
             log.outstanding -= 1;
             if log.committing != 0 {
-                println!("PANIC: log_committing");
-                loop {}
+                println!("log: handle dropped while committing");
+                self.xv6_log.abort(libc::EIO);
+            }
+            if self.xv6_log.aborted.load(Ordering::SeqCst) {
+                // Throw away the transaction rather than commit part of it.
+                if log.outstanding == 0 {
+                    log.lh.n = 0;
+                }
+                self.xv6_log.blocker.store(true, Ordering::SeqCst);
+                self.xv6_log.wait_q.notify_one();
+                return;
             }
 
             if log.outstanding == 0 {
                 do_commit = 1;
                 log.committing = 1;
             } else {
-                BLOCKER.store(true, Ordering::SeqCst);
+                self.xv6_log.blocker.store(true, Ordering::SeqCst);
                 self.xv6_log.wait_q.notify_one();
             }
 
             if do_commit != 0 {
                 let _com_out = self.xv6_log.commit(log);
                 log.committing = 0;
-                BLOCKER.store(true, Ordering::SeqCst);
+                self.xv6_log.blocker.store(true, Ordering::SeqCst);
                 self.xv6_log.wait_q.notify_one();
             }
         }